@@ -0,0 +1,110 @@
+// `synctool archives [list | prune [--dry-run]] [HOST...]` shows unison's
+// archive files here and on each host, marking the ones the other end has
+// too, or removes the ones left behind (see synctool_core::archives).
+
+use eyre::{bail, Result};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use synctool_core::{
+    archives::{self, ArchiveFile},
+    config::{Config, Host},
+    output::{human_bytes, human_duration},
+    runner::SystemRunner,
+};
+
+const USAGE: &str = "Usage: archives [list | prune [--dry-run]] [HOST...]";
+
+pub fn archives(config: &Config, args: &[String]) -> Result<()> {
+    let (prune, dry_run, names) = match args {
+        [command, rest @ ..] if command == "list" => (false, false, rest),
+        [command, flag, rest @ ..] if command == "prune" && flag == "--dry-run" => {
+            (true, true, rest)
+        }
+        [command, rest @ ..] if command == "prune" => (true, false, rest),
+        rest => (false, false, rest),
+    };
+    if names.iter().any(|name| name.starts_with('-')) {
+        bail!(USAGE);
+    }
+    let hosts = if names.is_empty() {
+        config.hosts.iter().collect::<Vec<_>>()
+    } else {
+        names
+            .iter()
+            .map(|name| config.host(name))
+            .collect::<Result<Vec<_>>>()?
+    };
+
+    let here = archives::list(&SystemRunner, None)?;
+    let mut there = Vec::new();
+    for host in hosts {
+        match archives::list(&SystemRunner, Some(host)) {
+            Ok(files) => there.push((host, files)),
+            Err(err) => println!("{:#}", err),
+        }
+    }
+    let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs() as i64;
+
+    if !prune {
+        show("This machine", &here, &there, now);
+        for (host, files) in &there {
+            show(&host.name, files, &[(host, here.clone())], now);
+        }
+        return Ok(());
+    }
+
+    let verb = if dry_run { "Would remove" } else { "Removed" };
+    let others = there
+        .iter()
+        .map(|(_, files)| files.as_slice())
+        .collect::<Vec<_>>();
+    let files = archives::prunable(config, &here, &others, now);
+    if !dry_run {
+        archives::remove(&SystemRunner, None, &files)?;
+    }
+    report(verb, "here", &files);
+    for (host, theirs) in &there {
+        let files = archives::prunable(config, theirs, &[&here], now);
+        if !dry_run {
+            archives::remove(&SystemRunner, Some(host), &files)?;
+        }
+        report(verb, &format!("on {}", host.name), &files);
+    }
+    Ok(())
+}
+
+fn show(place: &str, files: &[ArchiveFile], others: &[(&Host, Vec<ArchiveFile>)], now: i64) {
+    println!("{}", place);
+    if files.is_empty() {
+        println!("  no archives");
+    }
+    for file in files {
+        let also_on = others
+            .iter()
+            .filter(|(_, theirs)| theirs.iter().any(|theirs| theirs.hash() == file.hash()))
+            .map(|(host, _)| host.name.as_str())
+            .collect::<Vec<_>>();
+        println!(
+            "  {:34} {:12} {:10} {:>9} ago{}",
+            file.name,
+            file.kind(),
+            human_bytes(file.size as f64),
+            human_duration(Duration::from_secs(now.saturating_sub(file.modified) as u64)),
+            if also_on.is_empty() {
+                String::new()
+            } else {
+                format!("  (also on {})", also_on.join(", "))
+            }
+        );
+    }
+}
+
+fn report(verb: &str, place: &str, files: &[&ArchiveFile]) {
+    if files.is_empty() {
+        println!("Nothing to prune {}", place);
+        return;
+    }
+    println!("{} {} file(s) {}:", verb, files.len(), place);
+    for file in files {
+        println!("  {} ({})", file.name, file.kind());
+    }
+}
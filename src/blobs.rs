@@ -0,0 +1,53 @@
+// `synctool blobs [status | track | get HOST [PATH...]]` shows which blobs
+// (see synctool_core::blobs) are here, moves new files in the blob dirs into
+// the store without waiting for a sync, or fetches the missing ones under
+// PATH from HOST.
+
+use eyre::{bail, Result};
+use synctool_core::{blobs, config::Config, output::human_bytes, runner::SystemRunner};
+
+const USAGE: &str = "Usage: blobs [status | track | get HOST [PATH...]]";
+
+pub fn blobs(config: &Config, args: &[String]) -> Result<()> {
+    if config.blob_dirs.is_empty() {
+        bail!("Set dirs in the [blobs] section of the config first");
+    }
+    match args {
+        [] => status(config),
+        [command] if command == "status" => status(config),
+        [command] if command == "track" => {
+            let tracked = blobs::track(config)?;
+            if tracked == 0 {
+                println!("Nothing new to store");
+            }
+            Ok(())
+        }
+        [command, host, paths @ ..] if command == "get" => {
+            let host = config.host(host)?;
+            if !blobs::get(&SystemRunner, config, host, paths)? {
+                bail!("Couldn't get the blobs from {}", host.name);
+            }
+            Ok(())
+        }
+        _ => bail!(USAGE),
+    }
+}
+
+fn status(config: &Config) -> Result<()> {
+    let blobs = blobs::blobs(config)?;
+    let (present, missing): (Vec<_>, Vec<_>) = blobs.iter().partition(|blob| blob.present);
+    let bytes =
+        |blobs: &[&blobs::Blob]| human_bytes(blobs.iter().map(|b| b.size).sum::<u64>() as f64);
+    println!("{} blob(s) here ({})", present.len(), bytes(&present));
+    if !missing.is_empty() {
+        println!(
+            "{} missing ({}), get them with `synctool blobs get HOST`:",
+            missing.len(),
+            bytes(&missing)
+        );
+        for blob in &missing {
+            println!("  {}", blob.path);
+        }
+    }
+    Ok(())
+}
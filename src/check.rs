@@ -0,0 +1,168 @@
+// `synctool check [HOST...]` probes every configured host at once and prints
+// a line for each, to see at a glance which syncs are going to fail:
+//
+//     HOST     UP    SSH   UNISON  CLOCK  FREE
+//     desktop  ok    ok    ok      +1s    212.4 GB
+//     rpi      FAIL  -     -       -      -
+//
+// Unlike doctor it doesn't explain fixes, it just checks quickly. Problems
+// are a host that's down, ssh that needs a password, no unison on the remote,
+// a clock more than a minute off or less than a gigabyte free under the root.
+
+use eyre::{bail, Result};
+use std::{
+    process::{Command, Stdio},
+    thread,
+    time::{SystemTime, UNIX_EPOCH},
+};
+use synctool_core::{
+    config::{Config, Host},
+    output::human_bytes,
+    runner::SystemRunner,
+    shell_quote, ssh,
+    wake::reachable,
+};
+
+const MAX_SKEW: i64 = 60;
+const MIN_FREE: u64 = 1_000_000_000;
+
+#[derive(Default)]
+struct Row {
+    up: bool,
+    ssh: bool,
+    // None for hosts that only get an encrypted copy with rsync
+    unison: Option<bool>,
+    // Seconds the remote clock is ahead of this one
+    skew: Option<i64>,
+    free_bytes: Option<u64>,
+}
+
+impl Row {
+    fn ok(&self) -> bool {
+        self.up
+            && self.ssh
+            && self.unison != Some(false)
+            && self.skew.is_some_and(|skew| skew.abs() <= MAX_SKEW)
+            && self.free_bytes.is_some_and(|free| free >= MIN_FREE)
+    }
+}
+
+pub fn check(config: &Config, args: &[String]) -> Result<()> {
+    let hosts = if args.is_empty() {
+        config.hosts.iter().collect::<Vec<_>>()
+    } else {
+        args.iter()
+            .map(|name| config.host(name))
+            .collect::<Result<Vec<_>>>()?
+    };
+
+    let rows = thread::scope(|scope| {
+        let probes = hosts
+            .iter()
+            .map(|host| scope.spawn(move || probe(config, host)))
+            .collect::<Vec<_>>();
+        probes
+            .into_iter()
+            .map(|probe| probe.join().unwrap_or_default())
+            .collect::<Vec<_>>()
+    });
+
+    let width = hosts
+        .iter()
+        .map(|host| host.name.len())
+        .max()
+        .unwrap_or(0)
+        .max("HOST".len());
+    println!(
+        "{:width$}  {:5} {:5} {:7} {:6} FREE",
+        "HOST", "UP", "SSH", "UNISON", "CLOCK"
+    );
+    let mark = |ok: bool| if ok { "ok" } else { "FAIL" };
+    for (host, row) in hosts.iter().zip(&rows) {
+        let unison = match row.unison {
+            _ if !row.ssh => "-",
+            Some(ok) => mark(ok),
+            None => "n/a",
+        };
+        let skew = match row.skew {
+            Some(skew) if skew.abs() > MAX_SKEW => format!("{:+}s!", skew),
+            Some(skew) => format!("{:+}s", skew),
+            None => "-".to_string(),
+        };
+        let free = match row.free_bytes {
+            Some(free) if free < MIN_FREE => format!("{}!", human_bytes(free as f64)),
+            Some(free) => human_bytes(free as f64),
+            None => "-".to_string(),
+        };
+        println!(
+            "{:width$}  {:5} {:5} {:7} {:6} {}",
+            host.name,
+            mark(row.up),
+            if row.up { mark(row.ssh) } else { "-" },
+            unison,
+            skew,
+            free
+        );
+    }
+
+    let failing = rows.iter().filter(|row| !row.ok()).count();
+    if failing > 0 {
+        bail!("{} host(s) have problems", failing);
+    }
+    Ok(())
+}
+
+fn probe(config: &Config, host: &Host) -> Row {
+    let mut row = Row {
+        up: reachable(&SystemRunner, host).unwrap_or(false),
+        ..Row::default()
+    };
+    if !row.up {
+        return row;
+    }
+
+    // One connection answers everything: the time, the free space under the
+    // root and whether unison runs, a line each
+    let servercmd = host.unison_servercmd.as_deref().unwrap_or("unison");
+    let script = format!(
+        "date +%s; echo \"$(df -Pk {} 2>/dev/null | tail -n 1)\"; {} -version >/dev/null 2>&1 && echo yes || echo no",
+        shell_quote(host.root(config)),
+        servercmd
+    );
+    let before = now();
+    let output = Command::new("ssh")
+        .args(["-o", "BatchMode=yes"])
+        .args(ssh::args(host))
+        .args([&host.address, &script])
+        .stdin(Stdio::null())
+        .stderr(Stdio::null())
+        .output();
+    let after = now();
+    let output = match output {
+        Ok(output) if output.status.success() => output,
+        _ => return row,
+    };
+    row.ssh = true;
+
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let mut lines = stdout.lines();
+    row.skew = lines
+        .next()
+        .and_then(|time| time.trim().parse::<i64>().ok())
+        .map(|time| time - (before + after) / 2);
+    // "/dev/sda1 1024 512 512 50% /", with sizes in kilobytes
+    row.free_bytes = lines
+        .next()
+        .and_then(|line| line.split_whitespace().nth(3)?.parse::<u64>().ok())
+        .map(|kilobytes| kilobytes * 1024);
+    if host.gpg_recipient.is_none() {
+        row.unison = Some(lines.next().map(str::trim) == Some("yes"));
+    }
+    row
+}
+
+fn now() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |time| time.as_secs() as i64)
+}
@@ -0,0 +1,15 @@
+// `synctool checksums` is run over ssh by a --checksum sync on the other end,
+// which compares its output with the hashes of its own copy. Logging goes to
+// stderr, since the output is the list.
+
+use eyre::{bail, Result};
+use synctool_core::{checksum, config::Config, output};
+
+pub fn checksums(config: &Config, args: &[String]) -> Result<()> {
+    output::set_stderr(true);
+    if !args.is_empty() {
+        bail!("Usage: checksums");
+    }
+    print!("{}", checksum::to_text(&checksum::hashes(config)?));
+    Ok(())
+}
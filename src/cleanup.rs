@@ -0,0 +1,47 @@
+// `synctool cleanup [--dry-run] [HOST...]` removes the temp files and conflict
+// copies unison leaves around (see synctool_core::cleanup) from the root here
+// and on each host, or just lists them with --dry-run.
+
+use eyre::{bail, Result};
+use synctool_core::{cleanup, config::Config, runner::SystemRunner};
+
+pub fn cleanup(config: &Config, args: &[String]) -> Result<()> {
+    let mut dry_run = false;
+    let mut hosts = Vec::new();
+    for arg in args {
+        match arg.as_str() {
+            "--dry-run" => dry_run = true,
+            flag if flag.starts_with('-') => bail!("Usage: cleanup [--dry-run] [HOST...]"),
+            name => hosts.push(config.host(name)?),
+        }
+    }
+    if hosts.is_empty() {
+        hosts = config.hosts.iter().collect();
+    }
+    let verb = if dry_run { "Would remove" } else { "Removed" };
+
+    let files = cleanup::local(config)?;
+    if !dry_run {
+        cleanup::remove_local(config, &files)?;
+    }
+    report(verb, "here", &files);
+
+    for host in hosts {
+        match cleanup::remote(&SystemRunner, config, host, dry_run) {
+            Ok(files) => report(verb, &format!("on {}", host.name), &files),
+            Err(err) => println!("{}: {:#}", host.name, err),
+        }
+    }
+    Ok(())
+}
+
+fn report(verb: &str, place: &str, files: &[String]) {
+    if files.is_empty() {
+        println!("Nothing to clean up {}", place);
+        return;
+    }
+    println!("{} {} file(s) {}:", verb, files.len(), place);
+    for file in files {
+        println!("  {}", file);
+    }
+}
@@ -0,0 +1,486 @@
+// `synctool daemon` keeps syncing with the configured peers on a schedule.
+//
+// Syncs wait in a queue and run one at a time. The config is reloaded when
+// any of its files change or on SIGHUP, and the new hosts, ignores and
+// schedule apply from the next sync on. Whatever is queued stays queued; a
+// queued peer that was removed from the config is dropped when it comes up.
+// When one of the sync_on_connect connections comes up, or with
+// sync_on_unlock when someone logs in or unlocks the screen here, every peer
+// is queued right away, whenever they last synced. So is every peer in watch
+// mode, once a batch of changes under the root has settled (see watch.rs), and
+// with sync_on_remote_change, a peer is queued when its agent reports that
+// files changed there. On a relay, a peer the last sync failed with is pinged
+// every minute and queued as soon as it answers, to pass on the changes other
+// machines synced here while it was off. With a cooldown, a peer isn't synced
+// with again until that long after the last sync, except right after a
+// connection comes up, an unlock or a peer coming back, which are worth
+// catching up on at once. Projects with an interval of their own are left
+// out of all of those syncs and synced with every peer on their own schedule
+// instead, as a unison -path sync of just that directory.
+//
+// While it runs, a Unix socket at $XDG_RUNTIME_DIR/synctool/daemon.sock says
+// what the current sync is up to, for tray applets and bars: every client gets
+// a line of JSON right away and another whenever it changes (see progress.rs),
+// or every 30 seconds if it doesn't.
+
+use crate::{network, session};
+use eyre::{bail, Result};
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    fs,
+    io::Write,
+    os::unix::net::UnixListener,
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc::{self, Receiver},
+    },
+    thread::{self, sleep},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+use synctool_core::{
+    agent::Agent,
+    archive,
+    config::{Config, NotifyClass},
+    events::{self, Value},
+    history, notify,
+    output::human_duration,
+    progress::{self, Progress},
+    runner::SystemRunner,
+    runtime_dir, state, state_dir,
+    sync::{sync_to_host, SyncOptions},
+    wake::reachable_all,
+    watch,
+};
+
+static RELOAD_REQUESTED: AtomicBool = AtomicBool::new(false);
+
+extern "C" fn on_sighup(_: libc::c_int) {
+    RELOAD_REQUESTED.store(true, Ordering::SeqCst);
+}
+
+// Modification times of the config files, to notice when they change
+fn config_mtimes() -> Vec<(PathBuf, Option<SystemTime>)> {
+    Config::paths()
+        .into_iter()
+        .map(|path| {
+            let mtime = fs::metadata(&path).and_then(|meta| meta.modified()).ok();
+            (path, mtime)
+        })
+        .collect()
+}
+
+// Sends the digest of the history if the last one went out at least [daemon]
+// digest ago. When is kept in the state dir, so restarts don't send it again.
+fn send_digest_if_due(config: &Config) -> Result<()> {
+    let path = state_dir().join("digest-sent");
+    let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+    let last = fs::read_to_string(&path)
+        .ok()
+        .and_then(|last| last.trim().parse::<u64>().ok());
+    match last {
+        // The first period starts now
+        None => {}
+        Some(last) if now.saturating_sub(last) < config.daemon_digest => return Ok(()),
+        Some(_) => {
+            let runs = history::load()?;
+            let since = now.saturating_sub(config.daemon_digest);
+            let message = history::digest(&runs, since, now).join("\n");
+            notify::send(&SystemRunner, config, NotifyClass::Digest, &message);
+        }
+    }
+    fs::create_dir_all(state_dir())?;
+    fs::write(path, now.to_string())?;
+    Ok(())
+}
+
+fn socket_path() -> PathBuf {
+    runtime_dir().join("daemon.sock")
+}
+
+// Starts answering on the status socket
+fn serve_progress() -> Result<PathBuf> {
+    let path = socket_path();
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    // Left behind by a daemon that didn't get to clean up
+    let _ = fs::remove_file(&path);
+    let listener = UnixListener::bind(&path)?;
+    progress::watch();
+    thread::spawn(move || {
+        for client in listener.incoming().flatten() {
+            thread::spawn(move || {
+                let mut client = client;
+                let (mut seen, mut current) = progress::wait(0, Duration::ZERO);
+                // Until the client goes away
+                while writeln!(client, "{}", progress::to_json(&current)).is_ok() {
+                    (seen, current) = progress::wait(seen, Duration::from_secs(30));
+                }
+            });
+        }
+    });
+    Ok(path)
+}
+
+fn peers(config: &Config) -> Vec<String> {
+    if config.daemon_peers.is_empty() {
+        config.peer.iter().cloned().collect()
+    } else {
+        config.daemon_peers.clone()
+    }
+}
+
+// Watches the agent of every peer that has one, each in its own thread,
+// sending the peer's name and how many files changed whenever it reports
+// changes. A lost connection is retried every minute.
+fn watch_peers(config: &Config) -> Receiver<(String, usize)> {
+    let (sender, receiver) = mpsc::channel();
+    for peer in peers(config) {
+        let host = match config.host(&peer) {
+            Ok(host) if host.agent.is_some() => host.clone(),
+            _ => {
+                warn!(
+                    "{} has no agent configured, so changes there won't start a sync",
+                    peer
+                );
+                continue;
+            }
+        };
+        let sender = sender.clone();
+        thread::spawn(move || {
+            // Only the first failure in a row is worth a warning
+            let mut failing = false;
+            loop {
+                let result = Agent::connect(&SystemRunner, &host).and_then(|mut agent| {
+                    if failing {
+                        log!("Watching {} for changes again", host.name);
+                        failing = false;
+                    }
+                    agent.watch(|count| sender.send((host.name.clone(), count)).is_ok())
+                });
+                match result {
+                    // The daemon stopped listening
+                    Ok(()) => return,
+                    Err(err) if !failing => {
+                        warn!("Not watching {} for changes: {:#}", host.name, err);
+                        failing = true;
+                    }
+                    Err(_) => {}
+                }
+                sleep(Duration::from_secs(60));
+            }
+        });
+    }
+    receiver
+}
+
+pub fn run(overrides: &[String], args: &[String]) -> Result<()> {
+    if !args.is_empty() {
+        bail!("Usage: daemon");
+    }
+
+    let mut config = Config::load(overrides)?;
+    if peers(&config).is_empty() {
+        bail!("Set peers in the [daemon] section of the config, or a peer in [sync]");
+    }
+
+    unsafe {
+        libc::signal(libc::SIGHUP, on_sighup as *const () as libc::sighandler_t);
+    }
+
+    let mut mtimes = config_mtimes();
+    let mut last_sync: HashMap<String, Instant> = HashMap::new();
+    let mut queue: VecDeque<String> = VecDeque::new();
+    // Peers already notified about being stale, until they sync again
+    let mut notified_stale: HashSet<String> = HashSet::new();
+    let mut last_archive: Option<Instant> = None;
+    let mut last_digest_check: Option<Instant> = None;
+    let mut network = network::Watcher::new();
+    // Started the first time sync_on_unlock is on
+    let mut session_events = None;
+    // Started the first time watch is on, and again if the root or ignores
+    // change
+    let mut watcher: Option<watch::Watcher> = None;
+    let mut batch = watch::Batch::default();
+    // Started the first time sync_on_remote_change is on
+    let mut remote_changes = None;
+    // Peers the last sync failed with, and when the relay last checked on them
+    let mut unreachable: HashSet<String> = HashSet::new();
+    let mut last_probe: Option<Instant> = None;
+    // Queued peers that skip the cooldown
+    let mut forced: HashSet<String> = HashSet::new();
+    // Projects due a sync of their own, and when they last had one
+    let mut project_queue: VecDeque<String> = VecDeque::new();
+    let mut last_project_sync: HashMap<String, Instant> = HashMap::new();
+    phase!("Daemon started, syncing with {}", peers(&config).join(", "));
+    match serve_progress() {
+        Ok(path) => log!("Progress at {}", path.display()),
+        Err(err) => warn!("Couldn't open the status socket: {err:#}"),
+    }
+
+    loop {
+        let current_mtimes = config_mtimes();
+        if RELOAD_REQUESTED.swap(false, Ordering::SeqCst) || current_mtimes != mtimes {
+            mtimes = current_mtimes;
+            match Config::load(overrides) {
+                Ok(new_config) => {
+                    config = new_config;
+                    log!(
+                        "Reloaded config, syncing with {}",
+                        peers(&config).join(", ")
+                    );
+                }
+                Err(err) => warn!("Keeping the old config: {err:#}"),
+            }
+        }
+
+        for connection in network.connected(&config.daemon_sync_on_connect) {
+            log!("{} connected, catching up", connection);
+            for peer in peers(&config) {
+                forced.insert(peer.clone());
+                if !queue.contains(&peer) {
+                    queue.push_back(peer);
+                }
+            }
+        }
+
+        if config.daemon_sync_on_unlock && session_events.is_none() {
+            session_events = match session::watch() {
+                Ok(events) => Some(events),
+                Err(err) => {
+                    warn!("Couldn't watch for logins and unlocks: {}", err);
+                    Some(mpsc::channel().1)
+                }
+            };
+        }
+        let session_event = session_events
+            .as_ref()
+            .and_then(|events| events.try_iter().last());
+        if let Some(event) = session_event.filter(|_| config.daemon_sync_on_unlock) {
+            log!("Session {}, catching up", event);
+            for peer in peers(&config) {
+                forced.insert(peer.clone());
+                if !queue.contains(&peer) {
+                    queue.push_back(peer);
+                }
+            }
+        }
+
+        if config.daemon_sync_on_remote_change && remote_changes.is_none() {
+            remote_changes = Some(watch_peers(&config));
+        }
+        let changed = remote_changes
+            .as_ref()
+            .map_or(Vec::new(), |changes| changes.try_iter().collect());
+        for (peer, count) in changed {
+            if config.daemon_sync_on_remote_change && !queue.contains(&peer) {
+                log!("{} file(s) changed on {}, syncing", count, peer);
+                queue.push_back(peer);
+            }
+        }
+
+        if config.daemon_watch {
+            let stale = watcher.as_ref().is_none_or(|watcher| {
+                watcher.root != config.root || watcher.ignores != config.ignores
+            });
+            if stale {
+                watcher = Some(watch::Watcher::new(&config.root, &config.ignores));
+                batch = watch::Batch::default();
+            }
+            if let Some(watcher) = &mut watcher {
+                watcher.read(&mut batch);
+            }
+            if batch.ready(&config) {
+                log!("{} file(s) changed, syncing", batch.changed());
+                batch = watch::Batch::default();
+                for peer in peers(&config) {
+                    if !queue.contains(&peer) {
+                        queue.push_back(peer);
+                    }
+                }
+            }
+        }
+
+        let probe_due = last_probe.is_none_or(|last| last.elapsed() >= Duration::from_secs(60));
+        if config.daemon_relay && !unreachable.is_empty() && probe_due {
+            last_probe = Some(Instant::now());
+            let hosts = peers(&config)
+                .iter()
+                .filter(|peer| unreachable.contains(*peer) && !queue.contains(peer))
+                .filter_map(|peer| config.host(peer).ok())
+                .collect::<Vec<_>>();
+            for (host, back) in hosts.iter().zip(reachable_all(&SystemRunner, &hosts)) {
+                if back {
+                    log!("{} is back, passing on changes", host.name);
+                    forced.insert(host.name.clone());
+                    queue.push_back(host.name.clone());
+                }
+            }
+        }
+
+        let interval = Duration::from_secs(config.daemon_interval);
+        for peer in peers(&config) {
+            let due = last_sync
+                .get(&peer)
+                .is_none_or(|last| last.elapsed() >= interval);
+            if due && !queue.contains(&peer) {
+                queue.push_back(peer);
+            }
+        }
+
+        for project in config
+            .projects
+            .iter()
+            .filter(|project| project.interval > 0)
+        {
+            let due = last_project_sync
+                .get(&project.name)
+                .is_none_or(|last| last.elapsed() >= Duration::from_secs(project.interval));
+            if due && !project_queue.contains(&project.name) {
+                project_queue.push_back(project.name.clone());
+            }
+        }
+
+        let digest_check_due =
+            last_digest_check.is_none_or(|last| last.elapsed() >= Duration::from_secs(60));
+        if config.daemon_digest > 0 && digest_check_due {
+            if let Err(err) = send_digest_if_due(&config) {
+                warn!("Couldn't send the digest: {err:#}");
+            }
+            last_digest_check = Some(Instant::now());
+        }
+
+        let archive_due = config.archive_interval > 0
+            && last_archive
+                .is_none_or(|last| last.elapsed() >= Duration::from_secs(config.archive_interval));
+        let cooldown = Duration::from_secs(config.daemon_cooldown);
+        let next = queue.iter().position(|peer| {
+            forced.contains(peer)
+                || last_sync
+                    .get(peer)
+                    .is_none_or(|last| last.elapsed() >= cooldown)
+        });
+        if next.is_none() && archive_due {
+            if let Err(err) = archive::archive(&SystemRunner, &config, false) {
+                error!("Archive failed: {err:#}");
+            }
+            last_archive = Some(Instant::now());
+        } else if let Some(peer) = next.and_then(|next| queue.remove(next)) {
+            forced.remove(&peer);
+            match config.host(&peer) {
+                Ok(host) => {
+                    phase!("Syncing with {}", peer);
+                    events::emit("run_start", &[("peer", Value::Str(&peer))]);
+                    if let Err(err) = state::syncing(&peer) {
+                        warn!("Couldn't update the status file: {err:#}");
+                    }
+                    progress::update(|progress| {
+                        *progress = Progress {
+                            peer: Some(peer.clone()),
+                            ..Progress::default()
+                        }
+                    });
+                    let options = SyncOptions {
+                        ignores: config
+                            .projects
+                            .iter()
+                            .filter(|project| project.interval > 0)
+                            .map(|project| format!("Path {}", project.path))
+                            .collect(),
+                        ..SyncOptions::default()
+                    };
+                    let result = sync_to_host(&SystemRunner, &config, host, &options);
+                    // Nobody reads the daemon's output, so it notifies instead
+                    if let Err(err) = &result {
+                        error!("Sync with {} failed: {err:#}", peer);
+                        // Once per streak of failures rather than every retry
+                        if unreachable.insert(peer.clone()) {
+                            let message = format!("Sync with {} failed: {:#}", peer, err);
+                            notify::send(&SystemRunner, &config, NotifyClass::Failure, &message);
+                        }
+                    } else {
+                        unreachable.remove(&peer);
+                        let message = format!("Synced with {}", peer);
+                        notify::send(&SystemRunner, &config, NotifyClass::Success, &message);
+                    }
+                    let values =
+                        history::values(&events::take_timings(), events::take_transferred());
+                    if let Err(err) =
+                        history::record(&peer, result.is_ok(), events::take_found(), &values)
+                    {
+                        warn!("Couldn't record this sync in the history: {err:#}");
+                    }
+
+                    let runs = history::load().unwrap_or_default();
+                    match history::stale(&runs, &peer, config.stale_after_hours) {
+                        Some(age) if !notified_stale.contains(&peer) => {
+                            let message = format!(
+                                "No successful sync with {} in {}",
+                                peer,
+                                human_duration(age)
+                            );
+                            warn!("{}", message);
+                            notify::send(&SystemRunner, &config, NotifyClass::Failure, &message);
+                            notified_stale.insert(peer.clone());
+                        }
+                        Some(_) => {}
+                        None => {
+                            notified_stale.remove(&peer);
+                        }
+                    }
+                    let ok = Value::Bool(result.is_ok());
+                    events::emit("run_end", &[("peer", Value::Str(&peer)), ("ok", ok)]);
+                    progress::update(|progress| *progress = Progress::default());
+                }
+                Err(_) => warn!(
+                    "Dropping queued sync with {}, it's no longer configured",
+                    peer
+                ),
+            }
+            last_sync.insert(peer, Instant::now());
+            // The sync carried whatever had changed, and its own changes to
+            // the tree shouldn't start another one
+            if let Some(watcher) = &mut watcher {
+                watcher.read(&mut watch::Batch::default());
+            }
+            batch = watch::Batch::default();
+        } else if let Some(name) = project_queue.pop_front() {
+            match config.projects.iter().find(|project| project.name == name) {
+                Some(project) => {
+                    let options = SyncOptions {
+                        paths: vec![project.path.clone()],
+                        ..SyncOptions::default()
+                    };
+                    for peer in peers(&config) {
+                        let host = match config.host(&peer) {
+                            Ok(host) => host,
+                            Err(_) => continue,
+                        };
+                        phase!("Syncing {} with {}", project.name, peer);
+                        progress::update(|progress| {
+                            *progress = Progress {
+                                peer: Some(peer.clone()),
+                                ..Progress::default()
+                            }
+                        });
+                        if let Err(err) = sync_to_host(&SystemRunner, &config, host, &options) {
+                            error!("Sync of {} with {} failed: {err:#}", project.name, peer);
+                        }
+                        progress::update(|progress| *progress = Progress::default());
+                    }
+                }
+                None => warn!(
+                    "Dropping queued sync of {}, it's no longer configured",
+                    name
+                ),
+            }
+            last_project_sync.insert(name, Instant::now());
+            if let Some(watcher) = &mut watcher {
+                watcher.read(&mut watch::Batch::default());
+            }
+        } else {
+            sleep(Duration::from_secs(1));
+        }
+    }
+}
@@ -0,0 +1,324 @@
+// `synctool doctor [HOST...]` checks everything a sync run depends on and
+// says how to fix whatever's broken, instead of finding out halfway through.
+
+use eyre::Result;
+use std::process::{Command, Stdio};
+use synctool_core::{
+    agent::Agent,
+    bmc,
+    config::{Config, Host, PowerMethod, WakeMethod},
+    homeassistant,
+    output::human_bytes,
+    power::{self, PowerAction::*},
+    protocol::Message,
+    runner::SystemRunner,
+    ssh,
+    unison::choose_unisons,
+    wake::reachable,
+};
+
+struct Report {
+    problems: usize,
+}
+
+impl Report {
+    fn check(&mut self, ok: bool, what: &str, fix: &str) {
+        if ok {
+            println!("[ ok ] {}", what);
+        } else {
+            println!("[FAIL] {}", what);
+            println!("       fix: {}", fix);
+            self.problems += 1;
+        }
+    }
+}
+
+// Runs a command quietly and reports whether it succeeded
+fn succeeds(command: &mut Command) -> bool {
+    command
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .status()
+        .is_ok_and(|status| status.success())
+}
+
+fn ssh(host: &Host, script: &str) -> Command {
+    let mut command = Command::new("ssh");
+    command
+        .args(["-o", "BatchMode=yes"])
+        .args(ssh::args(host))
+        .args([&host.address, script]);
+    command
+}
+
+fn first_word(command: &str) -> &str {
+    command.split_whitespace().next().unwrap_or_default()
+}
+
+fn local_command_exists(name: &str) -> bool {
+    succeeds(Command::new("sh").args(["-c", &format!("command -v {}", name)]))
+}
+
+pub fn doctor(config: &Config, args: &[String]) -> Result<()> {
+    let mut report = Report { problems: 0 };
+
+    let hosts = if args.is_empty() {
+        config.hosts.iter().collect::<Vec<_>>()
+    } else {
+        args.iter()
+            .map(|name| config.host(name))
+            .collect::<Result<Vec<_>>>()?
+    };
+
+    println!("This machine");
+    let unison_ok = succeeds(Command::new(&config.unison.path).arg("-version"));
+    report.check(
+        unison_ok,
+        &format!("unison runs ({})", config.unison.path),
+        "install unison, or set path in the [unison] section of the config",
+    );
+    report.check(
+        local_command_exists("ssh"),
+        "ssh is installed",
+        "install an OpenSSH client",
+    );
+    let backend = power::local_backend(&SystemRunner, config);
+    let local_power = [
+        (Suspend, &config.power_suspend_command, "-ls"),
+        (Shutdown, &config.power_shutdown_command, "-lss"),
+    ];
+    for (action, configured, flag) in local_power {
+        let program = match configured {
+            Some(command) => Ok(first_word(command).to_string()),
+            None => backend
+                .command(action)
+                .map(|command| command.get_program().to_string_lossy().into_owned()),
+        };
+        match program {
+            Ok(program) => report.check(
+                local_command_exists(&program),
+                &format!("{} is available for {}", program, flag),
+                "install it, or set [power] backend or the command in the config",
+            ),
+            Err(err) => report.check(
+                false,
+                &format!("the {} power backend works for {}", backend.name(), flag),
+                &format!("{}", err),
+            ),
+        }
+    }
+
+    for host in &hosts {
+        println!();
+        println!("{} ({})", host.name, host.address);
+
+        let up = reachable(&SystemRunner, host).unwrap_or(false);
+        report.check(
+            up,
+            &format!("responds to {}", host.probe),
+            "check the address in the config, or wake the machine",
+        );
+
+        if host.power_method.bmc() {
+            let tool = match host.power_method {
+                PowerMethod::Ipmi => "ipmitool",
+                PowerMethod::Amt => "amttool",
+                _ => "curl",
+            };
+            report.check(
+                local_command_exists(tool),
+                &format!("{} is installed", tool),
+                &format!("install {}", tool),
+            );
+            report.check(
+                bmc::is_on(&SystemRunner, host).is_ok(),
+                "the management controller answers",
+                &format!(
+                    "check bmc_address and bmc_user, and store the password in the keyring as bmc@{}",
+                    host.name
+                ),
+            );
+        }
+
+        let ssh_ok = succeeds(&mut ssh(host, "true"));
+        report.check(
+            ssh_ok,
+            "ssh key authentication works",
+            &match &host.identity_file {
+                Some(identity_file) => {
+                    format!("run ssh-copy-id -i {} {}", identity_file, host.address)
+                }
+                None => format!(
+                    "run ssh-copy-id {} and load your key with ssh-add",
+                    host.address
+                ),
+            },
+        );
+        if !ssh_ok {
+            continue;
+        }
+
+        if host.gpg_recipient.is_some() {
+            report.check(
+                local_command_exists("gpg"),
+                "gpg is available for encrypted pushes",
+                "install gnupg and import the recipient's public key",
+            );
+            report.check(
+                succeeds(&mut ssh(host, "command -v rsync")),
+                "rsync is installed remotely",
+                "install rsync on the remote",
+            );
+            continue;
+        }
+
+        let servercmd = host.unison_servercmd.as_deref().unwrap_or("unison");
+        let remote_unison = succeeds(&mut ssh(host, &format!("{} -version", servercmd)));
+        report.check(
+            remote_unison,
+            &format!("unison runs remotely ({})", servercmd),
+            "install unison on the remote, or set unison_servercmd for this host",
+        );
+        if unison_ok && remote_unison {
+            report.check(
+                choose_unisons(&SystemRunner, config, &mut (*host).clone())?,
+                "unison versions are compatible",
+                "install matching unisons (or 2.52+ on both), or list them in [unison] binaries and unison_servercmds",
+            );
+        }
+
+        if host.agent.is_some() {
+            let status = Agent::connect(&SystemRunner, host)
+                .and_then(|mut agent| agent.request(Message::new("status")));
+            report.check(
+                status.is_ok(),
+                &match &status {
+                    Ok(status) => format!(
+                        "agent answers (idle: {}, free: {})",
+                        status.get("idle").unwrap_or("?"),
+                        human_bytes(
+                            status
+                                .get("free_bytes")
+                                .unwrap_or("0")
+                                .parse()
+                                .unwrap_or(0.)
+                        )
+                    ),
+                    Err(_) => "agent answers".to_string(),
+                },
+                "install synctool on the remote, or fix agent for this host in the config",
+            );
+        }
+
+        match host.power_method {
+            PowerMethod::Sudo | PowerMethod::Doas | PowerMethod::Root => {
+                let suspend = first_word(&host.suspend_command);
+                report.check(
+                    succeeds(&mut ssh(host, &format!("command -v {}", suspend))),
+                    &format!("{} is available remotely for -s", suspend),
+                    "install it on the remote, or set suspend_command for this host",
+                );
+                match host.power_method {
+                    PowerMethod::Sudo => report.check(
+                        host.sudo_password_from_keyring
+                            || succeeds(&mut ssh(host, "sudo -n true")),
+                        "sudo works without a password for -ss",
+                        "allow NOPASSWD shutdown in sudoers, or set power_method = \"logind\"",
+                    ),
+                    PowerMethod::Doas => report.check(
+                        succeeds(&mut ssh(host, "doas -n true")),
+                        "doas works without a password for -ss",
+                        "add a nopass rule for the shutdown command to doas.conf",
+                    ),
+                    _ => report.check(
+                        succeeds(
+                            Command::new("ssh")
+                                .args(["-o", "BatchMode=yes", "-l", "root"])
+                                .args(ssh::args(host))
+                                .args([&host.address, "true"]),
+                        ),
+                        "logging in as root works for -ss",
+                        "add this machine's key to root's authorized_keys, or set power_method = \"sudo\"",
+                    ),
+                }
+            }
+            PowerMethod::Agent | PowerMethod::Ipmi | PowerMethod::Amt | PowerMethod::Redfish => {}
+            PowerMethod::HomeAssistant => {
+                report.check(
+                    homeassistant::check(&SystemRunner, config).is_ok(),
+                    "Home Assistant answers",
+                    "check [homeassistant] url, and its token in the config or the keyring",
+                );
+            }
+            PowerMethod::Logind => {
+                report.check(
+                    succeeds(&mut ssh(host, "command -v systemctl")),
+                    "systemctl is available remotely",
+                    "logind power needs systemd, use power_method = \"sudo\" instead",
+                );
+            }
+        }
+    }
+
+    for host in hosts.iter().filter(|host| !host.power_method.bmc()) {
+        let command = match &host.wake_command {
+            Some(command) => command,
+            None => continue,
+        };
+        println!();
+        println!("Waking {}", host.name);
+        let program = command.split_whitespace().next().unwrap_or_default();
+        match host.wake_via.as_deref().map(|via| config.host(via)) {
+            Some(Ok(via)) => report.check(
+                succeeds(&mut ssh(via, &format!("command -v {}", program))),
+                &format!("{} has {}", via.name, program),
+                &format!("install {} on {} and make it executable", program, via.name),
+            ),
+            Some(Err(err)) => report.check(false, &err.to_string(), "fix wake_via in the config"),
+            None => report.check(
+                local_command_exists(program),
+                &format!("{} is available", program),
+                &format!("install {} on this machine", program),
+            ),
+        }
+    }
+
+    // Each place wake steps send magic packets from needs wakeonlan
+    for host in &hosts {
+        let mut checked = Vec::new();
+        for step in &host.wake {
+            let via = match &step.method {
+                WakeMethod::Wol { via, .. } => via.as_deref(),
+                _ => continue,
+            };
+            if checked.contains(&via) {
+                continue;
+            }
+            checked.push(via);
+            println!();
+            println!("Waking {} with {}", host.name, step);
+            match via.map(|via| config.host(via)) {
+                Some(Ok(via)) => report.check(
+                    succeeds(&mut ssh(via, "command -v wakeonlan")),
+                    &format!("{} has wakeonlan", via.name),
+                    &format!("install wakeonlan on {}", via.name),
+                ),
+                Some(Err(err)) => report.check(false, &err.to_string(), "fix wake in the config"),
+                None => report.check(
+                    local_command_exists("wakeonlan"),
+                    "wakeonlan is available",
+                    "install wakeonlan on this machine",
+                ),
+            }
+        }
+    }
+
+    println!();
+    if report.problems == 0 {
+        println!("Everything looks good");
+    } else {
+        println!("{} problem(s) found", report.problems);
+    }
+    Ok(())
+}
@@ -0,0 +1,108 @@
+// `synctool config dump [--json]` prints every setting as it ends up after
+// the config files, the environment and -c flags, with where each one came
+// from: a file and line, the environment, a -c flag or the built in default.
+// The TOML loads back as the same config. With --json it's one object per
+// setting per line, like the events. Tokens and keys are printed as
+// "<redacted>" unless --show-secrets is given.
+
+use eyre::{bail, Result};
+use std::collections::BTreeMap;
+use synctool_core::{
+    config::{format_key, format_value, parse_document, Config, Value},
+    events::json_string,
+};
+
+// The table each is in, by its first part, and its key
+const SECRETS: &[(&str, &str)] = &[
+    ("agent", "token"),
+    ("syncthing", "api_key"),
+    ("homeassistant", "token"),
+    ("hosts", "agent_token"),
+];
+
+pub fn dump(overrides: &[String], args: &[String]) -> Result<()> {
+    let mut json = false;
+    let mut show_secrets = false;
+    for arg in args {
+        match arg.as_str() {
+            "--json" => json = true,
+            "--show-secrets" => show_secrets = true,
+            _ => bail!("Usage: config dump [--json] [--show-secrets]"),
+        }
+    }
+
+    let layers = Config::layers(overrides)?;
+    let config = Config::load(overrides)?;
+    let files = Config::paths()
+        .iter()
+        .map(|path| path.display().to_string())
+        .collect::<Vec<_>>();
+
+    // The last layer that sets each one wins
+    let mut sources = BTreeMap::new();
+    for (source, text) in &layers {
+        for (table, entries) in parse_document(text)? {
+            for (key, entry) in entries.entries {
+                let from = if files.contains(source) {
+                    format!("{}:{}", source, entry.line)
+                } else {
+                    source.clone()
+                };
+                sources.insert((table.clone(), key), from);
+            }
+        }
+    }
+
+    for (table, entries) in config.to_document() {
+        if !json {
+            let name = table.iter().map(|part| format_key(part));
+            println!("[{}]", name.collect::<Vec<_>>().join("."));
+        }
+        for (key, mut entry) in entries.entries {
+            if !show_secrets && is_secret(&table, &key) {
+                entry.value = Value::String("<redacted>".to_string());
+            }
+            let from = sources
+                .get(&(table.clone(), key.clone()))
+                .map_or("default", String::as_str);
+            if json {
+                println!(
+                    "{{\"table\":{},\"key\":{},\"value\":{},\"from\":{}}}",
+                    json_string(&table.join(".")),
+                    json_string(&key),
+                    json_value(&entry.value),
+                    json_string(from)
+                );
+            } else {
+                println!(
+                    "{} = {}  # {}",
+                    format_key(&key),
+                    format_value(&entry.value),
+                    from
+                );
+            }
+        }
+        if !json {
+            println!();
+        }
+    }
+    Ok(())
+}
+
+fn is_secret(table: &[String], key: &str) -> bool {
+    SECRETS
+        .iter()
+        .any(|(first, secret)| table.first().is_some_and(|part| part == first) && key == *secret)
+}
+
+fn json_value(value: &Value) -> String {
+    match value {
+        Value::String(s) => json_string(s),
+        Value::Integer(n) => n.to_string(),
+        Value::Bool(b) => b.to_string(),
+        Value::Array(values) => {
+            let values = values.iter().map(json_value).collect::<Vec<_>>();
+            format!("[{}]", values.join(","))
+        }
+    }
+}
@@ -0,0 +1,52 @@
+// `synctool duplicates [MIN_SIZE]` lists files under the root with the same
+// contents in more than one project (directory at the top of the root), the
+// ones wasting the most space first. Each copy is sent to and stored on every
+// peer, so deduplicating them here saves on all of them.
+
+use eyre::{bail, Result};
+use synctool_core::{checksum, config::Config, output::human_bytes};
+
+pub fn duplicates(config: &Config, args: &[String]) -> Result<()> {
+    let min_size = match args {
+        [] => 1024,
+        [size] => match size.parse() {
+            Ok(size) => size,
+            Err(_) => bail!("MIN_SIZE must be a number of bytes"),
+        },
+        _ => bail!("Usage: duplicates [MIN_SIZE]"),
+    };
+
+    let project = |path: &str| path.split('/').next().unwrap_or_default().to_string();
+    let groups = checksum::duplicates(config, min_size)?
+        .into_iter()
+        .filter(|(_, paths)| paths.iter().any(|path| project(path) != project(&paths[0])))
+        .collect::<Vec<_>>();
+    if groups.is_empty() {
+        println!(
+            "No files of {} or more are in more than one project",
+            human_bytes(min_size as f64)
+        );
+        return Ok(());
+    }
+
+    let mut wasted = 0;
+    for (size, paths) in &groups {
+        let extra = size * (paths.len() as u64 - 1);
+        wasted += extra;
+        println!(
+            "{} in {} copies ({} extra):",
+            human_bytes(*size as f64),
+            paths.len(),
+            human_bytes(extra as f64)
+        );
+        for path in paths {
+            println!("  {}", path);
+        }
+    }
+    println!();
+    println!(
+        "{} could be saved on every peer",
+        human_bytes(wasted as f64)
+    );
+    Ok(())
+}
@@ -0,0 +1,138 @@
+// `synctool init` asks a few questions and writes a starter config, so setting
+// up a new machine doesn't mean copying another machine's config and editing.
+
+use eyre::{bail, Result};
+use std::{
+    fs,
+    io::{stdin, stdout, Write},
+    path::Path,
+};
+use synctool_core::config::{quote, Config};
+
+const PRESETS: &[(&str, &[&str])] = &[
+    ("rust", &["Name target"]),
+    ("python", &["Name __pycache__", "Name .venv"]),
+    ("node", &["Name node_modules"]),
+    (
+        "haskell",
+        &[
+            "Name .stack-work",
+            "Name .hie",
+            "Name dist-newstyle",
+            "Name *.hi",
+        ],
+    ),
+    ("java", &["Name *.class"]),
+];
+
+pub fn ask(question: &str, default: &str) -> Result<String> {
+    if default.is_empty() {
+        print!("{}: ", question);
+    } else {
+        print!("{} [{}]: ", question, default);
+    }
+    stdout().flush()?;
+
+    let mut answer = String::new();
+    if stdin().read_line(&mut answer)? == 0 {
+        bail!("No answer given");
+    }
+    let answer = answer.trim();
+    Ok(if answer.is_empty() { default } else { answer }.to_string())
+}
+
+pub fn init(args: &[String]) -> Result<()> {
+    if !args.is_empty() {
+        bail!("Usage: init");
+    }
+
+    let path = Config::path();
+    if path.exists() {
+        let overwrite = ask(
+            &format!("{} exists, overwrite it? (y/n)", path.display()),
+            "n",
+        )?;
+        if overwrite != "y" {
+            return Ok(());
+        }
+    }
+
+    let defaults = Config::default();
+    let mut config = String::from("# Written by synctool init\n\n[sync]\n");
+
+    let root = ask(
+        "Directory to sync (same path on every machine)",
+        &defaults.root,
+    )?;
+    config.push_str(&format!("root = {}\n", quote(&root)));
+
+    let preset_names = PRESETS.iter().map(|(name, _)| *name).collect::<Vec<_>>();
+    println!("Ignore presets: {}", preset_names.join(", "));
+    let chosen = ask("Presets to use, comma separated", &preset_names.join(","))?;
+    let mut ignores = Vec::new();
+    for name in chosen
+        .split(',')
+        .map(str::trim)
+        .filter(|name| !name.is_empty())
+    {
+        match PRESETS.iter().find(|(preset, _)| *preset == name) {
+            Some((_, patterns)) => ignores.extend(patterns.iter().map(|p| quote(p))),
+            None => println!("Skipping unknown preset {}", name),
+        }
+    }
+    config.push_str(&format!("ignores = [{}]\n", ignores.join(", ")));
+
+    println!();
+    println!("Peers are other machines to sync with. The usual names are laptop and");
+    println!("desktop; leave the name blank when you're done.");
+    let mut peers = Vec::new();
+    loop {
+        let name = ask("Peer name", "")?;
+        if name.is_empty() {
+            break;
+        }
+        if peers.contains(&name) {
+            println!("{} was already added", name);
+            continue;
+        }
+        let default_address = defaults
+            .host(&name)
+            .map(|host| host.address.clone())
+            .unwrap_or_default();
+        let address = ask(&format!("Address of {}", name), &default_address)?;
+        config.push_str(&format!(
+            "\n[hosts.{}]\naddress = {}\n",
+            quote(&name),
+            quote(&address)
+        ));
+        peers.push(name);
+    }
+
+    // The waking machine is the host named rpi, which may have been given above
+    if peers.iter().any(|name| name == "rpi") {
+        return write(&path, &config);
+    }
+
+    println!();
+    println!("The desktop can be woken by running ~/wake-computinator.sh on an always-on");
+    println!("machine over ssh.");
+    let waker = ask("Address of that machine (blank for none)", "")?;
+    if !waker.is_empty() {
+        config.push_str(&format!("\n[hosts.rpi]\naddress = {}\n", quote(&waker)));
+    }
+
+    write(&path, &config)
+}
+
+fn write(path: &Path, config: &str) -> Result<()> {
+    // Make sure what we wrote parses before saving it
+    Config::parse(config)?;
+
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    fs::write(path, config)?;
+    println!();
+    println!("Wrote {}", path.display());
+    Ok(())
+}
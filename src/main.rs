@@ -1,13 +1,65 @@
-use eyre::{bail, ensure, Result};
+#[macro_use]
+extern crate synctool_core;
+
+use eyre::{bail, ensure, eyre, Result};
 use gethostname::gethostname;
-use lazy_static::{initialize, lazy_static};
 use std::{
     env::args,
-    process::{exit, Command, Stdio},
-    time::Instant,
+    io::{stdin, stdout},
+    path::Path,
+    process::exit,
+};
+use synctool_core::{
+    agent, archive,
+    config::{Config, NotifyClass},
+    events::{self, Value},
+    grace, history, keyring, mesh, notify,
+    output::{self, human_bytes, human_duration, Timestamps},
+    power::{PowerAction, PowerAction::*},
+    recent,
+    runner::{MockRunner, Runner, SystemRunner},
+    state,
+    sync::{
+        sync_desktop_to_laptop, sync_laptop_to_desktop, sync_to_host, sync_waking_host, SyncOptions,
+    },
 };
 
 const HELP_MSG: &str = "\
+With no arguments at a terminal, synctool asks what to sync and what to do after.
+
+Subcommands:
+    agent [--listen [ADDR]]      Answer requests from synctool on another machine, over
+                                 ssh or on a TCP port
+    archive [--full]             Back up the tree, encrypted, to the [archive] remote
+    archives [list | prune [--dry-run]] [HOST...]
+                                 List unison's archive files on both ends, or remove
+                                 the ones left behind
+    blobs [status | track | get HOST [PATH...]]
+                                 Show, store or fetch the files kept in the blob store
+    cancel                       Stop the countdown before a run's power actions
+    check [HOST...]              Probe every host at once and show what would fail
+    checksums                    Print a hash of every file under the root (used over ssh)
+    cleanup [--dry-run] [HOST...]
+                                 Remove old unison temp files and conflict copies here
+                                 and on the hosts
+    config validate [--offline]  Check the config file for problems
+    config dump [--json] [--show-secrets]
+                                 Print the settings in effect and where each came from
+    daemon                       Keep syncing with the configured peers on a schedule
+    doctor [HOST...]             Check the environment and suggest fixes
+    duplicates [MIN_SIZE]        List files with the same contents in different projects
+    export-profile HOST          Write a unison profile equivalent to syncing with HOST
+    init                         Write a starter config by answering questions
+    install-polkit-rule HOST     Allow the logind power method on HOST
+    report [--since DURATION]    Summarize the runs of the last week, or DURATION
+    self-update [--force]        Replace this binary with the latest release
+    stats                        Show file counts and sizes for each directory in the root
+    status                       Show when each peer last synced
+    statusbar                    Print a line of JSON for waybar or i3blocks saying how
+                                 fresh each peer is and whether a sync is going
+    sync-mesh                    Bring every reachable host with synctool up to date
+    versions [--scan | --merge]  Print this machine's file versions (used over ssh)
+
 Arguments:
     -i    Run sync command interactively
     -s    Suspend remote computer after successful sync
@@ -16,260 +68,504 @@ Arguments:
     -ss   Shut down remote computer after successful sync
     -lss  Shut down this computer after successful sync
     -p    Print unison command
+    -r    Push with rsync instead of unison (resumes interrupted transfers)
+    -j N  Use N concurrent transfer streams with -r
+    -f    Fall back to rsync if the unison versions on both ends don't match
+    --restore  Put the remote back to sleep or off after syncing if it had to be woken,
+               instead of -s or -ss
+    --fast  Only sync what changed here since the last successful sync, and do nothing
+            at all, not even waking the remote, if nothing did
+    --checksum  Compare file contents instead of trusting sizes and times; with synctool on
+                the host, files whose fast hashes match are trusted to be the same
+    --path PATH  Only sync PATH under the root, e.g. a project's; can be repeated
+    -t HOST  Sync with HOST from the config instead of the usual peer
+    --profile NAME  Use the paths, ignores, host and power actions of [profiles.NAME],
+                    which flags override; `synctool NAME` does the same
+    NAME  The flags of [aliases] NAME from the config, e.g. `synctool night`
+    -c SECTION.KEY=VALUE  Override a config setting, e.g. -c sync.root=/tmp/x
+    -v    Show every command that's run, how it exited and how long it took
+    -vv   Like -v, plus the output captured from those commands
+    -q, --quiet  Only print errors, for cron and timers
+    --timestamps=KIND  Start log lines with elapsed (the default), wall or both times
+    --color=WHEN  Color the output: never, auto (the default) or always
+    --events-fd FD, --events-file PATH  Write run events as JSON lines to FD or PATH
+    --simulate SCRIPT  Don't run anything, fake each command's result from SCRIPT,
+                       e.g. 'unison -auto=fail,ok; ping=fail,ok' (unlisted ones succeed)
 ";
 
-const IGNORES: &[&str] = &[
-    "Name *.class",
-    "Name *.hi",
-    "Name __pycache__",
-    "Name target",
-    "Name License.sublime_license",
-    // "Path school/linux",
-    // "Path school/linux.7z",
-    // Reach stuff
-    "Name .stack-work",
-    "Name .hie",
-    "Name dist-newstyle",
-    "Name node_modules",
-    "Name cdk.out",
-    // "Regex reach/reach-lang/docs/build",
-    // "Regex reach/reach-lang/examples/.*/build",
-    // "Regex reach/reach-lang/hs/t/.*/build",
-    "Regex thegame/android/SDL",
-    "Regex thegame/android/TheGame/app/build",
-];
+mod archives;
+mod blobs;
+mod check;
+mod checksums;
+mod cleanup;
+mod daemon;
+mod doctor;
+mod dump;
+mod duplicates;
+mod init;
+mod menu;
+mod network;
+mod pick;
+mod polkit;
+mod profile;
+mod report;
+mod session;
+mod stats;
+mod status;
+mod statusbar;
+mod update;
+mod validate;
+mod versions;
 
-const LAPTOP_HOST: &str = "10.13.13.3";
-const DESKTOP_HOST: &str = "10.13.13.4";
-const RPI_HOST: &str = "10.13.13.6";
+fn main() {
+    keyring::askpass_main();
+    synctool_core::start_clock();
+
+    // -c overrides and the output flags are needed before anything else, so pick them
+    // out first
+    let mut config_overrides = Vec::new();
+    let mut color = "auto".to_string();
+    let mut timestamps = None;
+    let mut other_args = Vec::new();
+    let mut all_args = args().skip(1);
+    while let Some(arg) = all_args.next() {
+        if arg == "-c" {
+            config_overrides.extend(all_args.next());
+        } else if arg == "--events-fd" {
+            match all_args.next().and_then(|fd| fd.parse().ok()) {
+                Some(fd) => events::to_fd(fd),
+                None => {
+                    println!("--events-fd needs a file descriptor number");
+                    exit(1);
+                }
+            }
+        } else if arg == "--events-file" {
+            let path = all_args.next().unwrap_or_default();
+            if let Err(err) = events::to_file(Path::new(&path)) {
+                println!("Couldn't open events file {:?}: {}", path, err);
+                exit(1);
+            }
+        } else if arg == "-v" || arg == "-vv" {
+            output::set_verbosity(arg.len() as u8 - 1);
+        } else if arg == "-q" || arg == "--quiet" {
+            output::set_quiet(true);
+        } else if let Some(choice) = arg.strip_prefix("--timestamps=") {
+            match Timestamps::parse(choice) {
+                Some(choice) => timestamps = Some(choice),
+                None => {
+                    println!("--timestamps must be elapsed, wall or both");
+                    exit(1);
+                }
+            }
+        } else if let Some(choice) = arg.strip_prefix("--color=") {
+            color = choice.to_string();
+        } else {
+            other_args.push(arg);
+        }
+    }
 
-lazy_static! {
-    static ref START: Instant = Instant::now();
-}
+    if let Err(err) = output::set_color(&color) {
+        println!("{}", err);
+        exit(1);
+    }
 
-macro_rules! log {
-    ($($t:tt)*) => {
-        println!("[{:.2}] {}", Instant::now().duration_since(*START).as_secs_f32(), format!($($t)*))
+    let config = match Config::load(&config_overrides) {
+        Ok(config) => config,
+        Err(err) => {
+            error!("{err:#}");
+            exit(1);
+        }
     };
-}
 
-#[derive(Clone, Copy)]
-enum PowerAction {
-    Shutdown,
-    Suspend,
-    Nothing,
-}
-use PowerAction::*;
+    output::set_timestamps(timestamps.unwrap_or(config.log_timestamps));
 
-struct SyncOptions {
-    local_power: PowerAction,
-    remote_power: PowerAction,
-    interactive: bool,
-    skip_sync: bool,
-    print_unison_cmd: bool,
-}
+    if config.ssh_passphrase_from_keyring {
+        if let Err(err) = keyring::enable_askpass() {
+            error!("{err:#}");
+            exit(1);
+        }
+    }
 
-fn main() {
-    initialize(&START);
+    // Aliases are checked up front, so a bad one is found before it's needed
+    for (name, words) in &config.aliases {
+        if let Err(err) = parse_flags(words.clone()) {
+            error!("In alias {}: {err:#}", name);
+            exit(1);
+        }
+    }
 
-    // Process CLI args
-    let mut sync_options = SyncOptions {
-        local_power: Nothing,
-        remote_power: Nothing,
-        interactive: false,
-        skip_sync: false,
-        print_unison_cmd: false,
-    };
+    // With no arguments at a terminal, ask what to do
+    if args().len() == 1 && menu::is_terminal() {
+        other_args = match menu::menu(&config) {
+            Ok(flags) => flags,
+            Err(err) => {
+                error!("{err:#}");
+                exit(1);
+            }
+        };
+    }
 
-    for arg in args().skip(1) {
-        match arg.as_str() {
-            "-i" => sync_options.interactive = true,
-            "-ss" => sync_options.remote_power = Shutdown,
-            "-s" => sync_options.remote_power = Suspend,
-            "-lss" => sync_options.local_power = Shutdown,
-            "-ls" => sync_options.local_power = Suspend,
-            "-n" => sync_options.skip_sync = true,
-            "-p" => sync_options.print_unison_cmd = true,
-            "-h" => {
-                print!("{}", HELP_MSG);
-                exit(0);
+    let mut args = other_args.into_iter().peekable();
+    let mut expansion = None;
+    if let Some(subcommand) = args.next_if(|arg| !arg.starts_with('-')) {
+        let subcommand_args = args.collect::<Vec<_>>();
+        let result = match subcommand.as_str() {
+            "archive" => match subcommand_args.as_slice() {
+                [] => archive::archive(&SystemRunner, &config, false),
+                [flag] if flag == "--full" => archive::archive(&SystemRunner, &config, true),
+                _ => Err(eyre!("Usage: archive [--full]")),
+            },
+            "archives" => archives::archives(&config, &subcommand_args),
+            "blobs" => blobs::blobs(&config, &subcommand_args),
+            "cancel" => cancel(&subcommand_args),
+            "check" => check::check(&config, &subcommand_args),
+            "checksums" => checksums::checksums(&config, &subcommand_args),
+            "cleanup" => cleanup::cleanup(&config, &subcommand_args),
+            "config" => match subcommand_args.first().map(String::as_str) {
+                Some("validate") => validate::validate(&subcommand_args[1..]),
+                Some("dump") => dump::dump(&config_overrides, &subcommand_args[1..]),
+                _ => {
+                    println!("Usage: config [validate | dump]");
+                    exit(1);
+                }
+            },
+            "agent" => serve_agent(&config, &subcommand_args),
+            "daemon" => daemon::run(&config_overrides, &subcommand_args),
+            "doctor" => doctor::doctor(&config, &subcommand_args),
+            "duplicates" => duplicates::duplicates(&config, &subcommand_args),
+            "export-profile" => profile::export(&config, &subcommand_args),
+            "init" => init::init(&subcommand_args),
+            "install-polkit-rule" => polkit::install(&config, &subcommand_args),
+            "report" => report::report(&subcommand_args),
+            "self-update" => update::self_update(&config, &subcommand_args),
+            "stats" => stats::stats(&config, &subcommand_args),
+            "status" => status::status(&config, &subcommand_args),
+            "statusbar" => statusbar::statusbar(&config, &subcommand_args),
+            "sync-mesh" => sync_mesh(&config, &subcommand_args),
+            "versions" => versions::versions(&config, &subcommand_args),
+            // `synctool NAME` is short for `synctool --profile NAME`, or for
+            // the flags of the alias NAME
+            other if config.profile(other).is_ok() => {
+                expansion = Some(vec!["--profile".to_string(), other.to_string()]);
+                Ok(())
+            }
+            other if config.aliases.contains_key(other) => {
+                expansion = Some(config.aliases[other].clone());
+                Ok(())
             }
             other => {
-                println!("{} is not a valid flag", other);
+                println!("{} is not a valid subcommand", other);
                 exit(1);
             }
+        };
+
+        if let Err(err) = result {
+            error!("{err:#}");
+            exit(1);
+        }
+        match expansion {
+            Some(mut words) => {
+                words.extend(subcommand_args);
+                args = words.into_iter().peekable();
+            }
+            None => return,
         }
     }
 
+    let Flags {
+        mut sync_options,
+        simulation,
+        fast,
+        profile,
+        mut local_power,
+        mut remote_power,
+        help,
+    } = match parse_flags(args) {
+        Ok(flags) => flags,
+        Err(err) => {
+            println!("{}", err);
+            exit(1);
+        }
+    };
+    if help {
+        print!("{}", HELP_MSG);
+        exit(0);
+    }
+    // Flags win over the profile
+    if let Some(name) = &profile {
+        let profile = match config.profile(name) {
+            Ok(profile) => profile,
+            Err(err) => {
+                error!("{err:#}");
+                exit(1);
+            }
+        };
+        if sync_options.paths.is_empty() {
+            sync_options.paths = profile.paths.clone();
+        }
+        sync_options.ignores = profile.ignores.clone();
+        if sync_options.to_host.is_none() {
+            sync_options.to_host = profile.host.clone();
+        }
+        local_power = local_power.or(Some(profile.local_power));
+        remote_power = remote_power.or(Some(profile.remote_power));
+        sync_options.special_files = profile.special_files;
+    }
+    sync_options.local_power = local_power.unwrap_or(Nothing);
+    sync_options.remote_power = remote_power.unwrap_or(Nothing);
+    if sync_options.to_host.is_none() {
+        sync_options.to_host = config.peer.clone();
+    }
+
     // Determine hostname and which function to use to sync
     let hostname = gethostname().into_string().unwrap();
     let sync_fn = match hostname.as_str() {
+        _ if sync_options.to_host.is_some() => {
+            |runner: &dyn Runner, config: &Config, sync_options: &SyncOptions| {
+                let host = config.host(sync_options.to_host.as_deref().unwrap())?;
+                if host.wakeable() {
+                    sync_waking_host(runner, config, host, sync_options)
+                } else {
+                    sync_to_host(runner, config, host, sync_options)
+                }
+            }
+        }
         "ism" => sync_laptop_to_desktop,
         "computinator" => sync_desktop_to_laptop,
-        _ => |_: &SyncOptions| bail!("Running on unrecognized machine"),
+        _ => |_: &dyn Runner, _: &Config, _: &SyncOptions| bail!("Running on unrecognized machine"),
     };
 
-    if let Err(err) = sync_fn(&sync_options) {
-        log!("{err}");
-        exit(1);
-    }
-}
-
-fn sync_laptop_to_desktop(sync_options: &SyncOptions) -> Result<()> {
-    let do_power_actions = || -> Result<()> {
-        do_remote_power_action(DESKTOP_HOST, &sync_options.remote_power)?;
-        do_local_power_action(&sync_options.local_power)?;
-        Ok(())
+    let runner: &dyn Runner = match &simulation {
+        Some(simulation) => simulation,
+        None => &SystemRunner,
     };
-
-    let do_sync = || -> Result<bool> {
-        unison(
-            DESKTOP_HOST,
-            sync_options.interactive,
-            sync_options.print_unison_cmd,
-        )
+    let peer = match (&sync_options.to_host, hostname.as_str()) {
+        (Some(host), _) => host.as_str(),
+        (None, "ism") => "desktop",
+        (None, _) => "laptop",
     };
 
-    if sync_options.skip_sync {
-        log!("Skipped sync");
-        wake_desktop()?;
-        do_power_actions()?;
-        return Ok(());
+    let previous_runs = history::load().unwrap_or_default();
+    if let Some(age) = history::stale(&previous_runs, peer, config.stale_after_hours) {
+        warn!(
+            "No successful sync with {} in {}! Check `synctool status`",
+            peer,
+            human_duration(age)
+        );
     }
 
-    log!("Starting sync");
-    if do_sync()? {
-        do_power_actions()?;
-        return Ok(());
+    if let (true, Some(last)) = (fast, history::last_successful_run(&previous_runs, peer)) {
+        // A minute of slack for clocks and files written as that run started
+        let since = last.started() as i64 - 60;
+        match recent::changed_since(&config, since) {
+            // Without waking the peer or touching anyone's power
+            Ok(Some(paths)) if paths.is_empty() => {
+                log!("Nothing changed since last sync");
+                return;
+            }
+            Ok(Some(paths)) => {
+                // Within the paths of --path or the profile, if there are any
+                let paths = paths
+                    .into_iter()
+                    .filter(|path| {
+                        sync_options.paths.is_empty()
+                            || sync_options.paths.iter().any(|under| {
+                                path == under || path.starts_with(&format!("{}/", under))
+                            })
+                    })
+                    .collect::<Vec<_>>();
+                if paths.is_empty() {
+                    log!("Nothing changed since last sync");
+                    return;
+                }
+                log!("Syncing {} changed path(s)", paths.len());
+                sync_options.paths = paths;
+            }
+            Ok(None) => log!("Too much changed for --fast to narrow down, syncing everything"),
+            Err(err) => warn!("Couldn't look for changes, syncing everything: {err:#}"),
+        }
+    } else if fast {
+        log!("No successful sync with {} yet, syncing everything", peer);
     }
 
-    wake_desktop()?;
-
-    log!("Trying sync again");
-    if do_sync()? {
-        do_power_actions()?;
-        return Ok(());
+    events::emit("run_start", &[]);
+    if simulation.is_none() {
+        if let Err(err) = state::syncing(peer) {
+            warn!("Couldn't update the status file: {err:#}");
+        }
     }
-
-    bail!("Sync failed");
-}
-
-fn sync_desktop_to_laptop(sync_options: &SyncOptions) -> Result<()> {
-    log!("Starting sync");
-    if sync_options.skip_sync
-        || unison(
-            LAPTOP_HOST,
-            sync_options.interactive,
-            sync_options.print_unison_cmd,
-        )?
-    {
-        do_remote_power_action(LAPTOP_HOST, &sync_options.remote_power)?;
-        do_local_power_action(&sync_options.local_power)?;
-        Ok(())
-    } else {
-        bail!("Sync failed")
+    let result = sync_fn(runner, &config, &sync_options);
+    let timings = events::take_timings();
+    let transferred = events::take_transferred();
+    let found = events::take_found();
+    let total = synctool_core::elapsed().as_secs_f64();
+    let values = history::values(&timings, transferred);
+    if simulation.is_none() {
+        if let Err(err) = history::record(peer, result.is_ok(), found, &values) {
+            warn!("Couldn't record this run in the history: {err:#}");
+        }
     }
-}
-
-// Returns Ok(true) if sync was successful, Ok(false) if sync failed.
-fn unison(remote: &str, interactive: bool, print: bool) -> Result<bool> {
-    let remote_folder = format!("ssh://{}//home/user/prog/", remote);
-    let mut command_struct = Command::new("unison");
-    let mut command = command_struct.args(["-auto", "-sshargs", "-o ConnectTimeout=8"]);
 
-    if !interactive {
-        command = command.arg("-batch");
+    if let Err(err) = result {
+        error!("{err}");
+        let message = format!("Sync with {} failed: {}", peer, err);
+        notify::send(runner, &config, NotifyClass::Failure, &message);
+        events::emit("run_end", &[("ok", Value::Bool(false))]);
+        exit(1);
     }
-
-    for ignore in IGNORES {
-        command = command.args(["-ignore", ignore]);
+    let message = format!("Synced with {} in {:.1}s", peer, total);
+    notify::send(runner, &config, NotifyClass::Success, &message);
+    if timings.is_empty() {
+        summary!("Sync finished in {:.1}s", total);
+    } else {
+        summary!(
+            "Sync finished in {:.1}s ({})",
+            total,
+            events::breakdown(&timings)
+        );
     }
-
-    command = command.args(["/home/user/prog", remote_folder.as_str()]);
-    command = command
-        .stdin(Stdio::inherit())
-        .stdout(Stdio::inherit())
-        .stderr(Stdio::inherit());
-
-    if print {
-        let mut args = String::new();
-        for a in command.get_args() {
-            args.push_str(&format!("{:?} ", a));
+    if let Some((sent, received)) = transferred {
+        let mut line = format!(
+            "Sent {}, received {}",
+            human_bytes(sent as f64),
+            human_bytes(received as f64)
+        );
+        let sync_seconds = timings.iter().find(|(phase, _)| phase == "sync");
+        if let Some((_, seconds)) = sync_seconds.filter(|(_, seconds)| *seconds > 0.) {
+            let throughput = (sent + received) as f64 / seconds;
+            line.push_str(&format!(" at {}/s", human_bytes(throughput)));
+            if let Some(usual) = history::usual_throughput(&previous_runs, peer) {
+                line.push_str(&format!(" (usually {}/s)", human_bytes(usual)));
+            }
         }
-        log!("command: unison {}", args);
-        exit(0);
+        summary!("{}", line);
     }
-
-    let unison_status = command.spawn()?.wait()?;
-    Ok(unison_status.success())
+    events::emit("run_end", &[("ok", Value::Bool(true))]);
 }
 
-fn ping(host: &str) -> Result<bool> {
-    Ok(Command::new("ping")
-        .args(["-c", "3", host])
-        .stdin(Stdio::null())
-        .stdout(Stdio::null())
-        .stderr(Stdio::null())
-        .spawn()?
-        .wait()?
-        .success())
+// What the flags of a sync ask for. The power actions are None unless a flag
+// gives one, so they can fall back to the profile's.
+struct Flags {
+    sync_options: SyncOptions,
+    simulation: Option<MockRunner>,
+    fast: bool,
+    profile: Option<String>,
+    local_power: Option<PowerAction>,
+    remote_power: Option<PowerAction>,
+    help: bool,
 }
 
-fn do_local_power_action(action: &PowerAction) -> Result<()> {
-    match action {
-        Shutdown => {
-            log!("Shutting down this computer");
-            Command::new("shutdown").output()?;
-        }
+fn parse_flags(args: impl IntoIterator<Item = String>) -> Result<Flags> {
+    let mut flags = Flags {
+        sync_options: SyncOptions::default(),
+        simulation: None,
+        fast: false,
+        profile: None,
+        local_power: None,
+        remote_power: None,
+        help: false,
+    };
+    let sync_options = &mut flags.sync_options;
 
-        Suspend => {
-            log!("Suspending this computer");
-            Command::new("slp").output()?;
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-i" => sync_options.interactive = true,
+            "-ss" => flags.remote_power = Some(Shutdown),
+            "-s" => flags.remote_power = Some(Suspend),
+            "-lss" => flags.local_power = Some(Shutdown),
+            "-ls" => flags.local_power = Some(Suspend),
+            "-n" => sync_options.skip_sync = true,
+            "-p" => sync_options.print_unison_cmd = true,
+            "-r" => sync_options.use_rsync = true,
+            "-f" => sync_options.rsync_fallback = true,
+            "--restore" => sync_options.restore_power = true,
+            "--fast" => flags.fast = true,
+            "--checksum" => sync_options.checksum = true,
+            "--path" => match args.next().map(|path| path.trim_matches('/').to_string()) {
+                Some(path) if !path.is_empty() && !path.split('/').any(|part| part == "..") => {
+                    sync_options.paths.push(path)
+                }
+                _ => bail!("--path needs a path under the root"),
+            },
+            "--profile" => match args.next() {
+                Some(name) => flags.profile = Some(name),
+                None => bail!("--profile needs a profile name"),
+            },
+            "-t" => match args.next() {
+                Some(host) => sync_options.to_host = Some(host),
+                None => bail!("-t needs a host name"),
+            },
+            "--simulate" => match args.next() {
+                Some(script) => flags.simulation = Some(MockRunner::from_script(&script)?),
+                None => bail!("--simulate needs a script"),
+            },
+            "-j" => match args.next().and_then(|n| n.parse().ok()) {
+                Some(jobs) if jobs > 0 => sync_options.jobs = jobs,
+                _ => bail!("-j needs a positive number of jobs"),
+            },
+            "-h" => flags.help = true,
+            other => bail!("{} is not a valid flag", other),
         }
-
-        Nothing => {}
     }
-
-    Ok(())
+    ensure!(
+        !(flags.sync_options.restore_power && flags.remote_power.is_some()),
+        "--restore decides what happens to the remote, so it can't go with -s or -ss"
+    );
+    Ok(flags)
 }
 
-fn do_remote_power_action(remote: &str, action: &PowerAction) -> Result<()> {
-    match action {
-        Shutdown => {
-            log!("Shutting down remote computer");
-            Command::new("ssh")
-                .args([remote, "sudo", "shutdown", "now"])
-                .output()?;
-        }
-
-        Suspend => {
-            log!("Suspending remote computer");
-            Command::new("ssh").args([remote, "slp"]).output()?;
+// Answers over stdin and stdout for an ssh session, or on a TCP port with
+// --listen, which needs a token so not just anyone can suspend this machine.
+fn serve_agent(config: &Config, args: &[String]) -> Result<()> {
+    output::set_stderr(true);
+    match args {
+        [] => agent::serve(config, None, stdin().lock(), stdout()),
+        [flag, rest @ ..] if flag == "--listen" && rest.len() <= 1 => {
+            let address = match rest.first().or(config.agent_listen.as_ref()) {
+                Some(address) => address,
+                None => bail!("--listen needs an address, or set [agent] listen in the config"),
+            };
+            let token = match &config.agent_token {
+                Some(token) => token,
+                None => bail!("Set [agent] token in the config before listening on TCP"),
+            };
+            agent::listen(config, address, token)
         }
-
-        Nothing => {}
+        _ => bail!("Usage: agent [--listen [ADDR]]"),
     }
+}
 
+// Stops the countdowns before power actions, in any synctool
+fn cancel(args: &[String]) -> Result<()> {
+    if !args.is_empty() {
+        bail!("Usage: cancel");
+    }
+    match grace::cancel_all()? {
+        0 => bail!("No power actions are counting down"),
+        count => log!("Cancelled {} countdown(s)", count),
+    }
     Ok(())
 }
 
-fn wake_desktop() -> Result<()> {
-    log!("Waking desktop");
-    Command::new("ssh")
-        .args([RPI_HOST, "~/wake-computinator.sh"])
-        .output()?;
-
-    log!("Waiting 60 seconds for desktop to turn on");
-    let mut awake = false;
-    let ping_start = Instant::now();
-    while Instant::now().duration_since(ping_start).as_secs_f32() < 60. {
-        if ping(DESKTOP_HOST)? {
-            awake = true;
-            break;
-        }
+fn sync_mesh(config: &Config, args: &[String]) -> Result<()> {
+    if !args.is_empty() {
+        bail!("Usage: sync-mesh");
     }
+    // The machines predating the config are known by their hostnames
+    let this = match gethostname().into_string().unwrap_or_default().as_str() {
+        "ism" => "laptop".to_string(),
+        "computinator" => "desktop".to_string(),
+        hostname => hostname.to_string(),
+    };
 
-    ensure!(awake, "Could not reach desktop");
-
+    events::emit("run_start", &[]);
+    let result = mesh::sync_mesh(&SystemRunner, config, &this);
+    events::emit("run_end", &[("ok", Value::Bool(result.is_ok()))]);
+    result?;
+    summary!(
+        "Mesh synced in {:.1}s",
+        synctool_core::elapsed().as_secs_f64()
+    );
     Ok(())
 }
@@ -0,0 +1,85 @@
+// Running synctool with no arguments at a terminal asks what to sync with, and
+// which project if there are any, and what to do afterwards, then prints the flags that do the same, so they're
+// learned rather than looked up. Enter all the way through is a plain sync
+// with the usual peer.
+
+use crate::{init::ask, pick::pick};
+use eyre::Result;
+use synctool_core::config::Config;
+
+// Whether someone is there to answer
+pub fn is_terminal() -> bool {
+    unsafe { libc::isatty(libc::STDIN_FILENO) == 1 && libc::isatty(libc::STDOUT_FILENO) == 1 }
+}
+
+// Asks until the answer is one of the choices
+fn choose(question: &str, choices: &[&str], default: &str) -> Result<String> {
+    loop {
+        let answer = ask(&format!("{} ({})", question, choices.join("/")), default)?;
+        if choices.contains(&answer.as_str()) {
+            return Ok(answer);
+        }
+        println!("Answer one of {}", choices.join(", "));
+    }
+}
+
+// The flags for the sync chosen
+pub fn menu(config: &Config) -> Result<Vec<String>> {
+    let mut flags = Vec::new();
+
+    let names = config
+        .hosts
+        .iter()
+        .map(|host| host.name.clone())
+        .collect::<Vec<_>>();
+    let host = pick("Sync with, or the usual peer", &names)?;
+    if let Some(host) = &host {
+        flags.extend(["-t".to_string(), host.clone()]);
+    }
+    let host = host.unwrap_or_else(|| "the peer".to_string());
+
+    if !config.projects.is_empty() {
+        let names = config
+            .projects
+            .iter()
+            .map(|project| project.name.clone())
+            .collect::<Vec<_>>();
+        if let Some(name) = pick("Just this project, or everything", &names)? {
+            let project = config.projects.iter().find(|p| p.name == name).unwrap();
+            flags.extend(["--path".to_string(), project.path.clone()]);
+        }
+    }
+
+    let direction = choose(
+        "Both ways with unison, or push from here with rsync",
+        &["both", "push"],
+        "both",
+    )?;
+    if direction == "push" {
+        flags.push("-r".to_string());
+    }
+
+    let actions = ["nothing", "suspend", "shutdown"];
+    match choose(&format!("Then {}", host), &actions, "nothing")?.as_str() {
+        "suspend" => flags.push("-s".to_string()),
+        "shutdown" => flags.push("-ss".to_string()),
+        _ => {}
+    }
+    match choose("Then this computer", &actions, "nothing")?.as_str() {
+        "suspend" => flags.push("-ls".to_string()),
+        "shutdown" => flags.push("-lss".to_string()),
+        _ => {}
+    }
+
+    if direction == "both" && choose("Review each change first", &["y", "n"], "n")? == "y" {
+        flags.push("-i".to_string());
+    }
+
+    let mut line = "synctool".to_string();
+    for flag in &flags {
+        line.push(' ');
+        line.push_str(flag);
+    }
+    println!("Running `{}`", line);
+    Ok(flags)
+}
@@ -0,0 +1,128 @@
+// Notices network connections coming up, so the daemon can sync as soon as
+// the home Wi-Fi or VPN connects instead of at the next interval.
+//
+// A netlink route socket says when links or addresses change, and then the
+// active connections are listed again: NetworkManager's connection names, if
+// nmcli is installed, and the names of network interfaces that are up (for
+// VPNs like wg0 that NetworkManager doesn't manage). Without netlink the list
+// is checked every 30 seconds instead.
+
+use std::{
+    collections::HashSet,
+    fs::{self, File},
+    io::{ErrorKind, Read},
+    os::unix::io::FromRawFd,
+    process::{Command, Stdio},
+    time::{Duration, Instant},
+};
+
+pub struct Watcher {
+    socket: Option<File>,
+    active: HashSet<String>,
+    last_check: Instant,
+}
+
+impl Watcher {
+    pub fn new() -> Watcher {
+        let socket = open_netlink();
+        if socket.is_none() {
+            warn!("Couldn't watch for network changes, checking every 30s instead");
+        }
+        Watcher {
+            socket,
+            active: active_connections(),
+            last_check: Instant::now(),
+        }
+    }
+
+    // The connections among names that came up since the last call.
+    pub fn connected(&mut self, names: &[String]) -> Vec<String> {
+        let changed = match &mut self.socket {
+            Some(socket) => drain(socket),
+            None => self.last_check.elapsed() >= Duration::from_secs(30),
+        };
+        if !changed || names.is_empty() {
+            return Vec::new();
+        }
+
+        self.last_check = Instant::now();
+        let active = active_connections();
+        let connected = names
+            .iter()
+            .filter(|name| active.contains(*name) && !self.active.contains(*name))
+            .cloned()
+            .collect();
+        self.active = active;
+        connected
+    }
+}
+
+fn open_netlink() -> Option<File> {
+    unsafe {
+        let fd = libc::socket(
+            libc::AF_NETLINK,
+            libc::SOCK_RAW | libc::SOCK_NONBLOCK | libc::SOCK_CLOEXEC,
+            libc::NETLINK_ROUTE,
+        );
+        if fd < 0 {
+            return None;
+        }
+        let socket = File::from_raw_fd(fd);
+
+        let mut address: libc::sockaddr_nl = std::mem::zeroed();
+        address.nl_family = libc::AF_NETLINK as u16;
+        address.nl_groups =
+            (libc::RTMGRP_LINK | libc::RTMGRP_IPV4_IFADDR | libc::RTMGRP_IPV6_IFADDR) as u32;
+        let bound = libc::bind(
+            fd,
+            &address as *const libc::sockaddr_nl as *const libc::sockaddr,
+            std::mem::size_of::<libc::sockaddr_nl>() as u32,
+        );
+        if bound != 0 {
+            return None;
+        }
+        Some(socket)
+    }
+}
+
+// Reads every pending message, returning whether there were any. What they
+// say doesn't matter, only that something changed.
+fn drain(socket: &mut File) -> bool {
+    let mut buf = [0; 8192];
+    let mut any = false;
+    loop {
+        match socket.read(&mut buf) {
+            Ok(0) => return any,
+            Ok(_) => any = true,
+            Err(err) if err.kind() == ErrorKind::WouldBlock => return any,
+            // An overrun still means something changed
+            Err(_) => return true,
+        }
+    }
+}
+
+fn active_connections() -> HashSet<String> {
+    let mut active = HashSet::new();
+
+    if let Ok(output) = Command::new("nmcli")
+        .args(["-t", "-f", "NAME", "connection", "show", "--active"])
+        .stdin(Stdio::null())
+        .stderr(Stdio::null())
+        .output()
+    {
+        let names = String::from_utf8_lossy(&output.stdout);
+        // nmcli -t escapes colons in names
+        active.extend(names.lines().map(|name| name.replace("\\:", ":")));
+    }
+
+    if let Ok(interfaces) = fs::read_dir("/sys/class/net") {
+        for interface in interfaces.flatten() {
+            let state = fs::read_to_string(interface.path().join("operstate")).unwrap_or_default();
+            // Tunnels have no carrier to speak of, and report unknown
+            if matches!(state.trim(), "up" | "unknown") && interface.file_name() != "lo" {
+                active.insert(interface.file_name().to_string_lossy().into_owned());
+            }
+        }
+    }
+    active
+}
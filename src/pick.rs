@@ -0,0 +1,78 @@
+// Picking one of a list by typing part of it, with skim (sk) or fzf if either
+// is installed, or else at a prompt by number or by letters in the name in
+// order, e.g. "dsk" for desktop.
+
+use crate::init::ask;
+use eyre::Result;
+use std::{
+    io::{ErrorKind, Read, Write},
+    process::{Command, Stdio},
+};
+
+// The choice, or None if nothing was picked
+pub fn pick(prompt: &str, choices: &[String]) -> Result<Option<String>> {
+    for finder in ["sk", "fzf"] {
+        let spawned = Command::new(finder)
+            .args(["--height", "40%", "--prompt", &format!("{}> ", prompt)])
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn();
+        let mut child = match spawned {
+            Ok(child) => child,
+            Err(err) if err.kind() == ErrorKind::NotFound => continue,
+            Err(err) => return Err(err.into()),
+        };
+        if let Some(mut stdin) = child.stdin.take() {
+            stdin.write_all(choices.join("\n").as_bytes())?;
+        }
+        let mut picked = String::new();
+        if let Some(mut stdout) = child.stdout.take() {
+            stdout.read_to_string(&mut picked)?;
+        }
+        // Escape exits with 130, no match with 1
+        child.wait()?;
+        let picked = picked.trim();
+        return Ok(choices.iter().find(|choice| *choice == picked).cloned());
+    }
+
+    for (i, choice) in choices.iter().enumerate() {
+        println!("  {}. {}", i + 1, choice);
+    }
+    loop {
+        let answer = ask(&format!("{} (blank for none)", prompt), "")?;
+        if answer.is_empty() {
+            return Ok(None);
+        }
+        if let Some(choice) = answer
+            .parse::<usize>()
+            .ok()
+            .and_then(|n| choices.get(n.wrapping_sub(1)))
+        {
+            return Ok(Some(choice.clone()));
+        }
+        let matches = choices
+            .iter()
+            .filter(|choice| fuzzy_match(&answer, choice))
+            .collect::<Vec<_>>();
+        match matches.as_slice() {
+            [choice] => return Ok(Some(choice.to_string())),
+            [] => println!("Nothing matches {}", answer),
+            _ => match matches.iter().find(|choice| ***choice == answer) {
+                Some(choice) => return Ok(Some(choice.to_string())),
+                None => {
+                    let names = matches.iter().map(|choice| choice.as_str());
+                    println!("Which one: {}?", names.collect::<Vec<_>>().join(", "));
+                }
+            },
+        }
+    }
+}
+
+// Whether the letters of pattern appear in choice in order, ignoring case
+fn fuzzy_match(pattern: &str, choice: &str) -> bool {
+    let mut letters = choice.chars().map(|c| c.to_ascii_lowercase());
+    pattern
+        .chars()
+        .map(|c| c.to_ascii_lowercase())
+        .all(|c| letters.any(|letter| letter == c))
+}
@@ -0,0 +1,21 @@
+// `synctool install-polkit-rule HOST` lets the ssh user on HOST power off and
+// suspend through logind without being on the active seat (see
+// synctool_core::polkit). This is the one step that still needs sudo, and
+// only once.
+
+use eyre::{bail, Result};
+use synctool_core::{config::Config, polkit, runner::SystemRunner};
+
+pub fn install(config: &Config, args: &[String]) -> Result<()> {
+    let host = match args {
+        [host_name] => config.host(host_name)?,
+        _ => bail!("Usage: install-polkit-rule HOST"),
+    };
+
+    polkit::install(&SystemRunner, host)?;
+    log!(
+        "{} can now be powered off and suspended without sudo",
+        host.name
+    );
+    Ok(())
+}
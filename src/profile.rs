@@ -0,0 +1,60 @@
+// `synctool export-profile HOST` writes a unison profile with the same roots,
+// ignores and preferences that synctool passes to unison on the command line,
+// so unison can be run or debugged by hand with `unison synctool-HOST`.
+
+use eyre::{bail, Result, WrapErr};
+use std::{env, fs, path::PathBuf};
+use synctool_core::{config::Config, unison::unison_options};
+
+pub fn export(config: &Config, args: &[String]) -> Result<()> {
+    let host = match args {
+        [host_name] => config.host(host_name)?,
+        _ => bail!("Usage: export-profile HOST"),
+    };
+
+    let mut profile = format!("# Generated by synctool export-profile {}\n", host.name);
+    profile.push_str(&format!("root = {}\n", config.root));
+    profile.push_str(&format!(
+        "root = ssh://{}/{}/\n",
+        host.address,
+        host.root(config)
+    ));
+
+    // Leave out batch, since the point is usually to run unison interactively
+    for (option, value) in unison_options(config, host, true) {
+        let value = value.unwrap_or_else(|| "true".to_string());
+        profile.push_str(&format!("{} = {}\n", option, value));
+    }
+
+    // Extra arguments from the config are plain command line words, so guess
+    // which ones take a value: anything followed by a word not starting with -
+    let extra_args = config.unison.args.iter().chain(&host.unison_args);
+    let extra_args = extra_args.collect::<Vec<_>>();
+    let mut i = 0;
+    while i < extra_args.len() {
+        let option = extra_args[i].trim_start_matches('-');
+        match extra_args.get(i + 1) {
+            Some(value) if !value.starts_with('-') => {
+                profile.push_str(&format!("{} = {}\n", option, value));
+                i += 2;
+            }
+            _ => {
+                profile.push_str(&format!("{} = true\n", option));
+                i += 1;
+            }
+        }
+    }
+
+    let unison_dir = match env::var_os("UNISON") {
+        Some(dir) => PathBuf::from(dir),
+        None => PathBuf::from(env::var_os("HOME").unwrap_or_default()).join(".unison"),
+    };
+    fs::create_dir_all(&unison_dir)?;
+
+    let path = unison_dir.join(format!("synctool-{}.prf", host.name));
+    fs::write(&path, profile).wrap_err_with(|| format!("Couldn't write {}", path.display()))?;
+
+    log!("Wrote {}", path.display());
+    log!("Run it with: unison synctool-{}", host.name);
+    Ok(())
+}
@@ -0,0 +1,25 @@
+// `synctool report [--since DURATION]` summarizes the history of the last week
+// (or DURATION, like "30d"): how many runs there were and how many failed,
+// the data moved and the longest runs. The daemon can send the same as a
+// notification with [daemon] digest.
+
+use eyre::{bail, Result};
+use std::time::{SystemTime, UNIX_EPOCH};
+use synctool_core::{config::parse_duration, history};
+
+pub fn report(args: &[String]) -> Result<()> {
+    let since = match args {
+        [] => 7 * 24 * 60 * 60,
+        [flag, duration] if flag == "--since" => match parse_duration(duration) {
+            Some(seconds) => seconds,
+            None => bail!("--since takes a duration like \"7d\" or \"12h\""),
+        },
+        _ => bail!("Usage: report [--since DURATION]"),
+    };
+
+    let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+    for line in history::digest(&history::load()?, now.saturating_sub(since), now) {
+        println!("{}", line);
+    }
+    Ok(())
+}
@@ -0,0 +1,66 @@
+// Notices the desktop session starting or being unlocked, by watching logind's
+// signals on the system bus with gdbus, so the daemon can pull in changes
+// before anything gets opened in an editor.
+//
+// Only local sessions count; the ssh sessions synctool itself opens from the
+// other machine would otherwise set off a sync every time.
+
+use std::{
+    io::{BufRead, BufReader},
+    process::{Command, Stdio},
+    sync::mpsc::{self, Receiver},
+    thread,
+};
+
+// Starts watching, returning a channel that gets "login" or "unlock" for each
+// one. Nothing more arrives if gdbus dies.
+pub fn watch() -> std::io::Result<Receiver<&'static str>> {
+    let mut child = Command::new("gdbus")
+        .args(["monitor", "--system", "--dest", "org.freedesktop.login1"])
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()?;
+    let stdout = child.stdout.take().unwrap();
+
+    let (sender, receiver) = mpsc::channel();
+    thread::spawn(move || {
+        for line in BufReader::new(stdout).lines().map_while(Result::ok) {
+            let event =
+                if line.contains(".Session.Unlock ") || line.contains("'LockedHint': <false>") {
+                    Some("unlock")
+                } else if line.contains(".Manager.SessionNew ") {
+                    new_session_id(&line)
+                        .filter(|id| is_local(id))
+                        .map(|_| "login")
+                } else {
+                    None
+                };
+            if let Some(event) = event {
+                if sender.send(event).is_err() {
+                    break;
+                }
+            }
+        }
+        let _ = child.kill();
+        let _ = child.wait();
+    });
+    Ok(receiver)
+}
+
+// "/org/freedesktop/login1: org.freedesktop.login1.Manager.SessionNew ('3', objectpath ...)"
+fn new_session_id(line: &str) -> Option<&str> {
+    let (_, rest) = line.split_once("SessionNew ('")?;
+    Some(rest.split_once('\'')?.0)
+}
+
+fn is_local(id: &str) -> bool {
+    Command::new("loginctl")
+        .args(["show-session", id, "-p", "Remote", "-p", "Class"])
+        .output()
+        .map(|output| {
+            let properties = String::from_utf8_lossy(&output.stdout);
+            properties.contains("Remote=no") && properties.contains("Class=user")
+        })
+        .unwrap_or(false)
+}
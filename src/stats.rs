@@ -0,0 +1,126 @@
+// `synctool stats` scans the root and shows, for each directory at the top of
+// it, how many files get synced, how big they are and how much the ignores
+// leave out, then which ignores leave out the most. For spotting bloat and
+// tuning the ignores:
+//
+//     PROJECT        FILES    SIZE       IGNORED
+//     thegame        1204     88.1 MB    2.3 GB
+//     notes          310      4.2 MB     0 B
+//
+// Symlinks aren't followed.
+
+use eyre::{bail, Result, WrapErr};
+use std::{collections::BTreeMap, fs, path::Path};
+use synctool_core::{config::Config, ignore::ignore_matches, output::human_bytes};
+
+#[derive(Default)]
+struct Totals {
+    files: u64,
+    bytes: u64,
+    ignored_bytes: u64,
+}
+
+pub fn stats(config: &Config, args: &[String]) -> Result<()> {
+    if !args.is_empty() {
+        bail!("Usage: stats");
+    }
+
+    let mut projects: BTreeMap<String, Totals> = BTreeMap::new();
+    // Bytes left out by each ignore, by the first one that matches
+    let mut by_ignore: BTreeMap<&str, u64> = BTreeMap::new();
+    let root = Path::new(&config.root);
+    let entries =
+        fs::read_dir(root).wrap_err_with(|| format!("Couldn't list {}", root.display()))?;
+    for entry in entries {
+        let entry = entry?;
+        let name = entry.file_name().to_string_lossy().into_owned();
+        let project = if entry.file_type()?.is_dir() {
+            name.clone()
+        } else {
+            "(files in the root)".to_string()
+        };
+        let totals = projects.entry(project).or_default();
+        scan(config, &entry.path(), &name, totals, &mut by_ignore)?;
+    }
+
+    let width = projects.keys().map(String::len).max().unwrap_or(0).max(7);
+    println!("{:width$}  {:8} {:10} IGNORED", "PROJECT", "FILES", "SIZE");
+    let mut total = Totals::default();
+    for (project, totals) in &projects {
+        println!(
+            "{:width$}  {:<8} {:10} {}",
+            project,
+            totals.files,
+            human_bytes(totals.bytes as f64),
+            human_bytes(totals.ignored_bytes as f64)
+        );
+        total.files += totals.files;
+        total.bytes += totals.bytes;
+        total.ignored_bytes += totals.ignored_bytes;
+    }
+    println!(
+        "{:width$}  {:<8} {:10} {}",
+        "total",
+        total.files,
+        human_bytes(total.bytes as f64),
+        human_bytes(total.ignored_bytes as f64)
+    );
+
+    let mut by_ignore = by_ignore.into_iter().collect::<Vec<_>>();
+    by_ignore.sort_by_key(|&(_, bytes)| std::cmp::Reverse(bytes));
+    if !by_ignore.is_empty() {
+        println!();
+        println!("Ignored the most by:");
+        for (ignore, bytes) in by_ignore.iter().take(10) {
+            println!("  {:10} {}", human_bytes(*bytes as f64), ignore);
+        }
+    }
+    Ok(())
+}
+
+// Adds up the path, a file or everything under a directory, at rel relative
+// to the root
+fn scan<'a>(
+    config: &'a Config,
+    path: &Path,
+    rel: &str,
+    totals: &mut Totals,
+    by_ignore: &mut BTreeMap<&'a str, u64>,
+) -> Result<()> {
+    let metadata = fs::symlink_metadata(path)?;
+    if let Some(ignore) = config
+        .ignores
+        .iter()
+        .find(|ignore| ignore_matches(ignore, rel))
+    {
+        let bytes = size(path, &metadata)?;
+        totals.ignored_bytes += bytes;
+        *by_ignore.entry(ignore).or_default() += bytes;
+        return Ok(());
+    }
+
+    if metadata.is_dir() {
+        for entry in fs::read_dir(path)? {
+            let entry = entry?;
+            let rel = format!("{}/{}", rel, entry.file_name().to_string_lossy());
+            scan(config, &entry.path(), &rel, totals, by_ignore)?;
+        }
+    } else {
+        totals.files += 1;
+        totals.bytes += metadata.len();
+    }
+    Ok(())
+}
+
+// Bytes in a file, or in everything under a directory
+fn size(path: &Path, metadata: &fs::Metadata) -> Result<u64> {
+    if !metadata.is_dir() {
+        return Ok(metadata.len());
+    }
+    let mut bytes = 0;
+    for entry in fs::read_dir(path)? {
+        let entry = entry?;
+        bytes += size(&entry.path(), &fs::symlink_metadata(entry.path())?)?;
+    }
+    Ok(bytes)
+}
@@ -0,0 +1,72 @@
+// `synctool status` shows when each peer last synced, so silent breakage
+// (a cron job that stopped, a desktop that never wakes) gets noticed.
+
+use eyre::{bail, Result};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use synctool_core::{
+    bmc,
+    config::Config,
+    history,
+    output::{human_bytes, human_duration},
+    runner::SystemRunner,
+};
+
+pub fn status(config: &Config, args: &[String]) -> Result<()> {
+    if !args.is_empty() {
+        bail!("Usage: status");
+    }
+
+    let runs = history::load()?;
+    let mut peers = config.peer.iter().cloned().collect::<Vec<_>>();
+    for peer in config
+        .daemon_peers
+        .iter()
+        .chain(runs.iter().map(|run| &run.peer))
+    {
+        if !peers.contains(peer) {
+            peers.push(peer.clone());
+        }
+    }
+
+    if peers.is_empty() {
+        println!("No syncs recorded yet");
+        return Ok(());
+    }
+
+    let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+    for peer in &peers {
+        let mut line = match history::last_success(&runs, peer) {
+            Some(time) => format!(
+                "{}: last synced {} ago",
+                peer,
+                human_duration(Duration::from_secs(now.saturating_sub(time)))
+            ),
+            None => format!("{}: never synced", peer),
+        };
+
+        if history::stale(&runs, peer, config.stale_after_hours).is_some() {
+            line.push_str(" (stale)");
+        }
+        let last_run = runs.iter().rev().find(|run| run.peer == *peer);
+        if last_run.is_some_and(|run| !run.ok) {
+            line.push_str(", the latest attempt failed");
+        }
+        if let Some(throughput) = history::usual_throughput(&runs, peer) {
+            line.push_str(&format!(", usually {}/s", human_bytes(throughput)));
+        }
+        // Hosts with a management controller can say whether they're on
+        let bmc_host = config
+            .host(peer)
+            .ok()
+            .filter(|host| host.power_method.bmc());
+        if let Some(host) = bmc_host {
+            match bmc::is_on(&SystemRunner, host) {
+                Ok(true) => line.push_str(", powered on"),
+                Ok(false) => line.push_str(", powered off"),
+                Err(_) => line.push_str(", power state unknown"),
+            }
+        }
+        println!("{}", line);
+    }
+    Ok(())
+}
@@ -0,0 +1,109 @@
+// `synctool statusbar` prints one line of JSON for a custom waybar module
+// (return-type json) or an i3blocks block (format=json), like
+//
+//     {"text":"desktop 2h, rpi 3d","alt":"idle","class":"stale","tooltip":"...","full_text":"desktop 2h, rpi 3d","color":"#e5c07b"}
+//
+// alt is the state in status.json (see synctool_core::state), and class is
+// "syncing" while a run is going, "failed" if the last one failed,
+// "stale" if a peer hasn't synced in stale_after_hours and "idle" otherwise.
+// It's cheap enough to run every few seconds.
+
+use eyre::{bail, Result};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use synctool_core::{
+    config::Config,
+    events::json_string,
+    history,
+    output::human_duration,
+    state::{self, State},
+};
+
+pub fn statusbar(config: &Config, args: &[String]) -> Result<()> {
+    if !args.is_empty() {
+        bail!("Usage: statusbar");
+    }
+
+    let runs = history::load()?;
+    let mut peers = config.peer.iter().cloned().collect::<Vec<_>>();
+    for peer in config
+        .daemon_peers
+        .iter()
+        .chain(runs.iter().map(|run| &run.peer))
+    {
+        if !peers.contains(peer) {
+            peers.push(peer.clone());
+        }
+    }
+
+    let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+    let mut ages = Vec::new();
+    let mut tooltip = Vec::new();
+    for peer in &peers {
+        match history::last_success(&runs, peer) {
+            Some(time) => {
+                let age = now.saturating_sub(time);
+                ages.push(format!("{} {}", peer, short_age(age)));
+                tooltip.push(format!(
+                    "{}: last synced {} ago",
+                    peer,
+                    human_duration(Duration::from_secs(age))
+                ));
+            }
+            None => {
+                ages.push(format!("{} never", peer));
+                tooltip.push(format!("{}: never synced", peer));
+            }
+        }
+    }
+    let stale = peers
+        .iter()
+        .any(|peer| history::stale(&runs, peer, config.stale_after_hours).is_some());
+
+    let status = state::current();
+    let (text, class) = match &status {
+        Some(status) if status.state == State::Syncing => {
+            (format!("syncing with {}", status.peer), "syncing")
+        }
+        Some(status) if status.state == State::Failed => (ages.join(", "), "failed"),
+        _ if stale => (ages.join(", "), "stale"),
+        _ if peers.is_empty() => ("no syncs yet".to_string(), "idle"),
+        _ => (ages.join(", "), "idle"),
+    };
+    if let Some(status) = &status {
+        let ago = human_duration(Duration::from_secs(now.saturating_sub(status.since)));
+        tooltip.insert(
+            0,
+            match status.state {
+                State::Syncing => format!("Syncing with {} for {}", status.peer, ago),
+                State::Failed => format!("Sync with {} failed {} ago", status.peer, ago),
+                State::Idle => format!("Synced with {} {} ago", status.peer, ago),
+            },
+        );
+    }
+    let alt = status.as_ref().map_or("idle", |status| status.state.name());
+    // For i3blocks, which doesn't know about classes
+    let color = match class {
+        "failed" => ",\"color\":\"#e06c75\"",
+        "stale" => ",\"color\":\"#e5c07b\"",
+        _ => "",
+    };
+
+    println!(
+        "{{\"text\":{0},\"alt\":{1},\"class\":{2},\"tooltip\":{3},\"full_text\":{0}{4}}}",
+        json_string(&text),
+        json_string(alt),
+        json_string(class),
+        json_string(&tooltip.join("\n")),
+        color
+    );
+    Ok(())
+}
+
+// Like 45m, 5h or 3d
+fn short_age(seconds: u64) -> String {
+    match seconds / 60 {
+        minutes if minutes >= 48 * 60 => format!("{}d", minutes / (24 * 60)),
+        minutes if minutes >= 120 => format!("{}h", minutes / 60),
+        minutes => format!("{}m", minutes),
+    }
+}
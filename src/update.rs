@@ -0,0 +1,143 @@
+// `synctool self-update` fetches a newer build from the release host in the
+// config and replaces the running binary, so every machine can be brought to
+// the same version with one command. The release host is a plain directory
+// served over HTTP(S):
+//
+//     latest                               contains a version, e.g. 0.2.0
+//     0.2.0/synctool-x86_64-linux          the binary
+//     0.2.0/synctool-x86_64-linux.sha256   output of sha256sum
+//     0.2.0/synctool-x86_64-linux.sig      detached gpg signature
+//
+// The checksum comes from the same host as the binary, so it only catches a
+// broken download; the signature is what's trusted, and is required unless
+// [update] require_signature = false. It has to be by the key [update]
+// signing_key pins, going by gpg's VALIDSIG status line, since gpg --verify
+// alone takes a good signature by any key in the keyring.
+
+use eyre::{bail, ensure, eyre, Result, WrapErr};
+use std::{
+    env::{
+        self,
+        consts::{ARCH, OS},
+    },
+    fs,
+    os::unix::fs::PermissionsExt,
+    path::Path,
+    process::Command,
+};
+use synctool_core::config::Config;
+
+pub fn self_update(config: &Config, args: &[String]) -> Result<()> {
+    let force = match args {
+        [] => false,
+        [flag] if flag == "--force" => true,
+        _ => bail!("Usage: self-update [--force]"),
+    };
+    let url = config
+        .update_url
+        .as_deref()
+        .ok_or_else(|| eyre!("Set url in the [update] section of the config first"))?
+        .trim_end_matches('/');
+
+    let current = env!("CARGO_PKG_VERSION");
+    let latest = String::from_utf8(fetch(&format!("{}/latest", url))?)?;
+    let latest = latest.trim();
+    if !force && !newer(latest, current) {
+        summary!("Already up to date ({})", current);
+        return Ok(());
+    }
+
+    let exe = env::current_exe()?;
+    let new_exe = exe.with_extension("new");
+    let name = format!("synctool-{}-{}", ARCH, OS);
+    let binary_url = format!("{}/{}/{}", url, latest, name);
+
+    phase!("Downloading {}", binary_url);
+    fs::write(&new_exe, fetch(&binary_url)?)?;
+
+    let result = verify(config, &binary_url, &new_exe);
+    if result.is_err() {
+        fs::remove_file(&new_exe)?;
+    }
+    result?;
+
+    fs::set_permissions(&new_exe, fs::Permissions::from_mode(0o755))?;
+    fs::rename(&new_exe, &exe).wrap_err_with(|| format!("Couldn't replace {}", exe.display()))?;
+    summary!("Updated {} -> {}", current, latest);
+    Ok(())
+}
+
+fn verify(config: &Config, binary_url: &str, binary: &Path) -> Result<()> {
+    let expected = String::from_utf8(fetch(&format!("{}.sha256", binary_url))?)?;
+    let expected = expected.split_whitespace().next().unwrap_or_default();
+
+    let output = Command::new("sha256sum").arg(binary).output()?;
+    ensure!(output.status.success(), "sha256sum failed");
+    let actual = String::from_utf8_lossy(&output.stdout);
+    let actual = actual.split_whitespace().next().unwrap_or_default();
+    ensure!(
+        !expected.is_empty() && expected == actual,
+        "Checksum mismatch for downloaded binary"
+    );
+
+    if config.update_require_signature {
+        let key = config.update_signing_key.as_deref().ok_or_else(|| {
+            eyre!(
+                "Set [update] signing_key to the fingerprint of the release key, \
+                 or require_signature = false to skip the signature"
+            )
+        })?;
+        let signature = binary.with_extension("sig");
+        fs::write(&signature, fetch(&format!("{}.sig", binary_url))?)?;
+        let output = Command::new("gpg")
+            .args(["--status-fd", "1", "--verify"])
+            .arg(&signature)
+            .arg(binary)
+            .output();
+        fs::remove_file(&signature)?;
+        let output = output?;
+        ensure!(
+            output.status.success() && signed_by(&String::from_utf8_lossy(&output.stdout), key),
+            "Downloaded binary isn't signed by {}",
+            key
+        );
+    } else {
+        warn!("Not checking the signature, since [update] require_signature = false");
+    }
+
+    Ok(())
+}
+
+// Whether gpg's status output has a good signature by the key, either the
+// signing subkey or its primary key
+fn signed_by(status: &str, key: &str) -> bool {
+    status.lines().any(|line| {
+        let fields = line.split_whitespace().collect::<Vec<_>>();
+        match fields.as_slice() {
+            ["[GNUPG:]", "VALIDSIG", fingerprint, rest @ ..] => {
+                fingerprint.eq_ignore_ascii_case(key)
+                    || rest
+                        .get(8)
+                        .is_some_and(|primary| primary.eq_ignore_ascii_case(key))
+            }
+            _ => false,
+        }
+    })
+}
+
+fn fetch(url: &str) -> Result<Vec<u8>> {
+    let output = Command::new("curl").args(["-fsSL", url]).output()?;
+    ensure!(
+        output.status.success(),
+        "Couldn't download {}: {}",
+        url,
+        String::from_utf8_lossy(&output.stderr).trim()
+    );
+    Ok(output.stdout)
+}
+
+// Compares dotted version numbers, e.g. 0.10.0 is newer than 0.9.3
+fn newer(a: &str, b: &str) -> bool {
+    let parse = |v: &str| -> Vec<u64> { v.split('.').map(|n| n.parse().unwrap_or(0)).collect() };
+    parse(a) > parse(b)
+}
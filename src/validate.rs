@@ -0,0 +1,301 @@
+// `synctool config validate` reports every problem in the config file with its
+// line number, rather than stopping at the first one like a normal run does.
+
+use eyre::{bail, Result};
+use std::fs;
+use synctool_core::{
+    config::{parse_document, Config, Document},
+    ignore::ignore_matches,
+    runner::SystemRunner,
+    wake::reachable_all,
+};
+
+// Known keys for each table, "*" for any. "*" in a table name matches any single
+// name, e.g. a host.
+const SCHEMA: &[(&[&str], &[&str])] = &[
+    (
+        &["sync"],
+        &[
+            "peer",
+            "root",
+            "ignores",
+            "stale_after_hours",
+            "warn_clock_skew",
+            "max_clock_skew",
+            "large_file",
+            "cleanup_age",
+            "special_files",
+            "disk_images",
+            "dedup",
+        ],
+    ),
+    (&["unison"], &["path", "args", "binaries"]),
+    (
+        &["ssh"],
+        &[
+            "passphrase_from_keyring",
+            "connect_timeout",
+            "server_alive_interval",
+            "server_alive_count_max",
+        ],
+    ),
+    (
+        &["daemon"],
+        &[
+            "peers",
+            "interval",
+            "sync_on_connect",
+            "sync_on_unlock",
+            "watch",
+            "debounce",
+            "max_batch_wait",
+            "settle",
+            "sync_on_remote_change",
+            "relay",
+            "cooldown",
+            "digest",
+        ],
+    ),
+    (
+        &["power"],
+        &["grace", "backend", "shutdown_command", "suspend_command"],
+    ),
+    (&["update"], &["url", "signing_key", "require_signature"]),
+    (&["log"], &["timestamps"]),
+    (&["agent"], &["listen", "token"]),
+    (&["cloud"], &["remote"]),
+    (
+        &["archive"],
+        &["remote", "recipient", "interval", "full_every"],
+    ),
+    (&["backup"], &["tool", "repository", "host"]),
+    (&["blobs"], &["dirs", "min_size"]),
+    (&["syncthing"], &["api", "api_key", "folder", "timeout"]),
+    (&["homeassistant"], &["url", "token"]),
+    (&["aliases"], &["*"]),
+    (&["projects", "*"], &["path", "interval", "priority"]),
+    (&["databases", "*"], &["path", "dump", "snapshot"]),
+    (
+        &["profiles", "*"],
+        &[
+            "paths",
+            "ignores",
+            "host",
+            "local_power",
+            "remote_power",
+            "special_files",
+        ],
+    ),
+    (&["notify", "*"], &["url", "events"]),
+    (
+        &["hosts", "*"],
+        &[
+            "address",
+            "addresses",
+            "root",
+            "unison_servercmd",
+            "unison_servercmds",
+            "unison_path",
+            "after_sync",
+            "stop_services",
+            "unison_args",
+            "perms",
+            "owner",
+            "group",
+            "numeric_ids",
+            "xattrs",
+            "acls",
+            "symlinks",
+            "hard_links",
+            "sparse",
+            "normalize_names",
+            "case_collisions",
+            "gpg_recipient",
+            "syncthing_device",
+            "homeassistant",
+            "homeassistant_shutdown",
+            "homeassistant_suspend",
+            "homeassistant_wake",
+            "sudo_password_from_keyring",
+            "power_method",
+            "identity_file",
+            "identities_only",
+            "host_key_checking",
+            "host_key",
+            "bmc_address",
+            "bmc_user",
+            "ups",
+            "shutdown_command",
+            "suspend_command",
+            "flush",
+            "agent",
+            "agent_token",
+            "synctool",
+            "probe",
+            "relay",
+            "wake_command",
+            "wake_via",
+            "wake_timeout",
+            "wake_poll_interval",
+            "wake",
+        ],
+    ),
+];
+
+const IGNORE_KINDS: &[&str] = &["Name", "Path", "BelowPath", "Regex"];
+
+pub fn validate(args: &[String]) -> Result<()> {
+    let network = match args {
+        [] => true,
+        [flag] if flag == "--offline" => false,
+        _ => bail!("Usage: config validate [--offline]"),
+    };
+
+    let paths = Config::paths();
+    if paths.is_empty() {
+        println!("No config files, using the defaults");
+        return Ok(());
+    }
+
+    let mut total = 0;
+    for path in paths {
+        let text = match fs::read_to_string(&path) {
+            Ok(text) => text,
+            Err(err) => bail!("Couldn't read {}: {}", path.display(), err),
+        };
+
+        let problems = check_file(&text, network);
+        if problems.is_empty() {
+            println!("{} is valid", path.display());
+            continue;
+        }
+
+        println!("{}:", path.display());
+        for (line, problem) in &problems {
+            // Errors from the parser already say which line they're on
+            if *line == 0 {
+                println!("  {}", problem);
+            } else {
+                println!("  line {}: {}", line, problem);
+            }
+        }
+        total += problems.len();
+    }
+
+    if total > 0 {
+        bail!("{} problem(s) found", total);
+    }
+    Ok(())
+}
+
+// Returns (line, problem) pairs sorted by line
+fn check_file(text: &str, network: bool) -> Vec<(usize, String)> {
+    let mut problems = Vec::new();
+    match parse_document(text) {
+        // A syntax error means nothing after it can be checked
+        Err(err) => problems.push((0, err.to_string())),
+        Ok(document) => {
+            check_keys(&document, &mut problems);
+            match Config::parse(text) {
+                Err(err) => problems.push((0, err.to_string())),
+                Ok(config) => {
+                    check_ignores(&document, &config, &mut problems);
+                    if network {
+                        check_hosts(&document, &config, &mut problems);
+                    }
+                }
+            }
+        }
+    }
+
+    problems.sort_by_key(|(line, _)| *line);
+    problems
+}
+
+fn check_keys(document: &Document, problems: &mut Vec<(usize, String)>) {
+    for (name, table) in document {
+        if name.is_empty() {
+            for (key, entry) in &table.entries {
+                problems.push((entry.line, format!("unknown key {} outside a table", key)));
+            }
+            continue;
+        }
+
+        let known = SCHEMA.iter().find(|(pattern, _)| {
+            pattern.len() == name.len()
+                && pattern.iter().zip(name).all(|(p, n)| *p == "*" || p == n)
+        });
+
+        match known {
+            None => problems.push((table.line, format!("unknown table [{}]", name.join(".")))),
+            Some((_, keys)) => {
+                for (key, entry) in &table.entries {
+                    if !keys.contains(&"*") && !keys.contains(&key.as_str()) {
+                        let message = format!("unknown key {} in [{}]", key, name.join("."));
+                        problems.push((entry.line, message));
+                    }
+                }
+            }
+        }
+    }
+}
+
+fn check_ignores(document: &Document, config: &Config, problems: &mut Vec<(usize, String)>) {
+    let line = document
+        .get(&vec!["sync".to_string()])
+        .and_then(|table| table.get("ignores"))
+        .map_or(0, |entry| entry.line);
+
+    for (i, ignore) in config.ignores.iter().enumerate() {
+        let (kind, pattern) = ignore.split_once(' ').unwrap_or((ignore, ""));
+        if !IGNORE_KINDS.contains(&kind) || pattern.is_empty() {
+            let message = format!(
+                "ignore {:?} should look like \"Name PATTERN\" ({})",
+                ignore,
+                IGNORE_KINDS.join(", ")
+            );
+            problems.push((line, message));
+            continue;
+        }
+
+        if config.ignores[..i].contains(ignore) {
+            problems.push((line, format!("ignore {:?} is listed twice", ignore)));
+            continue;
+        }
+
+        for (j, other) in config.ignores.iter().enumerate() {
+            let first = !config.ignores[..j].contains(other);
+            if first && other != ignore && kind != "Name" && ignore_matches(other, pattern) {
+                let message = format!("ignore {:?} is already covered by {:?}", ignore, other);
+                problems.push((line, message));
+            }
+        }
+    }
+}
+
+fn check_hosts(document: &Document, config: &Config, problems: &mut Vec<(usize, String)>) {
+    // Only hosts mentioned in the file; the built in defaults aren't the user's problem
+    let hosts = config
+        .hosts
+        .iter()
+        .filter_map(|host| {
+            let name = vec!["hosts".to_string(), host.name.clone()];
+            Some((host, document.get(&name)?.line))
+        })
+        .collect::<Vec<_>>();
+
+    let reachable = reachable_all(
+        &SystemRunner,
+        &hosts.iter().map(|(host, _)| *host).collect::<Vec<_>>(),
+    );
+
+    for ((host, line), reachable) in hosts.iter().zip(reachable) {
+        if !reachable {
+            let message = format!(
+                "host {} ({}) doesn't respond to {}",
+                host.name, host.address, host.probe
+            );
+            problems.push((*line, message));
+        }
+    }
+}
@@ -0,0 +1,30 @@
+// `synctool versions` is run over ssh by the synctool on the other end of a
+// sync, to scan this end and trade file versions with it. Its output is the
+// version table, so logging goes to stderr.
+
+use eyre::{bail, Result};
+use std::io::{stdin, Read};
+use synctool_core::{config::Config, hostname, output, versions::Table};
+
+pub fn versions(config: &Config, args: &[String]) -> Result<()> {
+    output::set_stderr(true);
+    let mut table = Table::load()?;
+    match args {
+        [] => {}
+        [flag] if flag == "--scan" => {
+            table.scan(config, &hostname())?;
+            table.save()?;
+        }
+        // Reads the other end's table from stdin, after a sync
+        [flag] if flag == "--merge" => {
+            let mut text = String::new();
+            stdin().read_to_string(&mut text)?;
+            table.scan(config, &hostname())?;
+            table.merge_matching(&Table::parse(&text)?);
+            table.save()?;
+        }
+        _ => bail!("Usage: versions [--scan | --merge]"),
+    }
+    print!("{}", table.to_text());
+    Ok(())
+}
@@ -0,0 +1,567 @@
+// `synctool agent` runs on a remote and answers requests from the synctool on
+// the other end, instead of that side poking at the remote with one-off ssh
+// commands. It talks the protocol in protocol.rs, either over the stdin and
+// stdout of an ssh session or on a TCP port with --listen.
+//
+//     lock                 Take the sync lock, until unlock or disconnecting
+//     unlock
+//     status               idle=yes|no free_bytes=N changes=N booted=EPOCH
+//                          [power=ac|battery]
+//     changed path=PATH    Note that PATH changed on the other end
+//     power action=ACTION [force=true]
+//                          Suspend or shut down this machine, unless someone
+//                          is using it (or force is given) or another
+//                          session holds the lock
+//     trigger peer=NAME    Start syncing with NAME (from this machine's config)
+//     watch                Report changes under the root from now on, as a
+//                          "changed count=N" message for each batch (settled
+//                          like the daemon's watch mode), with a "keepalive"
+//                          every 30 seconds in between. Nothing else can be
+//                          sent on the connection afterwards.
+
+use crate::{
+    config::{Config, Host},
+    power::{do_local_power_action, PowerAction},
+    protocol::{self, Message, VERSION},
+    runner::{Process, Runner, SystemRunner},
+    ssh::ssh,
+    state_dir, ups,
+    watch::{Batch, Watcher},
+};
+use eyre::{bail, ensure, eyre, Result, WrapErr};
+use std::{
+    env,
+    ffi::CString,
+    fs::{self, File, OpenOptions},
+    io::{self, BufRead, BufReader, Read, Write},
+    net::{TcpListener, TcpStream, ToSocketAddrs},
+    os::unix::io::AsRawFd,
+    path::{Path, PathBuf},
+    process::{Command, Stdio},
+    thread,
+    time::{Duration, Instant},
+};
+
+// The server side, answering messages from input until it's closed. With a
+// token, hello must carry it.
+pub fn serve(
+    config: &Config,
+    token: Option<&str>,
+    input: impl BufRead,
+    output: impl Write,
+) -> Result<()> {
+    serve_in(&state_dir(), config, token, input, output)
+}
+
+// serve, keeping the lock and the changes file in `dir`
+fn serve_in(
+    dir: &Path,
+    config: &Config,
+    token: Option<&str>,
+    mut input: impl BufRead,
+    mut output: impl Write,
+) -> Result<()> {
+    let mut session = Session {
+        dir: dir.to_path_buf(),
+        greeted: false,
+        lock: None,
+    };
+    // Replies to hello always carry the version, so the client can say which
+    // end is out of date
+    while let Some(message) = protocol::read(&mut input)? {
+        if message.kind == "watch" && session.greeted {
+            protocol::write(&mut output, &Message::ok())?;
+            return stream_changes(config, dir, &mut output);
+        }
+        let mut response = session
+            .handle(config, token, &message)
+            .unwrap_or_else(|err| Message::error(&format!("{:#}", err)));
+        if message.kind == "hello" && response.get("version").is_none() {
+            response = response.with("version", VERSION);
+        }
+        protocol::write(&mut output, &response)?;
+    }
+    Ok(())
+}
+
+// Serves every connection to address, each in its own thread.
+pub fn listen(config: &Config, address: &str, token: &str) -> Result<()> {
+    let listener =
+        TcpListener::bind(address).wrap_err_with(|| format!("Couldn't listen on {}", address))?;
+    log!("Listening on {}", address);
+
+    thread::scope(|scope| {
+        for stream in listener.incoming() {
+            let stream = match stream {
+                Ok(stream) => stream,
+                Err(err) => {
+                    warn!("Couldn't accept a connection: {}", err);
+                    continue;
+                }
+            };
+            scope.spawn(move || {
+                let peer = stream
+                    .peer_addr()
+                    .map_or("?".to_string(), |peer| peer.to_string());
+                let served = stream
+                    .try_clone()
+                    .map_err(Into::into)
+                    .and_then(|input| serve(config, Some(token), BufReader::new(input), stream));
+                if let Err(err) = served {
+                    warn!("Connection from {}: {:#}", peer, err);
+                }
+            });
+        }
+    });
+    Ok(())
+}
+
+struct Session {
+    // Where the lock and the changes file are, the state dir but in tests
+    dir: PathBuf,
+    // Whether hello went through
+    greeted: bool,
+    // Held while this session has the sync lock; closing it releases the lock
+    lock: Option<File>,
+}
+
+impl Session {
+    fn handle(
+        &mut self,
+        config: &Config,
+        token: Option<&str>,
+        message: &Message,
+    ) -> Result<Message> {
+        if message.kind == "hello" {
+            let version = message.get("version").and_then(|v| v.parse().ok());
+            if version != Some(VERSION) {
+                bail!(
+                    "this agent speaks protocol version {}, not {}",
+                    VERSION,
+                    message.get("version").unwrap_or("?")
+                );
+            }
+            if token.is_some() && message.get("token") != token {
+                bail!("wrong token");
+            }
+            self.greeted = true;
+            return Ok(Message::ok().with("version", VERSION));
+        }
+        if !self.greeted {
+            bail!("hello must come first");
+        }
+
+        match message.kind.as_str() {
+            "lock" => {
+                if self.lock.is_none() {
+                    self.lock = Some(
+                        try_lock(&self.dir)?.ok_or_else(|| eyre!("another sync holds the lock"))?,
+                    );
+                }
+                Ok(Message::ok())
+            }
+            "unlock" => {
+                self.lock = None;
+                Ok(Message::ok())
+            }
+            "status" => {
+                let status = Message::ok()
+                    .with("idle", if user_active() { "no" } else { "yes" })
+                    .with("free_bytes", free_bytes(&config.root).unwrap_or(0))
+                    .with(
+                        "changes",
+                        fs::read_to_string(changes_path(&self.dir))
+                            .map_or(0, |changes| changes.lines().count()),
+                    )
+                    .with("booted", boot_time().unwrap_or(0));
+                Ok(match ups::local_on_battery() {
+                    Some(true) => status.with("power", "battery"),
+                    Some(false) => status.with("power", "ac"),
+                    None => status,
+                })
+            }
+            "changed" => {
+                let path = message
+                    .get("path")
+                    .ok_or_else(|| eyre!("changed needs a path"))?;
+                let mut changes = OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(changes_path(&self.dir))?;
+                writeln!(changes, "{}", path.replace('\n', " "))?;
+                Ok(Message::ok())
+            }
+            "power" => {
+                let action = match message.get("action") {
+                    Some("shutdown") => PowerAction::Shutdown,
+                    Some("suspend") => PowerAction::Suspend,
+                    other => bail!("unknown power action {:?}", other.unwrap_or("")),
+                };
+                if self.lock.is_none() && try_lock(&self.dir)?.is_none() {
+                    bail!("a sync is in progress");
+                }
+                if message.get("force") != Some("true") && user_active() {
+                    bail!("someone is using this machine");
+                }
+                do_local_power_action(&SystemRunner, config, &action)?;
+                Ok(Message::ok())
+            }
+            "trigger" => {
+                let peer = config
+                    .host(message.get("peer").unwrap_or_default())?
+                    .name
+                    .clone();
+                let child = Command::new(env::current_exe()?)
+                    .args(["-q", "-t", &peer])
+                    .stdin(Stdio::null())
+                    .stdout(Stdio::null())
+                    .spawn()?;
+                Ok(Message::ok().with("pid", child.id()))
+            }
+            other => bail!("unknown message {:?}", other),
+        }
+    }
+}
+
+// Sends changes under the root until the other end hangs up. Changes made
+// while a sync holds the lock are that sync's own and aren't reported.
+fn stream_changes(config: &Config, dir: &Path, output: &mut impl Write) -> Result<()> {
+    let mut watcher = Watcher::new(&config.root, &config.ignores);
+    let mut batch = Batch::default();
+    let mut last_sent = Instant::now();
+    let mut was_locked = false;
+    loop {
+        // The sync's last changes can still be queued when it unlocks
+        let locked = try_lock(dir)?.is_none();
+        if locked || was_locked {
+            watcher.read(&mut Batch::default());
+            batch = Batch::default();
+        } else {
+            watcher.read(&mut batch);
+        }
+        was_locked = locked;
+
+        let message = if batch.ready(config) {
+            let count = batch.changed();
+            batch = Batch::default();
+            Some(Message::new("changed").with("count", count))
+        } else if last_sent.elapsed() >= Duration::from_secs(30) {
+            Some(Message::new("keepalive"))
+        } else {
+            None
+        };
+        if let Some(message) = message {
+            if protocol::write(output, &message).is_err() {
+                return Ok(());
+            }
+            last_sent = Instant::now();
+        }
+        thread::sleep(Duration::from_secs(1));
+    }
+}
+
+fn changes_path(dir: &Path) -> PathBuf {
+    dir.join("agent-changes")
+}
+
+// The lock file in `dir`, if no other session holds it
+fn try_lock(dir: &Path) -> Result<Option<File>> {
+    fs::create_dir_all(dir)?;
+    let file = OpenOptions::new()
+        .create(true)
+        .write(true)
+        .truncate(false)
+        .open(dir.join("agent.lock"))?;
+    let locked = unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) } == 0;
+    Ok(if locked { Some(file) } else { None })
+}
+
+// Whether any logind session is active and not idle
+fn user_active() -> bool {
+    let sessions = match Command::new("loginctl")
+        .args(["list-sessions", "--no-legend"])
+        .output()
+    {
+        Ok(output) => String::from_utf8_lossy(&output.stdout).into_owned(),
+        Err(_) => return false,
+    };
+
+    sessions
+        .lines()
+        .filter_map(|line| line.split_whitespace().next())
+        .any(|id| {
+            let properties = Command::new("loginctl")
+                .args(["show-session", id, "-p", "Active", "-p", "IdleHint"])
+                .output()
+                .map(|output| String::from_utf8_lossy(&output.stdout).into_owned())
+                .unwrap_or_default();
+            properties.contains("Active=yes") && properties.contains("IdleHint=no")
+        })
+}
+
+// When this machine booted, in seconds since the epoch
+fn boot_time() -> Option<u64> {
+    fs::read_to_string("/proc/stat")
+        .ok()?
+        .lines()
+        .find_map(|line| line.strip_prefix("btime "))?
+        .trim()
+        .parse()
+        .ok()
+}
+
+fn free_bytes(path: &str) -> Option<u64> {
+    let path = CString::new(path).ok()?;
+    let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+    if unsafe { libc::statvfs(path.as_ptr(), &mut stat) } != 0 {
+        return None;
+    }
+    Some(stat.f_bavail as u64 * stat.f_frsize as u64)
+}
+
+// The client side: a session with the agent on a host.
+pub struct Agent {
+    // The ssh session, when not talking over TCP
+    process: Option<Box<dyn Process>>,
+    input: Box<dyn Write>,
+    output: BufReader<Box<dyn Read>>,
+    host: String,
+    // For --simulate, where every request just succeeds
+    simulated: bool,
+}
+
+impl Agent {
+    // Connects to the agent with ssh, or over TCP if the host's agent is
+    // tcp:ADDRESS:PORT, and says hello.
+    pub fn connect(runner: &dyn Runner, host: &Host) -> Result<Agent> {
+        let agent_command = host
+            .agent
+            .as_deref()
+            .ok_or_else(|| eyre!("No agent is configured for {}", host.name))?;
+        if runner.simulated() {
+            // Still started through the runner over ssh, so a host that
+            // doesn't answer can be simulated
+            if !agent_command.starts_with("tcp:") {
+                let status = runner.status(ssh(host).arg(agent_command).stdin(Stdio::null()))?;
+                ensure!(status.success(), "Agent on {} hung up", host.name);
+            }
+            return Ok(Agent {
+                process: None,
+                input: Box::new(io::sink()),
+                output: BufReader::new(Box::new(io::empty())),
+                host: host.name.clone(),
+                simulated: true,
+            });
+        }
+
+        let mut agent = match agent_command.strip_prefix("tcp:") {
+            Some(address) => {
+                let address = address
+                    .to_socket_addrs()?
+                    .next()
+                    .ok_or_else(|| eyre!("{} doesn't resolve", address))?;
+                let stream = TcpStream::connect_timeout(&address, Duration::from_secs(8))
+                    .wrap_err_with(|| format!("Couldn't reach the agent on {}", host.name))?;
+                stream.set_read_timeout(Some(Duration::from_secs(60)))?;
+                Agent {
+                    process: None,
+                    input: Box::new(stream.try_clone()?),
+                    output: BufReader::new(Box::new(stream)),
+                    host: host.name.clone(),
+                    simulated: false,
+                }
+            }
+            None => {
+                let mut process = runner.spawn(
+                    ssh(host)
+                        .arg(agent_command)
+                        .stdin(Stdio::piped())
+                        .stdout(Stdio::piped()),
+                )?;
+                let input = process
+                    .take_stdin()
+                    .ok_or_else(|| eyre!("No agent stdin"))?;
+                let output = process
+                    .take_stdout()
+                    .ok_or_else(|| eyre!("No agent stdout"))?;
+                Agent {
+                    process: Some(process),
+                    input,
+                    output: BufReader::new(output),
+                    host: host.name.clone(),
+                    simulated: false,
+                }
+            }
+        };
+
+        let mut hello = Message::new("hello").with("version", VERSION);
+        if let Some(token) = &host.agent_token {
+            hello = hello.with("token", token);
+        }
+        let reply = agent.exchange(&hello)?;
+        match reply.get("version") {
+            Some(version) if version == VERSION.to_string() => {}
+            version => bail!(
+                "The agent on {} speaks protocol version {} but this synctool speaks {}; \
+                 update synctool on the older end",
+                host.name,
+                version.unwrap_or("0"),
+                VERSION
+            ),
+        }
+        agent.check(reply)?;
+        Ok(agent)
+    }
+
+    // Sends a message and returns the agent's result if it went well.
+    pub fn request(&mut self, message: Message) -> Result<Message> {
+        let reply = self.exchange(&message)?;
+        self.check(reply)
+    }
+
+    // Sends watch, then calls on_change with the number of files changed in
+    // each batch the agent reports, until it returns false or the connection
+    // drops.
+    pub fn watch(&mut self, mut on_change: impl FnMut(usize) -> bool) -> Result<()> {
+        self.request(Message::new("watch"))?;
+        if self.simulated {
+            return Ok(());
+        }
+        loop {
+            let message = protocol::read(&mut self.output)
+                .wrap_err_with(|| format!("Lost the agent on {}", self.host))?
+                .ok_or_else(|| eyre!("Agent on {} hung up", self.host))?;
+            match message.kind.as_str() {
+                "changed" => {
+                    let count = message.get("count").and_then(|n| n.parse().ok());
+                    if !on_change(count.unwrap_or(0)) {
+                        return Ok(());
+                    }
+                }
+                "keepalive" => {}
+                other => bail!("Agent on {} sent {} while watching", self.host, other),
+            }
+        }
+    }
+
+    fn exchange(&mut self, message: &Message) -> Result<Message> {
+        if self.simulated {
+            log!("Simulated agent {} on {}", message.kind, self.host);
+            return Ok(Message::ok().with("version", VERSION));
+        }
+        protocol::write(&mut self.input, message)
+            .wrap_err_with(|| format!("Couldn't send to the agent on {}", self.host))?;
+        let reply = protocol::read(&mut self.output)
+            .wrap_err_with(|| format!("Bad reply from the agent on {}", self.host))?
+            .ok_or_else(|| eyre!("Agent on {} hung up", self.host))?;
+        if reply.kind != "result" {
+            bail!(
+                "Agent on {} sent {} instead of a result",
+                self.host,
+                reply.kind
+            );
+        }
+        Ok(reply)
+    }
+
+    fn check(&self, reply: Message) -> Result<Message> {
+        if reply.get("ok") != Some("true") {
+            bail!(
+                "Agent on {}: {}",
+                self.host,
+                reply.get("error").unwrap_or("failed")
+            );
+        }
+        Ok(reply)
+    }
+}
+
+impl Drop for Agent {
+    fn drop(&mut self) {
+        // Closing the connection ends the session, releasing anything it held
+        self.input = Box::new(io::sink());
+        if let Some(process) = &mut self.process {
+            let _ = process.wait();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Serves one connection on a free port, returning a config with a host
+    // called agent pointing at it. The agent's state goes in a directory of
+    // its own, removed when the connection is over.
+    fn agent_config(token: &'static str) -> Config {
+        serve_one(Config::default(), token)
+    }
+
+    fn serve_one(server_config: Config, token: &'static str) -> Config {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        thread::spawn(move || {
+            let state = crate::TempDir::new("agent-state");
+            let (stream, _) = listener.accept().unwrap();
+            let input = BufReader::new(stream.try_clone().unwrap());
+            let _ = serve_in(&state, &server_config, Some(token), input, stream);
+        });
+
+        Config::parse(&format!(
+            "[hosts.agent]\naddress = \"127.0.0.1\"\nagent = \"tcp:{}\"\nagent_token = \"secret\"\n",
+            address
+        ))
+        .unwrap()
+    }
+
+    #[test]
+    fn talks_over_tcp() {
+        let config = agent_config("secret");
+        let mut agent = Agent::connect(&SystemRunner, config.host("agent").unwrap()).unwrap();
+        let status = agent.request(Message::new("status")).unwrap();
+        assert!(status.get("idle").is_some());
+        assert_ne!(status.get("booted"), Some("0"));
+        assert!(agent.request(Message::new("frobnicate")).is_err());
+        assert!(agent
+            .request(Message::new("power").with("action", "dance"))
+            .is_err());
+    }
+
+    #[test]
+    fn reports_changes() {
+        let root = crate::TempDir::new("agent-watch");
+        fs::create_dir_all(root.join("target")).unwrap();
+        let server_config = Config::parse(&format!(
+            "[sync]\nroot = \"{}\"\n[daemon]\ndebounce = 0\n",
+            root.display()
+        ))
+        .unwrap();
+        let config = serve_one(server_config, "secret");
+        let mut agent = Agent::connect(&SystemRunner, config.host("agent").unwrap()).unwrap();
+
+        let writer_root = root.to_path_buf();
+        thread::spawn(move || {
+            thread::sleep(Duration::from_millis(500));
+            fs::write(writer_root.join("target/ignored.o"), "x").unwrap();
+            fs::write(writer_root.join("notes.txt"), "x").unwrap();
+        });
+        let mut counts = Vec::new();
+        agent
+            .watch(|count| {
+                counts.push(count);
+                false
+            })
+            .unwrap();
+        assert_eq!(counts, vec![1]);
+    }
+
+    #[test]
+    fn wrong_token() {
+        let config = agent_config("other");
+        let err = match Agent::connect(&SystemRunner, config.host("agent").unwrap()) {
+            Ok(_) => panic!("connected with the wrong token"),
+            Err(err) => err.to_string(),
+        };
+        assert!(err.contains("wrong token"), "{}", err);
+    }
+}
@@ -0,0 +1,125 @@
+// Off-site backups of the tree to object storage (S3, B2 or anything else
+// rclone can write to), set up in the [archive] section of the config.
+//
+// Each archive is a gzipped tar of the tree, minus the ignores, encrypted with
+// gpg for the configured recipient and uploaded as
+// REMOTE/HOSTNAME-TIME-full.tar.gz.gpg or ...-incr.tar.gz.gpg. tar's
+// --listed-incremental snapshot in the state dir makes each archive hold only
+// what changed since the last one, with a full archive every full_every runs.
+// Restoring means extracting the last full archive and every incremental after
+// it, in order, with tar --listed-incremental=/dev/null.
+
+use crate::{config::Config, events, hostname, output::wall_clock, runner::Runner, state_dir};
+use eyre::{ensure, eyre, Result};
+use std::{fs, path::PathBuf, process::Command};
+
+fn snapshot() -> PathBuf {
+    state_dir().join("archive.snar")
+}
+
+// How many incrementals have been made since the last full archive
+fn count_file() -> PathBuf {
+    state_dir().join("archive-count")
+}
+
+pub fn archive(runner: &dyn Runner, config: &Config, force_full: bool) -> Result<()> {
+    let remote = config
+        .archive_remote
+        .as_deref()
+        .ok_or_else(|| eyre!("Set remote in the [archive] section of the config"))?;
+    let recipient = config
+        .archive_recipient
+        .as_deref()
+        .ok_or_else(|| eyre!("Set recipient in the [archive] section of the config"))?;
+
+    let count = fs::read_to_string(count_file())
+        .ok()
+        .and_then(|count| count.trim().parse::<u64>().ok());
+    let full = force_full
+        || !snapshot().exists()
+        || count.is_none_or(|count| count + 1 >= config.archive_full_every);
+    let kind = if full { "full" } else { "incr" };
+    let name = format!(
+        "{}-{}-{}.tar.gz.gpg",
+        hostname(),
+        wall_clock().replace(':', ""),
+        kind
+    );
+
+    fs::create_dir_all(state_dir())?;
+    // tar updates the snapshot as it goes, so it works on a copy that only
+    // replaces the real one once the archive is safely uploaded
+    let new_snapshot = snapshot().with_extension("snar.new");
+    if full {
+        let _ = fs::remove_file(&new_snapshot);
+    } else {
+        fs::copy(snapshot(), &new_snapshot)?;
+    }
+    let tarball = state_dir().join("archive.tar.gz");
+    let encrypted = state_dir().join("archive.tar.gz.gpg");
+
+    phase!("Archiving {} ({})", config.root, kind);
+    let result = events::phase("archive", || {
+        let mut tar = Command::new("tar");
+        tar.arg("-czf")
+            .arg(&tarball)
+            .arg(format!("--listed-incremental={}", new_snapshot.display()));
+        // Unanchored excludes match a name at any depth, anchored ones a path
+        // from the root
+        let (names, paths): (Vec<_>, Vec<_>) = config
+            .ignores
+            .iter()
+            .filter_map(|ignore| ignore.split_once(' '))
+            .partition(|(kind, _)| *kind == "Name");
+        for (_, pattern) in names {
+            tar.arg(format!("--exclude={}", pattern));
+        }
+        tar.arg("--anchored");
+        for (_, pattern) in paths {
+            tar.arg(format!("--exclude=./{}", pattern.replace(".*", "*")));
+        }
+        tar.args(["-C", &config.root, "."]);
+        ensure!(runner.status(&mut tar)?.success(), "tar failed");
+
+        let _ = fs::remove_file(&encrypted);
+        let gpg = runner.status(
+            Command::new("gpg")
+                .args(["--batch", "--encrypt", "--recipient", recipient, "--output"])
+                .arg(&encrypted)
+                .arg(&tarball),
+        )?;
+        ensure!(gpg.success(), "gpg couldn't encrypt the archive");
+
+        let destination = format!("{}/{}", remote, name);
+        log!("Uploading to {}", destination);
+        let upload = runner.status(
+            Command::new("rclone")
+                .arg("copyto")
+                .arg(&encrypted)
+                .arg(&destination),
+        )?;
+        ensure!(
+            upload.success(),
+            "Couldn't upload the archive to {}",
+            remote
+        );
+        Ok(())
+    });
+
+    let _ = fs::remove_file(&tarball);
+    let _ = fs::remove_file(&encrypted);
+    if let Err(err) = result {
+        let _ = fs::remove_file(&new_snapshot);
+        return Err(err);
+    }
+
+    if runner.simulated() {
+        let _ = fs::remove_file(&new_snapshot);
+    } else {
+        fs::rename(&new_snapshot, snapshot())?;
+        let count = if full { 0 } else { count.unwrap_or(0) + 1 };
+        fs::write(count_file(), count.to_string())?;
+    }
+    log!("Archived to {}/{}", remote, name);
+    Ok(())
+}
@@ -0,0 +1,183 @@
+// Unison's own archives (not the off-site backups in archive.rs): the files
+// in ~/.unison where it remembers the state of each pair of roots it syncs.
+// Each is named after a hash of both roots, the same on both ends: ar* for
+// the archive, fp* for its fingerprint cache and lk* for the lock held while
+// a sync runs. Changing a root or a peer leaves the old ones behind, and a
+// run that's killed leaves its lock.
+//
+// They can't be migrated to new roots, since the names hash the roots and
+// the contents record them, and unison 2.52+ upgrades older archive formats
+// itself. The next sync rebuilds whatever is missing, so the old ones are
+// only worth pruning.
+
+use crate::{
+    config::{Config, Host},
+    runner::Runner,
+    shell_quote,
+    ssh::ssh,
+};
+use eyre::{ensure, Result};
+use std::{
+    collections::HashSet,
+    process::{Command, Stdio},
+};
+
+// Lone archives unused for this long are pruned. Only archives for the pair
+// of ends being looked at can be told apart, so one shared with a third
+// machine isn't removed while it's still in use.
+const UNUSED_DAYS: i64 = 30;
+
+// Where unison keeps them: $UNISON, or ~/.unison, or on macOS without that,
+// ~/Library/Application Support/Unison
+const DIR: &str = "d=${UNISON:-$HOME/.unison}; \
+                   [ -d \"$d\" ] || d=\"$HOME/Library/Application Support/Unison\"; \
+                   cd \"$d\" 2>/dev/null || exit 0";
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ArchiveFile {
+    pub name: String,
+    pub size: u64,
+    // Seconds since the epoch
+    pub modified: i64,
+}
+
+impl ArchiveFile {
+    pub fn kind(&self) -> &'static str {
+        match &self.name[..2] {
+            "ar" => "archive",
+            "fp" => "fingerprints",
+            _ => "lock",
+        }
+    }
+
+    // The hash of the roots, shared by the files for one pair of them
+    pub fn hash(&self) -> &str {
+        &self.name[2..]
+    }
+}
+
+// Runs a script in the archive directory, here or on the host
+fn run(runner: &dyn Runner, host: Option<&Host>, script: &str) -> Result<String> {
+    let script = format!("{}\n{}", DIR, script);
+    let mut command = match host {
+        Some(host) => {
+            let mut command = ssh(host);
+            command.arg(script);
+            command
+        }
+        None => {
+            let mut command = Command::new("sh");
+            command.arg("-c").arg(script);
+            command
+        }
+    };
+    let output = runner.output(command.stdin(Stdio::null()))?;
+    let place = host.map_or("this machine", |host| host.name.as_str());
+    ensure!(
+        output.status.success(),
+        "Couldn't read unison's archives on {}",
+        place
+    );
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+// The archive files here, or on the host, sorted by name
+pub fn list(runner: &dyn Runner, host: Option<&Host>) -> Result<Vec<ArchiveFile>> {
+    // date -r works on both GNU and BSD, unlike stat and find -printf
+    let script = "for f in ar* fp* lk*; do \
+                  [ -f \"$f\" ] && echo \"$f $(wc -c <\"$f\" | tr -d ' ') $(date -r \"$f\" +%s)\"; \
+                  done; true";
+    let mut files = run(runner, host, script)?
+        .lines()
+        .filter_map(|line| {
+            let mut words = line.split(' ');
+            Some(ArchiveFile {
+                name: words.next()?.to_string(),
+                size: words.next()?.parse().ok()?,
+                modified: words.next()?.parse().ok()?,
+            })
+        })
+        .collect::<Vec<_>>();
+    files.sort_by(|a, b| a.name.cmp(&b.name));
+    Ok(files)
+}
+
+// Which of the files can go: locks older than [sync] cleanup_age, since no
+// sync runs that long, and archives with no counterpart in any of others
+// that haven't been used for UNUSED_DAYS
+pub fn prunable<'a>(
+    config: &Config,
+    files: &'a [ArchiveFile],
+    others: &[&[ArchiveFile]],
+    now: i64,
+) -> Vec<&'a ArchiveFile> {
+    let paired = others
+        .iter()
+        .flat_map(|files| files.iter().map(ArchiveFile::hash))
+        .collect::<HashSet<_>>();
+    files
+        .iter()
+        .filter(|file| match file.kind() {
+            "lock" => now - file.modified > config.cleanup_age as i64,
+            _ => !paired.contains(file.hash()) && now - file.modified > UNUSED_DAYS * 24 * 60 * 60,
+        })
+        .collect()
+}
+
+pub fn remove(runner: &dyn Runner, host: Option<&Host>, files: &[&ArchiveFile]) -> Result<()> {
+    if files.is_empty() {
+        return Ok(());
+    }
+    let names = files
+        .iter()
+        .map(|file| shell_quote(&file.name))
+        .collect::<Vec<_>>()
+        .join(" ");
+    run(runner, host, &format!("rm -f -- {}", names))?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::runner::{MockRunner, Reply};
+
+    #[test]
+    fn prunes_lone_archives_and_old_locks() {
+        let day = 24 * 60 * 60;
+        let now = 1000 * day;
+        let runner = MockRunner::new();
+        runner.script_replies(
+            "sh",
+            vec![Reply {
+                code: 0,
+                stdout: format!(
+                    "ar1a 2048 {}\nar2b 4096 {}\nar3c 1024 {}\nlk1a 0 {}\n",
+                    now - day,
+                    now - 90 * day,
+                    now - day,
+                    now - 2 * day
+                ),
+            }],
+        );
+        runner.script_replies(
+            "ssh",
+            vec![Reply {
+                code: 0,
+                stdout: format!("ar1a 2048 {}\n", now - day),
+            }],
+        );
+        let config = Config::default();
+        let laptop = config.host("laptop").unwrap();
+
+        let here = list(&runner, None).unwrap();
+        let there = list(&runner, Some(laptop)).unwrap();
+        assert_eq!(here.len(), 4);
+        assert_eq!(there[0].kind(), "archive");
+        let names = prunable(&config, &here, &[&there], now)
+            .iter()
+            .map(|file| file.name.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(names, ["ar2b", "lk1a"]);
+    }
+}
@@ -0,0 +1,109 @@
+// An append-only log of every wake attempt and power action, in the state dir,
+// so there's an answer to "who shut down the desktop at 3pm". Lines look like
+//
+//     2023-06-01T15:02:11+02:00 by=user@ism action=shutdown target=desktop outcome=ok args="-t desktop -ss"
+
+use crate::{hostname, output::wall_clock, runner::Runner, state_dir};
+use eyre::Result;
+use std::{
+    env,
+    fmt::Display,
+    fs::{self, OpenOptions},
+    io::Write,
+    path::PathBuf,
+    process::ExitStatus,
+};
+
+pub fn path() -> PathBuf {
+    state_dir().join("audit.log")
+}
+
+// Records an action and how it went, e.g. "ok" or "exit status: 1". Failing
+// to write the log is only worth a warning; the action already happened.
+pub fn record(runner: &dyn Runner, action: &str, target: &str, outcome: &str) {
+    if runner.simulated() {
+        return;
+    }
+
+    let outcome = if outcome == "ok" {
+        outcome.to_string()
+    } else {
+        format!("{:?}", outcome)
+    };
+    let args = env::args().skip(1).collect::<Vec<_>>().join(" ");
+    let line = format!(
+        "{} by={}@{} action={} target={} outcome={} args={:?}\n",
+        wall_clock(),
+        env::var("USER").unwrap_or_default(),
+        hostname(),
+        action,
+        target,
+        outcome,
+        args
+    );
+
+    if let Err(err) = append(&line) {
+        warn!("Couldn't write to the audit log: {err:#}");
+    }
+}
+
+// The outcome of running a command: "ok", how it exited, or why it couldn't
+// be run.
+pub fn status_outcome<E: Display>(result: &Result<ExitStatus, E>) -> String {
+    match result {
+        Ok(status) if status.success() => "ok".to_string(),
+        Ok(status) => status.to_string(),
+        Err(err) => err.to_string(),
+    }
+}
+
+// The last of `actions` that went ok on `target`, e.g. whether the desktop was
+// last suspended or shut down.
+pub fn last_ok(target: &str, actions: &[&str]) -> Option<String> {
+    last_ok_in(&fs::read_to_string(path()).ok()?, target, actions)
+}
+
+fn last_ok_in(log: &str, target: &str, actions: &[&str]) -> Option<String> {
+    let target = format!("target={} ", target);
+    log.lines().rev().find_map(|line| {
+        if !line.contains(&target) || !line.contains(" outcome=ok ") {
+            return None;
+        }
+        let action = line
+            .split(' ')
+            .find_map(|field| field.strip_prefix("action="))?;
+        actions.contains(&action).then(|| action.to_string())
+    })
+}
+
+fn append(line: &str) -> Result<()> {
+    let path = path();
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+    file.write_all(line.as_bytes())?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn finds_last_ok_action() {
+        let log = "\
+2023-06-01T15:02:11+02:00 by=me@ism action=shutdown target=desktop outcome=ok args=\"-ss\"
+2023-06-02T09:00:00+02:00 by=me@ism action=wake target=desktop outcome=ok args=\"\"
+2023-06-02T15:02:11+02:00 by=me@ism action=suspend target=desktop outcome=\"exit status: 1\" args=\"-s\"
+2023-06-02T15:03:00+02:00 by=me@ism action=suspend target=nas outcome=ok args=\"-s\"
+";
+        let actions = ["suspend", "shutdown"];
+        assert_eq!(
+            last_ok_in(log, "desktop", &actions).as_deref(),
+            Some("shutdown")
+        );
+        assert_eq!(last_ok_in(log, "nas", &actions).as_deref(), Some("suspend"));
+        assert_eq!(last_ok_in(log, "laptop", &actions), None);
+    }
+}
@@ -0,0 +1,116 @@
+// A restic or borg backup of the root after each successful sync, set up in
+// the [backup] section of the config, so a tree that synced but didn't get
+// backed up says so in the same run. With host set it runs there over ssh,
+// e.g. on the desktop the backup drive hangs off, after syncs with that host
+// and before it's suspended; otherwise it runs here after every sync.
+// Passwords come from the tools' own environment, like RESTIC_PASSWORD_FILE
+// or BORG_PASSCOMMAND.
+
+use crate::{
+    config::{BackupTool, Config, Host},
+    events,
+    runner::Runner,
+    shell_quote,
+    ssh::ssh,
+};
+use eyre::Result;
+use std::process::{Command, Stdio};
+
+fn command_line(tool: &BackupTool, repository: &str, root: &str) -> Vec<String> {
+    match tool {
+        BackupTool::Restic => vec![
+            "restic".to_string(),
+            "-r".to_string(),
+            repository.to_string(),
+            "backup".to_string(),
+            root.to_string(),
+        ],
+        // borg fills in {now} itself
+        BackupTool::Borg => vec![
+            "borg".to_string(),
+            "create".to_string(),
+            format!("{}::synctool-{{now}}", repository),
+            root.to_string(),
+        ],
+    }
+}
+
+// Backs up the root if the config asks for it after syncing with the host,
+// reporting how it went. A failed backup doesn't fail the run.
+pub fn after_sync(runner: &dyn Runner, config: &Config, host: &Host) -> Result<()> {
+    let (tool, repository) = match (&config.backup_tool, &config.backup_repository) {
+        (Some(tool), Some(repository)) => (tool, repository),
+        _ => return Ok(()),
+    };
+    let mut command = match &config.backup_host {
+        Some(name) if *name != host.name => return Ok(()),
+        Some(_) => {
+            let words = command_line(tool, repository, host.root(config));
+            let mut command = ssh(host);
+            command.arg(
+                words
+                    .iter()
+                    .map(|word| shell_quote(word))
+                    .collect::<Vec<_>>()
+                    .join(" "),
+            );
+            command
+        }
+        None => {
+            let words = command_line(tool, repository, &config.root);
+            let mut command = Command::new(&words[0]);
+            command.args(&words[1..]);
+            command
+        }
+    };
+    let place = match &config.backup_host {
+        Some(name) => format!(" on {}", name),
+        None => String::new(),
+    };
+
+    phase!("Backing up to {}{}", repository, place);
+    let backed_up = events::phase("backup", || {
+        Ok(runner.status(command.stdin(Stdio::null()))?.success())
+    })?;
+    if backed_up {
+        summary!("Synced and backed up to {}{}", repository, place);
+    } else {
+        error!("Synced, but the backup to {}{} failed", repository, place);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::runner::MockRunner;
+
+    #[test]
+    fn backs_up_on_the_backup_host() {
+        let mut config = Config {
+            backup_tool: Some(BackupTool::Borg),
+            backup_repository: Some("/mnt/backup/borg".to_string()),
+            backup_host: Some("desktop".to_string()),
+            ..Config::default()
+        };
+        let runner = MockRunner::new();
+        after_sync(&runner, &config, config.host("laptop").unwrap()).unwrap();
+        assert!(runner.commands().is_empty());
+
+        after_sync(&runner, &config, config.host("desktop").unwrap()).unwrap();
+        let commands = runner.commands();
+        assert!(commands[0].starts_with("ssh"));
+        assert!(commands[0].ends_with(&format!(
+            "'borg' 'create' '/mnt/backup/borg::synctool-{{now}}' '{}'",
+            config.root
+        )));
+
+        config.backup_tool = Some(BackupTool::Restic);
+        config.backup_host = None;
+        after_sync(&runner, &config, config.host("laptop").unwrap()).unwrap();
+        assert_eq!(
+            runner.commands()[1],
+            format!("restic -r /mnt/backup/borg backup {}", config.root)
+        );
+    }
+}
@@ -0,0 +1,193 @@
+// Directories of big binaries, like the game's assets, kept out of the main
+// sync the way git-annex does it. Files in [blobs] dirs are moved into a
+// store at .synctool-blobs under the root, named after their contents, and
+// replaced by relative symlinks to it. The store itself isn't synced, so
+// unison only carries the links, and the contents come over with `synctool
+// blobs get` on the machines that need them; until then the links dangle.
+// Changing a blob means putting a new file in place of its link, which goes
+// into the store the next time.
+
+use crate::{
+    config::{Config, Host},
+    links,
+    runner::Runner,
+    ssh,
+    versions::hash_file,
+};
+use eyre::{Result, WrapErr};
+use std::{
+    fs,
+    os::unix::fs::symlink,
+    path::Path,
+    process::{Command, Stdio},
+};
+
+// Under the root, and ignored by every sync while there are blob dirs
+pub const STORE: &str = ".synctool-blobs";
+
+pub struct Blob {
+    // Of the link, relative to the root
+    pub path: String,
+    // In the store, e.g. "3f/3fa2c81d09e6b7a4-1048576"
+    pub name: String,
+    pub size: u64,
+    pub present: bool,
+}
+
+// Moves the files in the blob dirs into the store, leaving links in their
+// place. Returns how many there were.
+pub fn track(config: &Config) -> Result<usize> {
+    let root = Path::new(&config.root);
+    let mut files = Vec::new();
+    for dir in &config.blob_dirs {
+        links::walk(config, &root.join(dir), dir, &mut |path, entry| {
+            let metadata = entry.metadata()?;
+            if metadata.is_file() && metadata.len() >= config.blob_min_size {
+                files.push((path, metadata.len()));
+            }
+            Ok(())
+        })?;
+    }
+
+    for (path, size) in &files {
+        let file = root.join(path);
+        let hash = hash_file(&file)?;
+        let name = format!("{:02x}/{:016x}-{}", hash >> 56, hash, size);
+        let stored = root.join(STORE).join(&name);
+        if stored.exists() {
+            fs::remove_file(&file)?;
+        } else {
+            fs::create_dir_all(stored.parent().unwrap())?;
+            fs::rename(&file, &stored)
+                .wrap_err_with(|| format!("Couldn't move {} into the blob store", path))?;
+        }
+        let up = "../".repeat(path.matches('/').count());
+        symlink(format!("{}{}/{}", up, STORE, name), &file)?;
+    }
+    if !files.is_empty() {
+        log!("Moved {} file(s) into the blob store", files.len());
+    }
+    Ok(files.len())
+}
+
+// Every link into the store under the blob dirs, sorted by path
+pub fn blobs(config: &Config) -> Result<Vec<Blob>> {
+    let root = Path::new(&config.root);
+    let mut blobs = Vec::new();
+    for dir in &config.blob_dirs {
+        links::walk(config, &root.join(dir), dir, &mut |path, entry| {
+            if !entry.file_type()?.is_symlink() {
+                return Ok(());
+            }
+            let target = fs::read_link(entry.path())?;
+            let target = target.to_string_lossy();
+            let name = match target.split_once(&format!("{}/", STORE)) {
+                Some((_, name)) => name.to_string(),
+                None => return Ok(()),
+            };
+            let size = name
+                .rsplit('-')
+                .next()
+                .and_then(|size| size.parse().ok())
+                .unwrap_or(0);
+            let present = root.join(STORE).join(&name).exists();
+            blobs.push(Blob {
+                path,
+                name,
+                size,
+                present,
+            });
+            Ok(())
+        })?;
+    }
+    blobs.sort_by(|a, b| a.path.cmp(&b.path));
+    Ok(blobs)
+}
+
+// Copies the contents of the missing blobs linked from under the given paths
+// (or all of them) from the host's store. Returns Ok(true) if it worked.
+pub fn get(runner: &dyn Runner, config: &Config, host: &Host, paths: &[String]) -> Result<bool> {
+    let mut names = blobs(config)?
+        .into_iter()
+        .filter(|blob| !blob.present)
+        .filter(|blob| {
+            paths.is_empty()
+                || paths.iter().any(|path| {
+                    let path = path.trim_end_matches('/');
+                    blob.path == path || blob.path.starts_with(&format!("{}/", path))
+                })
+        })
+        .map(|blob| blob.name)
+        .collect::<Vec<_>>();
+    names.sort();
+    names.dedup();
+    if names.is_empty() {
+        log!("No blobs to get");
+        return Ok(true);
+    }
+
+    log!("Getting {} blob(s) from {}", names.len(), host.name);
+    // --relative recreates what comes after the /./ here
+    let mut pull = Command::new("rsync");
+    pull.args(["-a", "--partial-dir=.rsync-partial", "--relative", "-e"])
+        .arg(format!("ssh {}", ssh::args_line(host)))
+        .args(names.iter().map(|name| {
+            format!(
+                "{}:{}/./{}/{}",
+                host.address,
+                host.root(config),
+                STORE,
+                name
+            )
+        }))
+        .arg(format!("{}/", config.root))
+        .stdin(Stdio::null());
+    Ok(runner.status(&mut pull)?.success())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::runner::MockRunner;
+
+    #[test]
+    fn tracks_and_gets_blobs() {
+        let root = crate::TempDir::new("blobs");
+        fs::create_dir_all(root.join("thegame/assets/music")).unwrap();
+        fs::write(root.join("thegame/assets/music/theme.ogg"), "la la la").unwrap();
+        fs::write(root.join("thegame/assets/copy.ogg"), "la la la").unwrap();
+        fs::write(root.join("thegame/main.rs"), "fn main() {}").unwrap();
+        let config = Config {
+            root: root.to_string_lossy().into_owned(),
+            blob_dirs: vec!["thegame/assets".to_string()],
+            ..Config::default()
+        };
+
+        assert_eq!(track(&config).unwrap(), 2);
+        assert_eq!(track(&config).unwrap(), 0);
+        assert_eq!(
+            fs::read_to_string(root.join("thegame/assets/music/theme.ogg")).unwrap(),
+            "la la la"
+        );
+        assert!(root.join("thegame/main.rs").is_file());
+        let found = blobs(&config).unwrap();
+        assert_eq!(found.len(), 2);
+        assert_eq!(found[0].name, found[1].name);
+        assert_eq!(found[0].size, 8);
+        assert!(found.iter().all(|blob| blob.present));
+
+        fs::remove_dir_all(root.join(STORE)).unwrap();
+        let runner = MockRunner::new();
+        let laptop = config.host("laptop").unwrap();
+        assert!(get(
+            &runner,
+            &config,
+            laptop,
+            &["thegame/assets/music".to_string()]
+        )
+        .unwrap());
+        let commands = runner.commands();
+        assert_eq!(commands.len(), 1);
+        assert!(commands[0].ends_with(&format!("/./{}/{} {}/", STORE, found[0].name, config.root)));
+    }
+}
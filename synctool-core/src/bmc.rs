@@ -0,0 +1,299 @@
+// Power control through a host's management controller, for power_method =
+// "ipmi" (ipmitool over LAN), "amt" (Intel AMT with amttool) or "redfish" (its
+// REST API with curl), so waking and shutting down don't depend on a wake
+// script or sudo over ssh, or on the OS being up at all. The password comes
+// from the keyring, account bmc@NAME, and is passed in the environment or on
+// stdin so it doesn't show up in ps or -v output. None of them can suspend,
+// so suspending still goes over ssh.
+
+use crate::{
+    config::{Host, PowerMethod},
+    keyring,
+    runner::Runner,
+};
+use eyre::{bail, ensure, eyre, Result};
+use std::{
+    io::{Read, Write},
+    process::{Command, ExitStatus, Stdio},
+};
+
+pub fn power_on(runner: &dyn Runner, host: &Host) -> Result<()> {
+    phase!(
+        "Powering on {} through its management controller",
+        host.name
+    );
+    control(runner, host, true)
+}
+
+// A soft power off with IPMI, which the OS sees as the power button. AMT can
+// only cut the power.
+pub fn power_off(runner: &dyn Runner, host: &Host) -> Result<()> {
+    phase!(
+        "Powering off {} through its management controller",
+        host.name
+    );
+    control(runner, host, false)
+}
+
+// Whether the host is powered on, as the management controller sees it
+pub fn is_on(runner: &dyn Runner, host: &Host) -> Result<bool> {
+    let output = match host.power_method {
+        PowerMethod::Ipmi => runner.output(
+            ipmitool(runner, host)?
+                .args(["chassis", "power", "status"])
+                .stdin(Stdio::null()),
+        )?,
+        PowerMethod::Amt => {
+            runner.output(amttool(runner, host)?.arg("info").stdin(Stdio::null()))?
+        }
+        PowerMethod::Redfish => {
+            let system = redfish(runner, host, &redfish_system(runner, host)?, None)?;
+            return match json_string(&system, "PowerState").as_deref() {
+                Some("On") | Some("PoweringOff") => Ok(true),
+                Some(_) => Ok(false),
+                None => bail!("The management controller of {} didn't say", host.name),
+            };
+        }
+        _ => bail!("{} has no management controller configured", host.name),
+    };
+    ensure!(
+        output.status.success(),
+        "Couldn't ask the management controller of {} for its power state",
+        host.name
+    );
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    Ok(match host.power_method {
+        // "Chassis Power is on"
+        PowerMethod::Ipmi => stdout.trim_end().ends_with("on"),
+        // "Powerstate:   S0", where S0 is running and S3 and up are asleep or off
+        _ => stdout
+            .lines()
+            .filter_map(|line| line.trim().strip_prefix("Powerstate:"))
+            .any(|state| state.trim() == "S0"),
+    })
+}
+
+fn control(runner: &dyn Runner, host: &Host, on: bool) -> Result<()> {
+    let status = match host.power_method {
+        PowerMethod::Ipmi => runner.status(
+            ipmitool(runner, host)?
+                .args(["chassis", "power", if on { "on" } else { "soft" }])
+                .stdin(Stdio::null())
+                .stdout(Stdio::null()),
+        )?,
+        PowerMethod::Amt => {
+            // amttool asks before changing the power state
+            let mut child = runner.spawn(
+                amttool(runner, host)?
+                    .arg(if on { "powerup" } else { "powerdown" })
+                    .stdin(Stdio::piped())
+                    .stdout(Stdio::null()),
+            )?;
+            if let Some(mut stdin) = child.take_stdin() {
+                writeln!(stdin, "y")?;
+            }
+            child.wait()?
+        }
+        PowerMethod::Redfish => {
+            let reset = format!(
+                "{}/Actions/ComputerSystem.Reset",
+                redfish_system(runner, host)?
+            );
+            // Like IPMI's soft off, the OS gets to shut down cleanly
+            let body = format!(
+                "{{\"ResetType\": \"{}\"}}",
+                if on { "On" } else { "GracefulShutdown" }
+            );
+            redfish(runner, host, &reset, Some(&body))?;
+            ExitStatus::default()
+        }
+        _ => bail!("{} has no management controller configured", host.name),
+    };
+    ensure!(
+        status.success(),
+        "The management controller of {} refused to power it {}",
+        host.name,
+        if on { "on" } else { "off" }
+    );
+    Ok(())
+}
+
+fn bmc_address(host: &Host) -> Result<&str> {
+    host.bmc_address
+        .as_deref()
+        .ok_or_else(|| eyre!("Set bmc_address for {} in the config", host.name))
+}
+
+fn password(runner: &dyn Runner, host: &Host) -> Result<String> {
+    if runner.simulated() {
+        return Ok(String::new());
+    }
+    let account = format!("bmc@{}", host.name);
+    keyring::lookup(&account)?.ok_or_else(|| eyre!("No {} in the keyring", account))
+}
+
+fn ipmitool(runner: &dyn Runner, host: &Host) -> Result<Command> {
+    let mut command = Command::new("ipmitool");
+    command
+        .args([
+            "-I",
+            "lanplus",
+            "-H",
+            bmc_address(host)?,
+            "-U",
+            &host.bmc_user,
+            "-E",
+        ])
+        .env("IPMI_PASSWORD", password(runner, host)?);
+    Ok(command)
+}
+
+// The path of the host's system on the controller, the first (and usually
+// only) one there is
+fn redfish_system(runner: &dyn Runner, host: &Host) -> Result<String> {
+    let systems = redfish(runner, host, "/redfish/v1/Systems", None)?;
+    let members = &systems[systems.find("\"Members\"").unwrap_or(systems.len())..];
+    json_string(members, "@odata.id").ok_or_else(|| {
+        eyre!(
+            "The management controller of {} lists no systems",
+            host.name
+        )
+    })
+}
+
+// GETs a path from the controller's Redfish API, or POSTs body to it,
+// returning the response
+fn redfish(runner: &dyn Runner, host: &Host, path: &str, body: Option<&str>) -> Result<String> {
+    let mut command = Command::new("curl");
+    // Controllers come with self-signed certificates
+    command.args(["-fsSk", "--max-time", "20", "-K", "-"]);
+    if let Some(body) = body {
+        command.args(["-H", "Content-Type: application/json", "-d", body]);
+    }
+    command.arg(format!("https://{}{}", bmc_address(host)?, path));
+
+    let mut child = runner.spawn(command.stdin(Stdio::piped()).stdout(Stdio::piped()))?;
+    if let Some(mut stdin) = child.take_stdin() {
+        let user = format!("{}:{}", host.bmc_user, password(runner, host)?);
+        writeln!(
+            stdin,
+            "user = \"{}\"",
+            user.replace('\\', "\\\\").replace('"', "\\\"")
+        )?;
+    }
+    let mut response = String::new();
+    if let Some(mut stdout) = child.take_stdout() {
+        stdout.read_to_string(&mut response)?;
+    }
+    ensure!(
+        child.wait()?.success(),
+        "The management controller of {} didn't answer for {}",
+        host.name,
+        path
+    );
+    Ok(response)
+}
+
+// The first string value of key in some JSON, which is all that's needed
+// from Redfish's responses
+fn json_string(json: &str, key: &str) -> Option<String> {
+    let rest = &json[json.find(&format!("\"{}\"", key))? + key.len() + 2..];
+    let rest = rest.trim_start().strip_prefix(':')?.trim_start();
+    let value = rest.strip_prefix('"')?;
+    Some(value[..value.find('"')?].to_string())
+}
+
+fn amttool(runner: &dyn Runner, host: &Host) -> Result<Command> {
+    let mut command = Command::new("amttool");
+    command
+        .arg(bmc_address(host)?)
+        .env("AMT_PASSWORD", password(runner, host)?);
+    Ok(command)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::runner::{MockRunner, Reply};
+
+    fn host(power_method: PowerMethod) -> Host {
+        let mut config = crate::config::Config::parse(
+            "[hosts.nas]\naddress = \"10.13.13.8\"\nbmc_address = \"10.13.13.9\"\n",
+        )
+        .unwrap();
+        let mut host = config.hosts.pop().unwrap();
+        host.power_method = power_method;
+        host
+    }
+
+    #[test]
+    fn ipmi() {
+        let runner = MockRunner::new();
+        let reply = |stdout: &str| Reply {
+            code: 0,
+            stdout: stdout.to_string(),
+        };
+        runner.script_replies(
+            "ipmitool",
+            vec![
+                reply("Chassis Power is off\n"),
+                reply("Chassis Power is on\n"),
+            ],
+        );
+        let nas = host(PowerMethod::Ipmi);
+
+        assert!(!is_on(&runner, &nas).unwrap());
+        assert!(is_on(&runner, &nas).unwrap());
+        power_off(&runner, &nas).unwrap();
+        assert_eq!(
+            runner.commands()[2],
+            "ipmitool -I lanplus -H 10.13.13.9 -U admin -E chassis power soft"
+        );
+    }
+
+    #[test]
+    fn redfish() {
+        let runner = MockRunner::new();
+        let reply = |stdout: &str| Reply {
+            code: 0,
+            stdout: stdout.to_string(),
+        };
+        // The system's rule goes first, since the list's is a prefix of it
+        runner.script_replies(
+            "curl -fsSk --max-time 20 -K - https://10.13.13.9/redfish/v1/Systems/1",
+            vec![reply(r#"{"Id": "1", "PowerState": "Off"}"#)],
+        );
+        runner.script_replies(
+            "curl -fsSk --max-time 20 -K - https://10.13.13.9/redfish/v1/Systems",
+            vec![reply(
+                r#"{"@odata.id": "/redfish/v1/Systems", "Members": [{"@odata.id": "/redfish/v1/Systems/1"}]}"#,
+            )],
+        );
+        let nas = host(PowerMethod::Redfish);
+
+        assert!(!is_on(&runner, &nas).unwrap());
+        power_on(&runner, &nas).unwrap();
+        assert_eq!(
+            runner.commands()[3],
+            "curl -fsSk --max-time 20 -K - -H Content-Type: application/json -d {\"ResetType\": \"On\"} https://10.13.13.9/redfish/v1/Systems/1/Actions/ComputerSystem.Reset"
+        );
+    }
+
+    #[test]
+    fn amt() {
+        let runner = MockRunner::new();
+        runner.script_replies(
+            "amttool 10.13.13.9 info",
+            vec![Reply {
+                code: 0,
+                stdout: "AMT version:  6.0.3\nPowerstate:   S5\n".to_string(),
+            }],
+        );
+        let nas = host(PowerMethod::Amt);
+
+        assert!(!is_on(&runner, &nas).unwrap());
+        power_on(&runner, &nas).unwrap();
+        assert_eq!(runner.commands()[1], "amttool 10.13.13.9 powerup");
+        assert!(is_on(&runner, &host(PowerMethod::Sudo)).is_err());
+    }
+}
@@ -0,0 +1,199 @@
+// Names that differ only in case, like README and readme, which a
+// case-insensitive host (usually a Mac) can only store one of. Before syncing
+// with a host, a scratch file under its root tells whether it tells them
+// apart. If it doesn't, the host's case_collisions setting says what happens
+// to the names that collide here: the sync stops and lists them, they're left
+// out of it, or all but the first get renamed.
+
+use crate::{
+    config::{CaseCollisions, Config, Host},
+    ignore::ignored,
+    runner::Runner,
+    shell_quote,
+    ssh::ssh,
+};
+use eyre::{bail, Result, WrapErr};
+use std::{collections::BTreeMap, fs, io::ErrorKind, path::Path, process::Stdio};
+
+// Whether the host's root is on a case-insensitive filesystem. If it can't be
+// told, it's taken to be case-sensitive.
+pub fn case_insensitive(runner: &dyn Runner, config: &Config, host: &Host) -> Result<bool> {
+    let script = format!(
+        "d={}; touch \"$d/.synctool-Case-$$\" || exit 1; \
+         [ -e \"$d/.synctool-case-$$\" ] && echo insensitive || echo sensitive; \
+         rm -f \"$d/.synctool-Case-$$\"",
+        shell_quote(host.root(config))
+    );
+    let output = runner.output(ssh(host).arg(script).stdin(Stdio::null()))?;
+    Ok(String::from_utf8_lossy(&output.stdout).trim() == "insensitive")
+}
+
+// Groups of paths under the root whose names differ only in case, each
+// sorted, relative to the root
+pub fn collisions(config: &Config) -> Result<Vec<Vec<String>>> {
+    let mut groups = Vec::new();
+    let mut dirs = vec![String::new()];
+    while let Some(dir) = dirs.pop() {
+        let entries = match fs::read_dir(Path::new(&config.root).join(&dir)) {
+            Ok(entries) => entries,
+            Err(err) if err.kind() == ErrorKind::NotFound => continue,
+            Err(err) => return Err(err).wrap_err_with(|| format!("Couldn't list {}", dir)),
+        };
+        let mut names: BTreeMap<String, Vec<String>> = BTreeMap::new();
+        for entry in entries {
+            let entry = entry?;
+            let name = entry.file_name().to_string_lossy().into_owned();
+            let path = if dir.is_empty() {
+                name.clone()
+            } else {
+                format!("{}/{}", dir, name)
+            };
+            if ignored(config, &path) {
+                continue;
+            }
+            if entry.file_type()?.is_dir() {
+                dirs.push(path.clone());
+            }
+            names.entry(name.to_lowercase()).or_default().push(path);
+        }
+        for (_, mut paths) in names {
+            if paths.len() > 1 {
+                paths.sort();
+                groups.push(paths);
+            }
+        }
+    }
+    groups.sort();
+    Ok(groups)
+}
+
+// Deals with the collisions under the root when the host can't store them,
+// returning ignores for the paths to leave out of the sync
+pub fn handle(runner: &dyn Runner, config: &Config, host: &Host) -> Result<Vec<String>> {
+    if !case_insensitive(runner, config, host)? {
+        return Ok(Vec::new());
+    }
+    let groups = collisions(config)?;
+    if groups.is_empty() {
+        return Ok(Vec::new());
+    }
+
+    match host.case_collisions {
+        CaseCollisions::Error => {
+            error!("{} can't tell these names apart:", host.name);
+            for group in &groups {
+                error!("  {}", group.join(", "));
+            }
+            bail!(
+                "Rename them, or set case_collisions = \"skip\" or \"rename\" for {}",
+                host.name
+            )
+        }
+        CaseCollisions::Skip => {
+            warn!(
+                "Not syncing names {} can't tell apart from another:",
+                host.name
+            );
+            let mut ignores = Vec::new();
+            for group in &groups {
+                for path in &group[1..] {
+                    warn!("  {} (keeping {})", path, group[0]);
+                    ignores.push(format!("Path {}", path));
+                }
+            }
+            Ok(ignores)
+        }
+        CaseCollisions::Rename => {
+            for group in &groups {
+                for path in &group[1..] {
+                    let renamed = rename(config, path)?;
+                    warn!(
+                        "Renamed {} to {}, since {} can't tell it apart from {}",
+                        path, renamed, host.name, group[0]
+                    );
+                }
+            }
+            Ok(Vec::new())
+        }
+    }
+}
+
+// Renames a path under the root to "name (2).ext", or the first free number
+// after that, returning the new path
+fn rename(config: &Config, path: &str) -> Result<String> {
+    let (dir, name) = match path.rsplit_once('/') {
+        Some((dir, name)) => (format!("{}/", dir), name),
+        None => (String::new(), path),
+    };
+    let (stem, extension) = match name.rsplit_once('.') {
+        Some((stem, extension)) if !stem.is_empty() => (stem, format!(".{}", extension)),
+        _ => (name, String::new()),
+    };
+    let root = Path::new(&config.root);
+    for n in 2.. {
+        let renamed = format!("{}{} ({}){}", dir, stem, n, extension);
+        let taken = fs::read_dir(root.join(&dir).as_path())?.any(|entry| {
+            entry.is_ok_and(|entry| {
+                entry.file_name().to_string_lossy().to_lowercase()
+                    == renamed[dir.len()..].to_lowercase()
+            })
+        });
+        if !taken {
+            fs::rename(root.join(path), root.join(&renamed))?;
+            return Ok(renamed);
+        }
+    }
+    unreachable!()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::runner::{MockRunner, Reply};
+
+    #[test]
+    fn renames_collisions() {
+        let root = crate::TempDir::new("case");
+        fs::create_dir_all(root.join("docs")).unwrap();
+        for file in [
+            "docs/README.md",
+            "docs/readme.md",
+            "docs/Readme (2).md",
+            "a",
+        ] {
+            fs::write(root.join(file), "").unwrap();
+        }
+        let mut config = Config {
+            root: root.to_string_lossy().into_owned(),
+            ..Config::default()
+        };
+        assert_eq!(
+            collisions(&config).unwrap(),
+            [["docs/README.md", "docs/readme.md"]]
+        );
+
+        let runner = MockRunner::new();
+        runner.script_replies(
+            "ssh",
+            vec![Reply {
+                code: 0,
+                stdout: "insensitive\n".to_string(),
+            }],
+        );
+        let laptop = config.host("laptop").unwrap().clone();
+        assert!(handle(&runner, &config, &laptop).is_err());
+
+        config.hosts[0].case_collisions = CaseCollisions::Skip;
+        let laptop = config.host("laptop").unwrap();
+        assert_eq!(
+            handle(&runner, &config, laptop).unwrap(),
+            ["Path docs/readme.md"]
+        );
+
+        config.hosts[0].case_collisions = CaseCollisions::Rename;
+        let laptop = config.host("laptop").unwrap();
+        assert!(handle(&runner, &config, laptop).unwrap().is_empty());
+        assert!(root.join("docs/readme (3).md").exists());
+        assert!(collisions(&config).unwrap().is_empty());
+    }
+}
@@ -0,0 +1,227 @@
+// Content hashes of every file under the root, for --checksum runs after
+// clock trouble or a sync that looked wrong, when size and mtime can't be
+// trusted. Files are hashed on several threads at once. With synctool on the
+// other end too, it hashes its copy the same way over ssh, and unison only
+// has to fingerprint the files whose hashes differ. Otherwise unison
+// fingerprints everything itself.
+//
+// The hashes are 64 bit FNV-1a, which is fast but not cryptographic: equal
+// hashes make it very likely that two files match, not certain, so files are
+// only left out of unison's fingerprinting on that bet. Nothing here verifies
+// a copy.
+
+use crate::{
+    config::{Config, Host},
+    links,
+    runner::Runner,
+    ssh::ssh,
+    versions::hash_file,
+};
+use eyre::{bail, eyre, Result};
+use std::{
+    cmp::Reverse,
+    collections::{BTreeMap, HashSet},
+    fs::File,
+    io::Read,
+    os::unix::fs::MetadataExt,
+    path::Path,
+    process::Stdio,
+    thread::{self, available_parallelism},
+};
+
+// Hash of each file under the root, by path relative to it
+pub fn hashes(config: &Config) -> Result<BTreeMap<String, u64>> {
+    let mut paths = Vec::new();
+    links::walk(config, Path::new(&config.root), "", &mut |path, entry| {
+        if entry.file_type()?.is_file() {
+            paths.push(path);
+        }
+        Ok(())
+    })?;
+    hash_paths(config, paths)
+}
+
+// Groups of files under the root at least min_size bytes with the same
+// contents, as (size, paths), the most wasteful first. Hard links to the same
+// file aren't duplicates. Only files that share a size get hashed, and files
+// that share a hash too are compared byte for byte, since the list is what
+// gets deleted from.
+pub fn duplicates(config: &Config, min_size: u64) -> Result<Vec<(u64, Vec<String>)>> {
+    let mut by_size: BTreeMap<u64, Vec<String>> = BTreeMap::new();
+    let mut inodes = HashSet::new();
+    links::walk(config, Path::new(&config.root), "", &mut |path, entry| {
+        let metadata = entry.metadata()?;
+        let new_inode = inodes.insert((metadata.dev(), metadata.ino()));
+        if metadata.is_file() && metadata.len() >= min_size.max(1) && new_inode {
+            by_size.entry(metadata.len()).or_default().push(path);
+        }
+        Ok(())
+    })?;
+    let sizes = by_size
+        .iter()
+        .filter(|(_, paths)| paths.len() > 1)
+        .flat_map(|(&size, paths)| paths.iter().map(move |path| (path.clone(), size)))
+        .collect::<BTreeMap<_, _>>();
+
+    let mut groups: BTreeMap<(u64, u64), Vec<String>> = BTreeMap::new();
+    for (path, hash) in hash_paths(config, sizes.keys().cloned().collect())? {
+        groups.entry((sizes[&path], hash)).or_default().push(path);
+    }
+    let root = Path::new(&config.root);
+    let mut same = Vec::new();
+    for ((size, _), paths) in groups.into_iter().filter(|(_, paths)| paths.len() > 1) {
+        // Split by contents, in case different ones hash the same
+        let mut split: Vec<Vec<String>> = Vec::new();
+        for path in paths {
+            let mut matching = None;
+            for (i, group) in split.iter().enumerate() {
+                if same_contents(&root.join(&group[0]), &root.join(&path))? {
+                    matching = Some(i);
+                    break;
+                }
+            }
+            match matching {
+                Some(i) => split[i].push(path),
+                None => split.push(vec![path]),
+            }
+        }
+        same.extend(
+            split
+                .into_iter()
+                .filter(|paths| paths.len() > 1)
+                .map(|paths| (size, paths)),
+        );
+    }
+    let mut groups = same;
+    groups.sort_by_key(|(size, paths)| Reverse(size * (paths.len() as u64 - 1)));
+    Ok(groups)
+}
+
+// Whether two files of the same size have the same bytes
+fn same_contents(a: &Path, b: &Path) -> Result<bool> {
+    let (mut a, mut b) = (File::open(a)?, File::open(b)?);
+    let (mut a_buffer, mut b_buffer) = (vec![0; 64 * 1024], vec![0; 64 * 1024]);
+    loop {
+        let read = a.read(&mut a_buffer)?;
+        if read == 0 {
+            return Ok(b.read(&mut b_buffer[..1])? == 0);
+        }
+        b.read_exact(&mut b_buffer[..read])?;
+        if a_buffer[..read] != b_buffer[..read] {
+            return Ok(false);
+        }
+    }
+}
+
+// Hashes the files, relative to the root, on a thread per core
+fn hash_paths(config: &Config, paths: Vec<String>) -> Result<BTreeMap<String, u64>> {
+    let threads = available_parallelism().map_or(4, |n| n.get());
+    let chunk = paths.len().div_ceil(threads).max(1);
+    let root = Path::new(&config.root);
+    thread::scope(|scope| {
+        let workers = paths
+            .chunks(chunk)
+            .map(|paths| {
+                scope.spawn(move || {
+                    paths
+                        .iter()
+                        .map(|path| Ok((path.clone(), hash_file(&root.join(path))?)))
+                        .collect::<Result<Vec<_>>>()
+                })
+            })
+            .collect::<Vec<_>>();
+        let mut hashes = BTreeMap::new();
+        for worker in workers {
+            let hashed = worker
+                .join()
+                .map_err(|_| eyre!("A hashing thread panicked"))??;
+            hashes.extend(hashed);
+        }
+        Ok(hashes)
+    })
+}
+
+// One "HASH<tab>PATH" line per file, for `synctool checksums`
+pub fn to_text(hashes: &BTreeMap<String, u64>) -> String {
+    hashes
+        .iter()
+        .map(|(path, hash)| format!("{:016x}\t{}\n", hash, path))
+        .collect()
+}
+
+fn parse(text: &str) -> Result<BTreeMap<String, u64>> {
+    text.lines()
+        .map(|line| {
+            let (hash, path) = line
+                .split_once('\t')
+                .ok_or_else(|| eyre!("Bad checksum line: {}", line))?;
+            Ok((path.to_string(), u64::from_str_radix(hash, 16)?))
+        })
+        .collect()
+}
+
+// Files on both ends whose contents differ, hashing both at once
+pub fn differing(
+    runner: &dyn Runner,
+    config: &Config,
+    host: &Host,
+    synctool: &str,
+) -> Result<Vec<String>> {
+    log!("Hashing every file here and on {}", host.name);
+    let (ours, theirs) = thread::scope(|scope| {
+        let remote = scope.spawn(|| {
+            runner.output(
+                ssh(host)
+                    .arg(format!("{} checksums", synctool))
+                    .stdin(Stdio::null()),
+            )
+        });
+        let ours = hashes(config);
+        let remote = remote
+            .join()
+            .map_err(|_| eyre!("The remote hashing thread panicked"))?;
+        Ok::<_, eyre::Report>((ours?, remote?))
+    })?;
+    if !theirs.status.success() {
+        bail!("Couldn't hash the files on {}", host.name);
+    }
+    let theirs = parse(&String::from_utf8_lossy(&theirs.stdout))?;
+
+    Ok(ours
+        .into_iter()
+        .filter(|(path, hash)| theirs.get(path).is_some_and(|theirs| theirs != hash))
+        .map(|(path, _)| path)
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    #[test]
+    fn hashes_in_parallel() {
+        let root = crate::TempDir::new("checksum");
+        fs::create_dir_all(root.join("src")).unwrap();
+        for i in 0..20 {
+            fs::write(root.join(format!("src/{}", i)), i.to_string()).unwrap();
+        }
+        fs::write(root.join("same"), "0").unwrap();
+        let config = Config {
+            root: root.to_string_lossy().into_owned(),
+            ..Config::default()
+        };
+
+        let hashes = hashes(&config).unwrap();
+        assert_eq!(hashes.len(), 21);
+        assert_eq!(hashes["same"], hashes["src/0"]);
+        assert_ne!(hashes["src/1"], hashes["src/0"]);
+        assert_eq!(parse(&to_text(&hashes)).unwrap(), hashes);
+        assert_eq!(
+            duplicates(&config, 0).unwrap(),
+            [(1, vec!["same".to_string(), "src/0".to_string()])]
+        );
+        assert!(same_contents(&root.join("same"), &root.join("src/0")).unwrap());
+        assert!(!same_contents(&root.join("same"), &root.join("src/1")).unwrap());
+    }
+}
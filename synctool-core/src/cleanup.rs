@@ -0,0 +1,138 @@
+// Leftovers from unison runs: the temp files of transfers that got cut off
+// (.unison.NAME.HASH.unison.tmp) and the copies it keeps of the losing side
+// of a conflict ("NAME (conflict on HOST DATE)"). Both pile up all over the
+// tree and get synced like anything else. `synctool cleanup` removes the ones
+// that haven't been touched for [sync] cleanup_age here and on the hosts, so
+// a run that's still going doesn't lose its temp files.
+
+use crate::{
+    config::{Config, Host},
+    ignore::glob_match,
+    links,
+    runner::Runner,
+    shell_quote,
+    ssh::ssh,
+};
+use eyre::{ensure, Result, WrapErr};
+use std::{
+    fs,
+    path::Path,
+    process::Stdio,
+    time::{Duration, SystemTime},
+};
+
+// Names of leftovers, as globs for both ignore::glob_match and find -name
+const PATTERNS: &[&str] = &[".unison.*", "* (conflict on *"];
+
+pub fn is_leftover(name: &str) -> bool {
+    PATTERNS.iter().any(|pattern| glob_match(pattern, name))
+}
+
+// Leftovers under the root here old enough to remove, relative to the root
+pub fn local(config: &Config) -> Result<Vec<String>> {
+    let cutoff = SystemTime::now() - Duration::from_secs(config.cleanup_age);
+    let mut files = Vec::new();
+    links::walk(config, Path::new(&config.root), "", &mut |path, entry| {
+        let name = path.rsplit('/').next().unwrap_or_default();
+        if is_leftover(name) && entry.metadata()?.modified()? < cutoff {
+            files.push(path);
+        }
+        Ok(())
+    })?;
+    files.sort();
+    Ok(files)
+}
+
+pub fn remove_local(config: &Config, files: &[String]) -> Result<()> {
+    for file in files {
+        fs::remove_file(Path::new(&config.root).join(file))
+            .wrap_err_with(|| format!("Couldn't remove {}", file))?;
+    }
+    Ok(())
+}
+
+// Finds the leftovers under the host's root old enough to remove, relative to
+// the root, and removes them unless dry_run is set
+pub fn remote(
+    runner: &dyn Runner,
+    config: &Config,
+    host: &Host,
+    dry_run: bool,
+) -> Result<Vec<String>> {
+    let names = PATTERNS
+        .iter()
+        .map(|pattern| format!("-name {}", shell_quote(pattern)))
+        .collect::<Vec<_>>()
+        .join(" -o ");
+    // find's -mmin +N means more than N whole minutes
+    let script = format!(
+        "cd {} && find . -type f \\( {} \\) -mmin +{} -print{}",
+        shell_quote(host.root(config)),
+        names,
+        config.cleanup_age.div_ceil(60).saturating_sub(1),
+        if dry_run { "" } else { " -delete" }
+    );
+    let output = runner.output(ssh(host).arg(script).stdin(Stdio::null()))?;
+    ensure!(
+        output.status.success(),
+        "Couldn't look for leftovers on {}",
+        host.name
+    );
+    let mut files = String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .map(|line| line.strip_prefix("./").unwrap_or(line).to_string())
+        .collect::<Vec<_>>();
+    files.sort();
+    Ok(files)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::File;
+    use std::time::UNIX_EPOCH;
+
+    #[test]
+    fn finds_old_leftovers() {
+        let root = crate::TempDir::new("cleanup");
+        fs::create_dir_all(root.join("notes")).unwrap();
+        for file in [
+            "notes/todo.md",
+            "notes/.unison.todo.md.3f2a.unison.tmp",
+            "notes/todo (conflict on laptop 2024-05-01).md",
+            "notes/.unison.new.unison.tmp",
+        ] {
+            fs::write(root.join(file), "").unwrap();
+        }
+        let long_ago = UNIX_EPOCH + Duration::from_secs(1000);
+        for file in [
+            "notes/todo.md",
+            "notes/.unison.todo.md.3f2a.unison.tmp",
+            "notes/todo (conflict on laptop 2024-05-01).md",
+        ] {
+            File::options()
+                .write(true)
+                .open(root.join(file))
+                .unwrap()
+                .set_modified(long_ago)
+                .unwrap();
+        }
+        let config = Config {
+            root: root.to_string_lossy().into_owned(),
+            ..Config::default()
+        };
+
+        let files = local(&config).unwrap();
+        assert_eq!(
+            files,
+            [
+                "notes/.unison.todo.md.3f2a.unison.tmp",
+                "notes/todo (conflict on laptop 2024-05-01).md"
+            ]
+        );
+        remove_local(&config, &files).unwrap();
+        assert!(local(&config).unwrap().is_empty());
+        assert!(root.join("notes/todo.md").exists());
+        assert!(root.join("notes/.unison.new.unison.tmp").exists());
+    }
+}
@@ -0,0 +1,105 @@
+// A cloud remote as a catch-up buffer for when no peer can be reached. With
+// [cloud] remote set to an rclone remote, a failed sync pushes the tree to
+// REMOTE/HOSTNAME instead, only uploading files that changed. Before each sync
+// the buffers other machines left there are pulled in, without overwriting
+// anything newer here, and once a sync with a peer works again this machine's
+// own buffer is deleted, since the peer has everything in it.
+
+use crate::{config::Config, events, hostname, runner::Runner, state_dir};
+use eyre::Result;
+use std::{
+    fs,
+    path::PathBuf,
+    process::{Command, Stdio},
+};
+
+// Exists while this machine has changes buffered in the cloud
+fn marker() -> PathBuf {
+    state_dir().join("cloud-buffered")
+}
+
+// Pushes the tree to this machine's buffer. Returns Ok(true) if it worked.
+pub fn push(runner: &dyn Runner, config: &Config, remote: &str) -> Result<bool> {
+    let buffer = format!("{}/{}", remote, hostname());
+    phase!("Pushing changes to {} instead", buffer);
+    let success = events::phase("cloud", || {
+        let mut command = rclone(config, ["copy", "--update", &config.root, &buffer]);
+        Ok(runner.status(&mut command)?.success())
+    })?;
+    if success && !runner.simulated() {
+        fs::create_dir_all(state_dir())?;
+        fs::write(marker(), &buffer)?;
+    }
+    Ok(success)
+}
+
+// Pulls in the buffers other machines left, keeping newer local files.
+pub fn pull(runner: &dyn Runner, config: &Config, remote: &str) -> Result<()> {
+    let output = runner.output(
+        Command::new("rclone")
+            .args(["lsf", "--dirs-only", remote])
+            .stdin(Stdio::null()),
+    )?;
+    if !output.status.success() {
+        warn!("Couldn't list the buffers in {}", remote);
+        return Ok(());
+    }
+
+    let this = hostname();
+    let listing = String::from_utf8_lossy(&output.stdout);
+    for machine in listing.lines().map(|line| line.trim_end_matches('/')) {
+        if machine.is_empty() || machine == this {
+            continue;
+        }
+        let buffer = format!("{}/{}", remote, machine);
+        log!("Catching up on changes {} left in {}", machine, remote);
+        let mut command = rclone(config, ["copy", "--update", &buffer, &config.root]);
+        if !runner.status(&mut command)?.success() {
+            warn!("Couldn't pull {}", buffer);
+        }
+    }
+    Ok(())
+}
+
+// Deletes this machine's buffer after a sync with a peer worked.
+pub fn clear(runner: &dyn Runner) -> Result<()> {
+    let buffer = match fs::read_to_string(marker()) {
+        Ok(buffer) => buffer,
+        Err(_) => return Ok(()),
+    };
+    let status = runner.status(
+        Command::new("rclone")
+            .args(["purge", &buffer])
+            .stdin(Stdio::null()),
+    )?;
+    if !status.success() {
+        warn!("Couldn't delete the buffer in {}", buffer);
+    } else if !runner.simulated() {
+        fs::remove_file(marker())?;
+    }
+    Ok(())
+}
+
+fn rclone<'a>(config: &Config, args: impl IntoIterator<Item = &'a str>) -> Command {
+    let mut command = Command::new("rclone");
+    command.args(args);
+    for ignore in &config.ignores {
+        for filter in rclone_excludes(ignore) {
+            command.arg("--exclude").arg(filter);
+        }
+    }
+    command.stdin(Stdio::null());
+    command
+}
+
+// Translates a unison ignore pattern into rclone filters, which match a file
+// or anything under a directory of that name.
+fn rclone_excludes(ignore: &str) -> Vec<String> {
+    let pattern = match ignore.split_once(' ') {
+        Some(("Name", pattern)) => pattern.to_string(),
+        Some(("Path", pattern)) => format!("/{}", pattern),
+        Some(("Regex", pattern)) => format!("/{}", pattern.replace(".*", "*")),
+        _ => return Vec::new(),
+    };
+    vec![pattern.clone(), format!("{}/**", pattern)]
+}
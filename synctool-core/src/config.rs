@@ -0,0 +1,2799 @@
+// Configuration file handling.
+//
+// The config lives at $XDG_CONFIG_HOME/synctool/config.toml (usually
+// ~/.config/synctool/config.toml) and is written in a small subset of TOML:
+// [tables], key = value, strings, integers, booleans and arrays. Everything is
+// optional; without a config file synctool behaves exactly as it always has.
+//
+// Settings are layered, each layer overriding the ones before it:
+//
+//     1. built in defaults
+//     2. /etc/synctool/config.toml, for machine-wide defaults
+//     3. the user config
+//     4. environment variables
+//     5. -c SECTION.KEY=VALUE flags on the command line
+//
+// `synctool config dump` shows what each setting ends up as and which layer
+// it came from.
+//
+// The environment variables are (see env_to_toml):
+//
+//     SYNCTOOL_CONFIG   path of the config file itself
+//     SYNCTOOL_PEER     [sync] peer
+//     SYNCTOOL_ROOT     [sync] root
+//     SYNCTOOL_IGNORES  [sync] ignores, separated by newlines
+//     SYNCTOOL_UNISON   [unison] path
+//
+//     [sync]
+//     peer = "desktop"
+//     root = "/home/user/prog"
+//     ignores = ["Name target", "Name node_modules"]
+//     stale_after_hours = 72
+//     warn_clock_skew = "5s"  # warn if a host's clock is further off than this
+//     max_clock_skew = "2m"  # and don't sync at all past this, 0 (the default) for never
+//     large_file = "1GB"  # ask before syncing new files this big, 0 (the default) for never
+//     cleanup_age = "48h"  # leftovers synctool cleanup removes are older than this (24h)
+//     special_files = "skip"  # sockets, FIFOs and devices: "report" (the default) or "recreate"
+//     disk_images = "delta"  # VM images: "exclude" (the default), "sparse" or "delta"
+//     dedup = true  # send content repeated across files once, with synctool on the host (false)
+//
+//     [unison]
+//     path = "/usr/bin/unison"
+//     args = ["-fastcheck", "true"]
+//     binaries = ["/opt/unison-2.51/bin/unison"]  # others to try with hosts path can't sync with
+//
+//     [hosts.desktop]
+//     address = "10.13.13.4"
+//     addresses = ["desktop.lan", "100.64.0.4"]  # whichever answers first is used
+//     synctool = "/home/user/.cargo/bin/sync"
+//     unison_servercmd = "/home/user/.nix-profile/bin/unison"
+//     unison_servercmds = ["unison-2.51"]  # others to try if that one can't sync with ours
+//     unison_path = "/usr/bin/unison-2.53"  # instead of [unison] path for this host
+//     unison_args = ["-times"]
+//     after_sync = ["make -C notes html"]  # run there from the root after each sync
+//     stop_services = ["plexmediaserver", "user:tracker-miner-fs-3"]  # while syncing (see services.rs)
+//     perms = true  # sync permission bits (the default)
+//     owner = false  # and not owners or groups (the default), with
+//     group = false  # numeric_ids = true to keep ids rather than names
+//     xattrs = true  # extended attributes, e.g. file capabilities
+//     acls = true  # and ACLs from setfacl
+//     symlinks = "skip-absolute"  # or "copy" (the default), "follow" or "skip"
+//     hard_links = true  # link files that are hard linked here there too
+//     sparse = true  # keep the holes in sparse files like VM images
+//     normalize_names = true  # respell names macOS wrote in NFD
+//     case_collisions = "skip"  # if it's case-insensitive: "error" (the default) or "rename"
+//     relay = "rpi"  # sync with the rpi's copy instead of waking the desktop
+//     wake_via = "rpi"  # where wake_command runs, or here if not set
+//     wake_command = "~/wake-computinator.sh"
+//     wake_timeout = "3m"  # how long it gets to come up after that (60s)
+//     wake_poll_interval = "5s"  # between probes while waiting (1s)
+//     wake = [  # tried in order until it's up, instead of just wake_command
+//         "wol 00:11:22:33:44:55 for 90s",  # a magic packet from here, waiting 90s
+//         "wol 00:11:22:33:44:55 via rpi",  # or from the rpi, waiting wake_timeout
+//         "plug http://plug.lan/off http://plug.lan/on for 3m",  # POSTed in turn
+//         "command",  # wake_command; also "bmc" and "homeassistant"
+//     ]
+//     probe = "tcp:22"  # or "ping" (the default), "ssh" or an http(s) URL
+//
+//     [hosts.rpi]
+//     identity_file = "~/.ssh/id_rpi"
+//     identities_only = true  # don't offer the agent's other keys
+//     host_key_checking = "accept-new"  # or "strict" or "ask"
+//     host_key = "ssh-ed25519 AAAA..."  # only ever accept this key
+//     gpg_recipient = "me@example.com"  # only contents are encrypted, names stay readable
+//     sudo_password_from_keyring = true
+//     power_method = "doas"  # or "sudo" (the default), "root" to log in as root, "logind",
+//                            # "agent", "ipmi" or "amt"
+//     shutdown_command = "poweroff"  # run with doas, sudo or as root ("shutdown now")
+//     suspend_command = "zzz"  # the same for -s ("systemctl suspend")
+//     flush = "freeze"  # before -ss also fsfreeze the root's filesystem, or "unmount" it,
+//                       # beyond running sync (the default), or "none"
+//
+//     [hosts.nas]
+//     address = "10.13.13.8"
+//     power_method = "ipmi"  # or "amt" or "redfish"; password in the keyring as bmc@nas
+//     bmc_address = "10.13.13.9"
+//     bmc_user = "admin"
+//     ups = "ups@10.13.13.8"  # its UPS in NUT, checked with upsc before waking or shutting it down
+//
+//     [hosts.laptop]
+//     agent = "synctool agent"  # or "tcp:10.13.13.3:7811"
+//     agent_token = "..."
+//     power_method = "agent"
+//
+//     [agent]
+//     listen = "10.13.13.4:7811"  # for synctool agent --listen
+//     token = "..."
+//
+//     [ssh]
+//     passphrase_from_keyring = true
+//     connect_timeout = "8s"
+//     server_alive_interval = "15s"  # 0 (the default) for no keepalives
+//     server_alive_count_max = 3  # keepalives missed before giving up
+//
+//     [daemon]
+//     peers = ["desktop"]
+//     interval = 900
+//     sync_on_connect = ["Home Wi-Fi", "wg0"]
+//     sync_on_unlock = true
+//     watch = true  # sync when files under the root change
+//     debounce = "30s"  # once nothing has changed for this long
+//     max_batch_wait = "5m"  # or this long after the first change
+//     settle = true  # and nothing changed is still being written
+//     sync_on_remote_change = true  # when peers' agents report changes
+//     relay = true  # on the relay, pass changes on as soon as a peer is back
+//     cooldown = "10m"  # at least this long between syncs with a peer
+//     digest = "7d"  # notify a summary of the history this often, 0 (the default) for never
+//
+//     [projects.notes]
+//     interval = "5m"  # the daemon syncs just this directory every 5 minutes
+//     priority = true  # synced before the rest of the tree in every run
+//
+//     [projects.android]
+//     path = "work/android"  # under the root, the project's name if not set
+//     interval = "1h"
+//
+//     [databases.notes]  # dumped here and on the host before each sync, and the live files left out
+//     path = "notes/app.db"  # under the root, the database's name if not set
+//     snapshot = "notes/app.sql"  # where the dumps go, as notes/app.HOSTNAME.sql; path plus .sql if not set
+//
+//     [databases.photos]
+//     path = "photos/pgdata"
+//     dump = "pg_dump photos"  # run from the root, printing the dump; sqlite3's .dump of path if not set
+//
+//     [profiles.quick]  # chosen with --profile quick, or just `synctool quick`
+//     paths = ["notes"]  # sync just these under the root, all of it if not set
+//     ignores = ["Name *.mp4"]  # on top of [sync] ignores
+//
+//     [profiles.bedtime]
+//     host = "desktop"  # like -t, unless that's given
+//     local_power = "suspend"  # like -ls, or "shutdown" like -lss, unless flags say
+//     remote_power = "suspend"  # otherwise, like -s; "nothing" (the default) for neither
+//     special_files = "recreate"  # instead of [sync] special_files
+//
+//     [power]
+//     grace = "30s"  # count down this long before -s, -ss, -ls and -lss, 0 (the default) for not at all
+//     backend = "pm-utils"  # or "systemctl" or "shutdown" for -ls and -lss, found by looking if not set
+//     shutdown_command = "sudo poweroff"  # what -lss runs here instead of the backend's
+//     suspend_command = "slp"  # and -ls
+//
+//     [aliases]  # `synctool night` for `synctool -s -ls`
+//     night = ["-s", "-ls"]
+//     away = ["-t", "rpi", "-r"]
+//
+//     [backup]
+//     tool = "restic"  # or "borg", run after each successful sync
+//     repository = "/mnt/backup/restic"
+//     host = "desktop"  # run there after syncs with it, here if not set
+//
+//     [blobs]
+//     dirs = ["thegame/assets"]  # kept out of the sync, fetched on demand (see blobs.rs)
+//     min_size = "1MB"  # smaller files in them sync as usual (0, the default, for none)
+//
+//     [notify.desktop]
+//     events = ["success", "failure", "conflict", "power", "digest"]  # all (the default)
+//
+//     [notify.phone]
+//     url = "https://ntfy.sh/my-synctool"  # POSTed to instead of notify-send
+//     events = ["failure", "conflict"]
+//
+//     [update]
+//     url = "https://example.com/synctool"
+//     signing_key = "3AA5C34371567BD2..."  # fingerprint of the key releases must be signed with
+//     require_signature = false  # only check the .sha256 from the same host (true)
+//
+//     [cloud]
+//     remote = "b2:bucket/synctool"  # rclone remote for when no peer is reachable
+//
+//     [archive]
+//     remote = "s3:backups/synctool"
+//     recipient = "me@example.com"
+//     interval = 86400  # daily from the daemon
+//     full_every = 7
+//
+//     [syncthing]
+//     api = "http://127.0.0.1:8384"  # the local Syncthing (the default)
+//     api_key = "..."  # from its settings
+//     folder = "synctool"  # id of the folder holding the root (the default)
+//     timeout = "10m"  # how long to wait for a device to catch up
+//
+//     [hosts.phone]
+//     address = "10.13.13.7"
+//     syncthing_device = "MFZWI3D-BONSGYC-..."  # sync through Syncthing instead
+//
+//     [homeassistant]
+//     url = "http://homeassistant.local:8123"
+//     token = "..."  # a long-lived access token, or in the keyring as homeassistant
+//
+//     [hosts.htpc]
+//     address = "10.13.13.10"
+//     power_method = "homeassistant"  # -ss, -s and waking through Home Assistant
+//     homeassistant = "switch.htpc"  # turned on to wake it
+//     homeassistant_shutdown = "script.htpc_shutdown"  # for -ss, which goes over ssh if not set
+//     homeassistant_suspend = "script.htpc_suspend"  # for -s, which goes over ssh if not set
+//     homeassistant_wake = "button.htpc_wol"  # pressed instead of turning it on
+//
+//     [log]
+//     timestamps = "both"  # "elapsed" (the default), "wall" or "both"
+
+use crate::{
+    ignore::IGNORES,
+    output::Timestamps,
+    power::{PowerAction, BACKENDS},
+    state_dir,
+};
+use eyre::{bail, ensure, eyre, Result, WrapErr};
+use std::{collections::BTreeMap, env, fmt, fs, io::ErrorKind, path::PathBuf};
+
+pub struct Config {
+    // Host to sync with when -t isn't given, instead of picking by hostname
+    pub peer: Option<String>,
+    // The synced directory, at the same path on every machine
+    pub root: String,
+    // Unison ignore patterns (Name, Path or Regex)
+    pub ignores: Vec<String>,
+    // Warn when a peer hasn't synced successfully for this many hours, 0 for never
+    pub stale_after_hours: u64,
+    // Seconds a host's clock can be off from this one's before a sync warns,
+    // since unison compares modification times, and before it refuses (0 for
+    // never)
+    pub warn_clock_skew: u64,
+    pub max_clock_skew: u64,
+    // Bytes from which a changed file is asked about before syncing, or left
+    // out when there's nobody to ask (see large.rs), 0 for no limit
+    pub large_file: u64,
+    // Directories under the root whose files are kept in the blob store, and
+    // the smallest file that is (see blobs.rs)
+    pub blob_dirs: Vec<String>,
+    pub blob_min_size: u64,
+    // Seconds since unison's temp files and conflict copies were last
+    // modified before `synctool cleanup` removes them
+    pub cleanup_age: u64,
+    // What syncing does with sockets, FIFOs and device nodes under the root,
+    // which unison can't sync (see special.rs)
+    pub special_files: SpecialFiles,
+    // What syncing does with VM and container disk images under the root (see
+    // images.rs)
+    pub disk_images: DiskImages,
+    // Whether content repeated across the files a sync sends goes over once
+    // (see dedup.rs)
+    pub dedup: bool,
+    pub unison: UnisonConfig,
+    pub hosts: Vec<Host>,
+    // Directories under the root the daemon syncs on schedules of their own
+    pub projects: Vec<Project>,
+    // Live databases under the root, synced as dumps of them instead
+    pub databases: Vec<Database>,
+    // Bundles of run options picked with --profile
+    pub profiles: Vec<Profile>,
+    // Words that stand for lists of flags, by name
+    pub aliases: BTreeMap<String, Vec<String>>,
+    // Where notifications go (see notify.rs)
+    pub notifiers: Vec<Notifier>,
+    // Answer ssh key passphrase prompts from the OS keyring
+    pub ssh_passphrase_from_keyring: bool,
+    // Seconds ssh waits for a connection
+    pub ssh_connect_timeout: u64,
+    // Seconds between keepalives on quiet connections, 0 for none
+    pub ssh_server_alive_interval: u64,
+    // Keepalives that can go unanswered before ssh disconnects
+    pub ssh_server_alive_count_max: u64,
+    // Hosts the daemon syncs with, defaulting to the peer
+    pub daemon_peers: Vec<String>,
+    // Seconds between the daemon's syncs with each peer
+    pub daemon_interval: u64,
+    // Network connections (NetworkManager names or interfaces) that make the
+    // daemon sync as soon as they come up
+    pub daemon_sync_on_connect: Vec<String>,
+    // Sync as soon as a local session logs in or unlocks
+    pub daemon_sync_on_unlock: bool,
+    // Sync when files under the root change
+    pub daemon_watch: bool,
+    // Seconds without changes before watch mode syncs
+    pub daemon_debounce: u64,
+    // Seconds after the first change that watch mode syncs regardless
+    pub daemon_max_batch_wait: u64,
+    // Also wait for changed files to be closed after writing
+    pub daemon_settle: bool,
+    // Sync when the agent on a peer reports changes there
+    pub daemon_sync_on_remote_change: bool,
+    // Keep checking on peers that couldn't be synced with, and sync as soon as
+    // they're back, to pass on what was relayed here
+    pub daemon_relay: bool,
+    // Seconds after a sync with a peer before the daemon syncs with it again,
+    // unless a connection, an unlock or the peer coming back calls for it
+    pub daemon_cooldown: u64,
+    // Seconds between digests of the history sent as notifications, 0 for none
+    pub daemon_digest: u64,
+    // Seconds to count down before power actions, giving a chance to cancel
+    // them (see grace.rs)
+    pub power_grace: u64,
+    // How this machine is shut down and suspended, detected if not set (see
+    // power.rs), unless these shell commands are set
+    pub power_backend: Option<String>,
+    pub power_shutdown_command: Option<String>,
+    pub power_suspend_command: Option<String>,
+    // Where self-update looks for releases
+    pub update_url: Option<String>,
+    // Refuse updates without a good gpg signature by the key with this
+    // fingerprint, unless turned off
+    pub update_signing_key: Option<String>,
+    pub update_require_signature: bool,
+    // Elapsed seconds, wall clock time or both at the start of log lines
+    pub log_timestamps: Timestamps,
+    // Where `synctool agent --listen` listens
+    pub agent_listen: Option<String>,
+    // What clients connecting over TCP must say in hello
+    pub agent_token: Option<String>,
+    // rclone remote that failed syncs push to instead, e.g. "b2:bucket/sync"
+    pub cloud_remote: Option<String>,
+    // rclone remote that archives are uploaded to
+    pub archive_remote: Option<String>,
+    // gpg recipient archives are encrypted for
+    pub archive_recipient: Option<String>,
+    // Seconds between the daemon's archives, 0 for only `synctool archive`
+    pub archive_interval: u64,
+    // Every this many archives is a full one instead of incremental
+    pub archive_full_every: u64,
+    // Backup run after each successful sync (see backup.rs)
+    pub backup_tool: Option<BackupTool>,
+    pub backup_repository: Option<String>,
+    // Host it runs on, after syncs with it, instead of here
+    pub backup_host: Option<String>,
+    // The local Syncthing's REST API, for hosts with syncthing_device set
+    // (see syncthing.rs)
+    pub syncthing_api: String,
+    pub syncthing_api_key: Option<String>,
+    // Id of the Syncthing folder shared with them at the root
+    pub syncthing_folder: String,
+    // Seconds to wait for a device to have everything
+    pub syncthing_timeout: u64,
+    // Home Assistant's REST API, for hosts with power_method homeassistant
+    // (see homeassistant.rs)
+    pub homeassistant_url: Option<String>,
+    pub homeassistant_token: Option<String>,
+}
+
+// The program [backup] runs
+#[derive(Clone, Copy, PartialEq)]
+pub enum BackupTool {
+    Restic,
+    Borg,
+}
+
+pub struct UnisonConfig {
+    // Local unison binary
+    pub path: String,
+    // Extra arguments passed to every unison run
+    pub args: Vec<String>,
+    // Other local unison binaries, for hosts whose unison can't sync with
+    // the one at path
+    pub binaries: Vec<String>,
+}
+
+pub struct Project {
+    pub name: String,
+    // Relative to the root
+    pub path: String,
+    // Seconds between the daemon's syncs of this project, which the syncs of
+    // the whole tree leave out. 0 keeps it in those instead.
+    pub interval: u64,
+    // Synced in a pass of its own before the rest of the tree
+    pub priority: bool,
+}
+
+pub struct Database {
+    pub name: String,
+    // The live file or directory, relative to the root
+    pub path: String,
+    // Command run from the root that prints a consistent dump, sqlite3's
+    // .dump of path if not set
+    pub dump: Option<String>,
+    // Where the dumps are kept, relative to the root, with each machine's
+    // hostname put before the extension
+    pub snapshot: String,
+}
+
+pub struct Profile {
+    pub name: String,
+    // Paths under the root to sync, or all of it if empty
+    pub paths: Vec<String>,
+    // Unison ignore patterns on top of the configured ones
+    pub ignores: Vec<String>,
+    // Host to sync with when -t isn't given
+    pub host: Option<String>,
+    // Power actions after a successful sync when no flags ask for any
+    pub local_power: PowerAction,
+    pub remote_power: PowerAction,
+    // Instead of [sync] special_files
+    pub special_files: Option<SpecialFiles>,
+}
+
+pub struct Notifier {
+    pub name: String,
+    // Sent to with curl if set, otherwise to the desktop
+    pub url: Option<String>,
+    pub events: Vec<NotifyClass>,
+}
+
+// What a notification is about
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum NotifyClass {
+    Success,
+    Failure,
+    Conflict,
+    Power,
+    // The daemon's summary of the history
+    Digest,
+}
+
+#[derive(Clone)]
+pub struct Host {
+    pub name: String,
+    pub address: String,
+    // Other addresses the host may be reachable at, e.g. over a VPN. Syncs
+    // use whichever answers quickest, address included.
+    pub addresses: Vec<String>,
+    // Where the tree lives on this host, if not at the same path as here
+    pub root: Option<String>,
+    // Command used to start unison on this host, passed as -servercmd
+    pub unison_servercmd: Option<String>,
+    // Other commands that start unison on this host, tried when that one
+    // can't sync with any unison here
+    pub unison_servercmds: Vec<String>,
+    // Local unison binary used with this host instead of [unison] path
+    pub unison_path: Option<String>,
+    // Extra arguments passed to unison when syncing with this host
+    pub unison_args: Vec<String>,
+    // Commands run on this host after each successful sync (see hooks.rs)
+    pub after_sync: Vec<String>,
+    // systemd units stopped there while syncing, "user:NAME" for user units
+    pub stop_services: Vec<String>,
+    // What file metadata syncs with this host, with unison or rsync. The
+    // defaults are unison's: permission bits but not owners or groups.
+    pub perms: bool,
+    pub owner: bool,
+    pub group: bool,
+    // Owners and groups by id rather than by name
+    pub numeric_ids: bool,
+    // Extended attributes and ACLs, which are left out unless the remote
+    // filesystem can store them
+    pub xattrs: bool,
+    pub acls: bool,
+    pub symlinks: Symlinks,
+    // Keep files that are hard links to each other here linked on this host,
+    // rather than separate copies
+    pub hard_links: bool,
+    // Copy sparse files with their holes rather than all the zeros (see
+    // sparse.rs)
+    pub sparse: bool,
+    // Respell names under the root in NFC before syncing (see unicode.rs)
+    pub normalize_names: bool,
+    // What happens to names that differ only in case if this host can't tell
+    // them apart (see case.rs)
+    pub case_collisions: CaseCollisions,
+    // If set, this host only ever gets a gpg-encrypted copy of the tree. Only
+    // file contents are encrypted: the names of files and directories, and
+    // roughly their sizes, are left in plaintext on the host
+    pub gpg_recipient: Option<String>,
+    // If set, this host is synced through Syncthing, as this device id
+    pub syncthing_device: Option<String>,
+    // Home Assistant entity that stands for this host's power, and entities
+    // to activate instead of it for each action
+    pub homeassistant: Option<String>,
+    pub homeassistant_shutdown: Option<String>,
+    pub homeassistant_suspend: Option<String>,
+    pub homeassistant_wake: Option<String>,
+    // Feed the remote sudo password from the OS keyring (account sudo@NAME)
+    pub sudo_password_from_keyring: bool,
+    pub power_method: PowerMethod,
+    // What power_method sudo, doas or root runs for -ss and -s
+    pub shutdown_command: String,
+    pub suspend_command: String,
+    // How written data is made safe before shutting this host down
+    pub flush: Flush,
+    // The management controller, for power_method = "ipmi" or "amt"
+    pub bmc_address: Option<String>,
+    pub bmc_user: String,
+    // The UPS it's plugged into, as NUT's upsc knows it, e.g. "ups@nas"
+    pub ups: Option<String>,
+    // Command that starts `synctool agent` on this host, if it runs one, or
+    // tcp:ADDRESS:PORT where it's listening
+    pub agent: Option<String>,
+    // Sent to an agent listening on TCP, which must match its [agent] token
+    pub agent_token: Option<String>,
+    // Command that runs synctool on this host, if it's installed there, for
+    // tracking file versions on both ends
+    pub synctool: Option<String>,
+    // Always-on host with its own copy of the tree, synced with instead when
+    // this one is off, whose daemon passes the changes on once it's back
+    pub relay: Option<String>,
+    // Command that turns this host on, run over ssh on wake_via if that's set
+    // or on this machine if not, e.g. "wakeonlan 00:11:22:33:44:55"
+    pub wake_command: Option<String>,
+    pub wake_via: Option<String>,
+    // Seconds this host gets to come up once woken, and between probes while
+    // it does
+    pub wake_timeout: u64,
+    pub wake_poll_interval: u64,
+    // Ways of waking this host to try in turn, or just wake_command (or the
+    // BMC with an ipmi or amt power_method) if empty
+    pub wake: Vec<WakeStep>,
+    // How to tell whether this host is up
+    pub probe: Probe,
+    // Key to log in with, and whether to offer only that one
+    pub identity_file: Option<String>,
+    pub identities_only: bool,
+    // StrictHostKeyChecking for this host, if not left to ssh's own config
+    pub host_key_checking: Option<HostKeyChecking>,
+    // The only host key accepted, as "TYPE BASE64" like in known_hosts
+    pub host_key: Option<String>,
+    // -o options for every ssh connection to this host, from [ssh] and the
+    // settings above (see Config::resolve)
+    pub ssh_options: Vec<String>,
+}
+
+// A way of waking a host
+#[derive(Clone, PartialEq)]
+pub enum WakeMethod {
+    // A magic packet sent with wakeonlan, here or over ssh on another host
+    Wol { mac: String, via: Option<String> },
+    // POSTing to each of a smart plug's URLs in turn, e.g. off then on
+    Plug(Vec<String>),
+    // The host's wake_command, run where wake_via says
+    Command,
+    // The host's management controller
+    Bmc,
+    // The host's entities in Home Assistant
+    HomeAssistant,
+}
+
+// A way of waking a host, and the seconds it gets to come up after it if not
+// the host's wake_timeout
+#[derive(Clone, PartialEq)]
+pub struct WakeStep {
+    pub method: WakeMethod,
+    pub timeout: Option<u64>,
+}
+
+impl WakeStep {
+    // Parses e.g. "wol 00:11:22:33:44:55 via rpi for 90s"
+    fn parse(s: &str) -> Option<WakeStep> {
+        let mut words = s.split_whitespace().collect::<Vec<_>>();
+        let mut timeout = None;
+        if let [.., "for", duration] = words[..] {
+            timeout = Some(parse_duration(duration)?);
+            words.truncate(words.len() - 2);
+        }
+        let method = match words[..] {
+            ["wol", mac] => WakeMethod::Wol {
+                mac: mac.to_string(),
+                via: None,
+            },
+            ["wol", mac, "via", via] => WakeMethod::Wol {
+                mac: mac.to_string(),
+                via: Some(via.to_string()),
+            },
+            ["plug", ref urls @ ..] if !urls.is_empty() => {
+                WakeMethod::Plug(urls.iter().map(|url| url.to_string()).collect())
+            }
+            ["command"] => WakeMethod::Command,
+            ["bmc"] => WakeMethod::Bmc,
+            ["homeassistant"] => WakeMethod::HomeAssistant,
+            _ => return None,
+        };
+        Some(WakeStep { method, timeout })
+    }
+}
+
+impl fmt::Display for WakeStep {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match &self.method {
+            WakeMethod::Wol { mac, via: None } => write!(f, "wol {}", mac)?,
+            WakeMethod::Wol {
+                mac,
+                via: Some(via),
+            } => write!(f, "wol {} via {}", mac, via)?,
+            WakeMethod::Plug(urls) => write!(f, "plug {}", urls.join(" "))?,
+            WakeMethod::Command => write!(f, "command")?,
+            WakeMethod::Bmc => write!(f, "bmc")?,
+            WakeMethod::HomeAssistant => write!(f, "homeassistant")?,
+        }
+        match self.timeout {
+            Some(timeout) => write!(f, " for {}s", timeout),
+            None => Ok(()),
+        }
+    }
+}
+
+// Ways of checking that a host is up, for networks that drop ICMP
+#[derive(Clone, PartialEq)]
+pub enum Probe {
+    Ping,
+    // Connecting to this TCP port
+    Tcp(u16),
+    // Running `echo ok` over ssh
+    Ssh,
+    // A successful response from this URL
+    Http(String),
+}
+
+impl Probe {
+    fn parse(s: &str) -> Option<Probe> {
+        match s {
+            "ping" => Some(Probe::Ping),
+            "ssh" => Some(Probe::Ssh),
+            _ if s.starts_with("http://") || s.starts_with("https://") => {
+                Some(Probe::Http(s.to_string()))
+            }
+            _ => s.strip_prefix("tcp:")?.parse().ok().map(Probe::Tcp),
+        }
+    }
+}
+
+impl fmt::Display for Probe {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Probe::Ping => write!(f, "ping"),
+            Probe::Tcp(port) => write!(f, "TCP port {}", port),
+            Probe::Ssh => write!(f, "ssh"),
+            Probe::Http(url) => write!(f, "{}", url),
+        }
+    }
+}
+
+// What syncing does with symlinks under the root
+#[derive(Clone, Copy, PartialEq)]
+pub enum Symlinks {
+    // Sync them as links
+    Copy,
+    // Sync what they point to instead
+    Follow,
+    // Leave them out, saying which
+    Skip,
+    // Leave out links to absolute paths, which are often wrong on other
+    // machines, and sync the rest as links
+    SkipAbsolute,
+}
+
+// What syncing does with files under the root that are neither regular files,
+// directories nor symlinks
+#[derive(Clone, Copy, PartialEq)]
+pub enum SpecialFiles {
+    // Leave them out without a word
+    Skip,
+    // Leave them out, saying which
+    Report,
+    // Make FIFOs and device nodes again on the host, and leave out sockets,
+    // which only mean something to the program listening on them
+    Recreate,
+}
+
+impl SpecialFiles {
+    pub fn name(self) -> &'static str {
+        match self {
+            SpecialFiles::Skip => "skip",
+            SpecialFiles::Report => "report",
+            SpecialFiles::Recreate => "recreate",
+        }
+    }
+}
+
+// What syncing does with disk images
+#[derive(Clone, Copy, PartialEq)]
+pub enum DiskImages {
+    // Leave them out, saying which
+    Exclude,
+    // Copy them with rsync, keeping their holes
+    Sparse,
+    // Copy them with rsync, sending only the blocks that changed
+    Delta,
+}
+
+impl DiskImages {
+    pub fn name(self) -> &'static str {
+        match self {
+            DiskImages::Exclude => "exclude",
+            DiskImages::Sparse => "sparse",
+            DiskImages::Delta => "delta",
+        }
+    }
+}
+
+// What happens to names that differ only in case, on a host that can only
+// store one of them
+#[derive(Clone, Copy, PartialEq)]
+pub enum CaseCollisions {
+    // Stop before syncing and list them
+    Error,
+    // Sync the first of each and leave the rest out
+    Skip,
+    // Rename all but the first here
+    Rename,
+}
+
+// What's done on a host before shutting it down, so a hard power-off right
+// after can't lose what was just synced
+#[derive(Clone, Copy, PartialEq)]
+pub enum Flush {
+    // Nothing
+    None,
+    // Write out dirty pages with sync(1)
+    Sync,
+    // Also freeze and thaw the root's filesystem, which leaves it consistent
+    Freeze,
+    // Also unmount the root, which has to be a mount point
+    Unmount,
+}
+
+// What ssh does about host keys it hasn't seen before
+#[derive(Clone, Copy, PartialEq)]
+pub enum HostKeyChecking {
+    // Refuse to connect
+    Strict,
+    // Remember the key and connect, but refuse keys that changed
+    AcceptNew,
+    // Ask on the terminal
+    Ask,
+}
+
+// How remote power actions are carried out
+#[derive(Clone, Copy, PartialEq)]
+pub enum PowerMethod {
+    // shutdown_command and suspend_command with sudo
+    Sudo,
+    // The same with doas instead of sudo
+    Doas,
+    // Logging in as root for shutdown_command, with no escalation
+    Root,
+    // `systemctl poweroff` and `systemctl suspend`, authorized by logind/polkit
+    Logind,
+    // Asking the host's agent, which refuses while someone is using it
+    Agent,
+    // The host's IPMI management controller, which can also wake it
+    Ipmi,
+    // Intel AMT, which can also wake the host
+    Amt,
+    // A management controller with a Redfish API, which can also wake it
+    Redfish,
+    // Home Assistant, which can also wake the host
+    HomeAssistant,
+}
+
+impl PowerMethod {
+    // Whether power goes through the host's management controller
+    pub fn bmc(self) -> bool {
+        matches!(
+            self,
+            PowerMethod::Ipmi | PowerMethod::Amt | PowerMethod::Redfish
+        )
+    }
+}
+
+impl Host {
+    fn new(name: &str, address: &str) -> Host {
+        Host {
+            name: name.to_string(),
+            address: address.to_string(),
+            addresses: Vec::new(),
+            root: None,
+            unison_servercmd: None,
+            unison_servercmds: Vec::new(),
+            unison_path: None,
+            after_sync: Vec::new(),
+            stop_services: Vec::new(),
+            unison_args: Vec::new(),
+            gpg_recipient: None,
+            syncthing_device: None,
+            homeassistant: None,
+            homeassistant_shutdown: None,
+            homeassistant_suspend: None,
+            homeassistant_wake: None,
+            sudo_password_from_keyring: false,
+            power_method: PowerMethod::Sudo,
+            shutdown_command: "shutdown now".to_string(),
+            suspend_command: "systemctl suspend".to_string(),
+            flush: Flush::Sync,
+            bmc_address: None,
+            bmc_user: "admin".to_string(),
+            ups: None,
+            agent: None,
+            agent_token: None,
+            synctool: None,
+            relay: None,
+            wake_command: None,
+            wake_via: None,
+            wake_timeout: 60,
+            wake_poll_interval: 1,
+            wake: Vec::new(),
+            probe: Probe::Ping,
+            identity_file: None,
+            identities_only: false,
+            host_key_checking: None,
+            host_key: None,
+            perms: true,
+            owner: false,
+            group: false,
+            numeric_ids: false,
+            xattrs: false,
+            acls: false,
+            symlinks: Symlinks::Copy,
+            hard_links: false,
+            sparse: false,
+            normalize_names: false,
+            case_collisions: CaseCollisions::Error,
+            ssh_options: Vec::new(),
+        }
+    }
+
+    // Whether a failed sync can wake this host and try again
+    pub fn wakeable(&self) -> bool {
+        self.wake_command.is_some()
+            || !self.wake.is_empty()
+            || self.power_method.bmc()
+            || self.power_method == PowerMethod::HomeAssistant
+    }
+
+    // The root of the tree on this host
+    pub fn root<'a>(&'a self, config: &'a Config) -> &'a str {
+        self.root.as_deref().unwrap_or(&config.root)
+    }
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        let mut config = Config {
+            peer: None,
+            root: "/home/user/prog".to_string(),
+            ignores: IGNORES.iter().map(|ignore| ignore.to_string()).collect(),
+            stale_after_hours: 72,
+            warn_clock_skew: 5,
+            max_clock_skew: 0,
+            large_file: 0,
+            blob_dirs: Vec::new(),
+            blob_min_size: 0,
+            cleanup_age: 24 * 60 * 60,
+            special_files: SpecialFiles::Report,
+            disk_images: DiskImages::Exclude,
+            dedup: false,
+            unison: UnisonConfig {
+                path: "unison".to_string(),
+                args: Vec::new(),
+                binaries: Vec::new(),
+            },
+            hosts: vec![
+                Host::new("laptop", "10.13.13.3"),
+                Host {
+                    wake_command: Some("~/wake-computinator.sh".to_string()),
+                    wake_via: Some("rpi".to_string()),
+                    ..Host::new("desktop", "10.13.13.4")
+                },
+                Host::new("rpi", "10.13.13.6"),
+            ],
+            projects: Vec::new(),
+            databases: Vec::new(),
+            profiles: Vec::new(),
+            aliases: BTreeMap::new(),
+            notifiers: Vec::new(),
+            ssh_passphrase_from_keyring: false,
+            ssh_connect_timeout: 8,
+            ssh_server_alive_interval: 0,
+            ssh_server_alive_count_max: 3,
+            daemon_peers: Vec::new(),
+            daemon_interval: 15 * 60,
+            daemon_sync_on_connect: Vec::new(),
+            daemon_sync_on_unlock: false,
+            daemon_watch: false,
+            daemon_debounce: 30,
+            daemon_max_batch_wait: 5 * 60,
+            daemon_settle: true,
+            daemon_sync_on_remote_change: false,
+            daemon_relay: false,
+            daemon_cooldown: 0,
+            daemon_digest: 0,
+            power_grace: 0,
+            power_backend: None,
+            power_shutdown_command: None,
+            power_suspend_command: None,
+            update_url: None,
+            update_signing_key: None,
+            update_require_signature: true,
+            log_timestamps: Timestamps::Elapsed,
+            agent_listen: None,
+            agent_token: None,
+            cloud_remote: None,
+            archive_remote: None,
+            archive_recipient: None,
+            archive_interval: 0,
+            archive_full_every: 7,
+            backup_tool: None,
+            backup_repository: None,
+            backup_host: None,
+            syncthing_api: "http://127.0.0.1:8384".to_string(),
+            syncthing_api_key: None,
+            syncthing_folder: "synctool".to_string(),
+            syncthing_timeout: 10 * 60,
+            homeassistant_url: None,
+            homeassistant_token: None,
+        };
+        config.resolve();
+        config
+    }
+}
+
+pub const SYSTEM_PATH: &str = "/etc/synctool/config.toml";
+
+impl Config {
+    // The user config file
+    pub fn path() -> PathBuf {
+        if let Some(path) = env::var_os("SYNCTOOL_CONFIG") {
+            return PathBuf::from(path);
+        }
+
+        env::var_os("XDG_CONFIG_HOME")
+            .map(PathBuf::from)
+            .unwrap_or_else(|| {
+                PathBuf::from(env::var_os("HOME").unwrap_or_default()).join(".config")
+            })
+            .join("synctool")
+            .join("config.toml")
+    }
+
+    // The config files that exist, in the order they're applied
+    pub fn paths() -> Vec<PathBuf> {
+        vec![PathBuf::from(SYSTEM_PATH), Config::path()]
+            .into_iter()
+            .filter(|path| path.exists())
+            .collect()
+    }
+
+    // Builds the config from every layer. Overrides are SECTION.KEY=VALUE
+    // strings from the command line.
+    pub fn load(overrides: &[String]) -> Result<Config> {
+        let mut config = Config::default();
+        for (source, text) in Config::layers(overrides)? {
+            config
+                .apply(&text)
+                .wrap_err_with(|| format!("In {}", source))?;
+        }
+        config.resolve();
+        Ok(config)
+    }
+
+    // The layers on top of the defaults, in the order they're applied, as
+    // where each came from and the config file it amounts to
+    pub fn layers(overrides: &[String]) -> Result<Vec<(String, String)>> {
+        let mut layers = Vec::new();
+        for path in Config::paths() {
+            let text = match fs::read_to_string(&path) {
+                Ok(text) => text,
+                Err(err) if err.kind() == ErrorKind::NotFound => continue,
+                Err(err) => {
+                    return Err(err).wrap_err_with(|| format!("Couldn't read {}", path.display()))
+                }
+            };
+            layers.push((path.display().to_string(), text));
+        }
+
+        let env = env_to_toml();
+        if !env.is_empty() {
+            layers.push(("the environment".to_string(), env));
+        }
+
+        for assignment in overrides {
+            let text =
+                override_to_toml(assignment).wrap_err_with(|| format!("In -c {}", assignment))?;
+            layers.push((format!("-c {}", assignment), text));
+        }
+        Ok(layers)
+    }
+
+    // Parses a single config file on top of the defaults
+    pub fn parse(text: &str) -> Result<Config> {
+        let mut config = Config::default();
+        config.apply(text)?;
+        config.resolve();
+        Ok(config)
+    }
+
+    // Works out the settings that depend on more than one section, once every
+    // layer is applied
+    fn resolve(&mut self) {
+        let mut options = vec![format!("ConnectTimeout={}", self.ssh_connect_timeout)];
+        if self.ssh_server_alive_interval > 0 {
+            options.push(format!(
+                "ServerAliveInterval={}",
+                self.ssh_server_alive_interval
+            ));
+            options.push(format!(
+                "ServerAliveCountMax={}",
+                self.ssh_server_alive_count_max
+            ));
+        }
+        for host in &mut self.hosts {
+            host.ssh_options = options.clone();
+            if let Some(identity_file) = &host.identity_file {
+                host.ssh_options
+                    .push(format!("IdentityFile={}", identity_file));
+            }
+            if host.identities_only {
+                host.ssh_options.push("IdentitiesOnly=yes".to_string());
+            }
+            let checking = match host.host_key_checking {
+                // A pinned key is all that's accepted
+                _ if host.host_key.is_some() => Some("yes"),
+                Some(HostKeyChecking::Strict) => Some("yes"),
+                Some(HostKeyChecking::AcceptNew) => Some("accept-new"),
+                Some(HostKeyChecking::Ask) => Some("ask"),
+                None => None,
+            };
+            if let Some(checking) = checking {
+                host.ssh_options
+                    .push(format!("StrictHostKeyChecking={}", checking));
+            }
+            if host.host_key.is_some() {
+                host.ssh_options.push(format!(
+                    "UserKnownHostsFile={}",
+                    known_hosts_path(&host.name).display()
+                ));
+                host.ssh_options
+                    .push("GlobalKnownHostsFile=/dev/null".to_string());
+            }
+        }
+    }
+
+    // Applies the settings in a config file on top of this config
+    fn apply(&mut self, text: &str) -> Result<()> {
+        let document = parse_document(text)?;
+        let config = self;
+
+        for (name, table) in &document {
+            match name.as_slice() {
+                [] => {}
+                [section] if section == "sync" => {
+                    if let Some(peer) = get_string(table, "peer")? {
+                        config.peer = Some(peer);
+                    }
+                    if let Some(root) = get_string(table, "root")? {
+                        config.root = root.trim_end_matches('/').to_string();
+                    }
+                    if let Some(ignores) = get_string_array(table, "ignores")? {
+                        config.ignores = ignores;
+                    }
+                    if let Some(hours) = get_integer(table, "stale_after_hours")? {
+                        config.stale_after_hours = hours;
+                    }
+                    if let Some(skew) = get_duration(table, "warn_clock_skew")? {
+                        config.warn_clock_skew = skew;
+                    }
+                    if let Some(skew) = get_duration(table, "max_clock_skew")? {
+                        config.max_clock_skew = skew;
+                    }
+                    if let Some(size) = get_size(table, "large_file")? {
+                        config.large_file = size;
+                    }
+                    if let Some(age) = get_duration(table, "cleanup_age")? {
+                        config.cleanup_age = age;
+                    }
+                    if let Some(special_files) = get_special_files(table)? {
+                        config.special_files = special_files;
+                    }
+                    if let Some(disk_images) = get_string(table, "disk_images")? {
+                        config.disk_images = match disk_images.as_str() {
+                            "exclude" => DiskImages::Exclude,
+                            "sparse" => DiskImages::Sparse,
+                            "delta" => DiskImages::Delta,
+                            _ => bail!(
+                                "line {}: disk_images must be \"exclude\", \"sparse\" or \"delta\"",
+                                table.entries["disk_images"].line
+                            ),
+                        };
+                    }
+                    if let Some(enabled) = get_bool(table, "dedup")? {
+                        config.dedup = enabled;
+                    }
+                }
+                [section] if section == "ssh" => {
+                    if let Some(enabled) = get_bool(table, "passphrase_from_keyring")? {
+                        config.ssh_passphrase_from_keyring = enabled;
+                    }
+                    if let Some(timeout) = get_duration(table, "connect_timeout")? {
+                        config.ssh_connect_timeout = timeout;
+                    }
+                    if let Some(interval) = get_duration(table, "server_alive_interval")? {
+                        config.ssh_server_alive_interval = interval;
+                    }
+                    if let Some(count) = get_integer(table, "server_alive_count_max")? {
+                        config.ssh_server_alive_count_max = count;
+                    }
+                }
+                [section] if section == "unison" => {
+                    if let Some(path) = get_string(table, "path")? {
+                        config.unison.path = path;
+                    }
+                    if let Some(args) = get_string_array(table, "args")? {
+                        config.unison.args = args;
+                    }
+                    if let Some(binaries) = get_string_array(table, "binaries")? {
+                        config.unison.binaries = binaries;
+                    }
+                }
+                [section] if section == "daemon" => {
+                    if let Some(peers) = get_string_array(table, "peers")? {
+                        config.daemon_peers = peers;
+                    }
+                    if let Some(interval) = get_integer(table, "interval")? {
+                        config.daemon_interval = interval;
+                    }
+                    if let Some(connections) = get_string_array(table, "sync_on_connect")? {
+                        config.daemon_sync_on_connect = connections;
+                    }
+                    if let Some(enabled) = get_bool(table, "sync_on_unlock")? {
+                        config.daemon_sync_on_unlock = enabled;
+                    }
+                    if let Some(enabled) = get_bool(table, "watch")? {
+                        config.daemon_watch = enabled;
+                    }
+                    if let Some(debounce) = get_duration(table, "debounce")? {
+                        config.daemon_debounce = debounce;
+                    }
+                    if let Some(wait) = get_duration(table, "max_batch_wait")? {
+                        config.daemon_max_batch_wait = wait;
+                    }
+                    if let Some(enabled) = get_bool(table, "settle")? {
+                        config.daemon_settle = enabled;
+                    }
+                    if let Some(enabled) = get_bool(table, "sync_on_remote_change")? {
+                        config.daemon_sync_on_remote_change = enabled;
+                    }
+                    if let Some(enabled) = get_bool(table, "relay")? {
+                        config.daemon_relay = enabled;
+                    }
+                    if let Some(cooldown) = get_duration(table, "cooldown")? {
+                        config.daemon_cooldown = cooldown;
+                    }
+                    if let Some(digest) = get_duration(table, "digest")? {
+                        config.daemon_digest = digest;
+                    }
+                }
+                [section] if section == "power" => {
+                    if let Some(grace) = get_duration(table, "grace")? {
+                        config.power_grace = grace;
+                    }
+                    if let Some(backend) = get_string(table, "backend")? {
+                        ensure!(
+                            BACKENDS.contains(&backend.as_str()),
+                            "line {}: backend must be \"systemctl\", \"pm-utils\" or \"shutdown\"",
+                            table.entries["backend"].line
+                        );
+                        config.power_backend = Some(backend);
+                    }
+                    if let Some(command) = get_string(table, "shutdown_command")? {
+                        config.power_shutdown_command = Some(command);
+                    }
+                    if let Some(command) = get_string(table, "suspend_command")? {
+                        config.power_suspend_command = Some(command);
+                    }
+                }
+                [section] if section == "update" => {
+                    if let Some(url) = get_string(table, "url")? {
+                        config.update_url = Some(url);
+                    }
+                    if let Some(key) = get_string(table, "signing_key")? {
+                        // However gpg prints it, e.g. in groups of four
+                        config.update_signing_key = Some(key.replace(' ', "").to_uppercase());
+                    }
+                    if let Some(required) = get_bool(table, "require_signature")? {
+                        config.update_require_signature = required;
+                    }
+                }
+                [section] if section == "log" => {
+                    if let Some(timestamps) = get_string(table, "timestamps")? {
+                        config.log_timestamps =
+                            Timestamps::parse(&timestamps).ok_or_else(|| {
+                                eyre!(
+                                    "line {}: timestamps must be \"elapsed\", \"wall\" or \"both\"",
+                                    table.entries["timestamps"].line
+                                )
+                            })?;
+                    }
+                }
+                [section] if section == "agent" => {
+                    if let Some(listen) = get_string(table, "listen")? {
+                        config.agent_listen = Some(listen);
+                    }
+                    if let Some(token) = get_string(table, "token")? {
+                        config.agent_token = Some(token);
+                    }
+                }
+                [section] if section == "archive" => {
+                    if let Some(remote) = get_string(table, "remote")? {
+                        config.archive_remote = Some(remote);
+                    }
+                    if let Some(recipient) = get_string(table, "recipient")? {
+                        config.archive_recipient = Some(recipient);
+                    }
+                    if let Some(interval) = get_integer(table, "interval")? {
+                        config.archive_interval = interval;
+                    }
+                    if let Some(full_every) = get_integer(table, "full_every")? {
+                        config.archive_full_every = full_every;
+                    }
+                }
+                [section] if section == "backup" => {
+                    if let Some(tool) = get_string(table, "tool")? {
+                        config.backup_tool = Some(match tool.as_str() {
+                            "restic" => BackupTool::Restic,
+                            "borg" => BackupTool::Borg,
+                            _ => bail!(
+                                "line {}: tool must be \"restic\" or \"borg\"",
+                                table.entries["tool"].line
+                            ),
+                        });
+                    }
+                    if let Some(repository) = get_string(table, "repository")? {
+                        config.backup_repository = Some(repository);
+                    }
+                    if let Some(host) = get_string(table, "host")? {
+                        config.backup_host = Some(host);
+                    }
+                }
+                [section] if section == "blobs" => {
+                    if let Some(dirs) = get_string_array(table, "dirs")? {
+                        config.blob_dirs = dirs
+                            .iter()
+                            .map(|dir| dir.trim_matches('/').to_string())
+                            .collect();
+                    }
+                    if let Some(size) = get_size(table, "min_size")? {
+                        config.blob_min_size = size;
+                    }
+                }
+                [section] if section == "syncthing" => {
+                    if let Some(api) = get_string(table, "api")? {
+                        config.syncthing_api = api.trim_end_matches('/').to_string();
+                    }
+                    if let Some(key) = get_string(table, "api_key")? {
+                        config.syncthing_api_key = Some(key);
+                    }
+                    if let Some(folder) = get_string(table, "folder")? {
+                        config.syncthing_folder = folder;
+                    }
+                    if let Some(timeout) = get_duration(table, "timeout")? {
+                        config.syncthing_timeout = timeout;
+                    }
+                }
+                [section] if section == "homeassistant" => {
+                    if let Some(url) = get_string(table, "url")? {
+                        config.homeassistant_url = Some(url.trim_end_matches('/').to_string());
+                    }
+                    if let Some(token) = get_string(table, "token")? {
+                        config.homeassistant_token = Some(token);
+                    }
+                }
+                [section] if section == "aliases" => {
+                    for (name, entry) in &table.entries {
+                        if name.starts_with('-') {
+                            bail!("line {}: alias names can't start with -", entry.line);
+                        }
+                        if let Some(words) = get_string_array(table, name)? {
+                            config.aliases.insert(name.clone(), words);
+                        }
+                    }
+                }
+                [section] if section == "cloud" => {
+                    if let Some(remote) = get_string(table, "remote")? {
+                        config.cloud_remote = Some(remote);
+                    }
+                }
+                [section, notifier_name] if section == "notify" => {
+                    let mut notifier = Notifier {
+                        name: notifier_name.to_string(),
+                        url: get_string(table, "url")?,
+                        events: vec![
+                            NotifyClass::Success,
+                            NotifyClass::Failure,
+                            NotifyClass::Conflict,
+                            NotifyClass::Power,
+                            NotifyClass::Digest,
+                        ],
+                    };
+                    if let Some(events) = get_string_array(table, "events")? {
+                        notifier.events = events
+                            .iter()
+                            .map(|event| match event.as_str() {
+                                "success" => Ok(NotifyClass::Success),
+                                "failure" => Ok(NotifyClass::Failure),
+                                "conflict" => Ok(NotifyClass::Conflict),
+                                "power" => Ok(NotifyClass::Power),
+                                "digest" => Ok(NotifyClass::Digest),
+                                _ => bail!(
+                                    "line {}: events must be \"success\", \"failure\", \"conflict\", \"power\" or \"digest\"",
+                                    table.entries["events"].line
+                                ),
+                            })
+                            .collect::<Result<_>>()?;
+                    }
+                    config.notifiers.push(notifier);
+                }
+                [section, project_name] if section == "projects" => {
+                    let project = match config.projects.iter_mut().find(|p| p.name == *project_name)
+                    {
+                        Some(project) => project,
+                        None => {
+                            config.projects.push(Project {
+                                name: project_name.clone(),
+                                path: project_name.clone(),
+                                interval: 0,
+                                priority: false,
+                            });
+                            config.projects.last_mut().unwrap()
+                        }
+                    };
+
+                    if let Some(path) = get_string(table, "path")? {
+                        let path = path.trim_matches('/');
+                        if path.is_empty() || path.split('/').any(|part| part == "..") {
+                            bail!(
+                                "line {}: path must be a directory under the root",
+                                table.entries["path"].line
+                            );
+                        }
+                        project.path = path.to_string();
+                    }
+                    if let Some(interval) = get_duration(table, "interval")? {
+                        project.interval = interval;
+                    }
+                    if let Some(enabled) = get_bool(table, "priority")? {
+                        project.priority = enabled;
+                    }
+                }
+                [section, database_name] if section == "databases" => {
+                    let database = match config
+                        .databases
+                        .iter_mut()
+                        .find(|d| d.name == *database_name)
+                    {
+                        Some(database) => database,
+                        None => {
+                            config.databases.push(Database {
+                                name: database_name.clone(),
+                                path: database_name.clone(),
+                                dump: None,
+                                snapshot: format!("{}.sql", database_name),
+                            });
+                            config.databases.last_mut().unwrap()
+                        }
+                    };
+
+                    let under_root = |key: &str| -> Result<Option<String>> {
+                        let path = match get_string(table, key)? {
+                            Some(path) => path.trim_matches('/').to_string(),
+                            None => return Ok(None),
+                        };
+                        if path.is_empty() || path.split('/').any(|part| part == "..") {
+                            bail!(
+                                "line {}: {} must be under the root",
+                                table.entries[key].line,
+                                key
+                            );
+                        }
+                        Ok(Some(path))
+                    };
+                    if let Some(path) = under_root("path")? {
+                        // The snapshot follows the path unless it's set too
+                        if database.snapshot == format!("{}.sql", database.path) {
+                            database.snapshot = format!("{}.sql", path);
+                        }
+                        database.path = path;
+                    }
+                    if let Some(snapshot) = under_root("snapshot")? {
+                        database.snapshot = snapshot;
+                    }
+                    if let Some(dump) = get_string(table, "dump")? {
+                        database.dump = Some(dump);
+                    }
+                }
+                [section, profile_name] if section == "profiles" => {
+                    let profile = match config.profiles.iter_mut().find(|p| p.name == *profile_name)
+                    {
+                        Some(profile) => profile,
+                        None => {
+                            config.profiles.push(Profile {
+                                name: profile_name.clone(),
+                                paths: Vec::new(),
+                                ignores: Vec::new(),
+                                host: None,
+                                local_power: PowerAction::Nothing,
+                                remote_power: PowerAction::Nothing,
+                                special_files: None,
+                            });
+                            config.profiles.last_mut().unwrap()
+                        }
+                    };
+
+                    if let Some(paths) = get_string_array(table, "paths")? {
+                        let paths = paths
+                            .iter()
+                            .map(|path| path.trim_matches('/').to_string())
+                            .collect::<Vec<_>>();
+                        if paths
+                            .iter()
+                            .any(|path| path.is_empty() || path.split('/').any(|part| part == ".."))
+                        {
+                            bail!(
+                                "line {}: paths must be under the root",
+                                table.entries["paths"].line
+                            );
+                        }
+                        profile.paths = paths;
+                    }
+                    if let Some(ignores) = get_string_array(table, "ignores")? {
+                        profile.ignores = ignores;
+                    }
+                    if let Some(host) = get_string(table, "host")? {
+                        profile.host = Some(host);
+                    }
+                    for key in ["local_power", "remote_power"] {
+                        if let Some(action) = get_string(table, key)? {
+                            let action = match action.as_str() {
+                                "nothing" => PowerAction::Nothing,
+                                "suspend" => PowerAction::Suspend,
+                                "shutdown" => PowerAction::Shutdown,
+                                _ => bail!(
+                                    "line {}: {} must be \"nothing\", \"suspend\" or \"shutdown\"",
+                                    table.entries[key].line,
+                                    key
+                                ),
+                            };
+                            if key == "local_power" {
+                                profile.local_power = action;
+                            } else {
+                                profile.remote_power = action;
+                            }
+                        }
+                    }
+                    if let Some(special_files) = get_special_files(table)? {
+                        profile.special_files = Some(special_files);
+                    }
+                }
+                [section, host_name] if section == "hosts" => {
+                    let host = match config.hosts.iter_mut().find(|h| h.name == *host_name) {
+                        Some(host) => host,
+                        None => {
+                            let address = get_string(table, "address")?
+                                .ok_or_else(|| eyre!("Host {} needs an address", host_name))?;
+                            config.hosts.push(Host::new(host_name, &address));
+                            config.hosts.last_mut().unwrap()
+                        }
+                    };
+
+                    if let Some(address) = get_string(table, "address")? {
+                        host.address = address;
+                    }
+                    if let Some(addresses) = get_string_array(table, "addresses")? {
+                        host.addresses = addresses;
+                    }
+                    if let Some(root) = get_string(table, "root")? {
+                        host.root = Some(root.trim_end_matches('/').to_string());
+                    }
+                    if let Some(servercmd) = get_string(table, "unison_servercmd")? {
+                        host.unison_servercmd = Some(servercmd);
+                    }
+                    if let Some(servercmds) = get_string_array(table, "unison_servercmds")? {
+                        host.unison_servercmds = servercmds;
+                    }
+                    if let Some(path) = get_string(table, "unison_path")? {
+                        host.unison_path = Some(path);
+                    }
+                    if let Some(args) = get_string_array(table, "unison_args")? {
+                        host.unison_args = args;
+                    }
+                    if let Some(commands) = get_string_array(table, "after_sync")? {
+                        host.after_sync = commands;
+                    }
+                    if let Some(units) = get_string_array(table, "stop_services")? {
+                        host.stop_services = units;
+                    }
+                    if let Some(enabled) = get_bool(table, "perms")? {
+                        host.perms = enabled;
+                    }
+                    if let Some(enabled) = get_bool(table, "owner")? {
+                        host.owner = enabled;
+                    }
+                    if let Some(enabled) = get_bool(table, "group")? {
+                        host.group = enabled;
+                    }
+                    if let Some(enabled) = get_bool(table, "numeric_ids")? {
+                        host.numeric_ids = enabled;
+                    }
+                    if let Some(enabled) = get_bool(table, "xattrs")? {
+                        host.xattrs = enabled;
+                    }
+                    if let Some(enabled) = get_bool(table, "acls")? {
+                        host.acls = enabled;
+                    }
+                    if let Some(enabled) = get_bool(table, "hard_links")? {
+                        host.hard_links = enabled;
+                    }
+                    if let Some(enabled) = get_bool(table, "sparse")? {
+                        host.sparse = enabled;
+                    }
+                    if let Some(enabled) = get_bool(table, "normalize_names")? {
+                        host.normalize_names = enabled;
+                    }
+                    if let Some(policy) = get_string(table, "case_collisions")? {
+                        host.case_collisions = match policy.as_str() {
+                            "error" => CaseCollisions::Error,
+                            "skip" => CaseCollisions::Skip,
+                            "rename" => CaseCollisions::Rename,
+                            _ => bail!(
+                                "line {}: case_collisions must be \"error\", \"skip\" or \"rename\"",
+                                table.entries["case_collisions"].line
+                            ),
+                        };
+                    }
+                    if let Some(symlinks) = get_string(table, "symlinks")? {
+                        host.symlinks = match symlinks.as_str() {
+                            "copy" => Symlinks::Copy,
+                            "follow" => Symlinks::Follow,
+                            "skip" => Symlinks::Skip,
+                            "skip-absolute" => Symlinks::SkipAbsolute,
+                            _ => bail!(
+                                "line {}: symlinks must be \"copy\", \"follow\", \"skip\" or \"skip-absolute\"",
+                                table.entries["symlinks"].line
+                            ),
+                        };
+                    }
+                    if let Some(recipient) = get_string(table, "gpg_recipient")? {
+                        host.gpg_recipient = Some(recipient);
+                    }
+                    if let Some(device) = get_string(table, "syncthing_device")? {
+                        host.syncthing_device = Some(device);
+                    }
+                    if let Some(entity) = get_string(table, "homeassistant")? {
+                        host.homeassistant = Some(entity);
+                    }
+                    if let Some(entity) = get_string(table, "homeassistant_shutdown")? {
+                        host.homeassistant_shutdown = Some(entity);
+                    }
+                    if let Some(entity) = get_string(table, "homeassistant_suspend")? {
+                        host.homeassistant_suspend = Some(entity);
+                    }
+                    if let Some(entity) = get_string(table, "homeassistant_wake")? {
+                        host.homeassistant_wake = Some(entity);
+                    }
+                    if let Some(enabled) = get_bool(table, "sudo_password_from_keyring")? {
+                        host.sudo_password_from_keyring = enabled;
+                    }
+                    if let Some(agent) = get_string(table, "agent")? {
+                        host.agent = Some(agent);
+                    }
+                    if let Some(token) = get_string(table, "agent_token")? {
+                        host.agent_token = Some(token);
+                    }
+                    if let Some(synctool) = get_string(table, "synctool")? {
+                        host.synctool = Some(synctool);
+                    }
+                    if let Some(relay) = get_string(table, "relay")? {
+                        host.relay = Some(relay);
+                    }
+                    if let Some(command) = get_string(table, "wake_command")? {
+                        host.wake_command = Some(command);
+                    }
+                    if let Some(via) = get_string(table, "wake_via")? {
+                        host.wake_via = Some(via);
+                    }
+                    if let Some(timeout) = get_duration(table, "wake_timeout")? {
+                        host.wake_timeout = timeout;
+                    }
+                    if let Some(steps) = get_string_array(table, "wake")? {
+                        host.wake = steps
+                            .iter()
+                            .map(|step| WakeStep::parse(step))
+                            .collect::<Option<_>>()
+                            .ok_or_else(|| {
+                                eyre!(
+                                    "line {}: wake steps must be \"wol MAC [via HOST]\", \"plug URL...\", \"command\", \"bmc\" or \"homeassistant\", each optionally followed by \"for DURATION\"",
+                                    table.entries["wake"].line
+                                )
+                            })?;
+                    }
+                    if let Some(interval) = get_duration(table, "wake_poll_interval")? {
+                        ensure!(
+                            interval > 0,
+                            "line {}: wake_poll_interval must be at least a second",
+                            table.entries["wake_poll_interval"].line
+                        );
+                        host.wake_poll_interval = interval;
+                    }
+                    if let Some(identity_file) = get_string(table, "identity_file")? {
+                        host.identity_file = Some(identity_file);
+                    }
+                    if let Some(enabled) = get_bool(table, "identities_only")? {
+                        host.identities_only = enabled;
+                    }
+                    if let Some(checking) = get_string(table, "host_key_checking")? {
+                        host.host_key_checking = Some(match checking.as_str() {
+                            "strict" => HostKeyChecking::Strict,
+                            "accept-new" => HostKeyChecking::AcceptNew,
+                            "ask" => HostKeyChecking::Ask,
+                            _ => bail!(
+                                "line {}: host_key_checking must be \"strict\", \"accept-new\" or \"ask\"",
+                                table.entries["host_key_checking"].line
+                            ),
+                        });
+                    }
+                    if let Some(key) = get_string(table, "host_key")? {
+                        if key.split_whitespace().count() != 2 {
+                            bail!(
+                                "line {}: host_key should look like \"ssh-ed25519 AAAA...\"",
+                                table.entries["host_key"].line
+                            );
+                        }
+                        host.host_key = Some(key);
+                    }
+                    if let Some(probe) = get_string(table, "probe")? {
+                        host.probe = Probe::parse(&probe).ok_or_else(|| {
+                            eyre!(
+                                "line {}: probe must be \"ping\", \"tcp:PORT\", \"ssh\" or an http(s) URL",
+                                table.entries["probe"].line
+                            )
+                        })?;
+                    }
+                    if let Some(address) = get_string(table, "bmc_address")? {
+                        host.bmc_address = Some(address);
+                    }
+                    if let Some(user) = get_string(table, "bmc_user")? {
+                        host.bmc_user = user;
+                    }
+                    if let Some(command) = get_string(table, "shutdown_command")? {
+                        host.shutdown_command = command;
+                    }
+                    if let Some(command) = get_string(table, "suspend_command")? {
+                        host.suspend_command = command;
+                    }
+                    if let Some(flush) = get_string(table, "flush")? {
+                        host.flush = match flush.as_str() {
+                            "none" => Flush::None,
+                            "sync" => Flush::Sync,
+                            "freeze" => Flush::Freeze,
+                            "unmount" => Flush::Unmount,
+                            _ => bail!(
+                                "line {}: flush must be \"none\", \"sync\", \"freeze\" or \"unmount\"",
+                                table.entries["flush"].line
+                            ),
+                        };
+                    }
+                    if let Some(ups) = get_string(table, "ups")? {
+                        host.ups = Some(ups);
+                    }
+                    if let Some(method) = get_string(table, "power_method")? {
+                        host.power_method = match method.as_str() {
+                            "sudo" => PowerMethod::Sudo,
+                            "doas" => PowerMethod::Doas,
+                            "root" => PowerMethod::Root,
+                            "logind" => PowerMethod::Logind,
+                            "agent" => PowerMethod::Agent,
+                            "ipmi" => PowerMethod::Ipmi,
+                            "amt" => PowerMethod::Amt,
+                            "redfish" => PowerMethod::Redfish,
+                            "homeassistant" => PowerMethod::HomeAssistant,
+                            _ => bail!(
+                                "line {}: power_method must be \"sudo\", \"doas\", \"root\", \"logind\", \"agent\", \"ipmi\", \"amt\", \"redfish\" or \"homeassistant\"",
+                                table.entries["power_method"].line
+                            ),
+                        };
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn host(&self, name: &str) -> Result<&Host> {
+        self.hosts
+            .iter()
+            .find(|host| host.name == name)
+            .ok_or_else(|| eyre!("No host named {} in config", name))
+    }
+
+    pub fn profile(&self, name: &str) -> Result<&Profile> {
+        self.profiles
+            .iter()
+            .find(|profile| profile.name == name)
+            .ok_or_else(|| eyre!("No profile named {} in config", name))
+    }
+
+    // Every setting as it ended up, as a config file that would load back the
+    // same. Durations are in seconds, sizes in bytes, and unset settings are
+    // left out.
+    pub fn to_document(&self) -> Document {
+        let mut document = Document::new();
+        let mut set = |table: &[&str], key: &str, value: Value| {
+            document
+                .entry(table.iter().map(|part| part.to_string()).collect())
+                .or_insert_with(|| Table::new(0))
+                .entries
+                .insert(key.to_string(), Entry { value, line: 0 });
+        };
+        let string = |s: &str| Value::String(s.to_string());
+        let integer = |n: u64| Value::Integer(n as i64);
+        let strings = |v: &[String]| Value::Array(v.iter().map(|s| string(s)).collect());
+
+        if let Some(peer) = &self.peer {
+            set(&["sync"], "peer", string(peer));
+        }
+        set(&["sync"], "root", string(&self.root));
+        set(&["sync"], "ignores", strings(&self.ignores));
+        set(
+            &["sync"],
+            "stale_after_hours",
+            integer(self.stale_after_hours),
+        );
+        set(&["sync"], "warn_clock_skew", integer(self.warn_clock_skew));
+        set(&["sync"], "max_clock_skew", integer(self.max_clock_skew));
+        set(&["sync"], "large_file", integer(self.large_file));
+        set(&["sync"], "cleanup_age", integer(self.cleanup_age));
+        set(
+            &["sync"],
+            "special_files",
+            string(self.special_files.name()),
+        );
+        set(&["sync"], "disk_images", string(self.disk_images.name()));
+        set(&["sync"], "dedup", Value::Bool(self.dedup));
+
+        set(&["unison"], "path", string(&self.unison.path));
+        set(&["unison"], "args", strings(&self.unison.args));
+        set(&["unison"], "binaries", strings(&self.unison.binaries));
+
+        let ssh = &["ssh"];
+        set(
+            ssh,
+            "passphrase_from_keyring",
+            Value::Bool(self.ssh_passphrase_from_keyring),
+        );
+        set(ssh, "connect_timeout", integer(self.ssh_connect_timeout));
+        set(
+            ssh,
+            "server_alive_interval",
+            integer(self.ssh_server_alive_interval),
+        );
+        set(
+            ssh,
+            "server_alive_count_max",
+            integer(self.ssh_server_alive_count_max),
+        );
+
+        let daemon = &["daemon"];
+        set(daemon, "peers", strings(&self.daemon_peers));
+        set(daemon, "interval", integer(self.daemon_interval));
+        set(
+            daemon,
+            "sync_on_connect",
+            strings(&self.daemon_sync_on_connect),
+        );
+        set(
+            daemon,
+            "sync_on_unlock",
+            Value::Bool(self.daemon_sync_on_unlock),
+        );
+        set(daemon, "watch", Value::Bool(self.daemon_watch));
+        set(daemon, "debounce", integer(self.daemon_debounce));
+        set(
+            daemon,
+            "max_batch_wait",
+            integer(self.daemon_max_batch_wait),
+        );
+        set(daemon, "settle", Value::Bool(self.daemon_settle));
+        set(
+            daemon,
+            "sync_on_remote_change",
+            Value::Bool(self.daemon_sync_on_remote_change),
+        );
+        set(daemon, "relay", Value::Bool(self.daemon_relay));
+        set(daemon, "cooldown", integer(self.daemon_cooldown));
+        set(daemon, "digest", integer(self.daemon_digest));
+        set(&["power"], "grace", integer(self.power_grace));
+        let power = [
+            ("backend", &self.power_backend),
+            ("shutdown_command", &self.power_shutdown_command),
+            ("suspend_command", &self.power_suspend_command),
+        ];
+        for (key, value) in power {
+            if let Some(value) = value {
+                set(&["power"], key, string(value));
+            }
+        }
+
+        if let Some(url) = &self.update_url {
+            set(&["update"], "url", string(url));
+        }
+        if let Some(key) = &self.update_signing_key {
+            set(&["update"], "signing_key", string(key));
+        }
+        set(
+            &["update"],
+            "require_signature",
+            Value::Bool(self.update_require_signature),
+        );
+
+        let timestamps = match self.log_timestamps {
+            Timestamps::Elapsed => "elapsed",
+            Timestamps::Wall => "wall",
+            Timestamps::Both => "both",
+        };
+        set(&["log"], "timestamps", string(timestamps));
+
+        if let Some(listen) = &self.agent_listen {
+            set(&["agent"], "listen", string(listen));
+        }
+        if let Some(token) = &self.agent_token {
+            set(&["agent"], "token", string(token));
+        }
+        if let Some(remote) = &self.cloud_remote {
+            set(&["cloud"], "remote", string(remote));
+        }
+
+        if let Some(remote) = &self.archive_remote {
+            set(&["archive"], "remote", string(remote));
+        }
+        if let Some(recipient) = &self.archive_recipient {
+            set(&["archive"], "recipient", string(recipient));
+        }
+        set(&["archive"], "interval", integer(self.archive_interval));
+        set(&["archive"], "full_every", integer(self.archive_full_every));
+
+        if let Some(tool) = self.backup_tool {
+            let tool = match tool {
+                BackupTool::Restic => "restic",
+                BackupTool::Borg => "borg",
+            };
+            set(&["backup"], "tool", string(tool));
+        }
+        if let Some(repository) = &self.backup_repository {
+            set(&["backup"], "repository", string(repository));
+        }
+        if let Some(host) = &self.backup_host {
+            set(&["backup"], "host", string(host));
+        }
+
+        set(&["blobs"], "dirs", strings(&self.blob_dirs));
+        set(&["blobs"], "min_size", integer(self.blob_min_size));
+
+        set(&["syncthing"], "api", string(&self.syncthing_api));
+        if let Some(key) = &self.syncthing_api_key {
+            set(&["syncthing"], "api_key", string(key));
+        }
+        set(&["syncthing"], "folder", string(&self.syncthing_folder));
+        set(&["syncthing"], "timeout", integer(self.syncthing_timeout));
+
+        if let Some(url) = &self.homeassistant_url {
+            set(&["homeassistant"], "url", string(url));
+        }
+        if let Some(token) = &self.homeassistant_token {
+            set(&["homeassistant"], "token", string(token));
+        }
+
+        for project in &self.projects {
+            let table = &["projects", project.name.as_str()];
+            set(table, "path", string(&project.path));
+            set(table, "interval", integer(project.interval));
+            set(table, "priority", Value::Bool(project.priority));
+        }
+
+        for database in &self.databases {
+            let table = &["databases", database.name.as_str()];
+            set(table, "path", string(&database.path));
+            if let Some(dump) = &database.dump {
+                set(table, "dump", string(dump));
+            }
+            set(table, "snapshot", string(&database.snapshot));
+        }
+
+        for profile in &self.profiles {
+            let table = &["profiles", profile.name.as_str()];
+            set(table, "paths", strings(&profile.paths));
+            set(table, "ignores", strings(&profile.ignores));
+            if let Some(host) = &profile.host {
+                set(table, "host", string(host));
+            }
+            set(table, "local_power", string(profile.local_power.name()));
+            set(table, "remote_power", string(profile.remote_power.name()));
+            if let Some(special_files) = profile.special_files {
+                set(table, "special_files", string(special_files.name()));
+            }
+        }
+
+        for (name, words) in &self.aliases {
+            set(&["aliases"], name, strings(words));
+        }
+
+        for notifier in &self.notifiers {
+            let table = &["notify", notifier.name.as_str()];
+            if let Some(url) = &notifier.url {
+                set(table, "url", string(url));
+            }
+            let events = notifier.events.iter().map(|class| {
+                string(match class {
+                    NotifyClass::Success => "success",
+                    NotifyClass::Failure => "failure",
+                    NotifyClass::Conflict => "conflict",
+                    NotifyClass::Power => "power",
+                    NotifyClass::Digest => "digest",
+                })
+            });
+            set(table, "events", Value::Array(events.collect()));
+        }
+
+        for host in &self.hosts {
+            let table = &["hosts", host.name.as_str()];
+            let optional = [
+                ("root", &host.root),
+                ("unison_servercmd", &host.unison_servercmd),
+                ("unison_path", &host.unison_path),
+                ("gpg_recipient", &host.gpg_recipient),
+                ("syncthing_device", &host.syncthing_device),
+                ("homeassistant", &host.homeassistant),
+                ("homeassistant_shutdown", &host.homeassistant_shutdown),
+                ("homeassistant_suspend", &host.homeassistant_suspend),
+                ("homeassistant_wake", &host.homeassistant_wake),
+                ("bmc_address", &host.bmc_address),
+                ("ups", &host.ups),
+                ("agent", &host.agent),
+                ("agent_token", &host.agent_token),
+                ("synctool", &host.synctool),
+                ("relay", &host.relay),
+                ("wake_command", &host.wake_command),
+                ("wake_via", &host.wake_via),
+                ("identity_file", &host.identity_file),
+                ("host_key", &host.host_key),
+            ];
+            for (key, value) in optional {
+                if let Some(value) = value {
+                    set(table, key, string(value));
+                }
+            }
+            set(table, "wake_timeout", integer(host.wake_timeout));
+            if !host.wake.is_empty() {
+                let steps = host.wake.iter().map(|step| step.to_string());
+                set(table, "wake", strings(&steps.collect::<Vec<_>>()));
+            }
+            set(
+                table,
+                "wake_poll_interval",
+                integer(host.wake_poll_interval),
+            );
+            let flags = [
+                ("perms", host.perms),
+                ("owner", host.owner),
+                ("group", host.group),
+                ("numeric_ids", host.numeric_ids),
+                ("xattrs", host.xattrs),
+                ("acls", host.acls),
+                ("hard_links", host.hard_links),
+                ("sparse", host.sparse),
+                ("normalize_names", host.normalize_names),
+                (
+                    "sudo_password_from_keyring",
+                    host.sudo_password_from_keyring,
+                ),
+                ("identities_only", host.identities_only),
+            ];
+            for (key, enabled) in flags {
+                set(table, key, Value::Bool(enabled));
+            }
+            set(table, "address", string(&host.address));
+            set(table, "addresses", strings(&host.addresses));
+            set(table, "unison_servercmds", strings(&host.unison_servercmds));
+            set(table, "unison_args", strings(&host.unison_args));
+            set(table, "after_sync", strings(&host.after_sync));
+            set(table, "stop_services", strings(&host.stop_services));
+            set(table, "bmc_user", string(&host.bmc_user));
+
+            let symlinks = match host.symlinks {
+                Symlinks::Copy => "copy",
+                Symlinks::Follow => "follow",
+                Symlinks::Skip => "skip",
+                Symlinks::SkipAbsolute => "skip-absolute",
+            };
+            set(table, "symlinks", string(symlinks));
+            let case_collisions = match host.case_collisions {
+                CaseCollisions::Error => "error",
+                CaseCollisions::Skip => "skip",
+                CaseCollisions::Rename => "rename",
+            };
+            set(table, "case_collisions", string(case_collisions));
+            let flush = match host.flush {
+                Flush::None => "none",
+                Flush::Sync => "sync",
+                Flush::Freeze => "freeze",
+                Flush::Unmount => "unmount",
+            };
+            set(table, "flush", string(flush));
+            if let Some(checking) = host.host_key_checking {
+                let checking = match checking {
+                    HostKeyChecking::Strict => "strict",
+                    HostKeyChecking::AcceptNew => "accept-new",
+                    HostKeyChecking::Ask => "ask",
+                };
+                set(table, "host_key_checking", string(checking));
+            }
+            let probe = match &host.probe {
+                Probe::Ping => "ping".to_string(),
+                Probe::Tcp(port) => format!("tcp:{}", port),
+                Probe::Ssh => "ssh".to_string(),
+                Probe::Http(url) => url.clone(),
+            };
+            set(table, "probe", Value::String(probe));
+            let power_method = match host.power_method {
+                PowerMethod::Sudo => "sudo",
+                PowerMethod::Doas => "doas",
+                PowerMethod::Root => "root",
+                PowerMethod::Logind => "logind",
+                PowerMethod::Agent => "agent",
+                PowerMethod::Ipmi => "ipmi",
+                PowerMethod::Amt => "amt",
+                PowerMethod::Redfish => "redfish",
+                PowerMethod::HomeAssistant => "homeassistant",
+            };
+            set(table, "power_method", string(power_method));
+            set(table, "shutdown_command", string(&host.shutdown_command));
+            set(table, "suspend_command", string(&host.suspend_command));
+        }
+        document
+    }
+}
+
+// The known_hosts file holding just the pinned key of the host called name
+pub fn known_hosts_path(name: &str) -> PathBuf {
+    state_dir().join("known_hosts").join(name)
+}
+
+// Turns the environment variables into a config file
+fn env_to_toml() -> String {
+    let var = |name| {
+        env::var(name)
+            .ok()
+            .filter(|value: &String| !value.is_empty())
+    };
+
+    let mut sync = String::new();
+    if let Some(peer) = var("SYNCTOOL_PEER") {
+        sync.push_str(&format!("peer = {}\n", quote(&peer)));
+    }
+    if let Some(root) = var("SYNCTOOL_ROOT") {
+        sync.push_str(&format!("root = {}\n", quote(&root)));
+    }
+    if let Some(ignores) = var("SYNCTOOL_IGNORES") {
+        let ignores = ignores.lines().map(quote).collect::<Vec<_>>();
+        sync.push_str(&format!("ignores = [{}]\n", ignores.join(", ")));
+    }
+
+    let mut text = String::new();
+    if !sync.is_empty() {
+        text.push_str(&format!("[sync]\n{}", sync));
+    }
+    if let Some(unison) = var("SYNCTOOL_UNISON") {
+        text.push_str(&format!("[unison]\npath = {}\n", quote(&unison)));
+    }
+    text
+}
+
+// Turns SECTION.KEY=VALUE into a config file. VALUE is anything that would go
+// after = in the file, and is taken as a string if it isn't valid on its own.
+fn override_to_toml(assignment: &str) -> Result<String> {
+    let (name, value) = match assignment.split_once('=') {
+        Some(pair) => pair,
+        None => bail!("Config overrides look like SECTION.KEY=VALUE"),
+    };
+    let (section, key) = match name.trim().rsplit_once('.') {
+        Some(pair) => pair,
+        None => bail!("Config overrides look like SECTION.KEY=VALUE"),
+    };
+
+    let value = value.trim();
+    let line = format!("{} = {}", key, value);
+    let line = if parse_document(&line).is_ok() {
+        line
+    } else {
+        format!("{} = {}", key, quote(value))
+    };
+    Ok(format!("[{}]\n{}\n", section, line))
+}
+
+// Formats a string as a TOML basic string
+pub fn quote(s: &str) -> String {
+    let mut quoted = String::from("\"");
+    for c in s.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            '\t' => quoted.push_str("\\t"),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+// Formats a value the way it would be written in the config
+pub fn format_value(value: &Value) -> String {
+    match value {
+        Value::String(s) => quote(s),
+        Value::Integer(n) => n.to_string(),
+        Value::Bool(b) => b.to_string(),
+        Value::Array(values) => {
+            let values = values.iter().map(format_value).collect::<Vec<_>>();
+            format!("[{}]", values.join(", "))
+        }
+    }
+}
+
+// Formats a table name or key, quoting it unless it's a bare key
+pub fn format_key(key: &str) -> String {
+    let bare = !key.is_empty()
+        && key
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-');
+    if bare {
+        key.to_string()
+    } else {
+        quote(key)
+    }
+}
+
+fn get_string(table: &Table, key: &str) -> Result<Option<String>> {
+    match table.get(key) {
+        None => Ok(None),
+        Some(Entry {
+            value: Value::String(s),
+            ..
+        }) => Ok(Some(s.clone())),
+        Some(entry) => bail!("line {}: {} must be a string", entry.line, key),
+    }
+}
+
+fn get_bool(table: &Table, key: &str) -> Result<Option<bool>> {
+    match table.get(key) {
+        None => Ok(None),
+        Some(Entry {
+            value: Value::Bool(b),
+            ..
+        }) => Ok(Some(*b)),
+        Some(entry) => bail!("line {}: {} must be true or false", entry.line, key),
+    }
+}
+
+fn get_integer(table: &Table, key: &str) -> Result<Option<u64>> {
+    match table.get(key) {
+        None => Ok(None),
+        Some(Entry {
+            value: Value::Integer(n),
+            ..
+        }) if *n >= 0 => Ok(Some(*n as u64)),
+        Some(entry) => bail!("line {}: {} must be a positive number", entry.line, key),
+    }
+}
+
+// Seconds, either as a number or a string like "30s", "5m", "2h" or "7d"
+fn get_duration(table: &Table, key: &str) -> Result<Option<u64>> {
+    match table.get(key) {
+        None => Ok(None),
+        Some(Entry {
+            value: Value::String(s),
+            line,
+        }) => match parse_duration(s) {
+            Some(seconds) => Ok(Some(seconds)),
+            None => bail!(
+                "line {}: {} must be a duration like \"30s\" or \"5m\"",
+                line,
+                key
+            ),
+        },
+        _ => get_integer(table, key),
+    }
+}
+
+pub fn parse_duration(s: &str) -> Option<u64> {
+    let s = s.trim();
+    let (number, unit) = s.split_at(s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len()));
+    let multiplier = match unit.trim() {
+        "" | "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => 24 * 60 * 60,
+        _ => return None,
+    };
+    number.parse::<u64>().ok()?.checked_mul(multiplier)
+}
+
+// A size in bytes, as a string like "500MB" or "1G" or as a plain integer
+fn get_size(table: &Table, key: &str) -> Result<Option<u64>> {
+    match table.get(key) {
+        None => Ok(None),
+        Some(Entry {
+            value: Value::String(s),
+            line,
+        }) => match parse_size(s) {
+            Some(bytes) => Ok(Some(bytes)),
+            None => bail!(
+                "line {}: {} must be a size like \"500MB\" or \"1GB\"",
+                line,
+                key
+            ),
+        },
+        _ => get_integer(table, key),
+    }
+}
+
+fn parse_size(s: &str) -> Option<u64> {
+    let s = s.trim();
+    let (number, unit) = s.split_at(s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len()));
+    let multiplier = match unit
+        .trim()
+        .trim_end_matches(['B', 'b'])
+        .to_ascii_uppercase()
+        .as_str()
+    {
+        "" => 1,
+        "K" => 1000,
+        "M" => 1000 * 1000,
+        "G" => 1000 * 1000 * 1000,
+        "T" => 1000 * 1000 * 1000 * 1000,
+        _ => return None,
+    };
+    number.parse::<u64>().ok()?.checked_mul(multiplier)
+}
+
+fn get_special_files(table: &Table) -> Result<Option<SpecialFiles>> {
+    Ok(match get_string(table, "special_files")?.as_deref() {
+        None => None,
+        Some("skip") => Some(SpecialFiles::Skip),
+        Some("report") => Some(SpecialFiles::Report),
+        Some("recreate") => Some(SpecialFiles::Recreate),
+        Some(_) => bail!(
+            "line {}: special_files must be \"skip\", \"report\" or \"recreate\"",
+            table.entries["special_files"].line
+        ),
+    })
+}
+
+fn get_string_array(table: &Table, key: &str) -> Result<Option<Vec<String>>> {
+    let entry = match table.get(key) {
+        None => return Ok(None),
+        Some(entry) => entry,
+    };
+
+    let strings = match &entry.value {
+        Value::Array(values) => values
+            .iter()
+            .map(|value| match value {
+                Value::String(s) => Some(s.clone()),
+                _ => None,
+            })
+            .collect::<Option<Vec<_>>>(),
+        _ => None,
+    };
+
+    match strings {
+        Some(strings) => Ok(Some(strings)),
+        None => bail!("line {}: {} must be an array of strings", entry.line, key),
+    }
+}
+
+pub fn parse_document(text: &str) -> Result<Document> {
+    Parser::new(text).parse_document()
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Value {
+    String(String),
+    Integer(i64),
+    Bool(bool),
+    Array(Vec<Value>),
+}
+
+pub struct Entry {
+    pub value: Value,
+    pub line: usize,
+}
+
+pub struct Table {
+    // Line of the [header], or 0 for the root table
+    pub line: usize,
+    pub entries: BTreeMap<String, Entry>,
+}
+
+impl Table {
+    fn new(line: usize) -> Table {
+        Table {
+            line,
+            entries: BTreeMap::new(),
+        }
+    }
+
+    pub fn get(&self, key: &str) -> Option<&Entry> {
+        self.entries.get(key)
+    }
+}
+
+// Tables by their dotted name, split into parts. The root table is [].
+pub type Document = BTreeMap<Vec<String>, Table>;
+
+struct Parser {
+    chars: Vec<char>,
+    pos: usize,
+    line: usize,
+}
+
+impl Parser {
+    fn new(text: &str) -> Parser {
+        Parser {
+            chars: text.chars().collect(),
+            pos: 0,
+            line: 1,
+        }
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.chars.get(self.pos).copied()
+    }
+
+    fn next(&mut self) -> Option<char> {
+        let c = self.peek()?;
+        self.pos += 1;
+        if c == '\n' {
+            self.line += 1;
+        }
+        Some(c)
+    }
+
+    fn error<T>(&self, msg: &str) -> Result<T> {
+        bail!("line {}: {}", self.line, msg)
+    }
+
+    // Skips spaces and tabs on the current line
+    fn skip_spaces(&mut self) {
+        while matches!(self.peek(), Some(' ' | '\t')) {
+            self.next();
+        }
+    }
+
+    fn skip_comment(&mut self) {
+        if self.peek() == Some('#') {
+            while !matches!(self.peek(), None | Some('\n')) {
+                self.next();
+            }
+        }
+    }
+
+    // Skips whitespace, newlines and comments
+    fn skip_blank(&mut self) {
+        loop {
+            self.skip_spaces();
+            self.skip_comment();
+            match self.peek() {
+                Some('\n' | '\r') => {
+                    self.next();
+                }
+                _ => break,
+            }
+        }
+    }
+
+    fn expect_line_end(&mut self) -> Result<()> {
+        self.skip_spaces();
+        self.skip_comment();
+        if self.peek() == Some('\r') {
+            self.next();
+        }
+        match self.peek() {
+            None => Ok(()),
+            Some('\n') => {
+                self.next();
+                Ok(())
+            }
+            Some(c) => self.error(&format!("unexpected {:?}", c)),
+        }
+    }
+
+    fn parse_document(&mut self) -> Result<Document> {
+        let mut document = Document::new();
+        let mut current = Vec::new();
+        document.insert(current.clone(), Table::new(0));
+
+        loop {
+            self.skip_blank();
+            match self.peek() {
+                None => break,
+                Some('[') => {
+                    let line = self.line;
+                    self.next();
+                    current = self.parse_dotted_key()?;
+                    if self.next() != Some(']') {
+                        return self.error("expected ] after table name");
+                    }
+                    self.expect_line_end()?;
+                    if document.contains_key(&current) {
+                        return self.error(&format!("table [{}] defined twice", current.join(".")));
+                    }
+                    document.insert(current.clone(), Table::new(line));
+                }
+                Some(_) => {
+                    let line = self.line;
+                    let key = self.parse_key()?;
+                    self.skip_spaces();
+                    if self.next() != Some('=') {
+                        return self.error(&format!("expected = after {}", key));
+                    }
+                    self.skip_spaces();
+                    let value = self.parse_value()?;
+                    self.expect_line_end()?;
+
+                    let table = document.get_mut(&current).unwrap();
+                    if table.entries.contains_key(&key) {
+                        bail!("line {}: {} defined twice", line, key);
+                    }
+                    table.entries.insert(key, Entry { value, line });
+                }
+            }
+        }
+
+        Ok(document)
+    }
+
+    fn parse_dotted_key(&mut self) -> Result<Vec<String>> {
+        let mut parts = Vec::new();
+        loop {
+            self.skip_spaces();
+            parts.push(self.parse_key()?);
+            self.skip_spaces();
+            if self.peek() == Some('.') {
+                self.next();
+            } else {
+                return Ok(parts);
+            }
+        }
+    }
+
+    fn parse_key(&mut self) -> Result<String> {
+        match self.peek() {
+            Some('"') => self.parse_basic_string(),
+            Some('\'') => self.parse_literal_string(),
+            _ => {
+                let mut key = String::new();
+                while let Some(c) = self.peek() {
+                    if c.is_ascii_alphanumeric() || c == '_' || c == '-' {
+                        key.push(c);
+                        self.next();
+                    } else {
+                        break;
+                    }
+                }
+                if key.is_empty() {
+                    return self.error("expected a key");
+                }
+                Ok(key)
+            }
+        }
+    }
+
+    fn parse_value(&mut self) -> Result<Value> {
+        match self.peek() {
+            Some('"') => Ok(Value::String(self.parse_basic_string()?)),
+            Some('\'') => Ok(Value::String(self.parse_literal_string()?)),
+            Some('[') => self.parse_array(),
+            Some('t' | 'f') => {
+                let word = self.parse_key()?;
+                match word.as_str() {
+                    "true" => Ok(Value::Bool(true)),
+                    "false" => Ok(Value::Bool(false)),
+                    _ => self.error(&format!("unexpected {}", word)),
+                }
+            }
+            Some(c) if c.is_ascii_digit() || c == '-' || c == '+' => {
+                let mut number = String::new();
+                while let Some(c) = self.peek() {
+                    if c.is_ascii_digit() || c == '-' || c == '+' || c == '_' {
+                        if c != '_' {
+                            number.push(c);
+                        }
+                        self.next();
+                    } else {
+                        break;
+                    }
+                }
+                match number.parse() {
+                    Ok(n) => Ok(Value::Integer(n)),
+                    Err(_) => self.error(&format!("invalid number {}", number)),
+                }
+            }
+            _ => self.error("expected a value"),
+        }
+    }
+
+    fn parse_array(&mut self) -> Result<Value> {
+        self.next();
+        let mut values = Vec::new();
+        loop {
+            self.skip_blank();
+            if self.peek() == Some(']') {
+                self.next();
+                return Ok(Value::Array(values));
+            }
+            values.push(self.parse_value()?);
+            self.skip_blank();
+            match self.next() {
+                Some(',') => {}
+                Some(']') => return Ok(Value::Array(values)),
+                _ => return self.error("expected , or ] in array"),
+            }
+        }
+    }
+
+    fn parse_basic_string(&mut self) -> Result<String> {
+        self.next();
+        let mut s = String::new();
+        loop {
+            match self.next() {
+                None | Some('\n') => return self.error("unterminated string"),
+                Some('"') => return Ok(s),
+                Some('\\') => match self.next() {
+                    Some('"') => s.push('"'),
+                    Some('\\') => s.push('\\'),
+                    Some('n') => s.push('\n'),
+                    Some('t') => s.push('\t'),
+                    _ => return self.error("invalid escape in string"),
+                },
+                Some(c) => s.push(c),
+            }
+        }
+    }
+
+    fn parse_literal_string(&mut self) -> Result<String> {
+        self.next();
+        let mut s = String::new();
+        loop {
+            match self.next() {
+                None | Some('\n') => return self.error("unterminated string"),
+                Some('\'') => return Ok(s),
+                Some(c) => s.push(c),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn defaults() {
+        let config = Config::parse("").unwrap();
+        assert_eq!(config.root, "/home/user/prog");
+        assert_eq!(config.host("desktop").unwrap().address, "10.13.13.4");
+    }
+
+    #[test]
+    fn sections() {
+        let config = Config::parse(
+            "[sync]\nroot = \"/data/\"\nignores = [\n  \"Name x\",\n]\n\n[hosts.nas]\naddress = 'nas.lan'\n",
+        )
+        .unwrap();
+        assert_eq!(config.root, "/data");
+        assert_eq!(config.ignores, vec!["Name x"]);
+        assert_eq!(config.host("nas").unwrap().address, "nas.lan");
+    }
+
+    #[test]
+    fn errors_have_lines() {
+        let err = match Config::parse("[sync]\nroot = 3\n") {
+            Ok(_) => panic!("root = 3 should be rejected"),
+            Err(err) => err,
+        };
+        assert!(err.to_string().contains("line 2"), "{}", err);
+        assert!(parse_document("[a]\nx = 1\nx = 2\n").is_err());
+    }
+
+    #[test]
+    fn wake_steps() {
+        let config = Config::parse(
+            "[hosts.desktop]\nwake = [\"wol 00:11:22:33:44:55 for 90s\", \"wol 00:11:22:33:44:55 via rpi\", \"plug http://plug/off http://plug/on\", \"bmc for 5m\"]\n",
+        )
+        .unwrap();
+        let wake = &config.host("desktop").unwrap().wake;
+        assert!(
+            wake[1].method
+                == WakeMethod::Wol {
+                    mac: "00:11:22:33:44:55".to_string(),
+                    via: Some("rpi".to_string())
+                }
+        );
+        assert_eq!(
+            wake.iter().map(|step| step.to_string()).collect::<Vec<_>>(),
+            [
+                "wol 00:11:22:33:44:55 for 90s",
+                "wol 00:11:22:33:44:55 via rpi",
+                "plug http://plug/off http://plug/on",
+                "bmc for 300s"
+            ]
+        );
+        assert!(Config::parse("[hosts.desktop]\nwake = [\"plug\"]\n").is_err());
+        assert!(Config::parse("[hosts.desktop]\nwake = [\"bmc for ever\"]\n").is_err());
+    }
+
+    #[test]
+    fn probes() {
+        let config = Config::parse("[hosts.desktop]\nprobe = \"tcp:22\"\n").unwrap();
+        assert!(config.host("desktop").unwrap().probe == Probe::Tcp(22));
+        assert!(Probe::parse("https://nas.lan/health").is_some());
+        assert!(Probe::parse("tcp:ssh").is_none());
+        assert!(Config::parse("[hosts.desktop]\nprobe = \"icmp\"\n").is_err());
+    }
+
+    #[test]
+    fn ssh_options() {
+        let config = Config::parse(
+            "[ssh]\nconnect_timeout = \"20s\"\nserver_alive_interval = 15\n[hosts.nas]\naddress = \"nas.lan\"\nidentity_file = \"~/.ssh/id_nas\"\nidentities_only = true\n",
+        )
+        .unwrap();
+        assert_eq!(
+            config.host("nas").unwrap().ssh_options,
+            [
+                "ConnectTimeout=20",
+                "ServerAliveInterval=15",
+                "ServerAliveCountMax=3",
+                "IdentityFile=~/.ssh/id_nas",
+                "IdentitiesOnly=yes"
+            ]
+        );
+        assert_eq!(
+            Config::default().host("desktop").unwrap().ssh_options,
+            ["ConnectTimeout=8"]
+        );
+    }
+
+    #[test]
+    fn pinned_host_keys() {
+        let config = Config::parse(
+            "[hosts.desktop]\nhost_key_checking = \"ask\"\nhost_key = \"ssh-ed25519 AAAAC3Nz\"\n",
+        )
+        .unwrap();
+        let options = &config.host("desktop").unwrap().ssh_options;
+        assert!(options.contains(&"StrictHostKeyChecking=yes".to_string()));
+        assert!(options.iter().any(|o| o.starts_with("UserKnownHostsFile=")));
+        assert!(Config::parse("[hosts.desktop]\nhost_key = \"AAAAC3Nz\"\n").is_err());
+    }
+
+    #[test]
+    fn projects() {
+        let config = Config::parse(
+            "[projects.notes]\ninterval = \"5m\"\n[projects.android]\npath = \"/work/android/\"\ninterval = \"1h\"\n",
+        )
+        .unwrap();
+        let paths = config
+            .projects
+            .iter()
+            .map(|project| (project.path.as_str(), project.interval))
+            .collect::<Vec<_>>();
+        assert_eq!(paths, [("work/android", 3600), ("notes", 300)]);
+        assert!(Config::parse("[projects.up]\npath = \"../elsewhere\"\n").is_err());
+    }
+
+    #[test]
+    fn profiles() {
+        let config = Config::parse(
+            "[profiles.quick]\npaths = [\"notes/\"]\n[profiles.bedtime]\nhost = \"desktop\"\nlocal_power = \"suspend\"\nremote_power = \"shutdown\"\n",
+        )
+        .unwrap();
+        let quick = config.profile("quick").unwrap();
+        assert_eq!(quick.paths, ["notes"]);
+        assert!(matches!(quick.local_power, PowerAction::Nothing));
+        let bedtime = config.profile("bedtime").unwrap();
+        assert_eq!(bedtime.host.as_deref(), Some("desktop"));
+        assert!(matches!(bedtime.local_power, PowerAction::Suspend));
+        assert!(matches!(bedtime.remote_power, PowerAction::Shutdown));
+        assert!(config.profile("slow").is_err());
+        assert!(Config::parse("[profiles.x]\nlocal_power = \"hibernate\"\n").is_err());
+        assert!(Config::parse("[profiles.x]\npaths = [\"..\"]\n").is_err());
+    }
+
+    #[test]
+    fn aliases() {
+        let config =
+            Config::parse("[aliases]\nnight = [\"-s\", \"-ls\"]\naway = [\"-t\", \"rpi\"]\n")
+                .unwrap();
+        assert_eq!(config.aliases["night"], ["-s", "-ls"]);
+        assert_eq!(config.aliases.len(), 2);
+        assert!(Config::parse("[aliases]\n-x = [\"-s\"]\n").is_err());
+        assert!(Config::parse("[aliases]\nnight = \"-s\"\n").is_err());
+    }
+
+    #[test]
+    fn notifiers() {
+        let config = Config::parse(
+            "[notify.desktop]\n[notify.phone]\nurl = \"https://ntfy.sh/t\"\nevents = [\"failure\", \"conflict\"]\n",
+        )
+        .unwrap();
+        let phone = &config.notifiers[1];
+        assert_eq!(phone.name, "phone");
+        assert_eq!(phone.events, [NotifyClass::Failure, NotifyClass::Conflict]);
+        assert_eq!(config.notifiers[0].events.len(), 5);
+        assert!(Config::parse("[notify.phone]\nevents = [\"sometimes\"]\n").is_err());
+    }
+
+    #[test]
+    fn file_metadata() {
+        let config =
+            Config::parse("[hosts.rpi]\nperms = false\nowner = true\nnumeric_ids = true\n")
+                .unwrap();
+        let rpi = config.host("rpi").unwrap();
+        let options = crate::unison::unison_options(&config, rpi, false);
+        assert!(options.contains(&("perms", Some("0".to_string()))));
+        assert!(options.contains(&("owner", None)));
+        assert!(!options.contains(&("group", None)));
+        let rsync = crate::rsync::rsync_command(&config, rpi, &[], &[]);
+        let args = rsync.get_args().collect::<Vec<_>>();
+        assert!(args.contains(&"--no-group".as_ref()));
+        assert!(!args.contains(&"--no-owner".as_ref()));
+    }
+
+    #[test]
+    fn durations() {
+        let config =
+            Config::parse("[daemon]\ndebounce = \"45s\"\nmax_batch_wait = \"2m\"\n").unwrap();
+        assert_eq!(config.daemon_debounce, 45);
+        assert_eq!(config.daemon_max_batch_wait, 120);
+        assert_eq!(
+            Config::parse("[daemon]\ndebounce = 10\n")
+                .unwrap()
+                .daemon_debounce,
+            10
+        );
+        assert_eq!(parse_duration("1h"), Some(3600));
+        assert_eq!(parse_duration("5 m"), Some(300));
+        assert_eq!(parse_duration("m"), None);
+        assert_eq!(parse_duration("3 days"), None);
+        assert_eq!(parse_size("1GB"), Some(1_000_000_000));
+        assert_eq!(parse_size("500 M"), Some(500_000_000));
+        assert_eq!(parse_size("2kb"), Some(2000));
+        assert_eq!(parse_size("1 gallon"), None);
+    }
+
+    #[test]
+    fn dumps_what_loads_back() {
+        let config = Config::parse(
+            "[sync]\nlarge_file = \"1GB\"\n[hosts.\"my box\"]\naddress = \"10.0.0.9\"\nprobe = \"tcp:22\"\n[notify.phone]\nevents = [\"failure\"]\n",
+        )
+        .unwrap();
+        let document = config.to_document();
+        assert_eq!(
+            document[&vec!["sync".to_string()]].entries["large_file"].value,
+            Value::Integer(1_000_000_000)
+        );
+
+        let mut text = String::new();
+        for (table, entries) in &document {
+            let name = table.iter().map(|part| format_key(part));
+            text.push_str(&format!("[{}]\n", name.collect::<Vec<_>>().join(".")));
+            for (key, entry) in &entries.entries {
+                text.push_str(&format!(
+                    "{} = {}\n",
+                    format_key(key),
+                    format_value(&entry.value)
+                ));
+            }
+        }
+        let reloaded = Config::parse(&text).unwrap().to_document();
+        assert_eq!(reloaded.len(), document.len());
+        for (table, entries) in &document {
+            for (key, entry) in &entries.entries {
+                assert_eq!(reloaded[table].entries[key].value, entry.value, "{}", key);
+            }
+        }
+    }
+}
@@ -0,0 +1,127 @@
+// Databases living under the root, like an app's SQLite file or a Postgres
+// data directory, can't be synced as they are: a copy taken mid-write is
+// corrupt on the other end. Each one in [databases] is dumped to a snapshot
+// file before the sync, on this machine and on the host, and the live files
+// are left out of it. Every machine writes a snapshot of its own, with its
+// hostname before the extension (notes/app.desktop.sql), so the two dumps
+// never conflict. The snapshots are what travel; loading one back is up to
+// whoever needs it.
+//
+// A dump that fails only warns, leaving the last snapshot there was.
+
+use crate::{
+    config::{Config, Database, Host},
+    runner::Runner,
+    shell_quote,
+    ssh::ssh,
+};
+use eyre::Result;
+use std::process::{Command, Stdio};
+
+// Next to an SQLite file while it's open
+const SQLITE_FILES: &[&str] = &["-wal", "-shm", "-journal"];
+
+// The unison ignores for the live files
+pub fn ignores(config: &Config) -> Vec<String> {
+    let mut ignores = Vec::new();
+    for database in &config.databases {
+        ignores.push(format!("Path {}", database.path));
+        for suffix in SQLITE_FILES {
+            ignores.push(format!("Path {}{}", database.path, suffix));
+        }
+    }
+    ignores
+}
+
+// Dumps every database to its snapshot, here and on the host
+pub fn dump(runner: &dyn Runner, config: &Config, host: &Host) -> Result<()> {
+    for database in &config.databases {
+        log!("Dumping {} next to {}", database.name, database.snapshot);
+        let mut here = Command::new("sh");
+        here.args(["-c", &script(&config.root, database)]);
+        let mut there = ssh(host);
+        there.arg(script(host.root(config), database));
+
+        let on_host = format!("on {}", host.name);
+        for (mut command, place) in [(here, "here"), (there, on_host.as_str())] {
+            let output = runner.output(command.stdin(Stdio::null()))?;
+            if !output.status.success() {
+                warn!(
+                    "Couldn't dump {} {}, syncing its last snapshot: {}",
+                    database.name,
+                    place,
+                    String::from_utf8_lossy(&output.stderr).trim()
+                );
+            }
+        }
+    }
+    Ok(())
+}
+
+// Dumps from the root to a temporary file first, so a failed dump doesn't
+// leave half a snapshot. Nothing to dump is fine: the database may only
+// live on one end.
+fn script(root: &str, database: &Database) -> String {
+    let dump = match &database.dump {
+        Some(dump) => dump.clone(),
+        None => format!("sqlite3 {} .dump", shell_quote(&database.path)),
+    };
+    let partial = snapshot(&database.snapshot, ".partial");
+    let snapshot = snapshot(&database.snapshot, "");
+    format!(
+        "cd {} && {{ test -e {} || exit 0; }} && ({}) >{} && mv {} {}",
+        shell_quote(root),
+        shell_quote(&database.path),
+        dump,
+        partial,
+        partial,
+        snapshot
+    )
+}
+
+// The machine's own snapshot, as a shell word that puts its hostname before
+// the extension when it's run there
+fn snapshot(path: &str, suffix: &str) -> String {
+    let name_start = path.rfind('/').map_or(0, |slash| slash + 1);
+    let (stem, extension) = match path[name_start..].rfind('.') {
+        Some(dot) if dot > 0 => path.split_at(name_start + dot),
+        _ => (path, ""),
+    };
+    format!(
+        "{}\"$(uname -n)\"{}",
+        shell_quote(&format!("{}.", stem)),
+        shell_quote(&format!("{}{}", extension, suffix))
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::runner::MockRunner;
+
+    #[test]
+    fn dumps_both_ends() {
+        let runner = MockRunner::new();
+        runner.script("ssh", &[1]);
+        let config = Config::parse(
+            "[databases.notes]\npath = \"notes/app.db\"\n[databases.photos]\npath = \"pg\"\ndump = \"pg_dump photos\"\nsnapshot = \"photos.sql\"\n",
+        )
+        .unwrap();
+        let desktop = config.host("desktop").unwrap();
+
+        assert_eq!(
+            ignores(&config)[..2],
+            ["Path notes/app.db", "Path notes/app.db-wal"]
+        );
+        dump(&runner, &config, desktop).unwrap();
+        let commands = runner.commands();
+        assert_eq!(commands.len(), 4);
+        assert!(commands[0].starts_with("sh -c cd "));
+        assert!(commands[0].ends_with(
+            "&& { test -e 'notes/app.db' || exit 0; } && (sqlite3 'notes/app.db' .dump) >'notes/app.db.'\"$(uname -n)\"'.sql.partial' && mv 'notes/app.db.'\"$(uname -n)\"'.sql.partial' 'notes/app.db.'\"$(uname -n)\"'.sql'"
+        ));
+        assert!(commands[1].starts_with("ssh -o ConnectTimeout=8 10.13.13.4 cd "));
+        assert!(commands[3].contains("&& (pg_dump photos) >'photos.'\"$(uname -n)\"'.sql.partial'"));
+        assert_eq!(snapshot("db/dump", ""), "'db/dump.'\"$(uname -n)\"''");
+    }
+}
@@ -0,0 +1,280 @@
+// Content repeated across files, like the same vendored library in several
+// projects, goes over once per sync with [sync] dedup. It needs synctool on
+// the host, since both ends' version tables are what say which file holds
+// what. Before the backend runs:
+//
+// - a file going to the host whose contents the host already has somewhere
+//   else is copied from there, on the host
+// - contents that several files going over share, and the host doesn't have,
+//   are sent once into a store under the host's root named after them,
+//   .synctool-chunks/HASH-SIZE, copied out to each file, and the store is
+//   removed again
+//
+// The tables' hashes only pick the candidates: each copy is checked against a
+// sha256 of the file here before it's put in place, and one that doesn't
+// match is dropped, leaving that file to the backend. The backend then finds
+// the copied files already the same on both ends, with nothing to send.
+
+use crate::{
+    config::{Config, Host},
+    rsync,
+    runner::Runner,
+    shell_quote,
+    ssh::{self, ssh},
+    versions::{Order, Table},
+};
+use eyre::Result;
+use std::{
+    collections::BTreeMap,
+    process::{Command, Stdio},
+};
+
+// Under the host's root, and ignored by every sync with dedup on
+pub const STORE: &str = ".synctool-chunks";
+
+// Copies what it can of the files the plan sends from contents already on the
+// host or sent once. Returns how many files were put in place there.
+pub fn send(
+    runner: &dyn Runner,
+    config: &Config,
+    host: &Host,
+    ours: &Table,
+    theirs: &Table,
+    plan: &[(String, Order)],
+) -> Result<usize> {
+    // The files going over, by their contents, leaving out any that would
+    // replace a file on the host with no history to say which is newer
+    let mut groups = BTreeMap::<(u64, u64), Vec<&str>>::new();
+    for (path, order) in plan {
+        let live_there = theirs
+            .entries
+            .get(path)
+            .is_some_and(|entry| entry.hash.is_some());
+        let goes_over = match order {
+            Order::Newer => true,
+            Order::Unknown => !live_there,
+            _ => false,
+        };
+        let ours = ours.entries.get(path);
+        if let Some((Some(hash), size)) = ours.map(|entry| (entry.hash, entry.size)) {
+            if goes_over {
+                groups.entry((hash, size)).or_default().push(path);
+            }
+        }
+    }
+    if groups.is_empty() {
+        return Ok(0);
+    }
+
+    // Where the host has each of the contents, leaving out the files that are
+    // about to be replaced
+    let mut there = BTreeMap::new();
+    for (path, entry) in &theirs.entries {
+        if let Some(hash) = entry.hash {
+            if !groups.values().flatten().any(|target| target == path) {
+                there.entry((hash, entry.size)).or_insert(path.as_str());
+            }
+        }
+    }
+
+    let root = &config.root;
+    let mut copies = Vec::new();
+    let mut chunks = Vec::new();
+    for ((hash, size), targets) in &groups {
+        let source = match there.get(&(*hash, *size)) {
+            Some(path) => path.to_string(),
+            None if targets.len() > 1 => format!("{}/{:016x}-{}", STORE, hash, size),
+            None => continue,
+        };
+        let sum = match sha256(runner, &format!("{}/{}", root, targets[0]))? {
+            Some(sum) => sum,
+            None => continue,
+        };
+        if !there.contains_key(&(*hash, *size)) {
+            chunks.push((targets[0], source.clone()));
+        }
+        for target in targets {
+            copies.push((source.clone(), target.to_string(), sum.clone()));
+        }
+    }
+    if copies.is_empty() {
+        return Ok(0);
+    }
+
+    let remote_root = shell_quote(host.root(config));
+    if !chunks.is_empty() {
+        log!(
+            "Sending {} chunk(s) shared by several files to {} once",
+            chunks.len(),
+            host.name
+        );
+        let mut mkdir = ssh(host);
+        mkdir.arg(format!("cd {} && mkdir -p {}", remote_root, STORE));
+        if !runner.status(mkdir.stdin(Stdio::null()))?.success() {
+            warn!("Couldn't make the chunk store on {}", host.name);
+            return Ok(0);
+        }
+        let mut commands = chunks
+            .iter()
+            .map(|(path, chunk)| {
+                let mut command = Command::new("rsync");
+                command
+                    .args(["-az", "--stats", "-e"])
+                    .arg(format!("ssh {}", ssh::args_line(host)))
+                    .arg(format!("{}/{}", root, path))
+                    .arg(format!("{}:{}/{}", host.address, host.root(config), chunk))
+                    .stdout(Stdio::piped());
+                command
+            })
+            .collect::<Vec<_>>();
+        if !rsync::run(runner, &mut commands)? {
+            warn!("Couldn't send every chunk to {}", host.name);
+        }
+    }
+
+    // Each copy goes to a partial file first, which only takes the file's
+    // place if its sha256 is the one here
+    let steps = copies
+        .iter()
+        .map(|(source, target, sum)| {
+            let target = shell_quote(target);
+            let partial = format!("{}.synctool-partial", target);
+            format!(
+                "{{ mkdir -p \"$(dirname {})\" && cp -p {} {} && printf '%s  %s\\n' {} {} | sha256sum -c --status && mv {} {} && echo; }} || rm -f {}",
+                target,
+                shell_quote(source),
+                partial,
+                sum,
+                partial,
+                partial,
+                target,
+                partial
+            )
+        })
+        .collect::<Vec<_>>();
+    let script = format!(
+        "cd {} && {{ {}; rm -rf {}; }}",
+        remote_root,
+        steps.join("; "),
+        STORE
+    );
+    let output = runner.output(ssh(host).arg(script).stdin(Stdio::null()))?;
+    let copied = String::from_utf8_lossy(&output.stdout).lines().count();
+    if copied > 0 {
+        log!(
+            "Copied {} file(s) on {} from contents already there or sent once",
+            copied,
+            host.name
+        );
+    }
+    if copied < copies.len() {
+        warn!(
+            "Couldn't copy {} file(s) on {}, leaving them to the sync",
+            copies.len() - copied,
+            host.name
+        );
+    }
+    Ok(copied)
+}
+
+// The sha256 of a file here, or None if it couldn't be read
+fn sha256(runner: &dyn Runner, path: &str) -> Result<Option<String>> {
+    let output = runner.output(Command::new("sha256sum").arg(path).stdin(Stdio::null()))?;
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    Ok(stdout
+        .split_whitespace()
+        .next()
+        .filter(|sum| output.status.success() && sum.len() == 64)
+        .map(str::to_string))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        runner::{MockRunner, Reply},
+        versions::{Entry, Vector},
+    };
+
+    fn table(files: &[(&str, u64)]) -> Table {
+        let mut table = Table::default();
+        for (path, hash) in files {
+            table.entries.insert(
+                path.to_string(),
+                Entry {
+                    hash: Some(*hash),
+                    size: 100,
+                    mtime: 0,
+                    vector: Vector::new(),
+                },
+            );
+        }
+        table
+    }
+
+    #[test]
+    fn sends_repeated_contents_once() {
+        let config = Config {
+            root: "/home/user/prog".to_string(),
+            ..Config::default()
+        };
+        let desktop = config.host("desktop").unwrap();
+        let ours = table(&[
+            ("a/vendor/lib.js", 1),
+            ("b/vendor/lib.js", 1),
+            ("c/util.js", 2),
+            ("d/main.rs", 3),
+        ]);
+        let theirs = table(&[("old/util.js", 2)]);
+        let plan = ours
+            .entries
+            .keys()
+            .map(|path| (path.clone(), Order::Unknown))
+            .collect::<Vec<_>>();
+        let runner = MockRunner::new();
+        let sum = "9".repeat(64);
+        runner.script_replies(
+            "sha256sum",
+            vec![Reply {
+                code: 0,
+                stdout: format!("{}  file\n", sum),
+            }],
+        );
+        runner.script_replies(
+            "ssh -o ConnectTimeout=8 10.13.13.4 cd '/home/user/prog' && { {",
+            vec![Reply {
+                code: 0,
+                stdout: "\n\n\n".to_string(),
+            }],
+        );
+
+        assert_eq!(
+            send(&runner, &config, desktop, &ours, &theirs, &plan).unwrap(),
+            3
+        );
+        let commands = runner.commands();
+        assert_eq!(
+            commands[..2],
+            [
+                "sha256sum /home/user/prog/a/vendor/lib.js",
+                "sha256sum /home/user/prog/c/util.js"
+            ]
+        );
+        assert_eq!(
+            commands[2],
+            "ssh -o ConnectTimeout=8 10.13.13.4 cd '/home/user/prog' && mkdir -p .synctool-chunks"
+        );
+        assert!(commands[3].starts_with("rsync -az --stats -e ssh "));
+        assert!(commands[3].ends_with(
+            " /home/user/prog/a/vendor/lib.js 10.13.13.4:/home/user/prog/.synctool-chunks/0000000000000001-100"
+        ));
+        assert_eq!(commands.len(), 5);
+        assert!(commands[4].contains(&format!(
+            "cp -p '.synctool-chunks/0000000000000001-100' 'b/vendor/lib.js'.synctool-partial && printf '%s  %s\\n' {} 'b/vendor/lib.js'.synctool-partial | sha256sum -c --status",
+            sum
+        )));
+        assert!(commands[4].contains("cp -p 'old/util.js' 'c/util.js'.synctool-partial"));
+        assert!(!commands[4].contains("d/main.rs"));
+        assert!(commands[4].ends_with("; rm -rf .synctool-chunks; }"));
+    }
+}
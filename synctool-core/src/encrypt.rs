@@ -0,0 +1,145 @@
+// Encrypted pushes for hosts that shouldn't see plaintext.
+//
+// Every file in the tree is encrypted with gpg into a local staging mirror
+// (each file becomes FILE.gpg), and that mirror is pushed with rsync. Files are
+// only re-encrypted when the plaintext is newer than its ciphertext, so after
+// the first run this costs about as much as a normal rsync scan. The remote
+// copy is a mirror of the staging tree, so deletions propagate too.
+//
+// Only contents are hidden. Names of files and directories keep their
+// plaintext, with .gpg added, and ciphertext is about as big as what it
+// encrypts, so the host can still see the tree's layout and roughly how big
+// every file is.
+
+use crate::{
+    config::{Config, Host},
+    events::{self, Value},
+    ignore::ignored,
+    rsync::{self, rsync_command},
+    runner::Runner,
+    state_dir,
+};
+use eyre::{ensure, Result};
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    process::{exit, Command, Stdio},
+};
+
+pub fn push(
+    runner: &dyn Runner,
+    config: &Config,
+    host: &Host,
+    recipient: &str,
+    print: bool,
+) -> Result<bool> {
+    let root = Path::new(&config.root);
+    // By name, so switching between the host's addresses keeps what was staged
+    let staging = state_dir().join(format!("encrypted-{}", host.name));
+
+    let mut source = staging.to_string_lossy().into_owned();
+    source.push('/');
+    let mut command = rsync_command(config, host, &[source], &["--delete"]);
+
+    if print {
+        let mut args = String::new();
+        for a in command.get_args() {
+            args.push_str(&format!("{:?} ", a));
+        }
+        log!("command: rsync {}", args);
+        exit(0);
+    }
+
+    phase!("Encrypting changed files for {}", host.address);
+    events::phase("encrypt", || {
+        fs::create_dir_all(&staging)?;
+        let encrypted = stage(runner, config, root, &staging, recipient)?;
+        log!("Encrypted {} files", encrypted);
+        events::emit(
+            "files",
+            &[
+                ("count", Value::Number(encrypted as f64)),
+                ("what", Value::Str("encrypted")),
+            ],
+        );
+        prune(root, &staging, &staging)
+    })?;
+
+    rsync::run(runner, std::slice::from_mut(&mut command))
+}
+
+// Brings the staging mirror up to date. Returns the number of files encrypted.
+fn stage(
+    runner: &dyn Runner,
+    config: &Config,
+    dir: &Path,
+    staging: &Path,
+    recipient: &str,
+) -> Result<usize> {
+    let root = Path::new(&config.root);
+    let mut encrypted = 0;
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let path = entry.path();
+        let rel = path.strip_prefix(root)?;
+        if ignored(config, &rel.to_string_lossy()) {
+            continue;
+        }
+
+        let meta = entry.metadata()?;
+        if meta.is_dir() {
+            fs::create_dir_all(staging.join(rel))?;
+            encrypted += stage(runner, config, &path, staging, recipient)?;
+        } else if meta.is_file() {
+            let target = ciphertext_path(staging, rel);
+            let up_to_date = match fs::metadata(&target) {
+                Ok(target_meta) => target_meta.modified()? >= meta.modified()?,
+                Err(_) => false,
+            };
+            if up_to_date {
+                continue;
+            }
+
+            let status = runner.status(
+                Command::new("gpg")
+                    .args(["--batch", "--yes", "--trust-model", "always", "--encrypt"])
+                    .args(["--recipient", recipient, "--output"])
+                    .arg(&target)
+                    .arg(&path)
+                    .stdin(Stdio::null()),
+            )?;
+            ensure!(status.success(), "gpg couldn't encrypt {}", path.display());
+            encrypted += 1;
+        }
+    }
+    Ok(encrypted)
+}
+
+// Deletes ciphertext whose plaintext no longer exists.
+fn prune(root: &Path, staging: &Path, dir: &Path) -> Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let path = entry.path();
+        let rel = path.strip_prefix(staging)?;
+
+        if entry.file_type()?.is_dir() {
+            if root.join(rel).is_dir() {
+                prune(root, staging, &path)?;
+            } else {
+                fs::remove_dir_all(&path)?;
+            }
+        } else {
+            let plaintext = rel.with_extension("");
+            if !root.join(plaintext).is_file() {
+                fs::remove_file(&path)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+fn ciphertext_path(staging: &Path, rel: &Path) -> PathBuf {
+    let mut target = staging.join(rel).into_os_string();
+    target.push(".gpg");
+    PathBuf::from(target)
+}
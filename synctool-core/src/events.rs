@@ -0,0 +1,195 @@
+// A machine readable stream of what a run is doing, for status bars and other
+// scripts. With --events-fd or --events-file, every event is written as one
+// JSON object per line, e.g.
+//
+//     {"time":1686000000.12,"event":"phase_start","phase":"wake"}
+//     {"time":1686000031.40,"event":"phase_end","phase":"wake","ok":true,"seconds":31.28}
+//
+// Events are run_start, run_end (ok), phase_start and phase_end (phase, ok,
+// seconds) for wake, sync, encrypt, power, cloud and archive, files (count,
+// what), transfer (sent, received), found (state: awake, asleep or off, how
+// a host that can be woken was before) and error (message).
+//
+// Phase durations and bytes transferred are also kept for the summary at the end of the run,
+// whether or not events are being written anywhere, and so is the found state for the history.
+
+use crate::progress;
+use eyre::Result;
+use lazy_static::lazy_static;
+use std::{
+    fs::{File, OpenOptions},
+    io::Write,
+    os::unix::io::FromRawFd,
+    path::Path,
+    sync::Mutex,
+    time::{Instant, SystemTime, UNIX_EPOCH},
+};
+
+lazy_static! {
+    static ref SINK: Mutex<Option<File>> = Mutex::new(None);
+    static ref TIMINGS: Mutex<Vec<(String, f64)>> = Mutex::new(Vec::new());
+    static ref TRANSFERRED: Mutex<Option<(u64, u64)>> = Mutex::new(None);
+    static ref FOUND: Mutex<Option<&'static str>> = Mutex::new(None);
+}
+
+pub enum Value<'a> {
+    Str(&'a str),
+    Number(f64),
+    Bool(bool),
+}
+
+// Sends events to a file descriptor the caller opened for us, e.g. 3>pipe
+pub fn to_fd(fd: i32) {
+    *SINK.lock().unwrap() = Some(unsafe { File::from_raw_fd(fd) });
+}
+
+pub fn to_file(path: &Path) -> Result<()> {
+    let file = OpenOptions::new().create(true).append(true).open(path)?;
+    *SINK.lock().unwrap() = Some(file);
+    Ok(())
+}
+
+pub fn emit(event: &str, fields: &[(&str, Value)]) {
+    let mut sink = SINK.lock().unwrap();
+    let file = match sink.as_mut() {
+        Some(file) => file,
+        None => return,
+    };
+
+    let time = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0., |time| time.as_secs_f64());
+    let mut line = format!("{{\"time\":{:.2},\"event\":{}", time, json_string(event));
+    for (name, value) in fields {
+        let value = match value {
+            Value::Str(s) => json_string(s),
+            Value::Number(n) => format!("{}", n),
+            Value::Bool(b) => b.to_string(),
+        };
+        line.push_str(&format!(",{}:{}", json_string(name), value));
+    }
+    line.push_str("}\n");
+
+    // Whoever is reading may have gone away; that's no reason to stop syncing
+    let _ = file.write_all(line.as_bytes());
+}
+
+// What a phase returns when it went well: () or true.
+pub trait Outcome {
+    fn ok(&self) -> bool;
+}
+
+impl Outcome for () {
+    fn ok(&self) -> bool {
+        true
+    }
+}
+
+impl Outcome for bool {
+    fn ok(&self) -> bool {
+        *self
+    }
+}
+
+// Runs one phase of the run between phase_start and phase_end events.
+pub fn phase<T: Outcome>(name: &str, f: impl FnOnce() -> Result<T>) -> Result<T> {
+    emit("phase_start", &[("phase", Value::Str(name))]);
+    let outer = progress::current().phase;
+    progress::phase(Some(name));
+    let start = Instant::now();
+    let result = f();
+    progress::phase(outer.as_deref());
+    let seconds = (start.elapsed().as_secs_f64() * 100.).round() / 100.;
+    add_timing(name, seconds);
+    emit(
+        "phase_end",
+        &[
+            ("phase", Value::Str(name)),
+            ("ok", Value::Bool(result.as_ref().is_ok_and(Outcome::ok))),
+            ("seconds", Value::Number(seconds)),
+        ],
+    );
+    result
+}
+
+fn add_timing(name: &str, seconds: f64) {
+    let mut timings = TIMINGS.lock().unwrap();
+    match timings.iter_mut().find(|(phase, _)| phase == name) {
+        Some((_, total)) => *total += seconds,
+        None => timings.push((name.to_string(), seconds)),
+    }
+}
+
+// Total seconds spent in each phase since the last call, in the order the
+// phases first ran. A phase that ran more than once, like a retried sync, is
+// added up.
+pub fn take_timings() -> Vec<(String, f64)> {
+    std::mem::take(&mut *TIMINGS.lock().unwrap())
+}
+
+// Counts bytes sent and received by a backend that can tell, which is rsync
+// (and so encrypted pushes) but not unison.
+pub fn transferred(sent: u64, received: u64) {
+    emit(
+        "transfer",
+        &[
+            ("sent", Value::Number(sent as f64)),
+            ("received", Value::Number(received as f64)),
+        ],
+    );
+    let mut transferred = TRANSFERRED.lock().unwrap();
+    let (total_sent, total_received) = transferred.get_or_insert((0, 0));
+    *total_sent += sent;
+    *total_received += received;
+}
+
+// Bytes (sent, received) since the last call, if the backend reported any.
+pub fn take_transferred() -> Option<(u64, u64)> {
+    TRANSFERRED.lock().unwrap().take()
+}
+
+// Notes how a host was found before syncing: "awake", "asleep" or "off".
+pub fn found(state: &'static str) {
+    emit("found", &[("state", Value::Str(state))]);
+    *FOUND.lock().unwrap() = Some(state);
+}
+
+// The state noted since the last call, if any.
+pub fn take_found() -> Option<&'static str> {
+    FOUND.lock().unwrap().take()
+}
+
+// e.g. "wake 31.2s, sync 12.0s, power 0.5s"
+pub fn breakdown(timings: &[(String, f64)]) -> String {
+    timings
+        .iter()
+        .map(|(phase, seconds)| format!("{} {:.1}s", phase, seconds))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+pub fn json_string(s: &str) -> String {
+    let mut quoted = String::from("\"");
+    for c in s.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            c if (c as u32) < 0x20 => quoted.push_str(&format!("\\u{:04x}", c as u32)),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+// The value of the first "key" in a JSON object, unquoted. Enough for flat
+// objects without escapes, like Syncthing's responses and status.json.
+pub(crate) fn json_field<'a>(json: &'a str, key: &str) -> Option<&'a str> {
+    let start = json.find(&format!("\"{}\":", key))? + key.len() + 3;
+    let value = json[start..].trim_start();
+    match value.strip_prefix('"') {
+        Some(string) => string.split('"').next(),
+        None => value.split([',', '}']).next().map(str::trim),
+    }
+}
@@ -0,0 +1,191 @@
+// The wait before power actions with [power] grace set: a countdown that a
+// keypress at the terminal or `synctool cancel` from anywhere stops. Each
+// countdown listens on its own socket, cancel-PID.sock in the runtime dir,
+// and `synctool cancel` knocks on all of them.
+
+use crate::runtime_dir;
+use eyre::Result;
+use std::{
+    fs,
+    io::{self, Read, Write},
+    os::unix::{
+        io::AsRawFd,
+        net::{UnixListener, UnixStream},
+    },
+    path::{Path, PathBuf},
+    process,
+    time::{Duration, Instant},
+};
+
+// Counts down from `seconds`, returning false if it was cancelled and true if
+// it ran out.
+pub fn countdown(what: &str, seconds: u64) -> Result<bool> {
+    countdown_in(&runtime_dir(), what, seconds)
+}
+
+// The countdown, with its socket in `dir`
+fn countdown_in(dir: &Path, what: &str, seconds: u64) -> Result<bool> {
+    let socket = Socket::bind(dir)?;
+    let _terminal = RawTerminal::new();
+    let keys = unsafe { libc::isatty(libc::STDIN_FILENO) == 1 };
+    log!(
+        "{} in {}s; press any key or run `synctool cancel` to stop",
+        what,
+        seconds
+    );
+
+    let end = Instant::now() + Duration::from_secs(seconds);
+    let mut shown = seconds;
+    loop {
+        let left = end.saturating_duration_since(Instant::now());
+        if left.is_zero() {
+            return Ok(true);
+        }
+        let remaining = left.as_secs() + 1;
+        if remaining < shown && (remaining.is_multiple_of(10) || remaining <= 5) {
+            log!("{}s", remaining);
+        }
+        shown = remaining;
+
+        let mut fds = vec![libc::pollfd {
+            fd: socket.listener.as_raw_fd(),
+            events: libc::POLLIN,
+            revents: 0,
+        }];
+        if keys {
+            fds.push(libc::pollfd {
+                fd: libc::STDIN_FILENO,
+                events: libc::POLLIN,
+                revents: 0,
+            });
+        }
+        // Waking at least every second to show the time left
+        let timeout = left.min(Duration::from_secs(1)).as_millis().max(1) as i32;
+        if unsafe { libc::poll(fds.as_mut_ptr(), fds.len() as libc::nfds_t, timeout) } <= 0 {
+            continue;
+        }
+
+        if fds[0].revents != 0 {
+            if let Ok((mut stream, _)) = socket.listener.accept() {
+                let _ = writeln!(stream, "cancelled");
+                log!("Cancelled by `synctool cancel`");
+                return Ok(false);
+            }
+        }
+        if fds.get(1).is_some_and(|fd| fd.revents != 0) {
+            let _ = io::stdin().read(&mut [0; 64]);
+            log!("Cancelled");
+            return Ok(false);
+        }
+    }
+}
+
+// Cancels every countdown going on, returning how many there were.
+pub fn cancel_all() -> Result<usize> {
+    cancel_all_in(&runtime_dir())
+}
+
+// Cancels the countdowns with sockets in `dir`
+fn cancel_all_in(dir: &Path) -> Result<usize> {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(0),
+        Err(err) => return Err(err.into()),
+    };
+
+    let mut cancelled = 0;
+    for entry in entries {
+        let path = entry?.path();
+        let name = path.file_name().unwrap_or_default().to_string_lossy();
+        if !(name.starts_with("cancel-") && name.ends_with(".sock")) {
+            continue;
+        }
+        match UnixStream::connect(&path) {
+            Ok(mut stream) => {
+                // Waiting for the answer, so the countdown is over on return
+                stream.set_read_timeout(Some(Duration::from_secs(2)))?;
+                let _ = stream.read(&mut [0; 16]);
+                cancelled += 1;
+            }
+            // Left behind by a synctool that was killed
+            Err(_) => {
+                let _ = fs::remove_file(&path);
+            }
+        }
+    }
+    Ok(cancelled)
+}
+
+// The countdown's socket, removed when it's over
+struct Socket {
+    listener: UnixListener,
+    path: PathBuf,
+}
+
+impl Socket {
+    fn bind(dir: &Path) -> Result<Socket> {
+        let path = dir.join(format!("cancel-{}.sock", process::id()));
+        fs::create_dir_all(dir)?;
+        let _ = fs::remove_file(&path);
+        let listener = UnixListener::bind(&path)?;
+        listener.set_nonblocking(true)?;
+        Ok(Socket { listener, path })
+    }
+}
+
+impl Drop for Socket {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+// Stdin without line buffering or echo, so any key counts and doesn't show,
+// until dropped
+struct RawTerminal {
+    saved: Option<libc::termios>,
+}
+
+impl RawTerminal {
+    fn new() -> RawTerminal {
+        let mut saved: libc::termios = unsafe { std::mem::zeroed() };
+        if unsafe { libc::tcgetattr(libc::STDIN_FILENO, &mut saved) } != 0 {
+            return RawTerminal { saved: None };
+        }
+        let mut raw = saved;
+        raw.c_lflag &= !(libc::ICANON | libc::ECHO);
+        unsafe { libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &raw) };
+        RawTerminal { saved: Some(saved) }
+    }
+}
+
+impl Drop for RawTerminal {
+    fn drop(&mut self) {
+        if let Some(saved) = &self.saved {
+            unsafe { libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, saved) };
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    #[test]
+    fn cancels() {
+        let dir = crate::TempDir::new("grace");
+        let countdown = {
+            let dir = dir.to_path_buf();
+            thread::spawn(move || countdown_in(&dir, "Suspending desktop", 30).unwrap())
+        };
+        let started = Instant::now();
+        let mut cancelled = 0;
+        while cancelled == 0 && started.elapsed() < Duration::from_secs(10) {
+            thread::sleep(Duration::from_millis(50));
+            cancelled = cancel_all_in(&dir).unwrap();
+        }
+        assert_eq!(cancelled, 1);
+        assert!(!countdown.join().unwrap());
+        assert!(started.elapsed() < Duration::from_secs(10));
+    }
+}
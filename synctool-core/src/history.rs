@@ -0,0 +1,299 @@
+// A record of past runs in the state dir, one line per run like
+//
+//     time=1686000000 peer=desktop ok=true found=asleep wake=31.2 sync=12.04 power=0.51 sent=1048576 received=2048
+//
+// where found is how a peer that can be woken was before the run (awake,
+// asleep or off), if it got that far, and the fields after it are the seconds
+// spent in each phase and, for backends that report them, the bytes sent and
+// received.
+
+use crate::{
+    output::{human_bytes, human_duration},
+    state, state_dir,
+};
+use eyre::Result;
+use std::{
+    cmp::Reverse,
+    collections::BTreeMap,
+    fs::{self, OpenOptions},
+    io::{ErrorKind, Write},
+    path::PathBuf,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+pub struct Run {
+    // Seconds since the Unix epoch
+    pub time: u64,
+    pub peer: String,
+    pub ok: bool,
+    // "awake", "asleep" or "off"
+    pub found: Option<String>,
+    pub values: BTreeMap<String, f64>,
+}
+
+impl Run {
+    // When the run started, going by how long its phases took
+    pub fn started(&self) -> u64 {
+        let seconds: f64 = self
+            .values
+            .iter()
+            .filter(|(name, _)| !matches!(name.as_str(), "sent" | "received"))
+            .map(|(_, seconds)| seconds)
+            .sum();
+        self.time.saturating_sub(seconds.ceil() as u64)
+    }
+
+    // Bytes per second while syncing, if the backend reported bytes
+    pub fn throughput(&self) -> Option<f64> {
+        let bytes = self.values.get("sent")? + self.values.get("received")?;
+        let seconds = *self.values.get("sync")?;
+        if seconds > 0. {
+            Some(bytes / seconds)
+        } else {
+            None
+        }
+    }
+}
+
+pub fn path() -> PathBuf {
+    state_dir().join("history")
+}
+
+pub fn record(peer: &str, ok: bool, found: Option<&str>, values: &[(String, f64)]) -> Result<()> {
+    let time = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+    let mut line = format!("time={} peer={} ok={}", time, peer, ok);
+    if let Some(found) = found {
+        line.push_str(&format!(" found={}", found));
+    }
+    for (name, value) in values {
+        line.push_str(&format!(" {}={}", name, (value * 100.).round() / 100.));
+    }
+    line.push('\n');
+
+    let path = path();
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+    file.write_all(line.as_bytes())?;
+    state::finished(peer, ok)
+}
+
+// The values to record for a run: phase timings, then bytes transferred.
+pub fn values(timings: &[(String, f64)], transferred: Option<(u64, u64)>) -> Vec<(String, f64)> {
+    let mut values = timings.to_vec();
+    if let Some((sent, received)) = transferred {
+        values.push(("sent".to_string(), sent as f64));
+        values.push(("received".to_string(), received as f64));
+    }
+    values
+}
+
+// Every recorded run, oldest first. Lines that don't parse are skipped.
+pub fn load() -> Result<Vec<Run>> {
+    let text = match fs::read_to_string(path()) {
+        Ok(text) => text,
+        Err(err) if err.kind() == ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(err) => return Err(err.into()),
+    };
+
+    let mut runs = Vec::new();
+    for line in text.lines() {
+        let mut fields = line.split(' ').filter_map(|field| field.split_once('='));
+        let run = match (fields.next(), fields.next(), fields.next()) {
+            (Some(("time", time)), Some(("peer", peer)), Some(("ok", ok))) => Run {
+                time: match time.parse() {
+                    Ok(time) => time,
+                    Err(_) => continue,
+                },
+                peer: peer.to_string(),
+                ok: ok == "true",
+                found: line
+                    .split(' ')
+                    .find_map(|field| field.strip_prefix("found="))
+                    .map(str::to_string),
+                values: fields
+                    .filter_map(|(name, value)| Some((name.to_string(), value.parse().ok()?)))
+                    .collect(),
+            },
+            _ => continue,
+        };
+        runs.push(run);
+    }
+    Ok(runs)
+}
+
+// When the last successful run with a peer was, in seconds since the epoch
+pub fn last_success(runs: &[Run], peer: &str) -> Option<u64> {
+    last_successful_run(runs, peer).map(|run| run.time)
+}
+
+pub fn last_successful_run<'a>(runs: &'a [Run], peer: &str) -> Option<&'a Run> {
+    runs.iter().rev().find(|run| run.ok && run.peer == peer)
+}
+
+// How long it's been since the last successful run with a peer, if that's
+// more than stale_after_hours. Peers that have never synced aren't stale,
+// they're new.
+pub fn stale(runs: &[Run], peer: &str, stale_after_hours: u64) -> Option<Duration> {
+    let last = last_success(runs, peer)?;
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).ok()?.as_secs();
+    let age = Duration::from_secs(now.saturating_sub(last));
+    if stale_after_hours > 0 && age > Duration::from_secs(stale_after_hours * 3600) {
+        Some(age)
+    } else {
+        None
+    }
+}
+
+// Average throughput of the last few successful runs with a peer that
+// reported bytes, to compare the latest run against.
+pub fn usual_throughput(runs: &[Run], peer: &str) -> Option<f64> {
+    let recent = runs
+        .iter()
+        .rev()
+        .filter(|run| run.ok && run.peer == peer)
+        .filter_map(Run::throughput)
+        .take(10)
+        .collect::<Vec<_>>();
+    if recent.is_empty() {
+        None
+    } else {
+        Some(recent.iter().sum::<f64>() / recent.len() as f64)
+    }
+}
+
+// A summary of the runs since `since` (seconds since the epoch), as lines, for
+// `synctool report` and the daemon's digest: how many ran and failed, with
+// each peer and how often it had to be woken, the bytes moved and the longest
+// runs.
+pub fn digest(runs: &[Run], since: u64, now: u64) -> Vec<String> {
+    let runs = runs
+        .iter()
+        .filter(|run| run.time >= since)
+        .collect::<Vec<_>>();
+    if runs.is_empty() {
+        return vec!["No runs".to_string()];
+    }
+    let failed = |runs: &[&Run]| runs.iter().filter(|run| !run.ok).count();
+    let mut lines = vec![format!("{} run(s), {} failed", runs.len(), failed(&runs))];
+
+    let mut peers: BTreeMap<&str, Vec<&Run>> = BTreeMap::new();
+    for run in &runs {
+        peers.entry(&run.peer).or_default().push(run);
+    }
+    for (peer, runs) in &peers {
+        let found = ["awake", "asleep", "off"]
+            .iter()
+            .map(|state| {
+                let count = runs
+                    .iter()
+                    .filter(|run| run.found.as_deref() == Some(state))
+                    .count();
+                (state, count)
+            })
+            .filter(|(_, count)| *count > 0)
+            .map(|(state, count)| format!("{} {}", state, count))
+            .collect::<Vec<_>>();
+        lines.push(format!(
+            "  {}: {} run(s), {} failed{}",
+            peer,
+            runs.len(),
+            failed(runs),
+            if found.is_empty() {
+                String::new()
+            } else {
+                format!("; found {}", found.join(", "))
+            }
+        ));
+    }
+
+    let total = |name: &str| {
+        runs.iter()
+            .filter_map(|run| run.values.get(name))
+            .sum::<f64>()
+    };
+    let (sent, received) = (total("sent"), total("received"));
+    if sent + received > 0. {
+        lines.push(format!(
+            "Moved {} (sent {}, received {})",
+            human_bytes(sent + received),
+            human_bytes(sent),
+            human_bytes(received)
+        ));
+    }
+
+    let mut longest = runs.clone();
+    longest.sort_by_key(|run| Reverse(run.time - run.started()));
+    lines.push("Longest runs:".to_string());
+    for run in longest.iter().take(3) {
+        lines.push(format!(
+            "  {}s with {}, {} ago{}",
+            run.time - run.started(),
+            run.peer,
+            human_duration(Duration::from_secs(now.saturating_sub(run.time))),
+            if run.ok { "" } else { " (failed)" }
+        ));
+    }
+    lines
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn run(time: u64, peer: &str, ok: bool, values: &[(&str, f64)]) -> Run {
+        Run {
+            time,
+            peer: peer.to_string(),
+            ok,
+            found: None,
+            values: values
+                .iter()
+                .map(|(name, value)| (name.to_string(), *value))
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn digests_runs() {
+        let day = 24 * 60 * 60;
+        let now = 100 * day;
+        let runs = [
+            run(now - 30 * day, "desktop", true, &[("sync", 500.)]),
+            Run {
+                found: Some("asleep".to_string()),
+                ..run(
+                    now - 3 * day,
+                    "desktop",
+                    true,
+                    &[
+                        ("wake", 30.),
+                        ("sync", 12.),
+                        ("sent", 2e6),
+                        ("received", 1e6),
+                    ],
+                )
+            },
+            run(now - 2 * day, "rpi", false, &[("sync", 90.)]),
+            Run {
+                found: Some("awake".to_string()),
+                ..run(now - day, "desktop", true, &[("sync", 4.)])
+            },
+        ];
+        assert_eq!(
+            digest(&runs, now - 7 * day, now),
+            [
+                "3 run(s), 1 failed",
+                "  desktop: 2 run(s), 0 failed; found awake 1, asleep 1",
+                "  rpi: 1 run(s), 1 failed",
+                "Moved 3.0 MB (sent 2.0 MB, received 1.0 MB)",
+                "Longest runs:",
+                "  90s with rpi, 2 days ago (failed)",
+                "  42s with desktop, 3 days ago",
+                "  4s with desktop, 24 hours ago",
+            ]
+        );
+        assert_eq!(digest(&runs, now, now), ["No runs"]);
+    }
+}
@@ -0,0 +1,169 @@
+// Power through Home Assistant, for hosts with power_method = "homeassistant"
+// whose power is already an entity there, like a smart plug's switch or a
+// script that shuts the machine down. Its REST API is called with curl; the
+// token comes from [homeassistant] token, or the keyring as homeassistant, and
+// goes to curl on stdin so it doesn't show up in ps or -v output.
+//
+// The host's homeassistant entity is turned on to wake it, unless there's an
+// entity for waking, which is activated instead: scripts and scenes turned on,
+// buttons pressed. Shutting down and suspending need an entity of their own,
+// and otherwise go over ssh; turning the plug off would cut the power under a
+// running machine.
+
+use crate::{
+    config::{Config, Host},
+    keyring,
+    runner::Runner,
+};
+use eyre::{bail, ensure, eyre, Result};
+use std::{
+    io::{Read, Write},
+    process::{Command, Stdio},
+};
+
+pub fn wake(runner: &dyn Runner, config: &Config, host: &Host) -> Result<()> {
+    match (&host.homeassistant_wake, &host.homeassistant) {
+        (Some(entity), _) => activate(runner, config, entity),
+        (None, Some(entity)) => call(runner, config, entity, "turn_on"),
+        (None, None) => bail!(
+            "Set homeassistant or homeassistant_wake for {} in the config",
+            host.name
+        ),
+    }
+}
+
+// Whether the host can be shut down through Home Assistant
+pub fn can_shut_down(host: &Host) -> bool {
+    host.homeassistant_shutdown.is_some()
+}
+
+pub fn shut_down(runner: &dyn Runner, config: &Config, host: &Host) -> Result<()> {
+    match &host.homeassistant_shutdown {
+        Some(entity) => activate(runner, config, entity),
+        None => bail!("Set homeassistant_shutdown for {} in the config", host.name),
+    }
+}
+
+// Whether the host can be suspended through Home Assistant
+pub fn can_suspend(host: &Host) -> bool {
+    host.homeassistant_suspend.is_some()
+}
+
+pub fn suspend(runner: &dyn Runner, config: &Config, host: &Host) -> Result<()> {
+    match &host.homeassistant_suspend {
+        Some(entity) => activate(runner, config, entity),
+        None => bail!("Set homeassistant_suspend for {} in the config", host.name),
+    }
+}
+
+// Checks that Home Assistant answers and takes the token
+pub fn check(runner: &dyn Runner, config: &Config) -> Result<()> {
+    let response = request(runner, config, "/api/", None)?;
+    ensure!(
+        response.contains("API running"),
+        "Home Assistant answered with {}",
+        response.trim()
+    );
+    Ok(())
+}
+
+// Runs an action entity the way its domain is run
+fn activate(runner: &dyn Runner, config: &Config, entity: &str) -> Result<()> {
+    let service = match domain(entity) {
+        "button" | "input_button" => "press",
+        _ => "turn_on",
+    };
+    call(runner, config, entity, service)
+}
+
+fn call(runner: &dyn Runner, config: &Config, entity: &str, service: &str) -> Result<()> {
+    phase!(
+        "Asking Home Assistant to {} {}",
+        service.replace('_', " "),
+        entity
+    );
+    let path = format!("/api/services/{}/{}", domain(entity), service);
+    let body = format!("{{\"entity_id\": \"{}\"}}", entity);
+    request(runner, config, &path, Some(&body))?;
+    Ok(())
+}
+
+// "switch" for "switch.desktop"
+fn domain(entity: &str) -> &str {
+    entity.split('.').next().unwrap_or(entity)
+}
+
+// GETs a path from the API, or POSTs body to it, returning the response
+fn request(runner: &dyn Runner, config: &Config, path: &str, body: Option<&str>) -> Result<String> {
+    let url = config
+        .homeassistant_url
+        .as_deref()
+        .ok_or_else(|| eyre!("Set [homeassistant] url in the config"))?;
+    let mut command = Command::new("curl");
+    command.args(["-fsS", "--max-time", "20", "-K", "-"]);
+    if let Some(body) = body {
+        command.args(["-H", "Content-Type: application/json", "-d", body]);
+    }
+    command.arg(format!("{}{}", url, path));
+
+    let mut child = runner.spawn(command.stdin(Stdio::piped()).stdout(Stdio::piped()))?;
+    if let Some(mut stdin) = child.take_stdin() {
+        writeln!(
+            stdin,
+            "header = \"Authorization: Bearer {}\"",
+            token(runner, config)?
+        )?;
+    }
+    let mut response = String::new();
+    if let Some(mut stdout) = child.take_stdout() {
+        stdout.read_to_string(&mut response)?;
+    }
+    ensure!(
+        child.wait()?.success(),
+        "Home Assistant didn't answer at {}{}",
+        url,
+        path
+    );
+    Ok(response)
+}
+
+fn token(runner: &dyn Runner, config: &Config) -> Result<String> {
+    if let Some(token) = &config.homeassistant_token {
+        return Ok(token.clone());
+    }
+    if runner.simulated() {
+        return Ok(String::new());
+    }
+    keyring::lookup("homeassistant")?.ok_or_else(|| {
+        eyre!(
+            "Set [homeassistant] token in the config, or store it in the keyring as homeassistant"
+        )
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::runner::MockRunner;
+
+    #[test]
+    fn calls_services() {
+        let config = Config::parse(
+            "[homeassistant]\nurl = \"http://ha.lan:8123/\"\n[hosts.htpc]\naddress = \"10.13.13.10\"\npower_method = \"homeassistant\"\nhomeassistant = \"switch.htpc\"\nhomeassistant_wake = \"button.htpc_wol\"\n",
+        )
+        .unwrap();
+        let htpc = config.host("htpc").unwrap();
+        let runner = MockRunner::new();
+
+        wake(&runner, &config, htpc).unwrap();
+        assert!(!can_shut_down(htpc));
+        assert!(shut_down(&runner, &config, htpc).is_err());
+        assert!(!can_suspend(htpc));
+        assert_eq!(
+            runner.commands(),
+            [
+                "curl -fsS --max-time 20 -K - -H Content-Type: application/json -d {\"entity_id\": \"button.htpc_wol\"} http://ha.lan:8123/api/services/button/press"
+            ]
+        );
+    }
+}
@@ -0,0 +1,98 @@
+// Commands a host's after_sync setting runs there after each successful sync,
+// like rebuilding notes or restarting something that reads synced config.
+// They all go over one ssh connection, one after another from the host's
+// root, and what they print ends up in the log. One failing doesn't stop the
+// rest or fail the sync.
+
+use crate::{
+    config::{Config, Host},
+    runner::Runner,
+    shell_quote,
+    ssh::ssh,
+};
+use eyre::Result;
+use std::{
+    io::{Read, Write},
+    process::Stdio,
+};
+
+// Printed after each command's output, with its exit code
+const MARKER: &str = "synctool-hook-exit";
+
+// Runs the commands, returning how many of them failed
+pub fn run(runner: &dyn Runner, config: &Config, host: &Host) -> Result<usize> {
+    let mut script = format!("cd {} || exit 1\n", shell_quote(host.root(config)));
+    for command in &host.after_sync {
+        script.push_str(&format!(
+            "sh -c {} </dev/null 2>&1; echo \"{} $?\"\n",
+            shell_quote(command),
+            MARKER
+        ));
+    }
+
+    let mut process = runner.spawn(
+        ssh(host)
+            .args(["sh", "-s"])
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped()),
+    )?;
+    if let Some(mut stdin) = process.take_stdin() {
+        stdin.write_all(script.as_bytes())?;
+    }
+    let mut output = String::new();
+    if let Some(mut stdout) = process.take_stdout() {
+        stdout.read_to_string(&mut output)?;
+    }
+    process.wait()?;
+
+    let mut failed = 0;
+    let mut lines = output.lines();
+    for command in &host.after_sync {
+        log!("Ran `{}` on {}", command, host.name);
+        let code = loop {
+            match lines.next() {
+                Some(line) => match line.strip_prefix(MARKER) {
+                    Some(code) => break code.trim().to_string(),
+                    None => log!("  {}", line),
+                },
+                // The connection dropped or the root is missing
+                None => break "?".to_string(),
+            }
+        };
+        if code != "0" {
+            warn!("`{}` failed on {} (exit {})", command, host.name, code);
+            failed += 1;
+        }
+    }
+    Ok(failed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::runner::{MockRunner, Reply};
+
+    #[test]
+    fn runs_commands_after_sync() {
+        let mut config = Config::default();
+        config.hosts[0].after_sync = vec![
+            "make -C notes html".to_string(),
+            "systemctl --user restart feeds".to_string(),
+            "true".to_string(),
+        ];
+        let runner = MockRunner::new();
+        runner.script_replies(
+            "ssh",
+            vec![Reply {
+                code: 0,
+                stdout: format!(
+                    "make: Entering directory 'notes'\n{0} 0\nFailed to restart feeds.service\n{0} 5\n",
+                    MARKER
+                ),
+            }],
+        );
+        let laptop = config.host("laptop").unwrap();
+        // The last one never got to report back
+        assert_eq!(run(&runner, &config, laptop).unwrap(), 2);
+    }
+}
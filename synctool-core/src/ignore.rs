@@ -0,0 +1,240 @@
+// Matching paths against unison-style ignore patterns, and against the
+// gitignore-style rules in .syncignore and .gitignore files.
+
+use crate::config::Config;
+use std::{collections::BTreeMap, fs, path::Path};
+
+// Default for ignores in the [sync] section of the config
+pub const IGNORES: &[&str] = &[
+    "Name *.class",
+    "Name *.hi",
+    "Name __pycache__",
+    "Name target",
+    "Name License.sublime_license",
+    // "Path school/linux",
+    // "Path school/linux.7z",
+    // Reach stuff
+    "Name .stack-work",
+    "Name .hie",
+    "Name dist-newstyle",
+    "Name node_modules",
+    "Name cdk.out",
+    // "Regex reach/reach-lang/docs/build",
+    // "Regex reach/reach-lang/examples/.*/build",
+    // "Regex reach/reach-lang/hs/t/.*/build",
+    "Regex thegame/android/SDL",
+    "Regex thegame/android/TheGame/app/build",
+];
+
+// Checks a path relative to the sync root against the ignores, using the same
+// approximations as rsync_exclude.
+pub fn ignored(config: &Config, rel_path: &str) -> bool {
+    config
+        .ignores
+        .iter()
+        .any(|ignore| ignore_matches(ignore, rel_path))
+}
+
+pub fn ignore_matches(ignore: &str, rel_path: &str) -> bool {
+    match ignore.split_once(' ') {
+        Some(("Name", pattern)) => rel_path
+            .split('/')
+            .any(|component| glob_match(pattern, component)),
+        Some(("Path", pattern)) => rel_path == pattern,
+        Some(("Regex", pattern)) => glob_match(&pattern.replace(".*", "*"), rel_path),
+        _ => false,
+    }
+}
+
+// Matches text against a pattern where * stands for any run of characters.
+pub fn glob_match(pattern: &str, text: &str) -> bool {
+    match pattern.split_once('*') {
+        None => pattern == text,
+        Some((prefix, rest)) => {
+            let text = match text.strip_prefix(prefix) {
+                Some(text) => text,
+                None => return false,
+            };
+            (0..=text.len())
+                .filter(|&i| text.is_char_boundary(i))
+                .any(|i| glob_match(rest, &text[i..]))
+        }
+    }
+}
+
+// Files whose rules apply to the directory they're in and everything under it
+pub const IGNORE_FILES: &[&str] = &[".gitignore", ".syncignore"];
+
+struct Rule {
+    pattern: String,
+    negate: bool,
+    dir_only: bool,
+    // Matched against the path from the file's directory rather than any name
+    anchored: bool,
+}
+
+// The rules from every .gitignore and .syncignore loaded so far, by the
+// directory they're in relative to the root. Covers the common parts of the
+// gitignore format: comments, ! to re-include, a trailing / for directories
+// only and a / anywhere else to anchor. * and ** both match across slashes.
+#[derive(Default)]
+pub struct IgnoreFiles {
+    rules: BTreeMap<String, Vec<Rule>>,
+}
+
+impl IgnoreFiles {
+    // (Re)reads the ignore files in dir, a directory relative to root.
+    pub fn load(&mut self, root: &Path, dir: &str) {
+        let mut text = String::new();
+        for name in IGNORE_FILES {
+            if let Ok(contents) = fs::read_to_string(root.join(dir).join(name)) {
+                text.push_str(&contents);
+                text.push('\n');
+            }
+        }
+        self.set(dir, &text);
+    }
+
+    fn set(&mut self, dir: &str, text: &str) {
+        let rules = text.lines().filter_map(parse_rule).collect::<Vec<_>>();
+        if rules.is_empty() {
+            self.rules.remove(dir);
+        } else {
+            self.rules.insert(dir.to_string(), rules);
+        }
+    }
+
+    // Whether rel_path itself is ignored. The last matching rule wins, with
+    // the rules of deeper directories coming later.
+    pub fn ignored(&self, rel_path: &str, is_dir: bool) -> bool {
+        let mut ignored = false;
+        for (dir, rules) in &self.rules {
+            let rest = match dir.as_str() {
+                "" => rel_path,
+                dir => match rel_path
+                    .strip_prefix(dir)
+                    .and_then(|rest| rest.strip_prefix('/'))
+                {
+                    Some(rest) => rest,
+                    None => continue,
+                },
+            };
+            let name = rest.rsplit('/').next().unwrap_or(rest);
+            for rule in rules {
+                if rule.dir_only && !is_dir {
+                    continue;
+                }
+                let matched = if rule.anchored {
+                    glob_match(&rule.pattern, rest)
+                } else {
+                    glob_match(&rule.pattern, name)
+                };
+                if matched {
+                    ignored = !rule.negate;
+                }
+            }
+        }
+        ignored
+    }
+}
+
+fn parse_rule(line: &str) -> Option<Rule> {
+    let line = line.trim_end();
+    if line.is_empty() || line.starts_with('#') {
+        return None;
+    }
+    let (negate, line) = match line.strip_prefix('!') {
+        Some(line) => (true, line),
+        None => (false, line.strip_prefix('\\').unwrap_or(line)),
+    };
+    let (dir_only, line) = match line.strip_suffix('/') {
+        Some(line) => (true, line),
+        None => (false, line),
+    };
+    let line = line.strip_prefix("**/").unwrap_or(line);
+    let anchored = line.contains('/');
+    let pattern = line.trim_start_matches('/').replace("**", "*");
+    if pattern.is_empty() {
+        return None;
+    }
+    Some(Rule {
+        pattern,
+        negate,
+        dir_only,
+        anchored,
+    })
+}
+
+// Whether a change to rel_path is ignored, by the config's ignores or by ignore
+// files, either itself or through a directory it's in.
+pub fn change_ignored(
+    ignores: &[String],
+    files: &IgnoreFiles,
+    rel_path: &str,
+    is_dir: bool,
+) -> bool {
+    let dirs = rel_path
+        .match_indices('/')
+        .map(|(i, _)| (&rel_path[..i], true));
+    dirs.chain([(rel_path, is_dir)]).any(|(path, is_dir)| {
+        files.ignored(path, is_dir) || ignores.iter().any(|ignore| ignore_matches(ignore, path))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn glob() {
+        assert!(glob_match("*.hi", "Main.hi"));
+        assert!(glob_match("target", "target"));
+        assert!(!glob_match("*.hi", "Main.hs"));
+        assert!(glob_match("a*b*c", "axxbyyc"));
+    }
+
+    #[test]
+    fn ignores() {
+        assert!(ignore_matches("Name target", "proj/target/debug"));
+        assert!(!ignore_matches("Name target", "proj/targets"));
+        assert!(ignore_matches("Path school/linux", "school/linux"));
+        assert!(ignore_matches("Regex a/.*/build", "a/x/build"));
+    }
+
+    #[test]
+    fn ignore_files() {
+        let mut files = IgnoreFiles::default();
+        files.set("", "# build output\n*.o\n/out\nlogs/\n");
+        files.set("web", "node_modules\n!keep.o\ndist/**/*.map\n");
+        assert!(files.ignored("a/b.o", false));
+        assert!(!files.ignored("web/keep.o", false));
+        assert!(files.ignored("out", true));
+        assert!(!files.ignored("a/out", true));
+        assert!(files.ignored("a/logs", true));
+        assert!(!files.ignored("a/logs", false));
+        assert!(files.ignored("web/node_modules", true));
+        assert!(!files.ignored("node_modules", true));
+        assert!(files.ignored("web/dist/js/app.js.map", false));
+
+        let ignores = vec!["Name target".to_string(), "Path school/linux".to_string()];
+        assert!(change_ignored(
+            &ignores,
+            &files,
+            "proj/target/debug/x",
+            false
+        ));
+        assert!(change_ignored(
+            &ignores,
+            &files,
+            "school/linux/README",
+            false
+        ));
+        assert!(change_ignored(
+            &ignores,
+            &files,
+            "web/node_modules/x/index.js",
+            false
+        ));
+        assert!(!change_ignored(&ignores, &files, "web/src/index.js", false));
+    }
+}
@@ -0,0 +1,157 @@
+// VM and container disk images under the root, found by their extension or
+// the magic bytes their formats start with. One forgotten qcow2 would take
+// over every sync, so what happens to them is up to [sync] disk_images: they
+// can be held back, which is the default and lists them, or be copied with
+// rsync both ways around unison, keeping their holes or only sending the
+// blocks that changed.
+
+use crate::{
+    config::{Config, DiskImages, Host},
+    links,
+    output::human_bytes,
+    rsync,
+    runner::Runner,
+};
+use eyre::Result;
+use std::{fs::File, io::Read, path::Path};
+
+// Smaller ones are left alone, like any other file
+const MIN_SIZE: u64 = 64 * 1024 * 1024;
+
+const EXTENSIONS: &[&str] = &[
+    "qcow", "qcow2", "vmdk", "vdi", "vhd", "vhdx", "img", "raw", "ova",
+];
+
+const MAGIC: &[&[u8]] = &[
+    b"QFI\xfb",                  // qcow and qcow2
+    b"KDMV",                     // vmdk
+    b"# Disk DescriptorFile",    // vmdk's text descriptor
+    b"<<< Oracle VM VirtualBox", // vdi
+    b"vhdxfile",                 // vhdx
+    b"conectix",                 // dynamic vhd
+];
+
+// Disk images under the root that aren't ignored, relative to it, with their
+// sizes
+pub fn find(config: &Config) -> Result<Vec<(String, u64)>> {
+    let mut images = Vec::new();
+    links::walk(config, Path::new(&config.root), "", &mut |path, entry| {
+        let metadata = entry.metadata()?;
+        if metadata.is_file() && metadata.len() >= MIN_SIZE && is_image(&path, &entry.path()) {
+            images.push((path, metadata.len()));
+        }
+        Ok(())
+    })?;
+    images.sort();
+    Ok(images)
+}
+
+fn is_image(path: &str, full_path: &Path) -> bool {
+    let extension = Path::new(path)
+        .extension()
+        .map(|extension| extension.to_string_lossy().to_lowercase());
+    if extension.is_some_and(|extension| EXTENSIONS.contains(&extension.as_str())) {
+        return true;
+    }
+    let mut start = [0; 32];
+    let read = File::open(full_path)
+        .and_then(|mut file| file.read(&mut start))
+        .unwrap_or(0);
+    MAGIC.iter().any(|magic| start[..read].starts_with(magic))
+}
+
+// Lists the images that are held back from the sync with the host, returning
+// the unison ignores that do it
+pub fn hold_back(host: &Host, images: &[(String, u64)]) -> Vec<String> {
+    if images.is_empty() {
+        return Vec::new();
+    }
+    let total = images.iter().map(|(_, size)| size).sum::<u64>();
+    warn!(
+        "Held back {} disk image(s), {} in all, from the sync with {}:",
+        images.len(),
+        human_bytes(total as f64),
+        host.name
+    );
+    for (path, size) in images {
+        warn!("  {} ({})", path, human_bytes(*size as f64));
+    }
+    warn!("Set [sync] disk_images to \"sparse\" or \"delta\" to sync them");
+    images
+        .iter()
+        .map(|(path, _)| format!("Path {}", path))
+        .collect()
+}
+
+// Copies the images with rsync as the policy says. Returns Ok(true) if that
+// worked.
+pub fn transfer(
+    runner: &dyn Runner,
+    config: &Config,
+    host: &Host,
+    images: &[String],
+    policy: DiskImages,
+) -> Result<bool> {
+    let flags: &[&str] = match policy {
+        DiskImages::Exclude => return Ok(true),
+        DiskImages::Sparse => &["--sparse"],
+        // Rewriting only the blocks that differ in the copy that's there
+        DiskImages::Delta => &["--inplace", "--no-whole-file"],
+    };
+    log!(
+        "Copying {} disk image(s) with rsync {}",
+        images.len(),
+        flags.join(" ")
+    );
+    rsync::both_ways(runner, config, host, images, flags)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::runner::MockRunner;
+    use std::{fs, io::Write};
+
+    #[test]
+    fn finds_images() {
+        let root = crate::TempDir::new("images");
+        fs::create_dir_all(root.join("vms")).unwrap();
+        File::create(root.join("vms/win.qcow2"))
+            .unwrap()
+            .set_len(MIN_SIZE)
+            .unwrap();
+        let mut disk = File::create(root.join("vms/disk")).unwrap();
+        disk.write_all(b"KDMV").unwrap();
+        disk.set_len(2 * MIN_SIZE).unwrap();
+        File::create(root.join("vms/data.bin"))
+            .unwrap()
+            .set_len(MIN_SIZE)
+            .unwrap();
+        fs::write(root.join("tiny.img"), "").unwrap();
+        let config = Config {
+            root: root.to_string_lossy().into_owned(),
+            ..Config::default()
+        };
+
+        let images = find(&config).unwrap();
+        assert_eq!(
+            images,
+            [
+                ("vms/disk".to_string(), 2 * MIN_SIZE),
+                ("vms/win.qcow2".to_string(), MIN_SIZE)
+            ]
+        );
+        let desktop = config.host("desktop").unwrap();
+        assert_eq!(
+            hold_back(desktop, &images),
+            ["Path vms/disk", "Path vms/win.qcow2"]
+        );
+
+        let runner = MockRunner::new();
+        let paths = ["vms/disk".to_string()];
+        assert!(transfer(&runner, &config, desktop, &paths, DiskImages::Delta).unwrap());
+        let commands = runner.commands();
+        assert_eq!(commands.len(), 2);
+        assert!(commands[1].contains("--inplace --no-whole-file --update --relative"));
+    }
+}
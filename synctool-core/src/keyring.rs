@@ -0,0 +1,111 @@
+// Secrets from the OS keyring: secret-service (via secret-tool) on Linux and
+// the login keychain (via security) on macOS. Secrets are stored under the
+// service "synctool" with an account name, e.g. on Linux:
+//
+//     secret-tool store --label "synctool ssh key" service synctool account ssh-passphrase
+//     secret-tool store --label "desktop sudo" service synctool account sudo@desktop
+
+use crate::{config::Host, runner::Runner, ssh::ssh};
+use eyre::Result;
+use std::{
+    env,
+    io::Write,
+    process::{exit, Command, ExitStatus, Stdio},
+};
+
+// Set in the environment of ssh processes so that when ssh runs us as its
+// askpass program, we know to answer with the passphrase and exit.
+const ASKPASS_VAR: &str = "SYNCTOOL_ASKPASS";
+
+pub fn lookup(account: &str) -> Result<Option<String>> {
+    let mut command = if cfg!(target_os = "macos") {
+        let mut command = Command::new("security");
+        command.args([
+            "find-generic-password",
+            "-s",
+            "synctool",
+            "-a",
+            account,
+            "-w",
+        ]);
+        command
+    } else {
+        let mut command = Command::new("secret-tool");
+        command.args(["lookup", "service", "synctool", "account", account]);
+        command
+    };
+
+    let output = command
+        .stdin(Stdio::null())
+        .stderr(Stdio::null())
+        .output()?;
+    if !output.status.success() || output.stdout.is_empty() {
+        return Ok(None);
+    }
+
+    let secret = String::from_utf8_lossy(&output.stdout);
+    Ok(Some(secret.trim_end_matches('\n').to_string()))
+}
+
+// If we were started by ssh as SSH_ASKPASS, print the passphrase and exit.
+pub fn askpass_main() {
+    if env::var_os(ASKPASS_VAR).is_none() {
+        return;
+    }
+
+    match lookup("ssh-passphrase") {
+        Ok(Some(passphrase)) => {
+            println!("{}", passphrase);
+            exit(0);
+        }
+        _ => exit(1),
+    }
+}
+
+// Makes every ssh we start (directly, or through unison and rsync) ask us for
+// key passphrases instead of prompting on the terminal.
+pub fn enable_askpass() -> Result<()> {
+    env::set_var("SSH_ASKPASS", env::current_exe()?);
+    env::set_var("SSH_ASKPASS_REQUIRE", "force");
+    env::set_var(ASKPASS_VAR, "1");
+    Ok(())
+}
+
+// Runs `sudo` on the remote with the password from the keyring on stdin.
+pub fn remote_sudo(
+    runner: &dyn Runner,
+    host: &Host,
+    account: &str,
+    command: &[&str],
+) -> Result<ExitStatus> {
+    // Nothing is looked up for a dry run, which doesn't send it anywhere
+    let password = match runner.simulated() {
+        true => Some(String::new()),
+        false => lookup(account)?,
+    };
+    let mut ssh = ssh(host);
+
+    let mut child = match &password {
+        Some(_) => runner.spawn(
+            ssh.args(["sudo", "-S", "-p", "''"])
+                .args(command)
+                .stdin(Stdio::piped())
+                .stdout(Stdio::null())
+                .stderr(Stdio::null()),
+        )?,
+        None => {
+            warn!("No {} in the keyring, trying passwordless sudo", account);
+            runner.spawn(
+                ssh.arg("sudo")
+                    .args(command)
+                    .stdout(Stdio::null())
+                    .stderr(Stdio::null()),
+            )?
+        }
+    };
+
+    if let (Some(password), Some(mut stdin)) = (password, child.take_stdin()) {
+        writeln!(stdin, "{}", password)?;
+    }
+    Ok(child.wait()?)
+}
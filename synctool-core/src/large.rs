@@ -0,0 +1,97 @@
+// Files over [sync] large_file that changed here since the last successful
+// sync with a host, which are likely to be an ISO saved in the wrong place
+// rather than something worth an hour on Wi-Fi. On a terminal each one is
+// asked about; otherwise, as from the daemon, they're left out of the sync
+// and listed. Big files that changed on the other end aren't noticed.
+
+use crate::{
+    config::{Config, Host},
+    history, links,
+    output::human_bytes,
+    runner::Runner,
+};
+use eyre::Result;
+use std::{
+    io::{stdin, stdout, Write},
+    os::unix::fs::MetadataExt,
+    path::Path,
+};
+
+// Paths under the root, relative to it, of files at least threshold bytes
+// that were modified since `since` (seconds since the epoch), with their sizes
+pub fn large_files(config: &Config, threshold: u64, since: i64) -> Result<Vec<(String, u64)>> {
+    let mut files = Vec::new();
+    links::walk(config, Path::new(&config.root), "", &mut |path, entry| {
+        let metadata = entry.metadata()?;
+        if metadata.is_file() && metadata.len() >= threshold && metadata.mtime() >= since {
+            files.push((path, metadata.len()));
+        }
+        Ok(())
+    })?;
+    files.sort();
+    Ok(files)
+}
+
+// Ignores for the large files that shouldn't be synced with the host
+pub fn check(runner: &dyn Runner, config: &Config, host: &Host) -> Result<Vec<String>> {
+    if config.large_file == 0 {
+        return Ok(Vec::new());
+    }
+    let runs = history::load().unwrap_or_default();
+    // A minute of slack, like --fast
+    let since =
+        history::last_successful_run(&runs, &host.name).map_or(0, |run| run.started() as i64 - 60);
+
+    let terminal = unsafe { libc::isatty(libc::STDIN_FILENO) } == 1 && !runner.simulated();
+    let mut ignores = Vec::new();
+    for (path, size) in large_files(config, config.large_file, since)? {
+        let sync = terminal && {
+            print!(
+                "Sync {} ({}) with {}? [y/N] ",
+                path,
+                human_bytes(size as f64),
+                host.name
+            );
+            stdout().flush()?;
+            let mut answer = String::new();
+            stdin().read_line(&mut answer)?;
+            answer.trim().eq_ignore_ascii_case("y")
+        };
+        if !sync {
+            warn!(
+                "Not syncing {} ({}), it's over large_file",
+                path,
+                human_bytes(size as f64)
+            );
+            ignores.push(format!("Path {}", path));
+        }
+    }
+    Ok(ignores)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::{self, File};
+
+    #[test]
+    fn finds_large_files() {
+        let root = crate::TempDir::new("large");
+        fs::create_dir_all(root.join("Downloads")).unwrap();
+        File::create(root.join("Downloads/debian.iso"))
+            .unwrap()
+            .set_len(2000)
+            .unwrap();
+        fs::write(root.join("notes.md"), "small").unwrap();
+        let config = Config {
+            root: root.to_string_lossy().into_owned(),
+            ..Config::default()
+        };
+
+        assert_eq!(
+            large_files(&config, 1000, 0).unwrap(),
+            [("Downloads/debian.iso".to_string(), 2000)]
+        );
+        assert!(large_files(&config, 1000, i64::MAX).unwrap().is_empty());
+    }
+}
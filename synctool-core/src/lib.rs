@@ -0,0 +1,192 @@
+// The core of synctool: configuration, the sync backends, waking and power
+// management. The synctool binary is a thin command line interface on top.
+
+use lazy_static::{initialize, lazy_static};
+use std::{
+    env,
+    path::PathBuf,
+    time::{Duration, Instant},
+};
+
+lazy_static! {
+    static ref START: Instant = Instant::now();
+}
+
+// Prints a line prefixed with the seconds since start_clock() was called.
+#[macro_export]
+macro_rules! log {
+    ($($t:tt)*) => {
+        $crate::output::print($crate::output::Level::Info, format!($($t)*))
+    };
+}
+
+// log! for the start of a step of the run.
+#[macro_export]
+macro_rules! phase {
+    ($($t:tt)*) => {
+        $crate::output::print($crate::output::Level::Phase, format!($($t)*))
+    };
+}
+
+#[macro_export]
+macro_rules! warn {
+    ($($t:tt)*) => {
+        $crate::output::print($crate::output::Level::Warn, format!($($t)*))
+    };
+}
+
+#[macro_export]
+macro_rules! error {
+    ($($t:tt)*) => {
+        $crate::output::print($crate::output::Level::Error, format!($($t)*))
+    };
+}
+
+// log! for details that are only shown with -v.
+#[macro_export]
+macro_rules! debug {
+    ($($t:tt)*) => {
+        $crate::output::print($crate::output::Level::Debug, format!($($t)*))
+    };
+}
+
+// log! for how the run went, at the end.
+#[macro_export]
+macro_rules! summary {
+    ($($t:tt)*) => {
+        $crate::output::print($crate::output::Level::Summary, format!($($t)*))
+    };
+}
+
+pub mod agent;
+pub mod archive;
+pub mod archives;
+pub mod audit;
+pub mod backup;
+pub mod blobs;
+pub mod bmc;
+pub mod case;
+pub mod checksum;
+pub mod cleanup;
+pub mod cloud;
+pub mod config;
+pub mod databases;
+pub mod dedup;
+pub mod encrypt;
+pub mod events;
+pub mod grace;
+pub mod history;
+pub mod homeassistant;
+pub mod hooks;
+pub mod ignore;
+pub mod images;
+pub mod keyring;
+pub mod large;
+pub mod links;
+pub mod mesh;
+pub mod moves;
+pub mod notify;
+pub mod output;
+pub mod polkit;
+pub mod power;
+pub mod progress;
+pub mod protocol;
+pub mod recent;
+pub mod rsync;
+pub mod runner;
+pub mod services;
+pub mod sparse;
+pub mod special;
+pub mod ssh;
+pub mod state;
+pub mod sync;
+pub mod syncthing;
+pub mod unicode;
+pub mod unison;
+pub mod ups;
+pub mod versions;
+pub mod wake;
+pub mod watch;
+
+// Starts the clock that log! timestamps are relative to.
+pub fn start_clock() {
+    initialize(&START);
+}
+
+pub fn elapsed() -> Duration {
+    START.elapsed()
+}
+
+// Where synctool keeps state between runs, usually ~/.local/state/synctool
+pub fn state_dir() -> PathBuf {
+    env::var_os("XDG_STATE_HOME")
+        .map(PathBuf::from)
+        .unwrap_or_else(|| {
+            PathBuf::from(env::var_os("HOME").unwrap_or_default()).join(".local/state")
+        })
+        .join("synctool")
+}
+
+// Where the sockets of running synctools go, usually /run/user/UID/synctool
+pub fn runtime_dir() -> PathBuf {
+    match env::var_os("XDG_RUNTIME_DIR") {
+        Some(dir) => PathBuf::from(dir).join("synctool"),
+        None => state_dir(),
+    }
+}
+
+// Quotes a string for use as a single word in a remote shell command.
+pub fn shell_quote(s: &str) -> String {
+    format!("'{}'", s.replace('\'', "'\\''"))
+}
+
+// This machine's hostname, or "" if it can't be read
+pub fn hostname() -> String {
+    let mut buf = [0u8; 256];
+    let len = unsafe {
+        if libc::gethostname(buf.as_mut_ptr() as *mut libc::c_char, buf.len()) != 0 {
+            return String::new();
+        }
+        buf.iter().position(|&b| b == 0).unwrap_or(buf.len())
+    };
+    String::from_utf8_lossy(&buf[..len]).into_owned()
+}
+
+// A directory under the system's temp dir for a test, unique to it and
+// removed with everything in it when dropped.
+#[cfg(test)]
+pub(crate) struct TempDir(PathBuf);
+
+#[cfg(test)]
+impl TempDir {
+    pub(crate) fn new(name: &str) -> TempDir {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        static COUNT: AtomicUsize = AtomicUsize::new(0);
+        let dir = env::temp_dir().join(format!(
+            "synctool-{}-{}-{}",
+            name,
+            std::process::id(),
+            COUNT.fetch_add(1, Ordering::Relaxed)
+        ));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        TempDir(dir)
+    }
+}
+
+#[cfg(test)]
+impl std::ops::Deref for TempDir {
+    type Target = std::path::Path;
+
+    fn deref(&self) -> &std::path::Path {
+        &self.0
+    }
+}
+
+#[cfg(test)]
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.0);
+    }
+}
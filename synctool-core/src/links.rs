@@ -0,0 +1,183 @@
+// Symlinks and hard links under the root. Hosts whose symlinks setting skips
+// some symlinks get those ignored, and with hard_links, files hard linked to
+// each other here are linked again on the host after unison, which only sees
+// separate files with the same contents.
+
+use crate::{
+    config::{Config, Host},
+    ignore::ignored,
+    runner::Runner,
+    shell_quote,
+    ssh::ssh,
+};
+use eyre::{ensure, Result, WrapErr};
+use std::{
+    collections::BTreeMap,
+    fs::{self, DirEntry},
+    io::{ErrorKind, Read, Write},
+    os::unix::fs::MetadataExt,
+    path::{Path, PathBuf},
+    process::Stdio,
+};
+
+// Every symlink under the root that isn't ignored, relative to the root, with
+// where it points. Links aren't followed.
+pub fn symlinks(config: &Config) -> Result<Vec<(String, PathBuf)>> {
+    let mut links = Vec::new();
+    walk(config, Path::new(&config.root), "", &mut |path, entry| {
+        if entry.file_type()?.is_symlink() {
+            links.push((path, fs::read_link(entry.path())?));
+        }
+        Ok(())
+    })?;
+    links.sort();
+    Ok(links)
+}
+
+// Groups of files under the root that are hard links to each other, each
+// sorted by path
+pub fn hard_links(config: &Config) -> Result<Vec<Vec<String>>> {
+    let mut inodes: BTreeMap<(u64, u64), Vec<String>> = BTreeMap::new();
+    walk(config, Path::new(&config.root), "", &mut |path, entry| {
+        let metadata = entry.metadata()?;
+        if metadata.is_file() && metadata.nlink() > 1 {
+            inodes
+                .entry((metadata.dev(), metadata.ino()))
+                .or_default()
+                .push(path);
+        }
+        Ok(())
+    })?;
+    let mut groups = inodes
+        .into_values()
+        .filter(|paths| paths.len() > 1)
+        .map(|mut paths| {
+            paths.sort();
+            paths
+        })
+        .collect::<Vec<_>>();
+    groups.sort();
+    Ok(groups)
+}
+
+// Links the host's copies of each group of hard linked files to the first of
+// them, where the contents match. Files that differ are left alone, since
+// they'll be in the next sync.
+pub fn relink(runner: &dyn Runner, config: &Config, host: &Host) -> Result<()> {
+    let groups = hard_links(config)?;
+    if groups.is_empty() {
+        return Ok(());
+    }
+
+    let mut script = format!("cd {} || exit 1\n", shell_quote(host.root(config)));
+    for group in &groups {
+        let first = shell_quote(&group[0]);
+        for other in &group[1..] {
+            let other = shell_quote(other);
+            script.push_str(&format!(
+                "[ {0} -ef {1} ] || {{ cmp -s {0} {1} && ln -f {0} {1} && echo linked; }}\n",
+                first, other
+            ));
+        }
+    }
+
+    let mut process = runner.spawn(
+        ssh(host)
+            .args(["sh", "-s"])
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped()),
+    )?;
+    if let Some(mut stdin) = process.take_stdin() {
+        stdin.write_all(script.as_bytes())?;
+    }
+    let mut output = String::new();
+    if let Some(mut stdout) = process.take_stdout() {
+        stdout.read_to_string(&mut output)?;
+    }
+    ensure!(
+        process.wait()?.success(),
+        "Couldn't recreate hard links on {}",
+        host.name
+    );
+    let linked = output.lines().filter(|line| *line == "linked").count();
+    if linked > 0 {
+        log!("Hard linked {} file(s) on {}", linked, host.name);
+    }
+    Ok(())
+}
+
+// Calls f with the path relative to the root of everything under dir that
+// isn't a directory or ignored
+pub(crate) fn walk(
+    config: &Config,
+    dir: &Path,
+    rel: &str,
+    f: &mut dyn FnMut(String, &DirEntry) -> Result<()>,
+) -> Result<()> {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(err) if err.kind() == ErrorKind::NotFound => return Ok(()),
+        Err(err) => return Err(err).wrap_err_with(|| format!("Couldn't list {}", dir.display())),
+    };
+    for entry in entries {
+        let entry = entry?;
+        let name = entry.file_name().to_string_lossy().into_owned();
+        let path = if rel.is_empty() {
+            name
+        } else {
+            format!("{}/{}", rel, name)
+        };
+        if ignored(config, &path) {
+            continue;
+        }
+
+        if entry.file_type()?.is_dir() {
+            walk(config, &entry.path(), &path, f)?;
+        } else {
+            f(path, &entry)?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::symlink;
+
+    #[test]
+    fn finds_symlinks() {
+        let root = crate::TempDir::new("links");
+        fs::create_dir_all(root.join("a/target")).unwrap();
+        symlink("/etc/hosts", root.join("a/hosts")).unwrap();
+        symlink("../b", root.join("a/b")).unwrap();
+        symlink("/etc", root.join("a/target/etc")).unwrap();
+        let config = Config {
+            root: root.to_string_lossy().into_owned(),
+            ..Config::default()
+        };
+
+        assert_eq!(
+            symlinks(&config).unwrap(),
+            [
+                ("a/b".to_string(), PathBuf::from("../b")),
+                ("a/hosts".to_string(), PathBuf::from("/etc/hosts"))
+            ]
+        );
+    }
+
+    #[test]
+    fn finds_hard_links() {
+        let root = crate::TempDir::new("hard-links");
+        fs::create_dir_all(root.join("snapshots/1")).unwrap();
+        fs::write(root.join("a"), "a").unwrap();
+        fs::write(root.join("b"), "b").unwrap();
+        fs::hard_link(root.join("a"), root.join("snapshots/1/a")).unwrap();
+        let config = Config {
+            root: root.to_string_lossy().into_owned(),
+            ..Config::default()
+        };
+
+        assert_eq!(hard_links(&config).unwrap(), [["a", "snapshots/1/a"]]);
+    }
+}
@@ -0,0 +1,146 @@
+// `synctool sync-mesh` brings every reachable host with synctool installed (its
+// synctool setting, see versions.rs) up to date in one run. All transfers go
+// through this machine, so the version tables decide the order: first a sync
+// with each host that has changes this machine doesn't, gathering them all
+// here, then a sync with each host that's still missing any. Hosts that
+// already match are left alone.
+
+use crate::{
+    config::{Config, Host},
+    events, history,
+    runner::Runner,
+    state,
+    sync::{sync_with, SyncOptions},
+    versions::{self, Order},
+    wake,
+};
+use eyre::{bail, ensure, eyre, Result};
+
+// this is the name of this machine in the config, which is never synced with.
+pub fn sync_mesh(runner: &dyn Runner, config: &Config, this: &str) -> Result<()> {
+    let hosts = config
+        .hosts
+        .iter()
+        .filter(|host| host.name != this && host.synctool.is_some())
+        // Encrypted copies can't be scanned, so they aren't part of the mesh
+        .filter(|host| host.gpg_recipient.is_none())
+        .collect::<Vec<_>>();
+    ensure!(
+        !hosts.is_empty(),
+        "No other hosts have synctool set in the config, so there's no mesh to sync"
+    );
+
+    phase!("Checking which hosts are up");
+    let mut reachable = Vec::new();
+    let mut unreachable = Vec::new();
+    let up = wake::reachable_all(runner, &hosts);
+    for (host, up) in hosts.into_iter().zip(up) {
+        if up {
+            reachable.push(host);
+        } else {
+            warn!("{} is unreachable, leaving it out", host.name);
+            unreachable.push(host.name.as_str());
+        }
+    }
+
+    let mut pulled = Vec::new();
+    let mut pushed = Vec::new();
+    let mut failed = Vec::new();
+
+    phase!("Gathering changes");
+    for host in &reachable {
+        let plan = match plan(runner, config, host) {
+            Ok(plan) => plan,
+            Err(err) => {
+                warn!("{:#}", err);
+                failed.push(host.name.as_str());
+                continue;
+            }
+        };
+        let ahead = plan
+            .iter()
+            .any(|(_, order)| matches!(order, Order::Older | Order::Conflict | Order::Unknown));
+        if ahead {
+            if sync_one(runner, config, host) {
+                pulled.push(host.name.as_str());
+            } else {
+                failed.push(host.name.as_str());
+            }
+        }
+    }
+
+    phase!("Sending changes");
+    let mut current = Vec::new();
+    for host in &reachable {
+        if failed.contains(&host.name.as_str()) {
+            continue;
+        }
+        let plan = match plan(runner, config, host) {
+            Ok(plan) => plan,
+            Err(err) => {
+                warn!("{:#}", err);
+                failed.push(host.name.as_str());
+                continue;
+            }
+        };
+        if plan.is_empty() {
+            if !pulled.contains(&host.name.as_str()) {
+                current.push(host.name.as_str());
+            }
+            continue;
+        }
+        if sync_one(runner, config, host) {
+            pushed.push(host.name.as_str());
+        } else {
+            failed.push(host.name.as_str());
+        }
+    }
+
+    let list = |names: &[&str]| match names {
+        [] => "none".to_string(),
+        names => names.join(", "),
+    };
+    log!("Pulled from: {}", list(&pulled));
+    log!("Pushed to: {}", list(&pushed));
+    log!("Already up to date: {}", list(&current));
+    if !unreachable.is_empty() {
+        log!("Unreachable: {}", list(&unreachable));
+    }
+    if !failed.is_empty() {
+        bail!("Sync failed with {}", list(&failed));
+    }
+    Ok(())
+}
+
+fn plan(runner: &dyn Runner, config: &Config, host: &Host) -> Result<Vec<(String, Order)>> {
+    let synctool = host.synctool.as_deref().unwrap_or("synctool");
+    versions::plan_with(runner, config, host, synctool)?
+        .ok_or_else(|| eyre!("Couldn't get the file versions from {}", host.name))
+}
+
+// Syncs with one host, keeping its history like a normal run would.
+fn sync_one(runner: &dyn Runner, config: &Config, host: &Host) -> bool {
+    phase!("Syncing with {}", host.name);
+    if !runner.simulated() {
+        if let Err(err) = state::syncing(&host.name) {
+            warn!("Couldn't update the status file: {:#}", err);
+        }
+    }
+    let result = events::phase("sync", || {
+        sync_with(runner, config, host, &SyncOptions::default())
+    });
+    let values = history::values(&events::take_timings(), events::take_transferred());
+    let ok = matches!(result, Ok(true));
+    if !runner.simulated() {
+        if let Err(err) = history::record(&host.name, ok, None, &values) {
+            warn!(
+                "Couldn't record the sync with {} in the history: {:#}",
+                host.name, err
+            );
+        }
+    }
+    if let Err(err) = result {
+        warn!("Sync with {} failed: {:#}", host.name, err);
+    }
+    ok
+}
@@ -0,0 +1,164 @@
+// Rename and move detection for the rsync backend.
+//
+// After every successful push we record the inode of each directory and each
+// large file under the sync root. On the next push, anything whose inode now
+// lives at a different path (and whose old path is gone) was moved locally, so
+// we repeat the move on the remote with `mv` before rsync runs. rsync then sees
+// the content already in place instead of deleting and re-uploading it.
+
+use crate::{
+    config::{Config, Host},
+    events::{self, Value},
+    ignore::ignored,
+    runner::Runner,
+    shell_quote,
+    ssh::ssh,
+    state_dir,
+};
+use eyre::{Result, WrapErr};
+use std::{
+    collections::HashMap,
+    fs,
+    os::unix::fs::MetadataExt,
+    path::{Path, PathBuf},
+};
+
+// Files smaller than this aren't worth tracking; re-sending them is cheap.
+const MIN_TRACKED_FILE_SIZE: u64 = 1024 * 1024;
+
+type Snapshot = HashMap<(u64, u64), String>;
+
+// Kept per host name, which stays the same when the address used changes
+fn snapshot_file(host_name: &str) -> PathBuf {
+    state_dir().join(format!("moves-{}", host_name))
+}
+
+// Walks the root and maps (device, inode) to the path relative to the root.
+fn scan(config: &Config, root: &Path) -> Result<Snapshot> {
+    fn walk(config: &Config, root: &Path, dir: &Path, snapshot: &mut Snapshot) -> Result<()> {
+        for entry in fs::read_dir(dir)? {
+            let entry = entry?;
+            let path = entry.path();
+            let rel = path.strip_prefix(root)?.to_string_lossy().into_owned();
+            if ignored(config, &rel) {
+                continue;
+            }
+
+            let meta = entry.metadata()?;
+            if meta.is_dir() {
+                snapshot.insert((meta.dev(), meta.ino()), rel);
+                walk(config, root, &path, snapshot)?;
+            } else if meta.is_file() && meta.len() >= MIN_TRACKED_FILE_SIZE {
+                snapshot.insert((meta.dev(), meta.ino()), rel);
+            }
+        }
+        Ok(())
+    }
+
+    let mut snapshot = Snapshot::new();
+    walk(config, root, root, &mut snapshot)?;
+    Ok(snapshot)
+}
+
+fn load(host_name: &str) -> Result<Snapshot> {
+    let contents = match fs::read_to_string(snapshot_file(host_name)) {
+        Ok(contents) => contents,
+        Err(_) => return Ok(Snapshot::new()),
+    };
+
+    let mut snapshot = Snapshot::new();
+    for line in contents.lines() {
+        let mut fields = line.splitn(3, '\t');
+        if let (Some(dev), Some(ino), Some(path)) = (fields.next(), fields.next(), fields.next()) {
+            snapshot.insert((dev.parse()?, ino.parse()?), path.to_string());
+        }
+    }
+    Ok(snapshot)
+}
+
+// Records the current state of the root after a successful push.
+pub fn record(config: &Config, host: &Host) -> Result<()> {
+    let root = Path::new(&config.root);
+    let file = snapshot_file(&host.name);
+    if let Some(parent) = file.parent() {
+        fs::create_dir_all(parent)?;
+    }
+
+    let mut contents = String::new();
+    for ((dev, ino), path) in scan(config, root)? {
+        contents.push_str(&format!("{}\t{}\t{}\n", dev, ino, path));
+    }
+    fs::write(&file, contents).wrap_err("Couldn't save move detection snapshot")
+}
+
+// Finds local moves since the last recorded push and replays them on the remote.
+pub fn apply(runner: &dyn Runner, config: &Config, host: &Host) -> Result<()> {
+    let root = Path::new(&config.root);
+    let remote_root = Path::new(host.root(config));
+    let previous = load(&host.name)?;
+    if previous.is_empty() {
+        return Ok(());
+    }
+
+    let mut current = scan(config, root)?.into_iter().collect::<Vec<_>>();
+    current.sort_by(|a, b| a.1.cmp(&b.1));
+
+    // (old, new) pairs, parents before children
+    let mut moves: Vec<(String, String)> = Vec::new();
+    for (key, new_path) in current {
+        let old_path = match previous.get(&key) {
+            Some(old_path) if *old_path != new_path => old_path,
+            _ => continue,
+        };
+
+        // Already covered by moving one of its parents
+        let covered = moves.iter().any(|(old, new)| {
+            new_path
+                .strip_prefix(new.as_str())
+                .and_then(|rest| rest.strip_prefix('/'))
+                .is_some_and(|rest| *old_path == format!("{}/{}", old, rest))
+        });
+        if covered || root.join(old_path).exists() {
+            continue;
+        }
+
+        // The old path may itself be under a directory we're about to move
+        let remote_old_path = moves
+            .iter()
+            .find_map(|(old, new)| {
+                let rest = old_path.strip_prefix(old.as_str())?.strip_prefix('/')?;
+                Some(format!("{}/{}", new, rest))
+            })
+            .unwrap_or_else(|| old_path.clone());
+
+        moves.push((remote_old_path, new_path));
+    }
+
+    if !moves.is_empty() {
+        events::emit(
+            "files",
+            &[
+                ("count", Value::Number(moves.len() as f64)),
+                ("what", Value::Str("moved")),
+            ],
+        );
+    }
+    for (old, new) in &moves {
+        log!("Detected move: {} -> {}", old, new);
+        let old = remote_root.join(old);
+        let new = remote_root.join(new);
+        let parent = new.parent().unwrap_or(remote_root);
+        let script = format!(
+            "[ -e {old} ] && [ ! -e {new} ] && mkdir -p {parent} && mv {old} {new}",
+            old = shell_quote(&old.to_string_lossy()),
+            new = shell_quote(&new.to_string_lossy()),
+            parent = shell_quote(&parent.to_string_lossy()),
+        );
+        let status = runner.status(ssh(host).arg(&script))?;
+        if !status.success() {
+            warn!("Couldn't replay move on remote, it will be transferred instead");
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,110 @@
+// Notifications, sent to every [notify.NAME] backend subscribed to their
+// class: syncs that worked or failed, files changed on both ends, power
+// actions and the daemon's digest of the history. A backend with url gets
+// the message POSTed there with curl, which suits ntfy and similar push
+// services for a phone; one without pops up on the desktop with notify-send.
+// With none configured, failures and digests go to the desktop.
+//
+// Conflicts and power actions come up in the middle of a run, so they wait
+// in a queue and go out along with how the run ended.
+
+use crate::{
+    config::{Config, Notifier, NotifyClass},
+    runner::Runner,
+};
+use lazy_static::lazy_static;
+use std::{process::Command, sync::Mutex};
+
+lazy_static! {
+    static ref PENDING: Mutex<Vec<(NotifyClass, String)>> = Mutex::new(Vec::new());
+}
+
+// Keeps a notification for the next send
+pub fn queue(class: NotifyClass, message: String) {
+    PENDING.lock().unwrap().push((class, message));
+}
+
+// Sends whatever was queued, then this notification
+pub fn send(runner: &dyn Runner, config: &Config, class: NotifyClass, message: &str) {
+    let mut notifications = std::mem::take(&mut *PENDING.lock().unwrap());
+    notifications.push((class, message.to_string()));
+
+    let fallback = [Notifier {
+        name: "desktop".to_string(),
+        url: None,
+        events: vec![NotifyClass::Failure, NotifyClass::Digest],
+    }];
+    let notifiers = if config.notifiers.is_empty() {
+        &fallback[..]
+    } else {
+        &config.notifiers[..]
+    };
+    for (class, message) in &notifications {
+        for notifier in notifiers.iter().filter(|n| n.events.contains(class)) {
+            let mut command = match &notifier.url {
+                Some(url) => {
+                    let mut command = Command::new("curl");
+                    command
+                        .args(["-fsS", "--max-time", "10", "-H", "Title: synctool"])
+                        .args(["-d", message, url]);
+                    command
+                }
+                None => {
+                    let mut command = Command::new("notify-send");
+                    command.args(["synctool", message]);
+                    command
+                }
+            };
+            let sent = runner
+                .output(&mut command)
+                .is_ok_and(|output| output.status.success());
+            if !sent {
+                warn!("Couldn't notify {}: {}", notifier.name, message);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::runner::MockRunner;
+
+    #[test]
+    fn sends_to_subscribers() {
+        let config = Config::parse(
+            "[notify.desktop]\n[notify.phone]\nurl = \"https://ntfy.sh/t\"\nevents = [\"failure\", \"conflict\"]\n",
+        )
+        .unwrap();
+        let runner = MockRunner::new();
+        queue(
+            NotifyClass::Conflict,
+            "notes/todo.md changed on both ends".to_string(),
+        );
+        send(
+            &runner,
+            &config,
+            NotifyClass::Success,
+            "Synced with desktop",
+        );
+        // Other tests' syncs may queue notifications of their own meanwhile
+        let commands = runner.commands();
+        for command in [
+            "notify-send synctool notes/todo.md changed on both ends",
+            "curl -fsS --max-time 10 -H Title: synctool -d notes/todo.md changed on both ends https://ntfy.sh/t",
+            "notify-send synctool Synced with desktop",
+        ] {
+            assert!(commands.contains(&command.to_string()), "{}", command);
+        }
+        assert!(!commands
+            .iter()
+            .any(|c| c.starts_with("curl") && c.contains("Synced")));
+
+        let runner = MockRunner::new();
+        send(&runner, &Config::default(), NotifyClass::Success, "Synced");
+        send(&runner, &Config::default(), NotifyClass::Failure, "Failed");
+        let commands = runner.commands();
+        assert!(commands.contains(&"notify-send synctool Failed".to_string()));
+        assert!(!commands.contains(&"notify-send synctool Synced".to_string()));
+    }
+}
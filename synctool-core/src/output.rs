@@ -0,0 +1,192 @@
+// Console output. Every line starts with the seconds since start_clock() was
+// called and/or the time of day, and is colored by its level when writing to
+// a terminal.
+
+use crate::{
+    elapsed,
+    events::{self, Value},
+};
+use eyre::{bail, Result};
+use std::{
+    env,
+    sync::atomic::{AtomicBool, AtomicU8, Ordering},
+    time::Duration,
+};
+
+static COLOR: AtomicBool = AtomicBool::new(false);
+static QUIET: AtomicBool = AtomicBool::new(false);
+static STDERR: AtomicBool = AtomicBool::new(false);
+static VERBOSITY: AtomicU8 = AtomicU8::new(0);
+static TIMESTAMPS: AtomicU8 = AtomicU8::new(Timestamps::Elapsed as u8);
+
+// What the timestamp at the start of each line shows
+#[derive(Clone, Copy, PartialEq)]
+pub enum Timestamps {
+    // Seconds since the start of the run
+    Elapsed,
+    // RFC 3339 local time, for log files and the journal
+    Wall,
+    Both,
+}
+
+impl Timestamps {
+    pub fn parse(s: &str) -> Option<Timestamps> {
+        match s {
+            "elapsed" => Some(Timestamps::Elapsed),
+            "wall" => Some(Timestamps::Wall),
+            "both" => Some(Timestamps::Both),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum Level {
+    // The start of a step of the run, e.g. waking or syncing
+    Phase,
+    Info,
+    Warn,
+    Error,
+    // Details only shown with -v, like the commands being run
+    Debug,
+    // How the run went, at the end
+    Summary,
+}
+
+// Takes the value of --color: never, always, or auto for only when stdout is
+// a terminal and NO_COLOR isn't set.
+pub fn set_color(choice: &str) -> Result<()> {
+    let color = match choice {
+        "never" => false,
+        "always" => true,
+        "auto" => {
+            let terminal = unsafe { libc::isatty(libc::STDOUT_FILENO) } == 1;
+            terminal && env::var_os("NO_COLOR").is_none()
+        }
+        _ => bail!("--color must be never, auto or always"),
+    };
+    COLOR.store(color, Ordering::Relaxed);
+    Ok(())
+}
+
+// Quiet runs only print errors, so cron doesn't send mail about successful ones.
+pub fn set_quiet(quiet: bool) {
+    QUIET.store(quiet, Ordering::Relaxed);
+}
+
+// Log to stderr instead, when stdout is spoken for (by the agent protocol).
+pub fn set_stderr(stderr: bool) {
+    STDERR.store(stderr, Ordering::Relaxed);
+}
+
+pub fn quiet() -> bool {
+    QUIET.load(Ordering::Relaxed)
+}
+
+// 1 for -v, 2 for -vv
+pub fn set_verbosity(verbosity: u8) {
+    VERBOSITY.store(verbosity, Ordering::Relaxed);
+}
+
+pub fn verbosity() -> u8 {
+    VERBOSITY.load(Ordering::Relaxed)
+}
+
+pub fn set_timestamps(timestamps: Timestamps) {
+    TIMESTAMPS.store(timestamps as u8, Ordering::Relaxed);
+}
+
+pub fn print(level: Level, message: String) {
+    macro_rules! line {
+        ($($t:tt)*) => {
+            if STDERR.load(Ordering::Relaxed) {
+                eprintln!($($t)*)
+            } else {
+                println!($($t)*)
+            }
+        };
+    }
+
+    if level == Level::Error {
+        events::emit("error", &[("message", Value::Str(&message))]);
+    }
+    if quiet() && level != Level::Error || level == Level::Debug && verbosity() == 0 {
+        return;
+    }
+
+    let elapsed = format!("{:.2}", elapsed().as_secs_f32());
+    let timestamp = match TIMESTAMPS.load(Ordering::Relaxed) {
+        t if t == Timestamps::Wall as u8 => wall_clock(),
+        t if t == Timestamps::Both as u8 => format!("{} {}", wall_clock(), elapsed),
+        _ => elapsed,
+    };
+
+    if !COLOR.load(Ordering::Relaxed) {
+        line!("[{}] {}", timestamp, message);
+        return;
+    }
+
+    let style = match level {
+        Level::Phase => "1;36",
+        Level::Info => "0",
+        Level::Warn => "33",
+        Level::Error => "1;31",
+        Level::Debug => "2",
+        Level::Summary => "1;32",
+    };
+    line!(
+        "\x1b[2m[{}]\x1b[0m \x1b[{}m{}\x1b[0m",
+        timestamp,
+        style,
+        message
+    );
+}
+
+// e.g. "12.3 MB"
+pub fn human_bytes(bytes: f64) -> String {
+    let units = ["B", "kB", "MB", "GB", "TB"];
+    let mut value = bytes;
+    let mut unit = 0;
+    while value >= 1000. && unit < units.len() - 1 {
+        value /= 1000.;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{} B", value)
+    } else {
+        format!("{:.1} {}", value, units[unit])
+    }
+}
+
+// e.g. "3 days", "5 hours" or "10 minutes"
+pub fn human_duration(duration: Duration) -> String {
+    let minutes = duration.as_secs() / 60;
+    let (count, unit) = match minutes {
+        m if m >= 48 * 60 => (m / (24 * 60), "day"),
+        m if m >= 120 => (m / 60, "hour"),
+        m => (m, "minute"),
+    };
+    format!("{} {}{}", count, unit, if count == 1 { "" } else { "s" })
+}
+
+// The current local time like 2023-06-01T18:30:05+02:00
+pub fn wall_clock() -> String {
+    let mut tm: libc::tm = unsafe { std::mem::zeroed() };
+    unsafe {
+        let now = libc::time(std::ptr::null_mut());
+        libc::localtime_r(&now, &mut tm);
+    }
+    let offset = tm.tm_gmtoff / 60;
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}{}{:02}:{:02}",
+        tm.tm_year + 1900,
+        tm.tm_mon + 1,
+        tm.tm_mday,
+        tm.tm_hour,
+        tm.tm_min,
+        tm.tm_sec,
+        if offset < 0 { '-' } else { '+' },
+        offset.abs() / 60,
+        offset.abs() % 60
+    )
+}
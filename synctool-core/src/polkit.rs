@@ -0,0 +1,113 @@
+// The polkit rule that lets the ssh user on a host power off and suspend
+// through logind without being on the active seat, which is what
+// power_method = "logind" needs when the machine is sitting at a login screen.
+//
+// The rule goes up to a file mktemp makes on the host, which only the ssh
+// user can write, and sudo installs it from there, so nobody else on the host
+// can slip in a rule of their own.
+
+use crate::{
+    config::Host,
+    runner::Runner,
+    shell_quote,
+    ssh::{self, ssh},
+};
+use eyre::{ensure, Result};
+use std::{
+    io::{Read, Write},
+    process::{Command, Stdio},
+};
+
+pub const RULE_PATH: &str = "/etc/polkit-1/rules.d/50-synctool.rules";
+
+const RULE: &str = r#"// Installed by synctool install-polkit-rule
+polkit.addRule(function(action, subject) {
+    if ((action.id == "org.freedesktop.login1.power-off" ||
+         action.id == "org.freedesktop.login1.power-off-multiple-sessions" ||
+         action.id == "org.freedesktop.login1.suspend" ||
+         action.id == "org.freedesktop.login1.suspend-multiple-sessions") &&
+        subject.user == "USER") {
+        return polkit.Result.YES;
+    }
+});
+"#;
+
+pub fn install(runner: &dyn Runner, host: &Host) -> Result<()> {
+    let user = runner.output(ssh(host).args(["id", "-un"]).stdin(Stdio::null()))?;
+    ensure!(user.status.success(), "Couldn't log in to {}", host.name);
+    let user = String::from_utf8_lossy(&user.stdout).trim().to_string();
+
+    let mut upload = runner.spawn(
+        ssh(host)
+            .arg("file=$(mktemp) && cat >\"$file\" && echo \"$file\"")
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped()),
+    )?;
+    if let Some(mut stdin) = upload.take_stdin() {
+        stdin.write_all(RULE.replace("USER", &user).as_bytes())?;
+    }
+    let mut uploaded = String::new();
+    if let Some(mut stdout) = upload.take_stdout() {
+        stdout.read_to_string(&mut uploaded)?;
+    }
+    let uploaded = uploaded.trim();
+    ensure!(
+        upload.wait()?.success() && !uploaded.is_empty(),
+        "Couldn't upload polkit rule"
+    );
+
+    log!(
+        "Installing {} on {} (sudo will ask for a password)",
+        RULE_PATH,
+        host.name
+    );
+    let uploaded = shell_quote(uploaded);
+    let install = format!(
+        "sudo install -m 644 {} {}; status=$?; rm -f {}; exit $status",
+        uploaded,
+        shell_quote(RULE_PATH),
+        uploaded
+    );
+    // With a terminal for sudo's password prompt
+    let status = runner.status(
+        Command::new("ssh")
+            .arg("-t")
+            .args(ssh::args(host))
+            .args([&host.address, &install]),
+    )?;
+    ensure!(status.success(), "Couldn't install polkit rule");
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        config::Config,
+        runner::{MockRunner, Reply},
+    };
+
+    #[test]
+    fn installs_from_a_private_file() {
+        let config = Config::default();
+        let desktop = config.host("desktop").unwrap();
+        let runner = MockRunner::new();
+        runner.script_replies(
+            "ssh -o ConnectTimeout=8 10.13.13.4 file=",
+            vec![Reply {
+                code: 0,
+                stdout: "/tmp/tmp.Xa81kQ\n".to_string(),
+            }],
+        );
+
+        install(&runner, desktop).unwrap();
+        assert_eq!(
+            runner.commands(),
+            [
+                "ssh -o ConnectTimeout=8 10.13.13.4 id -un",
+                "ssh -o ConnectTimeout=8 10.13.13.4 file=$(mktemp) && cat >\"$file\" && echo \"$file\"",
+                "ssh -t -o ConnectTimeout=8 10.13.13.4 sudo install -m 644 '/tmp/tmp.Xa81kQ' '/etc/polkit-1/rules.d/50-synctool.rules'; status=$?; rm -f '/tmp/tmp.Xa81kQ'; exit $status",
+            ]
+        );
+    }
+}
@@ -0,0 +1,439 @@
+// Power actions after a successful sync, on this machine and the remote.
+
+use crate::{
+    agent::Agent,
+    audit, bmc,
+    config::{Config, Flush, Host, PowerMethod},
+    homeassistant, keyring,
+    protocol::Message,
+    runner::Runner,
+    shell_quote,
+    ssh::{self, ssh},
+    ups,
+};
+use eyre::{ensure, Result};
+use std::process::{Command, ExitStatus, Stdio};
+
+#[derive(Clone, Copy)]
+pub enum PowerAction {
+    Shutdown,
+    Suspend,
+    Nothing,
+}
+use PowerAction::*;
+
+impl PowerAction {
+    // Name for the audit log and the config
+    pub(crate) fn name(&self) -> &'static str {
+        match self {
+            Shutdown => "shutdown",
+            Suspend => "suspend",
+            Nothing => "nothing",
+        }
+    }
+}
+
+// How a host was found before syncing with it
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum PowerState {
+    Awake,
+    Asleep,
+    Off,
+}
+
+impl PowerState {
+    // Name for the logs, events and history
+    pub fn name(&self) -> &'static str {
+        match self {
+            PowerState::Awake => "awake",
+            PowerState::Asleep => "asleep",
+            PowerState::Off => "off",
+        }
+    }
+
+    // What puts a woken host back the way it was found
+    pub fn restore_action(&self) -> PowerAction {
+        match self {
+            PowerState::Awake => Nothing,
+            PowerState::Asleep => Suspend,
+            PowerState::Off => Shutdown,
+        }
+    }
+}
+
+// Whether an unreachable host is asleep or off, as its management controller
+// sees it. Other hosts can't be asked until they're awake.
+pub fn state_before_waking(runner: &dyn Runner, host: &Host) -> Option<PowerState> {
+    if !host.power_method.bmc() {
+        return None;
+    }
+    match bmc::is_on(runner, host) {
+        Ok(true) => Some(PowerState::Asleep),
+        Ok(false) => Some(PowerState::Off),
+        Err(err) => {
+            warn!("{err:#}");
+            None
+        }
+    }
+}
+
+// Whether a host woken at `woken_at` (seconds since the epoch) was off, going
+// by its agent booting since then, or asleep.
+pub fn state_after_waking(runner: &dyn Runner, host: &Host, woken_at: u64) -> Option<PowerState> {
+    host.agent.as_ref()?;
+    let booted = Agent::connect(runner, host)
+        .and_then(|mut agent| agent.request(Message::new("status")))
+        .map_err(|err| warn!("{err:#}"))
+        .ok()?
+        .get("booted")?
+        .parse::<u64>()
+        .ok()
+        .filter(|&booted| booted > 0)?;
+    // Allowing for the clocks being a little apart
+    Some(if booted + 60 >= woken_at {
+        PowerState::Off
+    } else {
+        PowerState::Asleep
+    })
+}
+
+// With nothing to ask, the last suspend or shutdown of the host in the audit
+// log, or else asleep.
+pub fn guess_state(host: &Host) -> PowerState {
+    match audit::last_ok(&host.name, &[Suspend.name(), Shutdown.name()]).as_deref() {
+        Some("shutdown") => PowerState::Off,
+        _ => PowerState::Asleep,
+    }
+}
+
+// A way of shutting down and suspending this machine
+pub trait PowerBackend {
+    // Its name in [power] backend
+    fn name(&self) -> &'static str;
+    // The command that carries out a shutdown or suspend
+    fn command(&self, action: PowerAction) -> Result<Command>;
+}
+
+// The names [power] backend can be set to
+pub const BACKENDS: &[&str] = &["systemctl", "pm-utils", "shutdown"];
+
+// systemd, through logind
+struct Systemctl;
+
+impl PowerBackend for Systemctl {
+    fn name(&self) -> &'static str {
+        "systemctl"
+    }
+
+    fn command(&self, action: PowerAction) -> Result<Command> {
+        let mut command = Command::new("systemctl");
+        command.arg(match action {
+            Shutdown => "poweroff",
+            _ => "suspend",
+        });
+        Ok(command)
+    }
+}
+
+// pm-suspend for systems without systemd
+struct PmUtils;
+
+impl PowerBackend for PmUtils {
+    fn name(&self) -> &'static str {
+        "pm-utils"
+    }
+
+    fn command(&self, action: PowerAction) -> Result<Command> {
+        Ok(match action {
+            Shutdown => Shutdown8.command(action)?,
+            _ => Command::new("pm-suspend"),
+        })
+    }
+}
+
+// shutdown(8) on its own, which can't suspend
+struct Shutdown8;
+
+impl PowerBackend for Shutdown8 {
+    fn name(&self) -> &'static str {
+        "shutdown"
+    }
+
+    fn command(&self, action: PowerAction) -> Result<Command> {
+        ensure!(
+            matches!(action, Shutdown),
+            "shutdown(8) can't suspend; set [power] backend or suspend_command"
+        );
+        let mut command = Command::new("shutdown");
+        command.args(["-h", "now"]);
+        Ok(command)
+    }
+}
+
+// The backend set in the config, or else systemctl if systemd is running,
+// pm-utils if pm-suspend is installed or plain shutdown(8)
+pub fn local_backend(runner: &dyn Runner, config: &Config) -> Box<dyn PowerBackend> {
+    let found = |script: &str| {
+        runner
+            .status(
+                Command::new("sh")
+                    .args(["-c", script])
+                    .stdin(Stdio::null())
+                    .stdout(Stdio::null())
+                    .stderr(Stdio::null()),
+            )
+            .is_ok_and(|status| status.success())
+    };
+    match config.power_backend.as_deref() {
+        Some("systemctl") => Box::new(Systemctl),
+        Some("pm-utils") => Box::new(PmUtils),
+        Some(_) => Box::new(Shutdown8),
+        None if found("test -d /run/systemd/system") => Box::new(Systemctl),
+        None if found("command -v pm-suspend") => Box::new(PmUtils),
+        None => Box::new(Shutdown8),
+    }
+}
+
+pub fn do_local_power_action(
+    runner: &dyn Runner,
+    config: &Config,
+    action: &PowerAction,
+) -> Result<()> {
+    let configured = match action {
+        Shutdown => {
+            phase!("Shutting down this computer");
+            &config.power_shutdown_command
+        }
+
+        Suspend => {
+            phase!("Suspending this computer");
+            &config.power_suspend_command
+        }
+
+        Nothing => return Ok(()),
+    };
+
+    let command = match configured {
+        Some(command) => {
+            let mut shell = Command::new("sh");
+            shell.args(["-c", command]);
+            Ok(shell)
+        }
+        None => local_backend(runner, config).command(*action),
+    };
+    let result = command.and_then(|mut command| Ok(runner.output(&mut command)?.status));
+    audit::record(
+        runner,
+        action.name(),
+        "local",
+        &audit::status_outcome(&result),
+    );
+    result?;
+    Ok(())
+}
+
+pub fn do_remote_power_action(
+    runner: &dyn Runner,
+    config: &Config,
+    host: &Host,
+    action: &PowerAction,
+) -> Result<()> {
+    if let Nothing = action {
+        return Ok(());
+    }
+    if let Shutdown = action {
+        match ups::on_battery(runner, host, true) {
+            Ok(Some(true)) => warn!("{} is running on battery", host.name),
+            Ok(_) => {}
+            Err(err) => debug!(
+                "Couldn't check whether {} is on battery: {err:#}",
+                host.name
+            ),
+        }
+        if let Err(err) = flush(runner, config, host) {
+            warn!(
+                "Couldn't flush {} before shutting it down: {err:#}",
+                host.name
+            );
+        }
+    }
+    let result = remote_power_action(runner, config, host, action);
+    audit::record(
+        runner,
+        action.name(),
+        &host.name,
+        &audit::status_outcome(&result),
+    );
+    result?;
+    Ok(())
+}
+
+// Makes sure what was just synced is on the host's disk, as its flush says
+fn flush(runner: &dyn Runner, config: &Config, host: &Host) -> Result<()> {
+    let root = shell_quote(host.root(config));
+    let script = match host.flush {
+        Flush::None => return Ok(()),
+        Flush::Sync => {
+            let status = runner.status(ssh(host).arg("sync").stdin(Stdio::null()))?;
+            ensure!(status.success(), "sync failed");
+            return Ok(());
+        }
+        Flush::Freeze => format!(
+            "sync && fsfreeze --freeze {root} && fsfreeze --unfreeze {root}",
+            root = root
+        ),
+        Flush::Unmount => format!("sync && umount {}", root),
+    };
+    log!("Flushing {} on {}", host.root(config), host.name);
+    let status = if host.sudo_password_from_keyring && host.power_method == PowerMethod::Sudo {
+        let account = format!("sudo@{}", host.name);
+        keyring::remote_sudo(runner, host, &account, &["sh", "-c", &shell_quote(&script)])?
+    } else {
+        let mut command = escalated(host, &format!("sh -c {}", shell_quote(&script)));
+        runner.status(command.stdin(Stdio::null()))?
+    };
+    ensure!(status.success(), "{} failed", script);
+    Ok(())
+}
+
+// Runs a command on the host as root, the way its power_method says to get
+// there
+pub(crate) fn escalated(host: &Host, command: &str) -> Command {
+    let mut ssh = Command::new("ssh");
+    ssh.args(ssh::args(host));
+    if host.power_method == PowerMethod::Root {
+        ssh.args(["-l", "root"]);
+    }
+    ssh.arg(&host.address);
+    match host.power_method {
+        PowerMethod::Sudo => {
+            ssh.arg("sudo");
+        }
+        PowerMethod::Doas => {
+            ssh.arg("doas");
+        }
+        _ => {}
+    }
+    ssh.arg(command);
+    ssh
+}
+
+fn remote_power_action(
+    runner: &dyn Runner,
+    config: &Config,
+    host: &Host,
+    action: &PowerAction,
+) -> Result<ExitStatus> {
+    Ok(match action {
+        Shutdown | Suspend if host.power_method == PowerMethod::Agent => {
+            phase!("Asking the agent on remote computer to {}", action.name());
+            let mut agent = Agent::connect(runner, host)?;
+            agent.request(Message::new("power").with("action", action.name()))?;
+            ExitStatus::default()
+        }
+
+        Shutdown | Suspend if host.power_method == PowerMethod::Logind => {
+            let verb = match action {
+                Shutdown => "poweroff",
+                _ => "suspend",
+            };
+            phase!("Asking logind on remote computer to {}", verb);
+            let status = runner.status(ssh(host).args(["systemctl", verb]).stdin(Stdio::null()))?;
+            ensure!(
+                status.success(),
+                "logind refused to {} {} (try install-polkit-rule)",
+                verb,
+                host.name
+            );
+            status
+        }
+
+        // Without an entity for it, shutting down goes over ssh below rather
+        // than switching the power off
+        Shutdown
+            if host.power_method == PowerMethod::HomeAssistant
+                && homeassistant::can_shut_down(host) =>
+        {
+            homeassistant::shut_down(runner, config, host)?;
+            ExitStatus::default()
+        }
+
+        // Without an entity for it, suspending goes over ssh below
+        Suspend
+            if host.power_method == PowerMethod::HomeAssistant
+                && homeassistant::can_suspend(host) =>
+        {
+            homeassistant::suspend(runner, config, host)?;
+            ExitStatus::default()
+        }
+
+        // Management controllers can't suspend, so that goes over ssh below
+        Shutdown if host.power_method.bmc() => {
+            bmc::power_off(runner, host)?;
+            ExitStatus::default()
+        }
+
+        Shutdown if host.sudo_password_from_keyring && host.power_method == PowerMethod::Sudo => {
+            phase!("Shutting down remote computer");
+            let account = format!("sudo@{}", host.name);
+            let command = host.shutdown_command.split_whitespace().collect::<Vec<_>>();
+            keyring::remote_sudo(runner, host, &account, &command)?
+        }
+
+        Shutdown => {
+            phase!("Shutting down remote computer");
+            runner
+                .output(&mut escalated(host, &host.shutdown_command))?
+                .status
+        }
+
+        Suspend if host.sudo_password_from_keyring && host.power_method == PowerMethod::Sudo => {
+            phase!("Suspending remote computer");
+            let account = format!("sudo@{}", host.name);
+            let command = host.suspend_command.split_whitespace().collect::<Vec<_>>();
+            keyring::remote_sudo(runner, host, &account, &command)?
+        }
+
+        Suspend => {
+            phase!("Suspending remote computer");
+            runner
+                .output(&mut escalated(host, &host.suspend_command))?
+                .status
+        }
+
+        Nothing => ExitStatus::default(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::runner::MockRunner;
+
+    #[test]
+    fn sudo_from_keyring() {
+        let config = Config::parse(
+            "[hosts.desktop]\naddress = \"10.13.13.4\"\nsudo_password_from_keyring = true\n",
+        )
+        .unwrap();
+        let desktop = config.host("desktop").unwrap();
+        let runner = MockRunner::new();
+        remote_power_action(&runner, &config, desktop, &Suspend).unwrap();
+        remote_power_action(&runner, &config, desktop, &Shutdown).unwrap();
+
+        let mut plain = desktop.clone();
+        plain.sudo_password_from_keyring = false;
+        remote_power_action(&runner, &config, &plain, &Suspend).unwrap();
+        remote_power_action(&runner, &config, &plain, &Shutdown).unwrap();
+
+        assert_eq!(
+            runner.commands(),
+            [
+                "ssh -o ConnectTimeout=8 10.13.13.4 sudo -S -p '' systemctl suspend",
+                "ssh -o ConnectTimeout=8 10.13.13.4 sudo -S -p '' shutdown now",
+                "ssh -o ConnectTimeout=8 10.13.13.4 sudo systemctl suspend",
+                "ssh -o ConnectTimeout=8 10.13.13.4 sudo shutdown now",
+            ]
+        );
+    }
+}
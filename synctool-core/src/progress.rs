@@ -0,0 +1,143 @@
+// What the current sync is up to, for the daemon's status socket: who it's
+// with, the phase it's in and, while unison is copying, which file and how far
+// along it is. Readers wait for it to change rather than polling.
+//
+// Unison's output only goes through here when something is watching (see
+// watch), since otherwise it's left on the terminal as is.
+
+use crate::events::json_string;
+use lazy_static::lazy_static;
+use std::{
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Condvar, Mutex,
+    },
+    time::Duration,
+};
+
+#[derive(Clone, Default, PartialEq, Debug)]
+pub struct Progress {
+    pub peer: Option<String>,
+    pub phase: Option<String>,
+    pub percent: Option<u8>,
+    pub file: Option<String>,
+}
+
+lazy_static! {
+    // The progress, and how many times it's changed
+    static ref CURRENT: Mutex<(u64, Progress)> = Mutex::new((0, Progress::default()));
+    static ref CHANGED: Condvar = Condvar::new();
+}
+static WATCHED: AtomicBool = AtomicBool::new(false);
+
+// Makes unison's output count towards the progress from now on
+pub fn watch() {
+    WATCHED.store(true, Ordering::SeqCst);
+}
+
+pub fn watched() -> bool {
+    WATCHED.load(Ordering::SeqCst)
+}
+
+pub fn update(f: impl FnOnce(&mut Progress)) {
+    let mut current = CURRENT.lock().unwrap();
+    let mut progress = current.1.clone();
+    f(&mut progress);
+    if progress != current.1 {
+        *current = (current.0 + 1, progress);
+        CHANGED.notify_all();
+    }
+}
+
+// Sets the phase, forgetting about the last one's file
+pub fn phase(phase: Option<&str>) {
+    update(|progress| {
+        progress.phase = phase.map(str::to_string);
+        progress.percent = None;
+        progress.file = None;
+    });
+}
+
+pub fn current() -> Progress {
+    CURRENT.lock().unwrap().1.clone()
+}
+
+// The progress once it's changed since version, or as it is after timeout,
+// with its version
+pub fn wait(version: u64, timeout: Duration) -> (u64, Progress) {
+    let current = CURRENT.lock().unwrap();
+    let (current, _) = CHANGED
+        .wait_timeout_while(current, timeout, |(current, _)| *current <= version)
+        .unwrap();
+    current.clone()
+}
+
+// Takes in a line of unison's output, or the part of one up to a \r
+pub fn unison_line(line: &str) {
+    update(|progress| read_unison_line(progress, line));
+}
+
+fn read_unison_line(progress: &mut Progress, line: &str) {
+    let line = line.trim();
+    if let Some(action) = line.strip_prefix("[BGN] ") {
+        let file = ["Updating file ", "Copying ", "Deleting "]
+            .iter()
+            .find_map(|verb| action.strip_prefix(verb))
+            .unwrap_or(action);
+        let file = file.split(" from ").next().unwrap_or(file);
+        progress.file = Some(file.to_string());
+    } else if let Some((percent, _)) = line.split_once('%') {
+        if let Ok(percent) = percent.trim().parse::<u8>() {
+            progress.percent = Some(percent.min(100));
+        }
+    }
+}
+
+// One line of JSON, like
+//
+//     {"state":"syncing","peer":"desktop","phase":"sync","percent":45,"file":"notes/todo.md"}
+//
+// with state "idle" and nothing else between syncs
+pub fn to_json(progress: &Progress) -> String {
+    let peer = match &progress.peer {
+        Some(peer) => peer,
+        None => return "{\"state\":\"idle\"}".to_string(),
+    };
+    let mut json = format!("{{\"state\":\"syncing\",\"peer\":{}", json_string(peer));
+    if let Some(phase) = &progress.phase {
+        json.push_str(&format!(",\"phase\":{}", json_string(phase)));
+    }
+    if let Some(percent) = progress.percent {
+        json.push_str(&format!(",\"percent\":{}", percent));
+    }
+    if let Some(file) = &progress.file {
+        json.push_str(&format!(",\"file\":{}", json_string(file)));
+    }
+    json.push('}');
+    json
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn follows_unison_output() {
+        let mut progress = Progress {
+            peer: Some("desktop".to_string()),
+            phase: Some("sync".to_string()),
+            ..Progress::default()
+        };
+        read_unison_line(
+            &mut progress,
+            "[BGN] Updating file notes/todo.md from /home/user/prog to //desktop//home/user/prog",
+        );
+        read_unison_line(&mut progress, "  45%  00:12 ETA");
+        read_unison_line(&mut progress, "Synchronization complete (100% done)");
+        assert_eq!(
+            to_json(&progress),
+            "{\"state\":\"syncing\",\"peer\":\"desktop\",\"phase\":\"sync\",\"percent\":45,\"file\":\"notes/todo.md\"}"
+        );
+        assert_eq!(to_json(&Progress::default()), "{\"state\":\"idle\"}");
+    }
+}
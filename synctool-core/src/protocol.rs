@@ -0,0 +1,155 @@
+// The messages synctool and `synctool agent` send each other, over ssh or
+// TCP. Each message is a header line with its kind and the length of its body
+// in bytes, then the body, which is key=value lines:
+//
+//     status 0
+//     result 33
+//     ok=true
+//     idle=yes
+//     free_bytes=1000
+//
+// Backslashes and newlines in values are escaped as \\ and \n. The length lets
+// either end skip messages and fields it doesn't know, so new ones can be added
+// without bumping VERSION. VERSION changes only when a message changes meaning,
+// and both ends refuse to talk across versions.
+//
+// The client starts with hello (version, and token over TCP), then sends any
+// of lock, unlock, status, changed (path), power (action, force) and trigger
+// (peer). The agent answers every message with a result: ok=true and any
+// data, or ok=false and an error.
+
+use eyre::{bail, eyre, Result};
+use std::io::{self, BufRead, Write};
+
+pub const VERSION: u32 = 1;
+
+// Bodies are a handful of short fields, so anything bigger is garbage
+const MAX_BODY: usize = 64 * 1024;
+
+#[derive(Debug, PartialEq)]
+pub struct Message {
+    pub kind: String,
+    pub fields: Vec<(String, String)>,
+}
+
+impl Message {
+    pub fn new(kind: &str) -> Message {
+        Message {
+            kind: kind.to_string(),
+            fields: Vec::new(),
+        }
+    }
+
+    pub fn with(mut self, key: &str, value: impl ToString) -> Message {
+        self.fields.push((key.to_string(), value.to_string()));
+        self
+    }
+
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.fields
+            .iter()
+            .find(|(k, _)| k == key)
+            .map(|(_, value)| value.as_str())
+    }
+
+    // A successful result
+    pub fn ok() -> Message {
+        Message::new("result").with("ok", true)
+    }
+
+    pub fn error(message: &str) -> Message {
+        Message::new("result")
+            .with("ok", false)
+            .with("error", message)
+    }
+}
+
+pub fn write(output: &mut impl Write, message: &Message) -> io::Result<()> {
+    let mut body = String::new();
+    for (key, value) in &message.fields {
+        let value = value.replace('\\', "\\\\").replace('\n', "\\n");
+        body.push_str(&format!("{}={}\n", key, value));
+    }
+    write!(output, "{} {}\n{}", message.kind, body.len(), body)?;
+    output.flush()
+}
+
+// The next message, or None if the other end closed the connection.
+pub fn read(input: &mut impl BufRead) -> Result<Option<Message>> {
+    let mut header = String::new();
+    if input.read_line(&mut header)? == 0 {
+        return Ok(None);
+    }
+    let (kind, length) = header
+        .trim_end()
+        .split_once(' ')
+        .and_then(|(kind, length)| Some((kind, length.parse::<usize>().ok()?)))
+        .ok_or_else(|| eyre!("Bad message header {:?}", header.trim_end()))?;
+    if length > MAX_BODY {
+        bail!("{} message is too long ({} bytes)", kind, length);
+    }
+
+    let mut body = vec![0; length];
+    input.read_exact(&mut body)?;
+    let body = String::from_utf8(body).map_err(|_| eyre!("{} message isn't UTF-8", kind))?;
+
+    let mut message = Message::new(kind);
+    for line in body.lines() {
+        let (key, value) = line
+            .split_once('=')
+            .ok_or_else(|| eyre!("Bad field {:?} in {} message", line, kind))?;
+        message.fields.push((key.to_string(), unescape(value)));
+    }
+    Ok(Some(message))
+}
+
+fn unescape(value: &str) -> String {
+    let mut unescaped = String::new();
+    let mut chars = value.chars();
+    while let Some(c) = chars.next() {
+        match (c, chars.clone().next()) {
+            ('\\', Some('n')) => {
+                unescaped.push('\n');
+                chars.next();
+            }
+            ('\\', Some('\\')) => {
+                unescaped.push('\\');
+                chars.next();
+            }
+            (c, _) => unescaped.push(c),
+        }
+    }
+    unescaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        let messages = [
+            Message::new("status"),
+            Message::new("changed").with("path", "a b=c\\d\ne"),
+            Message::error("no"),
+        ];
+        let mut stream = Vec::new();
+        for message in &messages {
+            write(&mut stream, message).unwrap();
+        }
+
+        let mut input = stream.as_slice();
+        for message in messages {
+            assert_eq!(read(&mut input).unwrap(), Some(message));
+        }
+        assert_eq!(read(&mut input).unwrap(), None);
+    }
+
+    #[test]
+    fn bad_frames() {
+        assert!(read(&mut "lock\n".as_bytes()).is_err());
+        assert!(read(&mut "lock 10\nok=1\n".as_bytes()).is_err());
+        assert!(read(&mut "lock 5\nokay\n".as_bytes()).is_err());
+        assert!(read(&mut "lock 99999999\n".as_bytes()).is_err());
+    }
+}
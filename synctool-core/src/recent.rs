@@ -0,0 +1,119 @@
+// What changed under the root since the last sync, for --fast runs that give
+// unison just those paths instead of letting it scan the whole tree.
+//
+// A file counts if it was modified since then. A directory whose own mtime is
+// newer had entries added, removed or renamed, which the files left in it
+// don't show, so it's synced whole and not looked into further. Only this
+// machine is scanned: whatever changed on the other end waits for a full run.
+
+use crate::{config::Config, ignore::ignored};
+use eyre::{Result, WrapErr};
+use std::{fs, io::ErrorKind, os::unix::fs::MetadataExt, path::Path};
+
+// Beyond this many paths unison's own scan is about as quick
+const MAX_PATHS: usize = 500;
+
+// Paths relative to the root that changed since `since`, in seconds since the
+// epoch. None if the whole tree has to be synced anyway, because entries of
+// the root itself changed or too much did.
+pub fn changed_since(config: &Config, since: i64) -> Result<Option<Vec<String>>> {
+    let root = Path::new(&config.root);
+    let metadata =
+        fs::metadata(root).wrap_err_with(|| format!("Couldn't read {}", root.display()))?;
+    if metadata.mtime() >= since {
+        return Ok(None);
+    }
+
+    let mut changed = Vec::new();
+    walk(config, root, "", since, &mut changed)?;
+    if changed.len() > MAX_PATHS {
+        return Ok(None);
+    }
+    changed.sort();
+    Ok(Some(changed))
+}
+
+fn walk(
+    config: &Config,
+    dir: &Path,
+    rel: &str,
+    since: i64,
+    changed: &mut Vec<String>,
+) -> Result<()> {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(err) if err.kind() == ErrorKind::NotFound => return Ok(()),
+        Err(err) => return Err(err).wrap_err_with(|| format!("Couldn't list {}", dir.display())),
+    };
+    for entry in entries {
+        // No point going on, a full run it is
+        if changed.len() > MAX_PATHS {
+            return Ok(());
+        }
+        let entry = entry?;
+        let name = entry.file_name().to_string_lossy().into_owned();
+        let path = if rel.is_empty() {
+            name.clone()
+        } else {
+            format!("{}/{}", rel, name)
+        };
+        if name == ".rsync-partial" || ignored(config, &path) {
+            continue;
+        }
+
+        let metadata = entry.metadata()?;
+        if metadata.mtime() >= since {
+            changed.push(path);
+        } else if metadata.is_dir() {
+            walk(config, &entry.path(), &path, since, changed)?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{
+        fs::File,
+        time::{Duration, SystemTime, UNIX_EPOCH},
+    };
+
+    #[test]
+    fn finds_changes() {
+        let root = crate::TempDir::new("recent");
+        fs::create_dir_all(root.join("a")).unwrap();
+        fs::create_dir_all(root.join("b")).unwrap();
+        fs::create_dir_all(root.join("target")).unwrap();
+        for file in ["a/x", "a/y", "b/z", "target/t"] {
+            fs::write(root.join(file), "").unwrap();
+        }
+        let long_ago = UNIX_EPOCH + Duration::from_secs(1000);
+        for path in ["a/x", "a/y", "b/z", "a", "b", "target", ""] {
+            File::open(root.join(path))
+                .unwrap()
+                .set_modified(long_ago)
+                .unwrap();
+        }
+        let config = Config {
+            root: root.to_string_lossy().into_owned(),
+            ..Config::default()
+        };
+        let since = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs() as i64
+            - 60;
+
+        assert_eq!(changed_since(&config, since).unwrap(), Some(Vec::new()));
+        fs::write(root.join("b/z"), "new").unwrap();
+        fs::write(root.join("target/t"), "ignored").unwrap();
+        fs::remove_file(root.join("a/y")).unwrap();
+        assert_eq!(
+            changed_since(&config, since).unwrap(),
+            Some(vec!["a".to_string(), "b/z".to_string()])
+        );
+        fs::write(root.join("top"), "").unwrap();
+        assert_eq!(changed_since(&config, since).unwrap(), None);
+    }
+}
@@ -0,0 +1,226 @@
+// The rsync backend, a one-way push of the tree to the remote.
+
+use crate::{
+    config::{Config, Host, Symlinks},
+    events,
+    runner::Runner,
+    ssh,
+};
+use eyre::Result;
+use std::{
+    io::Read,
+    process::{exit, Command, Stdio},
+};
+
+// One-way push of the local tree to the remote. Partially transferred files
+// are kept in .rsync-partial on the remote, and rsync uses them as the basis
+// for the next attempt, so a dropped link doesn't restart big files from zero.
+// With jobs > 1, the top-level entries of the tree are split between that many
+// concurrent rsync processes, which helps with lots of small files on the LAN.
+// Given paths, only those are pushed, to the same place under the remote root.
+// extra_args go after the options synctool sets, e.g. excludes.
+// Returns Ok(true) if sync was successful, Ok(false) if sync failed.
+pub fn rsync(
+    runner: &dyn Runner,
+    config: &Config,
+    host: &Host,
+    print: bool,
+    jobs: usize,
+    paths: &[String],
+    extra_args: &[String],
+) -> Result<bool> {
+    let source_groups = if !paths.is_empty() {
+        // --relative recreates what comes after the /./ on the remote
+        let entries = paths
+            .iter()
+            .map(|path| format!("{}/./{}", config.root, path))
+            .collect();
+        split(entries, jobs)
+    } else if jobs <= 1 {
+        vec![vec![format!("{}/", config.root)]]
+    } else {
+        let mut entries = std::fs::read_dir(&config.root)?
+            .map(|entry| Ok(entry?.path().to_string_lossy().into_owned()))
+            .collect::<Result<Vec<_>>>()?;
+        entries.sort();
+        split(entries, jobs)
+    };
+
+    let relative: &[&str] = if paths.is_empty() {
+        &[]
+    } else {
+        &["--relative"]
+    };
+    let mut commands = source_groups
+        .iter()
+        .map(|sources| {
+            let mut command = rsync_command(config, host, sources, relative);
+            command.args(extra_args);
+            command
+        })
+        .collect::<Vec<_>>();
+
+    if print {
+        for command in &commands {
+            let mut args = String::new();
+            for a in command.get_args() {
+                args.push_str(&format!("{:?} ", a));
+            }
+            log!("command: rsync {}", args);
+        }
+        exit(0);
+    }
+
+    run(runner, &mut commands)
+}
+
+// Splits entries between up to jobs groups
+fn split(entries: Vec<String>, jobs: usize) -> Vec<Vec<String>> {
+    let mut groups = vec![Vec::new(); jobs.max(1).min(entries.len())];
+    let group_count = groups.len();
+    for (i, entry) in entries.into_iter().enumerate() {
+        groups[i % group_count].push(entry);
+    }
+    groups
+}
+
+// Runs rsync commands from rsync_command concurrently, and reports how much
+// they transferred from their --stats. Returns Ok(true) if all succeeded.
+pub fn run(runner: &dyn Runner, commands: &mut [Command]) -> Result<bool> {
+    let mut processes = commands
+        .iter_mut()
+        .map(|command| runner.spawn(command))
+        .collect::<std::io::Result<Vec<_>>>()?;
+
+    let mut success = true;
+    let (mut sent, mut received) = (0, 0);
+    for process in &mut processes {
+        let mut stats = String::new();
+        if let Some(mut stdout) = process.take_stdout() {
+            stdout.read_to_string(&mut stats)?;
+        }
+        success &= process.wait()?.success();
+
+        for line in stats.lines() {
+            debug!("  {}", line);
+            let bytes = |prefix: &str| -> Option<u64> {
+                line.strip_prefix(prefix)?
+                    .trim()
+                    .replace(',', "")
+                    .parse()
+                    .ok()
+            };
+            sent += bytes("Total bytes sent:").unwrap_or(0);
+            received += bytes("Total bytes received:").unwrap_or(0);
+        }
+    }
+
+    events::transferred(sent, received);
+    Ok(success)
+}
+
+// Pushes then pulls files under the root, relative to it, the newer copy of
+// each winning, with extra_args on both rsyncs. Returns Ok(true) if both
+// worked.
+pub fn both_ways(
+    runner: &dyn Runner,
+    config: &Config,
+    host: &Host,
+    files: &[String],
+    extra_args: &[&str],
+) -> Result<bool> {
+    // --relative recreates what comes after the /./ on the other end
+    let mut flags = extra_args.to_vec();
+    flags.extend(["--update", "--relative"]);
+    let local = files
+        .iter()
+        .map(|file| format!("{}/./{}", config.root, file))
+        .collect::<Vec<_>>();
+    let push = rsync_command(config, host, &local, &flags);
+
+    let mut pull = Command::new("rsync");
+    pull.args(["-az", "--partial-dir=.rsync-partial", "--stats", "-e"])
+        .arg(format!("ssh {}", ssh::args_line(host)))
+        .args(&flags)
+        .args(
+            files
+                .iter()
+                .map(|file| format!("{}:{}/./{}", host.address, host.root(config), file)),
+        )
+        .arg(format!("{}/", config.root))
+        .stdout(Stdio::piped());
+
+    Ok(run(runner, &mut [push])? && run(runner, &mut [pull])?)
+}
+
+pub fn rsync_command(
+    config: &Config,
+    host: &Host,
+    sources: &[String],
+    extra_args: &[&str],
+) -> Command {
+    let remote_folder = format!("{}:{}/", host.address, host.root(config));
+    let mut command = Command::new("rsync");
+    command.args([
+        "-az",
+        "--partial-dir=.rsync-partial",
+        "--stats",
+        "-e",
+        &format!("ssh {}", ssh::args_line(host)),
+    ]);
+
+    // -a would take owners and groups too, unlike unison
+    if !host.perms {
+        command.arg("--no-perms");
+    }
+    if !host.owner {
+        command.arg("--no-owner");
+    }
+    if !host.group {
+        command.arg("--no-group");
+    }
+    if host.numeric_ids {
+        command.arg("--numeric-ids");
+    }
+    if host.xattrs {
+        command.arg("--xattrs");
+    }
+    if host.acls {
+        command.arg("--acls");
+    }
+    if host.symlinks == Symlinks::Follow {
+        command.arg("--copy-links");
+    }
+    // Only within each rsync, so with jobs > 1 sync_with relinks afterwards
+    if host.hard_links {
+        command.arg("--hard-links");
+    }
+    if host.sparse {
+        command.arg("--sparse");
+    }
+
+    for ignore in &config.ignores {
+        if let Some(exclude) = rsync_exclude(ignore) {
+            command.arg(exclude);
+        }
+    }
+
+    command.args(extra_args).args(sources).arg(remote_folder);
+    command
+        .stdin(Stdio::inherit())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::inherit());
+    command
+}
+
+// Translates a unison ignore pattern into an rsync --exclude argument.
+// Regexes are only supported as far as they're plain paths with .* wildcards.
+pub fn rsync_exclude(ignore: &str) -> Option<String> {
+    let (kind, pattern) = ignore.split_once(' ')?;
+    match kind {
+        "Name" => Some(format!("--exclude={}", pattern)),
+        "Path" => Some(format!("--exclude=/{}", pattern)),
+        "Regex" => Some(format!("--exclude=/{}", pattern.replace(".*", "*"))),
+        _ => None,
+    }
+}
@@ -0,0 +1,300 @@
+// External commands go through a Runner, so the sync flows can be driven by a
+// scripted mock instead of real hosts.
+
+use crate::output;
+use eyre::{eyre, Result};
+use std::{
+    io::{self, Read, Write},
+    os::unix::process::ExitStatusExt,
+    process::{Child, Command, ExitStatus, Output},
+    sync::Mutex,
+    time::Instant,
+};
+
+pub trait Runner: Sync {
+    // Runs a command to completion with whatever stdio it was set up with.
+    fn status(&self, command: &mut Command) -> io::Result<ExitStatus>;
+
+    // Runs a command to completion, capturing stdout and stderr.
+    fn output(&self, command: &mut Command) -> io::Result<Output>;
+
+    // Starts a command without waiting for it.
+    fn spawn(&self, command: &mut Command) -> io::Result<Box<dyn Process>>;
+
+    // True when nothing is really being run, so nothing should be recorded
+    // as having happened.
+    fn simulated(&self) -> bool {
+        false
+    }
+}
+
+// A started command.
+pub trait Process {
+    // The command's stdin, if it was set up with Stdio::piped().
+    fn take_stdin(&mut self) -> Option<Box<dyn Write>>;
+
+    // The command's stdout, if it was set up with Stdio::piped().
+    fn take_stdout(&mut self) -> Option<Box<dyn Read>>;
+
+    fn wait(&mut self) -> io::Result<ExitStatus>;
+}
+
+// Runs commands for real. With -v every command is logged with how it
+// exited and how long it took, and with -vv so is any output it captured.
+pub struct SystemRunner;
+
+impl Runner for SystemRunner {
+    fn status(&self, command: &mut Command) -> io::Result<ExitStatus> {
+        let mut process = self.spawn(command)?;
+        process.wait()
+    }
+
+    fn output(&self, command: &mut Command) -> io::Result<Output> {
+        let traced = Traced::start(command);
+        let output = command.output()?;
+        traced.finish(&output.status);
+        if output::verbosity() >= 2 {
+            for line in String::from_utf8_lossy(&output.stdout).lines() {
+                debug!("  stdout: {}", line);
+            }
+            for line in String::from_utf8_lossy(&output.stderr).lines() {
+                debug!("  stderr: {}", line);
+            }
+        }
+        Ok(output)
+    }
+
+    fn spawn(&self, command: &mut Command) -> io::Result<Box<dyn Process>> {
+        let traced = Traced::start(command);
+        let child = command.spawn()?;
+        Ok(Box::new(TracedChild { child, traced }))
+    }
+}
+
+// A command being run, for -v.
+struct Traced {
+    program: String,
+    start: Instant,
+}
+
+impl Traced {
+    fn start(command: &Command) -> Traced {
+        debug!("Running {}", command_line(command));
+        Traced {
+            program: command.get_program().to_string_lossy().into_owned(),
+            start: Instant::now(),
+        }
+    }
+
+    fn finish(&self, status: &ExitStatus) {
+        debug!(
+            "{} finished with {} after {:.2}s",
+            self.program,
+            status,
+            self.start.elapsed().as_secs_f32()
+        );
+    }
+}
+
+struct TracedChild {
+    child: Child,
+    traced: Traced,
+}
+
+impl Process for TracedChild {
+    fn take_stdin(&mut self) -> Option<Box<dyn Write>> {
+        Some(Box::new(self.child.stdin.take()?))
+    }
+
+    fn take_stdout(&mut self) -> Option<Box<dyn Read>> {
+        Some(Box::new(self.child.stdout.take()?))
+    }
+
+    fn wait(&mut self) -> io::Result<ExitStatus> {
+        let status = self.child.wait()?;
+        self.traced.finish(&status);
+        Ok(status)
+    }
+}
+
+// Records every command line instead of running it. Commands exit 0 with no
+// output unless a reply was scripted for them.
+#[derive(Default)]
+pub struct MockRunner {
+    commands: Mutex<Vec<String>>,
+    replies: Mutex<Vec<(String, Vec<Reply>)>>,
+    // Log each command as it's "run", for --simulate
+    pub verbose: bool,
+}
+
+#[derive(Clone)]
+pub struct Reply {
+    pub code: i32,
+    pub stdout: String,
+}
+
+impl MockRunner {
+    pub fn new() -> MockRunner {
+        MockRunner::default()
+    }
+
+    // Makes the commands whose line starts with prefix exit with the given
+    // codes, one per run. The last code keeps being used once they run out.
+    pub fn script(&self, prefix: &str, codes: &[i32]) {
+        let replies = codes
+            .iter()
+            .map(|&code| Reply {
+                code,
+                stdout: String::new(),
+            })
+            .collect();
+        self.script_replies(prefix, replies);
+    }
+
+    // Parses a --simulate script: rules separated by ';', each a command
+    // prefix, '=' and comma separated results (ok, fail or an exit code),
+    // e.g. "unison -auto=fail,ok; ping=fail,fail,ok".
+    pub fn from_script(script: &str) -> Result<MockRunner> {
+        let runner = MockRunner {
+            verbose: true,
+            ..MockRunner::default()
+        };
+        for rule in script.split(';').map(str::trim).filter(|r| !r.is_empty()) {
+            let (prefix, results) = rule
+                .rsplit_once('=')
+                .ok_or_else(|| eyre!("{:?} should look like PREFIX=RESULT,...", rule))?;
+            let codes = results
+                .split(',')
+                .map(|result| match result.trim() {
+                    "ok" => Ok(0),
+                    "fail" => Ok(1),
+                    code => code
+                        .parse()
+                        .map_err(|_| eyre!("{:?} isn't ok, fail or an exit code", code)),
+                })
+                .collect::<Result<Vec<_>>>()?;
+            runner.script(prefix.trim(), &codes);
+        }
+        Ok(runner)
+    }
+
+    pub fn script_replies(&self, prefix: &str, replies: Vec<Reply>) {
+        assert!(!replies.is_empty(), "no replies for {}", prefix);
+        self.replies
+            .lock()
+            .unwrap()
+            .push((prefix.to_string(), replies));
+    }
+
+    // Every command line run so far, in order.
+    pub fn commands(&self) -> Vec<String> {
+        self.commands.lock().unwrap().clone()
+    }
+
+    fn run(&self, command: &Command) -> Reply {
+        let line = command_line(command);
+        self.commands.lock().unwrap().push(line.clone());
+
+        let mut replies = self.replies.lock().unwrap();
+        let reply = match replies
+            .iter_mut()
+            .find(|(prefix, _)| line.starts_with(prefix.as_str()))
+        {
+            Some((_, queue)) if queue.len() > 1 => queue.remove(0),
+            Some((_, queue)) => queue[0].clone(),
+            None => Reply {
+                code: 0,
+                stdout: String::new(),
+            },
+        };
+
+        if self.verbose {
+            log!("Simulated {} (exit {})", line, reply.code);
+        }
+        reply
+    }
+}
+
+impl Runner for MockRunner {
+    fn status(&self, command: &mut Command) -> io::Result<ExitStatus> {
+        Ok(exit_status(self.run(command).code))
+    }
+
+    fn output(&self, command: &mut Command) -> io::Result<Output> {
+        let reply = self.run(command);
+        Ok(Output {
+            status: exit_status(reply.code),
+            stdout: reply.stdout.into_bytes(),
+            stderr: Vec::new(),
+        })
+    }
+
+    fn spawn(&self, command: &mut Command) -> io::Result<Box<dyn Process>> {
+        let reply = self.run(command);
+        Ok(Box::new(MockProcess {
+            status: exit_status(reply.code),
+            stdout: Some(reply.stdout),
+        }))
+    }
+
+    fn simulated(&self) -> bool {
+        true
+    }
+}
+
+// A mock process has already finished by the time it's spawned, with its
+// reply's output waiting to be read.
+struct MockProcess {
+    status: ExitStatus,
+    stdout: Option<String>,
+}
+
+impl Process for MockProcess {
+    fn take_stdin(&mut self) -> Option<Box<dyn Write>> {
+        Some(Box::new(io::sink()))
+    }
+
+    fn take_stdout(&mut self) -> Option<Box<dyn Read>> {
+        Some(Box::new(io::Cursor::new(self.stdout.take()?.into_bytes())))
+    }
+
+    fn wait(&mut self) -> io::Result<ExitStatus> {
+        Ok(self.status)
+    }
+}
+
+fn exit_status(code: i32) -> ExitStatus {
+    ExitStatus::from_raw(code << 8)
+}
+
+// The program and its arguments separated by spaces, e.g. "ssh host slp".
+pub fn command_line(command: &Command) -> String {
+    let mut line = command.get_program().to_string_lossy().into_owned();
+    for arg in command.get_args() {
+        line.push(' ');
+        line.push_str(&arg.to_string_lossy());
+    }
+    line
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn scripts() {
+        let runner = MockRunner::from_script("unison -auto=fail,ok; ping=3").unwrap();
+        let unison = || runner.status(Command::new("unison").arg("-auto")).unwrap();
+        assert_eq!(unison().code(), Some(1));
+        assert_eq!(unison().code(), Some(0));
+        assert_eq!(unison().code(), Some(0));
+        assert_eq!(
+            runner.status(&mut Command::new("ping")).unwrap().code(),
+            Some(3)
+        );
+        assert!(runner.status(&mut Command::new("ssh")).unwrap().success());
+
+        assert!(MockRunner::from_script("unison").is_err());
+        assert!(MockRunner::from_script("unison=maybe").is_err());
+    }
+}
@@ -0,0 +1,154 @@
+// Services on a host that get in the way of syncing, like a media indexer
+// opening files mid-transfer, are stopped for the sync with stop_services and
+// started again when it's over, however it ends. Names starting with "user:"
+// are the remote user's own systemd units; the rest are system units, stopped
+// and started as root the way the host's power_method says.
+//
+// An interrupt while they're stopped (Ctrl-C, which also stops unison) is held
+// back until they've been started again, and then let through.
+
+use crate::{config::Host, power::escalated, runner::Runner, ssh::ssh};
+use eyre::{bail, Result};
+use std::{
+    process::{Command, Stdio},
+    sync::atomic::{AtomicI32, Ordering},
+};
+
+static INTERRUPTED: AtomicI32 = AtomicI32::new(0);
+
+extern "C" fn on_interrupt(signal: libc::c_int) {
+    INTERRUPTED.store(signal, Ordering::SeqCst);
+}
+
+// The services stopped on a host, started again when dropped
+pub struct Stopped<'a> {
+    runner: &'a dyn Runner,
+    host: &'a Host,
+    units: Vec<String>,
+    handlers: Option<[libc::sighandler_t; 2]>,
+}
+
+// Stops the host's stop_services that are running.
+pub fn stop<'a>(runner: &'a dyn Runner, host: &'a Host) -> Result<Stopped<'a>> {
+    let mut stopped = Stopped {
+        runner,
+        host,
+        units: Vec::new(),
+        handlers: None,
+    };
+    for unit in &host.stop_services {
+        if !succeeds(runner, &mut systemctl(host, unit, "is-active --quiet"))? {
+            continue;
+        }
+        if stopped.handlers.is_none() {
+            let handler = on_interrupt as *const () as libc::sighandler_t;
+            stopped.handlers = Some(unsafe {
+                [
+                    libc::signal(libc::SIGINT, handler),
+                    libc::signal(libc::SIGTERM, handler),
+                ]
+            });
+        }
+        log!("Stopping {} on {} for the sync", unit, host.name);
+        if !succeeds(runner, &mut systemctl(host, unit, "stop"))? {
+            // Dropping stopped starts the ones before it again
+            bail!("Couldn't stop {} on {}", unit, host.name);
+        }
+        stopped.units.push(unit.clone());
+    }
+    Ok(stopped)
+}
+
+impl Drop for Stopped<'_> {
+    fn drop(&mut self) {
+        for unit in self.units.iter().rev() {
+            log!("Starting {} on {} again", unit, self.host.name);
+            // Once more if it fails, in case it was just slow to stop
+            let started = (0..2).any(|_| {
+                succeeds(self.runner, &mut systemctl(self.host, unit, "start")).unwrap_or(false)
+            });
+            if !started {
+                error!(
+                    "Couldn't start {} on {} again; start it by hand",
+                    unit, self.host.name
+                );
+            }
+        }
+
+        if let Some([interrupt, terminate]) = self.handlers {
+            unsafe {
+                libc::signal(libc::SIGINT, interrupt);
+                libc::signal(libc::SIGTERM, terminate);
+            }
+            let signal = INTERRUPTED.swap(0, Ordering::SeqCst);
+            if signal != 0 {
+                unsafe { libc::raise(signal) };
+            }
+        }
+    }
+}
+
+fn systemctl(host: &Host, unit: &str, verb: &str) -> Command {
+    match unit.strip_prefix("user:") {
+        Some(unit) => {
+            let mut command = ssh(host);
+            command.arg(format!("systemctl --user {} {}", verb, unit));
+            command
+        }
+        // Anyone can ask whether a system unit is running
+        None if verb.starts_with("is-active") => {
+            let mut command = ssh(host);
+            command.arg(format!("systemctl {} {}", verb, unit));
+            command
+        }
+        None => escalated(host, &format!("systemctl {} {}", verb, unit)),
+    }
+}
+
+fn succeeds(runner: &dyn Runner, command: &mut Command) -> Result<bool> {
+    Ok(runner
+        .status(command.stdin(Stdio::null()).stdout(Stdio::null()))?
+        .success())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{config::Config, runner::MockRunner};
+
+    #[test]
+    fn starts_what_it_stopped() {
+        let runner = MockRunner::new();
+        runner.script(
+            "ssh -o ConnectTimeout=8 10.13.13.4 systemctl is-active",
+            &[0, 3],
+        );
+        runner.script(
+            "ssh -o ConnectTimeout=8 10.13.13.4 systemctl --user is-active",
+            &[0],
+        );
+        runner.script(
+            "ssh -o ConnectTimeout=8 10.13.13.4 systemctl --user stop",
+            &[1],
+        );
+        let config = Config::parse(
+            "[hosts.desktop]\nstop_services = [\"plex\", \"minidlna\", \"user:tracker\"]\n",
+        )
+        .unwrap();
+        let desktop = config.host("desktop").unwrap();
+
+        assert!(stop(&runner, desktop).is_err());
+        let ssh = "ssh -o ConnectTimeout=8 10.13.13.4";
+        assert_eq!(
+            runner.commands(),
+            [
+                format!("{} systemctl is-active --quiet plex", ssh),
+                format!("{} sudo systemctl stop plex", ssh),
+                format!("{} systemctl is-active --quiet minidlna", ssh),
+                format!("{} systemctl --user is-active --quiet tracker", ssh),
+                format!("{} systemctl --user stop tracker", ssh),
+                format!("{} sudo systemctl start plex", ssh),
+            ]
+        );
+    }
+}
@@ -0,0 +1,70 @@
+// Sparse files, like VM disk images, for hosts with sparse = true. Unison
+// writes out every hole in them as zeros, turning a mostly empty 40 GB image
+// into 40 GB on the other end, so they're left out of unison and copied with
+// rsync --sparse both ways instead, the newer copy winning. Only files that
+// are sparse here are found; ones that are only sparse on the host go through
+// unison as before.
+
+use crate::{
+    config::{Config, Host},
+    links, rsync,
+    runner::Runner,
+};
+use eyre::Result;
+use std::{os::unix::fs::MetadataExt, path::Path};
+
+// Smaller files aren't worth the extra rsync runs
+const MIN_SIZE: u64 = 16 * 1024 * 1024;
+
+// Files under the root, relative to it, with less than half of their size
+// actually allocated
+pub fn sparse_files(config: &Config) -> Result<Vec<String>> {
+    let mut files = Vec::new();
+    links::walk(config, Path::new(&config.root), "", &mut |path, entry| {
+        let metadata = entry.metadata()?;
+        if metadata.is_file()
+            && metadata.len() >= MIN_SIZE
+            && metadata.blocks() * 512 < metadata.len() / 2
+        {
+            files.push(path);
+        }
+        Ok(())
+    })?;
+    files.sort();
+    Ok(files)
+}
+
+// Pushes then pulls the files with rsync, keeping their holes. Returns
+// Ok(true) if both worked.
+pub fn transfer(
+    runner: &dyn Runner,
+    config: &Config,
+    host: &Host,
+    files: &[String],
+) -> Result<bool> {
+    log!("Copying {} sparse file(s) with rsync", files.len());
+    rsync::both_ways(runner, config, host, files, &["--sparse"])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::{self, File};
+
+    #[test]
+    fn finds_sparse_files() {
+        let root = crate::TempDir::new("sparse");
+        fs::create_dir_all(root.join("vms")).unwrap();
+        File::create(root.join("vms/disk.raw"))
+            .unwrap()
+            .set_len(64 * 1024 * 1024)
+            .unwrap();
+        fs::write(root.join("small"), "").unwrap();
+        let config = Config {
+            root: root.to_string_lossy().into_owned(),
+            ..Config::default()
+        };
+
+        assert_eq!(sparse_files(&config).unwrap(), ["vms/disk.raw"]);
+    }
+}
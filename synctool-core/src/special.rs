@@ -0,0 +1,194 @@
+// Sockets, FIFOs and device nodes under the root, which unison only fails on,
+// like the socket a dev server leaves in a project. They're always left out
+// of the sync; special_files says whether that's done quietly, with a list of
+// them, or with FIFOs and device nodes made again on the host, where they
+// don't exist yet, with the same modes (device nodes as root, the way the
+// host's power_method says).
+
+use crate::{
+    config::{Config, Host, SpecialFiles},
+    links::walk,
+    power::escalated,
+    runner::Runner,
+    shell_quote,
+    ssh::ssh,
+};
+use eyre::Result;
+use std::{
+    os::unix::fs::{FileTypeExt, MetadataExt},
+    path::Path,
+    process::{Command, Stdio},
+};
+
+#[derive(Debug, PartialEq)]
+pub enum Kind {
+    Socket,
+    Fifo,
+    // With the major and minor device numbers
+    Block(u32, u32),
+    Char(u32, u32),
+}
+
+impl Kind {
+    fn name(&self) -> &'static str {
+        match self {
+            Kind::Socket => "socket",
+            Kind::Fifo => "FIFO",
+            Kind::Block(..) => "block device",
+            Kind::Char(..) => "character device",
+        }
+    }
+}
+
+// Every special file under the root that isn't ignored, relative to the root,
+// with what it is and its permission bits
+pub fn find(config: &Config) -> Result<Vec<(String, Kind, u32)>> {
+    let mut found = Vec::new();
+    walk(config, Path::new(&config.root), "", &mut |path, entry| {
+        let file_type = entry.file_type()?;
+        let kind = if file_type.is_socket() {
+            Kind::Socket
+        } else if file_type.is_fifo() {
+            Kind::Fifo
+        } else if file_type.is_block_device() || file_type.is_char_device() {
+            let device = entry.metadata()?.rdev();
+            let (major, minor) = unsafe { (libc::major(device), libc::minor(device)) };
+            match file_type.is_block_device() {
+                true => Kind::Block(major, minor),
+                false => Kind::Char(major, minor),
+            }
+        } else {
+            return Ok(());
+        };
+        found.push((path, kind, entry.metadata()?.mode() & 0o7777));
+        Ok(())
+    })?;
+    found.sort_by(|a, b| a.0.cmp(&b.0));
+    Ok(found)
+}
+
+// Handles the special files as the policy says, returning the unison ignores
+// that leave them out
+pub fn handle(
+    runner: &dyn Runner,
+    config: &Config,
+    host: &Host,
+    policy: SpecialFiles,
+) -> Result<Vec<String>> {
+    let found = find(config)?;
+    match policy {
+        SpecialFiles::Skip => {}
+        SpecialFiles::Report if !found.is_empty() => {
+            warn!(
+                "Not syncing {} special file(s) with {}:",
+                found.len(),
+                host.name
+            );
+            for (path, kind, _) in &found {
+                warn!("  {} ({})", path, kind.name());
+            }
+        }
+        SpecialFiles::Report => {}
+        SpecialFiles::Recreate => recreate(runner, config, host, &found)?,
+    }
+    Ok(found
+        .into_iter()
+        .map(|(path, _, _)| format!("Path {}", path))
+        .collect())
+}
+
+fn recreate(
+    runner: &dyn Runner,
+    config: &Config,
+    host: &Host,
+    found: &[(String, Kind, u32)],
+) -> Result<()> {
+    let mut fifos = Vec::new();
+    let mut devices = Vec::new();
+    for (path, kind, mode) in found {
+        let path = shell_quote(path);
+        let (make, list) = match kind {
+            Kind::Socket => continue,
+            Kind::Fifo => (format!("mkfifo -m {:o} -- {}", mode, path), &mut fifos),
+            Kind::Block(major, minor) | Kind::Char(major, minor) => {
+                let letter = if matches!(kind, Kind::Block(..)) {
+                    'b'
+                } else {
+                    'c'
+                };
+                let make = format!(
+                    "mknod -m {:o} -- {} {} {} {}",
+                    mode, path, letter, major, minor
+                );
+                (make, &mut devices)
+            }
+        };
+        list.push(format!(
+            "test -e {} || {{ mkdir -p \"$(dirname {})\" && {}; }}",
+            path, path, make
+        ));
+    }
+
+    let root = shell_quote(host.root(config));
+    for (lines, what, as_root) in [(fifos, "FIFO", false), (devices, "device node", true)] {
+        if lines.is_empty() {
+            continue;
+        }
+        log!("Making {} {}(s) on {}", lines.len(), what, host.name);
+        let script = format!("cd {} && {{ {}; }}", root, lines.join(" && "));
+        let mut command = match as_root {
+            true => escalated(host, &format!("sh -c {}", shell_quote(&script))),
+            false => {
+                let mut command = ssh(host);
+                command.arg(script);
+                command
+            }
+        };
+        if !run(runner, &mut command)? {
+            warn!("Couldn't make every {} on {}", what, host.name);
+        }
+    }
+    Ok(())
+}
+
+fn run(runner: &dyn Runner, command: &mut Command) -> Result<bool> {
+    Ok(runner.status(command.stdin(Stdio::null()))?.success())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::runner::MockRunner;
+    use std::{ffi::CString, fs, os::unix::net::UnixListener};
+
+    #[test]
+    fn recreates_fifos() {
+        let root = crate::TempDir::new("special");
+        fs::create_dir_all(root.join("app")).unwrap();
+        let _listener = UnixListener::bind(root.join("app/server.sock")).unwrap();
+        let fifo = CString::new(root.join("app/events").to_str().unwrap()).unwrap();
+        assert_eq!(unsafe { libc::mkfifo(fifo.as_ptr(), 0o600) }, 0);
+        fs::write(root.join("app/main.rs"), "").unwrap();
+        let config = Config {
+            root: root.to_string_lossy().into_owned(),
+            ..Config::default()
+        };
+        let runner = MockRunner::new();
+
+        let ignores = handle(
+            &runner,
+            &config,
+            config.host("desktop").unwrap(),
+            SpecialFiles::Recreate,
+        )
+        .unwrap();
+        assert_eq!(ignores, ["Path app/events", "Path app/server.sock"]);
+        assert_eq!(
+            runner.commands(),
+            [format!(
+                "ssh -o ConnectTimeout=8 10.13.13.4 cd {} && {{ test -e 'app/events' || {{ mkdir -p \"$(dirname 'app/events')\" && mkfifo -m 600 -- 'app/events'; }}; }}",
+                shell_quote(config.host("desktop").unwrap().root(&config))
+            )]
+        );
+    }
+}
@@ -0,0 +1,46 @@
+// Every ssh connection to a host, synctool's own and the ones unison and rsync
+// make, gets the same -o options. They're worked out from [ssh] and the host's
+// own settings when the config is loaded (see Config::resolve). A pinned
+// host_key is written to a known_hosts file of its own, which is the only one
+// ssh checks for that host.
+
+use crate::config::{known_hosts_path, Host};
+use std::{fs, process::Command};
+
+// ssh to the host, ready for the remote command to be added
+pub fn ssh(host: &Host) -> Command {
+    let mut command = Command::new("ssh");
+    command.args(args(host)).arg(&host.address);
+    command
+}
+
+// The host's options as ssh arguments
+pub fn args(host: &Host) -> Vec<String> {
+    if let Some(key) = &host.host_key {
+        // If this fails, ssh finds no key for the host and refuses to connect
+        let path = known_hosts_path(&host.name);
+        let written = path
+            .parent()
+            .map_or(Ok(()), fs::create_dir_all)
+            .and_then(|()| {
+                let mut addresses = vec![host.address.as_str()];
+                addresses.extend(host.addresses.iter().map(String::as_str));
+                fs::write(&path, format!("{} {}\n", addresses.join(","), key))
+            });
+        if let Err(err) = written {
+            warn!(
+                "Couldn't write the pinned host key of {}: {}",
+                host.name, err
+            );
+        }
+    }
+    host.ssh_options
+        .iter()
+        .flat_map(|option| ["-o".to_string(), option.clone()])
+        .collect()
+}
+
+// The options as one string, for unison's sshargs and after "ssh" in rsync's -e
+pub fn args_line(host: &Host) -> String {
+    args(host).join(" ")
+}
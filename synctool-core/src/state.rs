@@ -0,0 +1,142 @@
+// status.json in the state dir, saying what synctool is doing and when each
+// peer last synced, for status bars and scripts that would rather not read
+// the history:
+//
+//     {"state":"syncing","peer":"desktop","since":1686000000,"pid":4242,
+//      "peers":{"desktop":{"last_sync":1685990000,"ok":true}}}
+//
+// state is "syncing" while a run is going, and "idle" or "failed" after, by
+// how the last run ended. last_sync is when the last successful sync with the
+// peer ended, and ok whether the last run with it worked.
+
+use crate::{
+    events::{json_field, json_string},
+    history, state_dir,
+};
+use eyre::Result;
+use std::{
+    fs,
+    path::PathBuf,
+    process,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum State {
+    Idle,
+    Syncing,
+    Failed,
+}
+
+impl State {
+    pub fn name(&self) -> &'static str {
+        match self {
+            State::Idle => "idle",
+            State::Syncing => "syncing",
+            State::Failed => "failed",
+        }
+    }
+}
+
+pub struct Status {
+    pub state: State,
+    // Who the current or last run was with
+    pub peer: String,
+    // When it got to that state, in seconds since the epoch
+    pub since: u64,
+}
+
+pub fn path() -> PathBuf {
+    state_dir().join("status.json")
+}
+
+// Marks a run with the peer as started
+pub fn syncing(peer: &str) -> Result<()> {
+    write(State::Syncing, peer)
+}
+
+// Marks the run with the peer as over. Called when it's recorded in the history.
+pub fn finished(peer: &str, ok: bool) -> Result<()> {
+    write(if ok { State::Idle } else { State::Failed }, peer)
+}
+
+fn write(state: State, peer: &str) -> Result<()> {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+    let runs = history::load()?;
+    let mut peers = runs.iter().map(|run| run.peer.as_str()).collect::<Vec<_>>();
+    peers.sort_unstable();
+    peers.dedup();
+    let peers = peers
+        .iter()
+        .map(|name| {
+            let last_sync = match history::last_success(&runs, name) {
+                Some(time) => time.to_string(),
+                None => "null".to_string(),
+            };
+            let ok = runs.iter().rev().find(|run| run.peer == *name).unwrap().ok;
+            format!(
+                "{}:{{\"last_sync\":{},\"ok\":{}}}",
+                json_string(name),
+                last_sync,
+                ok
+            )
+        })
+        .collect::<Vec<_>>();
+    let json = format!(
+        "{{\"state\":\"{}\",\"peer\":{},\"since\":{},\"pid\":{},\"peers\":{{{}}}}}\n",
+        state.name(),
+        json_string(peer),
+        now,
+        process::id(),
+        peers.join(",")
+    );
+
+    // Written whole and moved into place, so readers never see half of it
+    fs::create_dir_all(state_dir())?;
+    let temporary = state_dir().join("status.json.tmp");
+    fs::write(&temporary, json)?;
+    fs::rename(temporary, path())?;
+    Ok(())
+}
+
+// What status.json says, if there is one. A run whose process is gone without
+// saying how it ended counts as failed.
+pub fn current() -> Option<Status> {
+    let json = fs::read_to_string(path()).ok()?;
+    parse(&json, |pid| unsafe { libc::kill(pid, 0) } == 0)
+}
+
+fn parse(json: &str, running: impl Fn(i32) -> bool) -> Option<Status> {
+    let mut state = match json_field(json, "state")? {
+        "idle" => State::Idle,
+        "syncing" => State::Syncing,
+        "failed" => State::Failed,
+        _ => return None,
+    };
+    let pid = json_field(json, "pid")?.parse().ok()?;
+    if state == State::Syncing && !running(pid) {
+        state = State::Failed;
+    }
+    Some(Status {
+        state,
+        peer: json_field(json, "peer")?.to_string(),
+        since: json_field(json, "since")?.parse().ok()?,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_status() {
+        let json = "{\"state\":\"syncing\",\"peer\":\"desktop\",\"since\":1686000000,\"pid\":4242,\"peers\":{\"desktop\":{\"last_sync\":1685990000,\"ok\":true}}}\n";
+        let status = parse(json, |_| true).unwrap();
+        assert_eq!(status.state, State::Syncing);
+        assert_eq!(status.peer, "desktop");
+        assert_eq!(status.since, 1686000000);
+        // The run died
+        assert_eq!(parse(json, |_| false).unwrap().state, State::Failed);
+        assert!(parse("{}", |_| true).is_none());
+    }
+}
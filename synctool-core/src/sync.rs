@@ -0,0 +1,1061 @@
+// The sync flows: which backend to use, retrying after waking the host or
+// syncing with its relay instead, and the power actions afterwards.
+
+use crate::{
+    agent::Agent,
+    backup, blobs, case, checksum, cloud,
+    config::{Config, DiskImages, Host, NotifyClass, SpecialFiles, Symlinks},
+    databases, dedup, encrypt, events, grace, hooks, hostname,
+    ignore::ignore_matches,
+    images, large, links, moves, notify,
+    power::{
+        do_local_power_action, do_remote_power_action, guess_state, state_after_waking,
+        state_before_waking, PowerAction, PowerAction::*, PowerState,
+    },
+    protocol::Message,
+    rsync::{rsync, rsync_exclude},
+    runner::Runner,
+    services, shell_quote, sparse, special,
+    ssh::ssh,
+    syncthing, unicode,
+    unison::{choose_unisons, remote_root, unison},
+    versions,
+    wake::{fastest_address, wake_host},
+};
+use eyre::{bail, ensure, Result};
+use std::{
+    process::Stdio,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+pub struct SyncOptions {
+    pub local_power: PowerAction,
+    pub remote_power: PowerAction,
+    pub interactive: bool,
+    pub skip_sync: bool,
+    pub print_unison_cmd: bool,
+    pub use_rsync: bool,
+    pub jobs: usize,
+    pub rsync_fallback: bool,
+    pub to_host: Option<String>,
+    // Only sync these paths under the root, or all of it if empty
+    pub paths: Vec<String>,
+    // Compare contents rather than trusting sizes and modification times
+    pub checksum: bool,
+    // Unison ignore patterns for this run on top of the configured ones
+    pub ignores: Vec<String>,
+    // After syncing, leave the remote as it was before: awake, or put back to
+    // sleep or off if it had to be woken. Replaces remote_power.
+    pub restore_power: bool,
+    // Instead of [sync] special_files, from the profile
+    pub special_files: Option<SpecialFiles>,
+}
+
+impl Default for SyncOptions {
+    fn default() -> SyncOptions {
+        SyncOptions {
+            local_power: Nothing,
+            remote_power: Nothing,
+            interactive: false,
+            skip_sync: false,
+            print_unison_cmd: false,
+            use_rsync: false,
+            jobs: 1,
+            rsync_fallback: false,
+            to_host: None,
+            paths: Vec::new(),
+            checksum: false,
+            ignores: Vec::new(),
+            restore_power: false,
+            special_files: None,
+        }
+    }
+}
+
+pub fn sync_laptop_to_desktop(
+    runner: &dyn Runner,
+    config: &Config,
+    sync_options: &SyncOptions,
+) -> Result<()> {
+    sync_waking_host(runner, config, config.host("desktop")?, sync_options)
+}
+
+// Syncs with a host that may be off, waking it (see wake.rs) and trying again
+// if the first sync fails.
+pub fn sync_waking_host(
+    runner: &dyn Runner,
+    config: &Config,
+    host: &Host,
+    sync_options: &SyncOptions,
+) -> Result<()> {
+    // With restore_power, the remote goes back to how it was found
+    let do_power_actions = |found: PowerState| {
+        let remote_power = if sync_options.restore_power {
+            found.restore_action()
+        } else {
+            sync_options.remote_power
+        };
+        power_actions(runner, config, host, remote_power, sync_options.local_power)
+    };
+    let do_sync = || -> Result<bool> {
+        let synced = events::phase("sync", || sync_with(runner, config, host, sync_options))?;
+        if synced {
+            clear_cloud_buffer(runner, config)?;
+            backup::after_sync(runner, config, host)?;
+        }
+        Ok(synced)
+    };
+
+    if sync_options.skip_sync {
+        log!("Skipped sync");
+        wake_host(runner, config, host)?;
+        return power_actions(
+            runner,
+            config,
+            host,
+            sync_options.remote_power,
+            sync_options.local_power,
+        );
+    }
+
+    catch_up_from_cloud(runner, config, sync_options)?;
+
+    phase!("Starting sync");
+    if do_sync()? {
+        events::found(PowerState::Awake.name());
+        do_power_actions(PowerState::Awake)?;
+        return Ok(());
+    }
+
+    if let Some(relay) = &host.relay {
+        if sync_with_relay(runner, config, relay, sync_options)? {
+            return Ok(());
+        }
+    }
+
+    let before = state_before_waking(runner, host);
+    let woken_at = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+    wake_host(runner, config, host)?;
+    let found = before
+        .or_else(|| state_after_waking(runner, host, woken_at))
+        .unwrap_or_else(|| guess_state(host));
+    log!("{} was {} before waking", host.name, found.name());
+    events::found(found.name());
+
+    phase!("Trying sync again");
+    if do_sync()? {
+        do_power_actions(found)?;
+        return Ok(());
+    }
+
+    fall_back_to_cloud(runner, config)
+}
+
+pub fn sync_desktop_to_laptop(
+    runner: &dyn Runner,
+    config: &Config,
+    sync_options: &SyncOptions,
+) -> Result<()> {
+    sync_to_host(runner, config, config.host("laptop")?, sync_options)
+}
+
+// Syncs once with a host that's expected to be awake already.
+pub fn sync_to_host(
+    runner: &dyn Runner,
+    config: &Config,
+    host: &Host,
+    sync_options: &SyncOptions,
+) -> Result<()> {
+    if sync_options.skip_sync {
+        return power_actions(
+            runner,
+            config,
+            host,
+            sync_options.remote_power,
+            sync_options.local_power,
+        );
+    }
+
+    catch_up_from_cloud(runner, config, sync_options)?;
+    phase!("Starting sync");
+    if events::phase("sync", || sync_with(runner, config, host, sync_options))? {
+        clear_cloud_buffer(runner, config)?;
+        backup::after_sync(runner, config, host)?;
+        power_actions(
+            runner,
+            config,
+            host,
+            sync_options.remote_power,
+            sync_options.local_power,
+        )
+    } else {
+        if let Some(relay) = &host.relay {
+            if sync_with_relay(runner, config, relay, sync_options)? {
+                return Ok(());
+            }
+        }
+        fall_back_to_cloud(runner, config)
+    }
+}
+
+// Syncs with the relay of a host that's off, whose daemon passes the changes
+// on once the host is back. Only the local power action happens afterwards,
+// since the host is already off. Returns Ok(true) if it worked.
+fn sync_with_relay(
+    runner: &dyn Runner,
+    config: &Config,
+    relay: &str,
+    sync_options: &SyncOptions,
+) -> Result<bool> {
+    let relay = config.host(relay)?;
+    phase!("Syncing with {} instead", relay.name);
+    if !events::phase("sync", || sync_with(runner, config, relay, sync_options))? {
+        warn!("Couldn't sync with {} either", relay.name);
+        return Ok(false);
+    }
+    clear_cloud_buffer(runner, config)?;
+    if !matches!(sync_options.local_power, Nothing) {
+        events::phase("power", || {
+            do_local_power_action(runner, config, &sync_options.local_power)
+        })?;
+    }
+    Ok(true)
+}
+
+// Pulls in whatever other machines buffered in the cloud while they couldn't
+// reach their peers.
+fn catch_up_from_cloud(
+    runner: &dyn Runner,
+    config: &Config,
+    sync_options: &SyncOptions,
+) -> Result<()> {
+    match &config.cloud_remote {
+        Some(remote) if !sync_options.print_unison_cmd => cloud::pull(runner, config, remote),
+        _ => Ok(()),
+    }
+}
+
+fn clear_cloud_buffer(runner: &dyn Runner, config: &Config) -> Result<()> {
+    match &config.cloud_remote {
+        Some(_) => cloud::clear(runner),
+        None => Ok(()),
+    }
+}
+
+// When no peer could be reached, the changes at least go to the cloud. The
+// run still failed, so nothing after the sync happens.
+fn fall_back_to_cloud(runner: &dyn Runner, config: &Config) -> Result<()> {
+    if let Some(remote) = &config.cloud_remote {
+        if cloud::push(runner, config, remote)? {
+            bail!("Sync failed, but the changes are buffered in {}", remote);
+        }
+    }
+    bail!("Sync failed")
+}
+
+// The remote goes first, since once this machine is off it can't do anything.
+fn power_actions(
+    runner: &dyn Runner,
+    config: &Config,
+    host: &Host,
+    remote_power: PowerAction,
+    local_power: PowerAction,
+) -> Result<()> {
+    if let (Nothing, Nothing) = (remote_power, local_power) {
+        return Ok(());
+    }
+    if config.power_grace > 0 && !runner.simulated() {
+        let what = [
+            (remote_power, host.name.as_str()),
+            (local_power, "this computer"),
+        ]
+        .iter()
+        .filter_map(|(action, target)| match action {
+            Shutdown => Some(format!("shutting down {}", target)),
+            Suspend => Some(format!("suspending {}", target)),
+            Nothing => None,
+        })
+        .collect::<Vec<_>>()
+        .join(" and ");
+        let what = format!("{}{}", what[..1].to_uppercase(), &what[1..]);
+        if !grace::countdown(&what, config.power_grace)? {
+            return Ok(());
+        }
+    }
+    events::phase("power", || {
+        do_remote_power_action(runner, config, host, &remote_power)?;
+        if let Some(action) = power_verb(&remote_power) {
+            notify::queue(NotifyClass::Power, format!("{} {}", action, host.name));
+        }
+        if let Some(action) = power_verb(&local_power) {
+            notify::queue(NotifyClass::Power, format!("{} {}", action, hostname()));
+        }
+        do_local_power_action(runner, config, &local_power)
+    })
+}
+
+fn power_verb(action: &PowerAction) -> Option<&'static str> {
+    match action {
+        Shutdown => Some("Shut down"),
+        Suspend => Some("Suspended"),
+        Nothing => None,
+    }
+}
+
+// Runs whichever sync backend was selected on the command line.
+pub fn sync_with(
+    runner: &dyn Runner,
+    config: &Config,
+    host: &Host,
+    sync_options: &SyncOptions,
+) -> Result<bool> {
+    // Syncthing does the rest, with nothing to run on the host
+    if host.syncthing_device.is_some() {
+        return syncthing::sync(runner, config, host, sync_options.print_unison_cmd);
+    }
+
+    let mut host = fastest_address(runner, host);
+    if !sync_options.print_unison_cmd {
+        check_clock(runner, config, &host)?;
+        check_metadata_support(runner, config, &mut host)?;
+    }
+    // Before the host is borrowed for good, since it may switch unisons
+    let unisons_match = sync_options.use_rsync
+        || sync_options.print_unison_cmd
+        || host.gpg_recipient.is_some()
+        || choose_unisons(runner, config, &mut host)?;
+    let host = &host;
+
+    // Held until the sync is over, so two machines don't sync with this host
+    // at once
+    let _agent = match &host.agent {
+        Some(_) if !sync_options.print_unison_cmd => {
+            let locked = Agent::connect(runner, host).and_then(|mut agent| {
+                agent.request(Message::new("lock"))?;
+                Ok(agent)
+            });
+            match locked {
+                Ok(agent) => Some(agent),
+                // Asleep, most likely, which a failed attempt gets it woken for
+                Err(err) => {
+                    warn!("Couldn't lock {}: {:#}", host.name, err);
+                    return Ok(false);
+                }
+            }
+        }
+        _ => None,
+    };
+    // Started again when the sync is over, however it ends
+    let _services = match sync_options.print_unison_cmd {
+        false => Some(services::stop(runner, host)?),
+        true => None,
+    };
+
+    if let Some(recipient) = &host.gpg_recipient {
+        return encrypt::push(
+            runner,
+            config,
+            host,
+            recipient,
+            sync_options.print_unison_cmd,
+        );
+    }
+
+    // With synctool on both ends, find out which end has the newer copy of
+    // each file that differs
+    let tracking = host
+        .synctool
+        .as_deref()
+        .filter(|_| !sync_options.print_unison_cmd);
+    let tables = match tracking {
+        Some(synctool) => match versions::tables(runner, config, host, synctool)? {
+            Some(tables) => Some(tables),
+            // Asleep, most likely, which a failed attempt gets it woken for
+            None => {
+                warn!("Couldn't get the file versions from {}", host.name);
+                return Ok(false);
+            }
+        },
+        None => None,
+    };
+    let plan = match &tables {
+        Some((ours, theirs)) => versions::before_sync(host, ours, theirs),
+        None => Vec::new(),
+    };
+
+    let mut use_rsync = sync_options.use_rsync;
+    if !unisons_match {
+        ensure!(
+            sync_options.rsync_fallback,
+            "Unison can't sync between these versions (use -f to fall back to rsync)"
+        );
+        warn!("Falling back to rsync for this run");
+        use_rsync = true;
+    }
+
+    let mut ignores = sync_options.ignores.clone();
+    if !config.blob_dirs.is_empty() {
+        ignores.push(format!("Path {}", blobs::STORE));
+    }
+    if config.dedup {
+        ignores.push(format!("Path {}", dedup::STORE));
+    }
+    ignores.extend(databases::ignores(config));
+    if !sync_options.print_unison_cmd {
+        databases::dump(runner, config, host)?;
+        if !config.blob_dirs.is_empty() && !runner.simulated() {
+            blobs::track(config)?;
+        }
+        if host.normalize_names && !runner.simulated() {
+            unicode::normalize(config)?;
+        }
+        ignores.extend(skipped_symlinks(config, host)?);
+        let special_files = sync_options.special_files.unwrap_or(config.special_files);
+        ignores.extend(special::handle(runner, config, host, special_files)?);
+        ignores.extend(case::handle(runner, config, host)?);
+        ignores.extend(large::check(runner, config, host)?);
+    }
+    // Held back, or copied around unison like sparse files; a single rsync
+    // copes with them itself
+    let mut disk_images = Vec::new();
+    if !sync_options.print_unison_cmd {
+        let found = images::find(config)?
+            .into_iter()
+            .filter(|(file, _)| in_run(file, sync_options, &ignores))
+            .collect::<Vec<_>>();
+        match config.disk_images {
+            DiskImages::Exclude => ignores.extend(images::hold_back(host, &found)),
+            _ if use_rsync => {}
+            _ => disk_images = found.into_iter().map(|(file, _)| file).collect(),
+        }
+    }
+    if let (true, Some((ours, theirs))) = (config.dedup, &tables) {
+        let sending = plan
+            .iter()
+            .filter(|(file, _)| in_run(file, sync_options, &ignores))
+            .filter(|(file, _)| !disk_images.contains(file))
+            .cloned()
+            .collect::<Vec<_>>();
+        dedup::send(runner, config, host, ours, theirs, &sending)?;
+    }
+
+    // High priority projects get a pass of their own first, so they've made
+    // it across even if the rest of the run is cut short
+    let mut passes = Vec::new();
+    if sync_options.paths.is_empty() && !sync_options.print_unison_cmd {
+        let priority = config
+            .projects
+            .iter()
+            .filter(|project| project.priority)
+            .filter(|project| {
+                !sync_options
+                    .ignores
+                    .contains(&format!("Path {}", project.path))
+            })
+            .collect::<Vec<_>>();
+        if !priority.is_empty() {
+            let names = priority.iter().map(|project| project.name.as_str());
+            log!("Syncing {} first", names.collect::<Vec<_>>().join(", "));
+            passes.push(
+                priority
+                    .iter()
+                    .map(|project| project.path.clone())
+                    .collect(),
+            );
+        }
+    }
+    passes.push(sync_options.paths.clone());
+
+    let success = if use_rsync {
+        if !sync_options.print_unison_cmd {
+            moves::apply(runner, config, host)?;
+        }
+        let excludes = versions::rsync_excludes(&plan);
+        if !excludes.is_empty() {
+            warn!(
+                "Not pushing {} file(s) that are newer on {}",
+                excludes.len(),
+                host.name
+            );
+        }
+        let mut rsync_args = excludes;
+        rsync_args.extend(ignores.iter().filter_map(|i| rsync_exclude(i)));
+        if sync_options.checksum {
+            rsync_args.push("--checksum".to_string());
+        }
+        let mut success = true;
+        for paths in &passes {
+            success = rsync(
+                runner,
+                config,
+                host,
+                sync_options.print_unison_cmd,
+                sync_options.jobs,
+                paths,
+                &rsync_args,
+            )?;
+            if !success {
+                break;
+            }
+        }
+        if success {
+            moves::record(config, host)?;
+        }
+        success
+    } else {
+        let mut preferences =
+            versions::unison_preferences(&plan, &config.root, &remote_root(config, host));
+        for ignore in &ignores {
+            preferences.extend(["-ignore".to_string(), ignore.clone()]);
+        }
+        // Unison would write out every hole, so these go by rsync afterwards
+        let sparse_files = if host.sparse && !sync_options.print_unison_cmd {
+            sparse::sparse_files(config)?
+                .into_iter()
+                .filter(|file| in_run(file, sync_options, &ignores))
+                .filter(|file| !disk_images.contains(file))
+                .collect()
+        } else {
+            Vec::new()
+        };
+        for file in sparse_files.iter().chain(&disk_images) {
+            preferences.extend(["-ignore".to_string(), format!("Path {}", file)]);
+        }
+        let mut success = true;
+        match tracking.filter(|_| sync_options.checksum) {
+            // Only the files whose contents differ need fingerprinting
+            Some(synctool) => {
+                let differing = checksum::differing(runner, config, host, synctool)?;
+                if !differing.is_empty() {
+                    log!("{} file(s) differ in content", differing.len());
+                    let mut args = preferences.clone();
+                    args.extend(["-fastcheck".to_string(), "false".to_string()]);
+                    for path in differing {
+                        args.extend(["-path".to_string(), path]);
+                    }
+                    success = unison(
+                        runner,
+                        config,
+                        host,
+                        sync_options.interactive,
+                        sync_options.print_unison_cmd,
+                        &args,
+                    )?;
+                }
+            }
+            None if sync_options.checksum => {
+                preferences.extend(["-fastcheck".to_string(), "false".to_string()]);
+            }
+            None => {}
+        }
+        for paths in &passes {
+            if !success {
+                break;
+            }
+            let mut args = preferences.clone();
+            for path in paths {
+                args.extend(["-path".to_string(), path.clone()]);
+            }
+            success = unison(
+                runner,
+                config,
+                host,
+                sync_options.interactive,
+                sync_options.print_unison_cmd,
+                &args,
+            )?;
+        }
+        if success && !sparse_files.is_empty() {
+            success = sparse::transfer(runner, config, host, &sparse_files)?;
+        }
+        if success && !disk_images.is_empty() {
+            success = images::transfer(runner, config, host, &disk_images, config.disk_images)?;
+        }
+        success
+    };
+
+    if let (true, Some(synctool)) = (success, tracking) {
+        // The files did sync, so this isn't worth failing the run over
+        if let Err(err) = versions::after_sync(runner, config, host, synctool) {
+            warn!("Couldn't record file versions: {:#}", err);
+        }
+    }
+    // A single rsync keeps the links itself
+    let relinking = host.hard_links && !(use_rsync && sync_options.jobs <= 1);
+    if success && relinking && !sync_options.print_unison_cmd {
+        if let Err(err) = links::relink(runner, config, host) {
+            warn!("Couldn't keep hard links: {:#}", err);
+        }
+    }
+    if success && !host.after_sync.is_empty() && !sync_options.print_unison_cmd {
+        hooks::run(runner, config, host)?;
+    }
+    Ok(success)
+}
+
+// Compares the host's clock with this one. Unison goes by modification
+// times, so a clock that's off makes it see changes that aren't there and pick
+// the wrong side as newer. If the host can't be asked, the sync finds out.
+fn check_clock(runner: &dyn Runner, config: &Config, host: &Host) -> Result<()> {
+    let now = || {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |time| time.as_secs() as i64)
+    };
+    let before = now();
+    let output = runner.output(ssh(host).args(["date", "+%s"]).stdin(Stdio::null()))?;
+    let after = now();
+    let remote = match String::from_utf8_lossy(&output.stdout)
+        .trim()
+        .parse::<i64>()
+    {
+        Ok(remote) if output.status.success() => remote,
+        _ => return Ok(()),
+    };
+
+    // Seconds the remote is ahead, against the middle of the round trip
+    let skew = remote - (before + after) / 2;
+    let off = skew.unsigned_abs();
+    let direction = if skew > 0 { "ahead of" } else { "behind" };
+    debug!("{}'s clock is {:+}s from this one", host.name, skew);
+    ensure!(
+        config.max_clock_skew == 0 || off <= config.max_clock_skew,
+        "{}'s clock is {}s {} this one's, fix it before syncing (or raise max_clock_skew)",
+        host.name,
+        off,
+        direction
+    );
+    if off > config.warn_clock_skew {
+        warn!(
+            "{}'s clock is {}s {} this one's, unison may see changes that aren't there",
+            host.name, off, direction
+        );
+    }
+    Ok(())
+}
+
+// Whether a file under the root is part of this run, being within its paths
+// and not ignored
+fn in_run(file: &str, sync_options: &SyncOptions, ignores: &[String]) -> bool {
+    let within = sync_options.paths.is_empty()
+        || sync_options
+            .paths
+            .iter()
+            .any(|path| file == path || file.starts_with(&format!("{}/", path)));
+    within && !ignores.iter().any(|ignore| ignore_matches(ignore, file))
+}
+
+// Ignores for the symlinks the host's symlinks setting leaves out, after
+// saying which they are
+fn skipped_symlinks(config: &Config, host: &Host) -> Result<Vec<String>> {
+    let absolute_only = match host.symlinks {
+        Symlinks::Skip => false,
+        Symlinks::SkipAbsolute => true,
+        Symlinks::Copy | Symlinks::Follow => return Ok(Vec::new()),
+    };
+    let skipped = links::symlinks(config)?
+        .into_iter()
+        .filter(|(_, target)| !absolute_only || target.is_absolute())
+        .collect::<Vec<_>>();
+    if !skipped.is_empty() {
+        warn!(
+            "Not syncing {} symlink(s) with {}:",
+            skipped.len(),
+            host.name
+        );
+        for (path, target) in &skipped {
+            warn!("  {} -> {}", path, target.display());
+        }
+    }
+    Ok(skipped
+        .into_iter()
+        .map(|(path, _)| format!("Path {}", path))
+        .collect())
+}
+
+// Tries storing an extended attribute and an ACL on a scratch file under the
+// host's root, if those are to be synced, and leaves out whichever its
+// filesystem can't take for this run, rather than have every file fail.
+fn check_metadata_support(runner: &dyn Runner, config: &Config, host: &mut Host) -> Result<()> {
+    if !host.xattrs && !host.acls {
+        return Ok(());
+    }
+    let root = host.root(config).to_string();
+    let script = format!(
+        "f={}/.synctool-probe-$$; touch \"$f\" || exit 1; \
+         setfattr -n user.synctool -v 1 \"$f\" 2>/dev/null && echo xattrs yes || echo xattrs no; \
+         setfacl -m u:$(id -u):rw \"$f\" 2>/dev/null && echo acls yes || echo acls no; \
+         rm -f \"$f\"",
+        shell_quote(&root)
+    );
+    let output = runner.output(ssh(host).arg(script).stdin(Stdio::null()))?;
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    for line in stdout.lines() {
+        let (enabled, what) = match line.trim() {
+            "xattrs no" => (&mut host.xattrs, "extended attributes"),
+            "acls no" => (&mut host.acls, "ACLs"),
+            _ => continue,
+        };
+        if *enabled {
+            warn!(
+                "{} can't store {} under {} (or lacks the tools to set them), not syncing them",
+                host.name, what, root
+            );
+            *enabled = false;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::runner::{MockRunner, Reply};
+
+    const WAKE: &str = "ssh -o ConnectTimeout=8 10.13.13.6 ~/wake-computinator.sh";
+    const PING: &str = "ping -c 3 -i 0.2 -W 1 10.13.13.4";
+
+    fn options(local_power: PowerAction, remote_power: PowerAction) -> SyncOptions {
+        SyncOptions {
+            local_power,
+            remote_power,
+            ..SyncOptions::default()
+        }
+    }
+
+    // Commands other than the checks before syncing (unison versions, the
+    // clock and the remote filesystem), before shutting down (the power
+    // source) and looking for a local power backend, with the unison runs
+    // shortened to just "unison".
+    fn actions(runner: &MockRunner) -> Vec<String> {
+        runner
+            .commands()
+            .into_iter()
+            .filter(|line| {
+                !line.ends_with("-version")
+                    && !line.ends_with("date +%s")
+                    && !line.contains("/.synctool-")
+                    && !line.ends_with("/uevent")
+                    && !line.starts_with("sh -c test -d")
+                    && !line.starts_with("sh -c command -v")
+            })
+            .map(|line| {
+                if line.starts_with("unison ") {
+                    "unison".to_string()
+                } else {
+                    line
+                }
+            })
+            .collect()
+    }
+
+    #[test]
+    fn retries_after_waking() {
+        let runner = MockRunner::new();
+        runner.script("unison -auto", &[1, 0]);
+        let config = Config::default();
+
+        sync_laptop_to_desktop(&runner, &config, &options(Suspend, Shutdown)).unwrap();
+        assert_eq!(
+            actions(&runner),
+            [
+                "unison",
+                WAKE,
+                PING,
+                "unison",
+                "ssh -o ConnectTimeout=8 10.13.13.4 sync",
+                "ssh -o ConnectTimeout=8 10.13.13.4 sudo shutdown now",
+                "systemctl suspend"
+            ]
+        );
+    }
+
+    #[test]
+    fn no_power_actions_after_failure() {
+        let runner = MockRunner::new();
+        runner.script("unison -auto", &[1]);
+        let config = Config::default();
+
+        let result = sync_laptop_to_desktop(&runner, &config, &options(Shutdown, Shutdown));
+        assert!(result.is_err());
+        assert_eq!(actions(&runner), ["unison", WAKE, PING, "unison"]);
+    }
+
+    #[test]
+    fn wakes_when_versions_are_unreachable() {
+        let runner = MockRunner::new();
+        let versions = "ssh -o ConnectTimeout=8 10.13.13.4 synctool versions --scan";
+        runner.script(versions, &[255]);
+        let mut config = Config::default();
+        config.hosts[1].synctool = Some("synctool".to_string());
+
+        let result = sync_laptop_to_desktop(&runner, &config, &options(Nothing, Nothing));
+        assert!(result.is_err());
+        assert_eq!(actions(&runner), [versions, WAKE, PING, versions]);
+    }
+
+    #[test]
+    fn wakes_when_the_agent_is_unreachable() {
+        let runner = MockRunner::new();
+        let agent = "ssh -o ConnectTimeout=8 10.13.13.4 synctool agent";
+        runner.script(agent, &[255, 0]);
+        let mut config = Config::default();
+        config.hosts[1].agent = Some("synctool agent".to_string());
+
+        sync_laptop_to_desktop(&runner, &config, &options(Nothing, Nothing)).unwrap();
+        assert_eq!(
+            actions(&runner),
+            // Once woken it is asked when it booted, then locked
+            [agent, WAKE, PING, agent, agent, "unison"]
+        );
+    }
+
+    #[test]
+    fn skip_sync_still_wakes() {
+        let runner = MockRunner::new();
+        let config = Config::default();
+        let sync_options = SyncOptions {
+            skip_sync: true,
+            ..options(Shutdown, Suspend)
+        };
+
+        sync_laptop_to_desktop(&runner, &config, &sync_options).unwrap();
+        assert_eq!(
+            actions(&runner),
+            [
+                WAKE,
+                PING,
+                "ssh -o ConnectTimeout=8 10.13.13.4 sudo systemctl suspend",
+                "systemctl poweroff"
+            ]
+        );
+    }
+
+    #[test]
+    fn other_hosts_are_not_woken() {
+        let runner = MockRunner::new();
+        runner.script("unison -auto", &[1]);
+        let config = Config::default();
+        let laptop = config.host("laptop").unwrap();
+
+        let result = sync_to_host(&runner, &config, laptop, &options(Shutdown, Nothing));
+        assert!(result.is_err());
+        assert_eq!(actions(&runner), ["unison"]);
+    }
+
+    #[test]
+    fn relays_instead_of_waking() {
+        let runner = MockRunner::new();
+        runner.script("unison -auto", &[1, 0]);
+        let mut config = Config::default();
+        config.hosts[1].relay = Some("rpi".to_string());
+
+        sync_laptop_to_desktop(&runner, &config, &options(Suspend, Shutdown)).unwrap();
+        assert_eq!(actions(&runner), ["unison", "unison", "systemctl suspend"]);
+        assert!(runner
+            .commands()
+            .iter()
+            .any(|line| line.starts_with("unison ") && line.contains("ssh://10.13.13.6/")));
+    }
+
+    #[test]
+    fn restores_power_state() {
+        let runner = MockRunner::new();
+        runner.script("unison -auto", &[0, 1, 0]);
+        runner.script_replies(
+            "ipmitool -I lanplus -H 10.13.13.9 -U admin -E chassis power status",
+            vec![Reply {
+                code: 0,
+                stdout: "Chassis Power is off\n".to_string(),
+            }],
+        );
+        let config = Config::parse(
+            "[hosts.nas]\naddress = \"10.13.13.8\"\npower_method = \"ipmi\"\nbmc_address = \"10.13.13.9\"\n",
+        )
+        .unwrap();
+        let nas = config.host("nas").unwrap();
+        let sync_options = SyncOptions {
+            restore_power: true,
+            ..options(Nothing, Suspend)
+        };
+
+        // Awake already, so it's left on
+        sync_waking_host(&runner, &config, nas, &sync_options).unwrap();
+        assert_eq!(actions(&runner), ["unison"]);
+
+        sync_waking_host(&runner, &config, nas, &sync_options).unwrap();
+        assert_eq!(
+            actions(&runner)[1..],
+            [
+                "unison",
+                "ipmitool -I lanplus -H 10.13.13.9 -U admin -E chassis power status",
+                "ipmitool -I lanplus -H 10.13.13.9 -U admin -E chassis power on",
+                "ping -c 3 -i 0.2 -W 1 10.13.13.8",
+                "unison",
+                "ssh -o ConnectTimeout=8 10.13.13.8 sync",
+                "ipmitool -I lanplus -H 10.13.13.9 -U admin -E chassis power soft"
+            ]
+        );
+    }
+
+    #[test]
+    fn escalates_as_configured() {
+        let runner = MockRunner::new();
+        let config = Config::parse(
+            "[hosts.rpi]\npower_method = \"doas\"\nshutdown_command = \"poweroff\"\nsuspend_command = \"zzz\"\n",
+        )
+        .unwrap();
+        let rpi = config.host("rpi").unwrap();
+
+        sync_to_host(&runner, &config, rpi, &options(Nothing, Shutdown)).unwrap();
+        sync_to_host(&runner, &config, rpi, &options(Nothing, Suspend)).unwrap();
+        assert_eq!(
+            actions(&runner),
+            [
+                "unison",
+                "ssh -o ConnectTimeout=8 10.13.13.6 sync",
+                "ssh -o ConnectTimeout=8 10.13.13.6 doas poweroff",
+                "unison",
+                "ssh -o ConnectTimeout=8 10.13.13.6 doas zzz"
+            ]
+        );
+    }
+
+    #[test]
+    fn uses_local_power_backend() {
+        let runner = MockRunner::new();
+        let laptop = Config::default();
+        let laptop = laptop.host("laptop").unwrap();
+        let suspend = options(Suspend, Nothing);
+        let config = |power: &str| Config::parse(&format!("[power]\n{}\n", power)).unwrap();
+
+        sync_to_host(&runner, &config("backend = \"pm-utils\""), laptop, &suspend).unwrap();
+        sync_to_host(
+            &runner,
+            &config("suspend_command = \"slp\""),
+            laptop,
+            &suspend,
+        )
+        .unwrap();
+        let shutdown_only = config("backend = \"shutdown\"");
+        assert!(sync_to_host(&runner, &shutdown_only, laptop, &suspend).is_err());
+        sync_to_host(&runner, &shutdown_only, laptop, &options(Shutdown, Nothing)).unwrap();
+        assert_eq!(
+            actions(&runner),
+            [
+                "unison",
+                "pm-suspend",
+                "unison",
+                "sh -c slp",
+                "unison",
+                "unison",
+                "shutdown -h now"
+            ]
+        );
+    }
+
+    #[test]
+    fn wakes_configured_hosts() {
+        let runner = MockRunner::new();
+        runner.script("unison -auto", &[1, 0]);
+        let config = Config::parse(
+            "[hosts.nas]\naddress = \"10.13.13.8\"\nwake_command = \"wakeonlan 00:11:22:33:44:55\"\n",
+        )
+        .unwrap();
+        let nas = config.host("nas").unwrap();
+
+        sync_waking_host(&runner, &config, nas, &options(Nothing, Nothing)).unwrap();
+        assert_eq!(
+            actions(&runner),
+            [
+                "unison",
+                "sh -c wakeonlan 00:11:22:33:44:55",
+                "ping -c 3 -i 0.2 -W 1 10.13.13.8",
+                "unison"
+            ]
+        );
+    }
+
+    #[test]
+    fn syncs_priority_projects_first() {
+        let runner = MockRunner::new();
+        let config = Config::parse(
+            "[projects.notes]\npriority = true\n[projects.android]\ninterval = \"1h\"\n",
+        )
+        .unwrap();
+
+        sync_desktop_to_laptop(&runner, &config, &options(Nothing, Nothing)).unwrap();
+        let runs = runner
+            .commands()
+            .into_iter()
+            .filter(|line| line.starts_with("unison -auto"))
+            .collect::<Vec<_>>();
+        assert_eq!(runs.len(), 2);
+        assert!(runs[0].contains("-path notes"), "{}", runs[0]);
+        assert!(!runs[1].contains("-path"), "{}", runs[1]);
+    }
+
+    #[test]
+    fn refuses_to_sync_with_a_skewed_clock() {
+        let runner = MockRunner::new();
+        runner.script_replies(
+            "ssh -o ConnectTimeout=8 10.13.13.3 date",
+            vec![Reply {
+                code: 0,
+                stdout: "1000000000\n".to_string(),
+            }],
+        );
+        let mut config = Config::parse("[sync]\nmax_clock_skew = \"2m\"\n").unwrap();
+
+        let result = sync_desktop_to_laptop(&runner, &config, &options(Nothing, Nothing));
+        assert!(result.unwrap_err().to_string().contains("behind"));
+        assert!(actions(&runner).is_empty());
+
+        config.max_clock_skew = 0;
+        sync_desktop_to_laptop(&runner, &config, &options(Nothing, Nothing)).unwrap();
+        assert_eq!(actions(&runner), ["unison"]);
+    }
+
+    #[test]
+    fn leaves_out_metadata_the_remote_cant_store() {
+        let runner = MockRunner::new();
+        runner.script_replies(
+            "ssh -o ConnectTimeout=8 10.13.13.3 f=",
+            vec![Reply {
+                code: 0,
+                stdout: "xattrs no\nacls yes\n".to_string(),
+            }],
+        );
+        let config = Config::parse("[hosts.laptop]\nxattrs = true\nacls = true\n").unwrap();
+
+        sync_desktop_to_laptop(&runner, &config, &options(Nothing, Nothing)).unwrap();
+        let unison = runner
+            .commands()
+            .into_iter()
+            .find(|line| line.starts_with("unison -auto"))
+            .unwrap();
+        assert!(
+            unison.contains("-acl") && !unison.contains("-xattrs"),
+            "{}",
+            unison
+        );
+    }
+
+    #[test]
+    fn falls_back_to_the_cloud() {
+        let runner = MockRunner::new();
+        runner.script("unison -auto", &[1]);
+        let config = Config {
+            cloud_remote: Some("b2:sync".to_string()),
+            ..Config::default()
+        };
+
+        let result = sync_laptop_to_desktop(&runner, &config, &options(Shutdown, Nothing));
+        assert!(result.is_err());
+        let actions = actions(&runner);
+        assert_eq!(actions[0], "rclone lsf --dirs-only b2:sync");
+        assert_eq!(actions[1..5], ["unison", WAKE, PING, "unison"]);
+        assert!(actions[5].starts_with("rclone copy --update /home/user/prog b2:sync/"));
+        assert_eq!(actions.len(), 6);
+    }
+}
@@ -0,0 +1,292 @@
+// Syncthing as the backend for hosts with syncthing_device set, like a phone
+// that has no ssh or a pair of always-on machines that keep each other up to
+// date by themselves. Nothing runs on the host: a sync makes sure the local
+// Syncthing shares the root with every such device as [syncthing] folder,
+// with the ignores, then has it rescan and waits until the device has
+// everything. Its REST API is called with curl.
+
+use crate::{
+    config::{Config, Host},
+    events::json_field,
+    rsync::rsync_exclude,
+    runner::Runner,
+};
+use eyre::{eyre, Result};
+use std::{
+    io::{Read, Write},
+    process::{Command, Stdio},
+    thread,
+    time::{Duration, Instant},
+};
+
+// Calls the API, returning the response or None if it answered with an error,
+// like 404 for a device or folder that isn't configured
+fn request(
+    runner: &dyn Runner,
+    config: &Config,
+    method: &str,
+    path: &str,
+    body: Option<&str>,
+) -> Result<Option<String>> {
+    let key = config
+        .syncthing_api_key
+        .as_deref()
+        .ok_or_else(|| eyre!("Set api_key in the [syncthing] section of the config"))?;
+    let mut command = Command::new("curl");
+    command
+        .args(["-X", method, &format!("{}{}", config.syncthing_api, path)])
+        .args(["-sS", "-f", "--max-time", "30"])
+        // On stdin rather than the command line, where other users can see it
+        .args(["-H", "@-"])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped());
+    let mut headers = format!("X-API-Key: {}\n", key);
+    if let Some(body) = body {
+        headers.push_str("Content-Type: application/json\n");
+        command.args(["--data-binary", body]);
+    }
+
+    let mut process = runner.spawn(&mut command)?;
+    if let Some(mut stdin) = process.take_stdin() {
+        stdin.write_all(headers.as_bytes())?;
+    }
+    let mut response = String::new();
+    if let Some(mut stdout) = process.take_stdout() {
+        stdout.read_to_string(&mut response)?;
+    }
+    match process.wait()?.code() {
+        Some(0) => Ok(Some(response)),
+        // curl -f's exit code for an HTTP error
+        Some(22) => Ok(None),
+        _ => Err(eyre!(
+            "Couldn't reach Syncthing at {}",
+            config.syncthing_api
+        )),
+    }
+}
+
+// Syncthing's ignore patterns are close enough to rsync's to share them
+fn ignore_patterns(config: &Config) -> Vec<String> {
+    config
+        .ignores
+        .iter()
+        .filter_map(|ignore| rsync_exclude(ignore))
+        .map(|exclude| exclude.trim_start_matches("--exclude=").to_string())
+        .collect()
+}
+
+fn quote(s: &str) -> String {
+    format!("\"{}\"", s.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+// Adds the devices of the hosts synced through Syncthing that it doesn't know
+// yet, shares the folder with all of them and sets its ignores
+pub fn configure(runner: &dyn Runner, config: &Config) -> Result<()> {
+    let hosts = config
+        .hosts
+        .iter()
+        .filter_map(|host| Some((host, host.syncthing_device.as_deref()?)))
+        .collect::<Vec<_>>();
+    for (host, device) in &hosts {
+        let path = format!("/rest/config/devices/{}", device);
+        if request(runner, config, "GET", &path, None)?.is_none() {
+            let body = format!(
+                "{{\"deviceID\":{},\"name\":{},\"addresses\":[\"dynamic\"]}}",
+                quote(device),
+                quote(&host.name)
+            );
+            request(runner, config, "PUT", &path, Some(&body))?
+                .ok_or_else(|| eyre!("Syncthing wouldn't add {}", host.name))?;
+            log!("Added {} to Syncthing", host.name);
+        }
+    }
+
+    let devices = hosts
+        .iter()
+        .map(|(_, device)| format!("{{\"deviceID\":{}}}", quote(device)))
+        .collect::<Vec<_>>()
+        .join(",");
+    let folder = &config.syncthing_folder;
+    let path = format!("/rest/config/folders/{}", folder);
+    let (method, body) = match request(runner, config, "GET", &path, None)? {
+        Some(_) => ("PATCH", format!("{{\"devices\":[{}]}}", devices)),
+        None => (
+            "PUT",
+            format!(
+                "{{\"id\":{},\"label\":\"synctool\",\"path\":{},\"devices\":[{}]}}",
+                quote(folder),
+                quote(&config.root),
+                devices
+            ),
+        ),
+    };
+    request(runner, config, method, &path, Some(&body))?
+        .ok_or_else(|| eyre!("Syncthing wouldn't share {}", config.root))?;
+
+    let ignores = ignore_patterns(config)
+        .iter()
+        .map(|pattern| quote(pattern))
+        .collect::<Vec<_>>()
+        .join(",");
+    let path = format!("/rest/db/ignores?folder={}", folder);
+    request(
+        runner,
+        config,
+        "POST",
+        &path,
+        Some(&format!("{{\"ignore\":[{}]}}", ignores)),
+    )?
+    .ok_or_else(|| eyre!("Syncthing wouldn't take the ignores"))?;
+    Ok(())
+}
+
+// Syncs with a host through Syncthing: configures it, rescans the folder and
+// waits up to [syncthing] timeout for the device to have everything. Returns
+// Ok(true) if it does by then.
+pub fn sync(runner: &dyn Runner, config: &Config, host: &Host, print: bool) -> Result<bool> {
+    let device = host.syncthing_device.as_deref().unwrap_or_default();
+    if print {
+        log!(
+            "{} syncs through Syncthing at {}, as device {}",
+            host.name,
+            config.syncthing_api,
+            device
+        );
+        return Ok(true);
+    }
+
+    configure(runner, config)?;
+    let folder = &config.syncthing_folder;
+    request(
+        runner,
+        config,
+        "POST",
+        &format!("/rest/db/scan?folder={}", folder),
+        None,
+    )?
+    .ok_or_else(|| eyre!("Syncthing wouldn't rescan {}", folder))?;
+
+    let deadline = Instant::now() + Duration::from_secs(config.syncthing_timeout);
+    let mut waiting_to_connect = false;
+    loop {
+        let connections =
+            request(runner, config, "GET", "/rest/system/connections", None)?.unwrap_or_default();
+        let connected = connections
+            .find(&format!("\"{}\":", device))
+            .and_then(|at| json_field(&connections[at..], "connected"))
+            == Some("true");
+        if !connected && !waiting_to_connect {
+            log!("Waiting for {} to connect to Syncthing", host.name);
+            waiting_to_connect = true;
+        }
+
+        // Only once the scan here is done does the completion count it
+        let status = request(
+            runner,
+            config,
+            "GET",
+            &format!("/rest/db/status?folder={}", folder),
+            None,
+        )?
+        .unwrap_or_default();
+        if connected && json_field(&status, "state") == Some("idle") {
+            let completion = request(
+                runner,
+                config,
+                "GET",
+                &format!("/rest/db/completion?folder={}&device={}", folder, device),
+                None,
+            )?
+            .unwrap_or_default();
+            let need = json_field(&completion, "needBytes").and_then(|n| n.parse::<u64>().ok());
+            let need_deletes =
+                json_field(&completion, "needDeletes").and_then(|n| n.parse::<u64>().ok());
+            if need == Some(0) && need_deletes.unwrap_or(0) == 0 {
+                log!("{} has everything", host.name);
+                return Ok(true);
+            }
+        }
+
+        if Instant::now() >= deadline {
+            warn!(
+                "{} didn't catch up through Syncthing within {}s",
+                host.name, config.syncthing_timeout
+            );
+            return Ok(false);
+        }
+        if !runner.simulated() {
+            thread::sleep(Duration::from_secs(2));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::runner::{MockRunner, Reply};
+
+    fn reply(stdout: &str) -> Vec<Reply> {
+        vec![Reply {
+            code: 0,
+            stdout: stdout.to_string(),
+        }]
+    }
+
+    #[test]
+    fn syncs_through_syncthing() {
+        let mut config = Config {
+            syncthing_api_key: Some("key".to_string()),
+            ..Config::default()
+        };
+        config.hosts[0].syncthing_device = Some("PHONE".to_string());
+        let api = "http://127.0.0.1:8384";
+
+        let runner = MockRunner::new();
+        runner.script(
+            &format!("curl -X GET {}/rest/config/devices/PHONE", api),
+            &[22],
+        );
+        runner.script(&format!("curl -X GET {}/rest/config/folders", api), &[0]);
+        runner.script_replies(
+            &format!("curl -X GET {}/rest/system/connections", api),
+            reply("{\"connections\":{\"PHONE\":{\"at\":\"\",\"connected\":true}}}"),
+        );
+        runner.script_replies(
+            &format!("curl -X GET {}/rest/db/status", api),
+            reply("{\"errors\":0,\"state\":\"idle\"}"),
+        );
+        runner.script_replies(
+            &format!("curl -X GET {}/rest/db/completion", api),
+            vec![
+                Reply {
+                    code: 0,
+                    stdout: "{\"completion\":95.2,\"needBytes\":1024,\"needDeletes\":0}"
+                        .to_string(),
+                },
+                Reply {
+                    code: 0,
+                    stdout: "{\"completion\":100,\"needBytes\":0,\"needDeletes\":0}".to_string(),
+                },
+            ],
+        );
+        let laptop = config.host("laptop").unwrap();
+        assert!(sync(&runner, &config, laptop, false).unwrap());
+
+        let requests = runner
+            .commands()
+            .iter()
+            .filter(|line| !line.contains(" GET "))
+            .map(|line| line.split(" -sS").next().unwrap().to_string())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            requests,
+            [
+                format!("curl -X PUT {}/rest/config/devices/PHONE", api),
+                format!("curl -X PATCH {}/rest/config/folders/synctool", api),
+                format!("curl -X POST {}/rest/db/ignores?folder=synctool", api),
+                format!("curl -X POST {}/rest/db/scan?folder=synctool", api),
+            ]
+        );
+        assert_eq!(ignore_patterns(&config)[0], "*.class");
+    }
+}
@@ -0,0 +1,312 @@
+// Filenames spelled in NFD, as macOS writes them, where Linux uses NFC: "é"
+// as "e" followed by a combining acute accent rather than the one character.
+// Unison takes the two spellings for different files, so a name made on the
+// Mac ends up twice here, or goes back and forth on every sync. Hosts with
+// normalize_names get the names under the root respelled in NFC before each
+// sync. Only Latin letters with accents are composed, which is what shows up
+// in filenames; anything else is left as it is.
+
+use crate::{config::Config, ignore::ignored};
+use eyre::{Result, WrapErr};
+use std::{fs, io::ErrorKind, path::Path};
+
+// (letter, combining mark, the two composed), sorted for binary search.
+// Generated from the Unicode data for U+00C0-U+024F and U+1E00-U+1EFF.
+#[rustfmt::skip]
+const COMPOSITIONS: &[(char, char, char)] = &[
+    ('A', '\u{300}', '\u{C0}'), ('A', '\u{301}', '\u{C1}'), ('A', '\u{302}', '\u{C2}'),
+    ('A', '\u{303}', '\u{C3}'), ('A', '\u{304}', '\u{100}'), ('A', '\u{306}', '\u{102}'),
+    ('A', '\u{307}', '\u{226}'), ('A', '\u{308}', '\u{C4}'), ('A', '\u{309}', '\u{1EA2}'),
+    ('A', '\u{30A}', '\u{C5}'), ('A', '\u{30C}', '\u{1CD}'), ('A', '\u{30F}', '\u{200}'),
+    ('A', '\u{311}', '\u{202}'), ('A', '\u{323}', '\u{1EA0}'), ('A', '\u{325}', '\u{1E00}'),
+    ('A', '\u{328}', '\u{104}'), ('B', '\u{307}', '\u{1E02}'), ('B', '\u{323}', '\u{1E04}'),
+    ('B', '\u{331}', '\u{1E06}'), ('C', '\u{301}', '\u{106}'), ('C', '\u{302}', '\u{108}'),
+    ('C', '\u{307}', '\u{10A}'), ('C', '\u{30C}', '\u{10C}'), ('C', '\u{327}', '\u{C7}'),
+    ('D', '\u{307}', '\u{1E0A}'), ('D', '\u{30C}', '\u{10E}'), ('D', '\u{323}', '\u{1E0C}'),
+    ('D', '\u{327}', '\u{1E10}'), ('D', '\u{32D}', '\u{1E12}'), ('D', '\u{331}', '\u{1E0E}'),
+    ('E', '\u{300}', '\u{C8}'), ('E', '\u{301}', '\u{C9}'), ('E', '\u{302}', '\u{CA}'),
+    ('E', '\u{303}', '\u{1EBC}'), ('E', '\u{304}', '\u{112}'), ('E', '\u{306}', '\u{114}'),
+    ('E', '\u{307}', '\u{116}'), ('E', '\u{308}', '\u{CB}'), ('E', '\u{309}', '\u{1EBA}'),
+    ('E', '\u{30C}', '\u{11A}'), ('E', '\u{30F}', '\u{204}'), ('E', '\u{311}', '\u{206}'),
+    ('E', '\u{323}', '\u{1EB8}'), ('E', '\u{327}', '\u{228}'), ('E', '\u{328}', '\u{118}'),
+    ('E', '\u{32D}', '\u{1E18}'), ('E', '\u{330}', '\u{1E1A}'), ('F', '\u{307}', '\u{1E1E}'),
+    ('G', '\u{301}', '\u{1F4}'), ('G', '\u{302}', '\u{11C}'), ('G', '\u{304}', '\u{1E20}'),
+    ('G', '\u{306}', '\u{11E}'), ('G', '\u{307}', '\u{120}'), ('G', '\u{30C}', '\u{1E6}'),
+    ('G', '\u{327}', '\u{122}'), ('H', '\u{302}', '\u{124}'), ('H', '\u{307}', '\u{1E22}'),
+    ('H', '\u{308}', '\u{1E26}'), ('H', '\u{30C}', '\u{21E}'), ('H', '\u{323}', '\u{1E24}'),
+    ('H', '\u{327}', '\u{1E28}'), ('H', '\u{32E}', '\u{1E2A}'), ('I', '\u{300}', '\u{CC}'),
+    ('I', '\u{301}', '\u{CD}'), ('I', '\u{302}', '\u{CE}'), ('I', '\u{303}', '\u{128}'),
+    ('I', '\u{304}', '\u{12A}'), ('I', '\u{306}', '\u{12C}'), ('I', '\u{307}', '\u{130}'),
+    ('I', '\u{308}', '\u{CF}'), ('I', '\u{309}', '\u{1EC8}'), ('I', '\u{30C}', '\u{1CF}'),
+    ('I', '\u{30F}', '\u{208}'), ('I', '\u{311}', '\u{20A}'), ('I', '\u{323}', '\u{1ECA}'),
+    ('I', '\u{328}', '\u{12E}'), ('I', '\u{330}', '\u{1E2C}'), ('J', '\u{302}', '\u{134}'),
+    ('K', '\u{301}', '\u{1E30}'), ('K', '\u{30C}', '\u{1E8}'), ('K', '\u{323}', '\u{1E32}'),
+    ('K', '\u{327}', '\u{136}'), ('K', '\u{331}', '\u{1E34}'), ('L', '\u{301}', '\u{139}'),
+    ('L', '\u{30C}', '\u{13D}'), ('L', '\u{323}', '\u{1E36}'), ('L', '\u{327}', '\u{13B}'),
+    ('L', '\u{32D}', '\u{1E3C}'), ('L', '\u{331}', '\u{1E3A}'), ('M', '\u{301}', '\u{1E3E}'),
+    ('M', '\u{307}', '\u{1E40}'), ('M', '\u{323}', '\u{1E42}'), ('N', '\u{300}', '\u{1F8}'),
+    ('N', '\u{301}', '\u{143}'), ('N', '\u{303}', '\u{D1}'), ('N', '\u{307}', '\u{1E44}'),
+    ('N', '\u{30C}', '\u{147}'), ('N', '\u{323}', '\u{1E46}'), ('N', '\u{327}', '\u{145}'),
+    ('N', '\u{32D}', '\u{1E4A}'), ('N', '\u{331}', '\u{1E48}'), ('O', '\u{300}', '\u{D2}'),
+    ('O', '\u{301}', '\u{D3}'), ('O', '\u{302}', '\u{D4}'), ('O', '\u{303}', '\u{D5}'),
+    ('O', '\u{304}', '\u{14C}'), ('O', '\u{306}', '\u{14E}'), ('O', '\u{307}', '\u{22E}'),
+    ('O', '\u{308}', '\u{D6}'), ('O', '\u{309}', '\u{1ECE}'), ('O', '\u{30B}', '\u{150}'),
+    ('O', '\u{30C}', '\u{1D1}'), ('O', '\u{30F}', '\u{20C}'), ('O', '\u{311}', '\u{20E}'),
+    ('O', '\u{31B}', '\u{1A0}'), ('O', '\u{323}', '\u{1ECC}'), ('O', '\u{328}', '\u{1EA}'),
+    ('P', '\u{301}', '\u{1E54}'), ('P', '\u{307}', '\u{1E56}'), ('R', '\u{301}', '\u{154}'),
+    ('R', '\u{307}', '\u{1E58}'), ('R', '\u{30C}', '\u{158}'), ('R', '\u{30F}', '\u{210}'),
+    ('R', '\u{311}', '\u{212}'), ('R', '\u{323}', '\u{1E5A}'), ('R', '\u{327}', '\u{156}'),
+    ('R', '\u{331}', '\u{1E5E}'), ('S', '\u{301}', '\u{15A}'), ('S', '\u{302}', '\u{15C}'),
+    ('S', '\u{307}', '\u{1E60}'), ('S', '\u{30C}', '\u{160}'), ('S', '\u{323}', '\u{1E62}'),
+    ('S', '\u{326}', '\u{218}'), ('S', '\u{327}', '\u{15E}'), ('T', '\u{307}', '\u{1E6A}'),
+    ('T', '\u{30C}', '\u{164}'), ('T', '\u{323}', '\u{1E6C}'), ('T', '\u{326}', '\u{21A}'),
+    ('T', '\u{327}', '\u{162}'), ('T', '\u{32D}', '\u{1E70}'), ('T', '\u{331}', '\u{1E6E}'),
+    ('U', '\u{300}', '\u{D9}'), ('U', '\u{301}', '\u{DA}'), ('U', '\u{302}', '\u{DB}'),
+    ('U', '\u{303}', '\u{168}'), ('U', '\u{304}', '\u{16A}'), ('U', '\u{306}', '\u{16C}'),
+    ('U', '\u{308}', '\u{DC}'), ('U', '\u{309}', '\u{1EE6}'), ('U', '\u{30A}', '\u{16E}'),
+    ('U', '\u{30B}', '\u{170}'), ('U', '\u{30C}', '\u{1D3}'), ('U', '\u{30F}', '\u{214}'),
+    ('U', '\u{311}', '\u{216}'), ('U', '\u{31B}', '\u{1AF}'), ('U', '\u{323}', '\u{1EE4}'),
+    ('U', '\u{324}', '\u{1E72}'), ('U', '\u{328}', '\u{172}'), ('U', '\u{32D}', '\u{1E76}'),
+    ('U', '\u{330}', '\u{1E74}'), ('V', '\u{303}', '\u{1E7C}'), ('V', '\u{323}', '\u{1E7E}'),
+    ('W', '\u{300}', '\u{1E80}'), ('W', '\u{301}', '\u{1E82}'), ('W', '\u{302}', '\u{174}'),
+    ('W', '\u{307}', '\u{1E86}'), ('W', '\u{308}', '\u{1E84}'), ('W', '\u{323}', '\u{1E88}'),
+    ('X', '\u{307}', '\u{1E8A}'), ('X', '\u{308}', '\u{1E8C}'), ('Y', '\u{300}', '\u{1EF2}'),
+    ('Y', '\u{301}', '\u{DD}'), ('Y', '\u{302}', '\u{176}'), ('Y', '\u{303}', '\u{1EF8}'),
+    ('Y', '\u{304}', '\u{232}'), ('Y', '\u{307}', '\u{1E8E}'), ('Y', '\u{308}', '\u{178}'),
+    ('Y', '\u{309}', '\u{1EF6}'), ('Y', '\u{323}', '\u{1EF4}'), ('Z', '\u{301}', '\u{179}'),
+    ('Z', '\u{302}', '\u{1E90}'), ('Z', '\u{307}', '\u{17B}'), ('Z', '\u{30C}', '\u{17D}'),
+    ('Z', '\u{323}', '\u{1E92}'), ('Z', '\u{331}', '\u{1E94}'), ('a', '\u{300}', '\u{E0}'),
+    ('a', '\u{301}', '\u{E1}'), ('a', '\u{302}', '\u{E2}'), ('a', '\u{303}', '\u{E3}'),
+    ('a', '\u{304}', '\u{101}'), ('a', '\u{306}', '\u{103}'), ('a', '\u{307}', '\u{227}'),
+    ('a', '\u{308}', '\u{E4}'), ('a', '\u{309}', '\u{1EA3}'), ('a', '\u{30A}', '\u{E5}'),
+    ('a', '\u{30C}', '\u{1CE}'), ('a', '\u{30F}', '\u{201}'), ('a', '\u{311}', '\u{203}'),
+    ('a', '\u{323}', '\u{1EA1}'), ('a', '\u{325}', '\u{1E01}'), ('a', '\u{328}', '\u{105}'),
+    ('b', '\u{307}', '\u{1E03}'), ('b', '\u{323}', '\u{1E05}'), ('b', '\u{331}', '\u{1E07}'),
+    ('c', '\u{301}', '\u{107}'), ('c', '\u{302}', '\u{109}'), ('c', '\u{307}', '\u{10B}'),
+    ('c', '\u{30C}', '\u{10D}'), ('c', '\u{327}', '\u{E7}'), ('d', '\u{307}', '\u{1E0B}'),
+    ('d', '\u{30C}', '\u{10F}'), ('d', '\u{323}', '\u{1E0D}'), ('d', '\u{327}', '\u{1E11}'),
+    ('d', '\u{32D}', '\u{1E13}'), ('d', '\u{331}', '\u{1E0F}'), ('e', '\u{300}', '\u{E8}'),
+    ('e', '\u{301}', '\u{E9}'), ('e', '\u{302}', '\u{EA}'), ('e', '\u{303}', '\u{1EBD}'),
+    ('e', '\u{304}', '\u{113}'), ('e', '\u{306}', '\u{115}'), ('e', '\u{307}', '\u{117}'),
+    ('e', '\u{308}', '\u{EB}'), ('e', '\u{309}', '\u{1EBB}'), ('e', '\u{30C}', '\u{11B}'),
+    ('e', '\u{30F}', '\u{205}'), ('e', '\u{311}', '\u{207}'), ('e', '\u{323}', '\u{1EB9}'),
+    ('e', '\u{327}', '\u{229}'), ('e', '\u{328}', '\u{119}'), ('e', '\u{32D}', '\u{1E19}'),
+    ('e', '\u{330}', '\u{1E1B}'), ('f', '\u{307}', '\u{1E1F}'), ('g', '\u{301}', '\u{1F5}'),
+    ('g', '\u{302}', '\u{11D}'), ('g', '\u{304}', '\u{1E21}'), ('g', '\u{306}', '\u{11F}'),
+    ('g', '\u{307}', '\u{121}'), ('g', '\u{30C}', '\u{1E7}'), ('g', '\u{327}', '\u{123}'),
+    ('h', '\u{302}', '\u{125}'), ('h', '\u{307}', '\u{1E23}'), ('h', '\u{308}', '\u{1E27}'),
+    ('h', '\u{30C}', '\u{21F}'), ('h', '\u{323}', '\u{1E25}'), ('h', '\u{327}', '\u{1E29}'),
+    ('h', '\u{32E}', '\u{1E2B}'), ('h', '\u{331}', '\u{1E96}'), ('i', '\u{300}', '\u{EC}'),
+    ('i', '\u{301}', '\u{ED}'), ('i', '\u{302}', '\u{EE}'), ('i', '\u{303}', '\u{129}'),
+    ('i', '\u{304}', '\u{12B}'), ('i', '\u{306}', '\u{12D}'), ('i', '\u{308}', '\u{EF}'),
+    ('i', '\u{309}', '\u{1EC9}'), ('i', '\u{30C}', '\u{1D0}'), ('i', '\u{30F}', '\u{209}'),
+    ('i', '\u{311}', '\u{20B}'), ('i', '\u{323}', '\u{1ECB}'), ('i', '\u{328}', '\u{12F}'),
+    ('i', '\u{330}', '\u{1E2D}'), ('j', '\u{302}', '\u{135}'), ('j', '\u{30C}', '\u{1F0}'),
+    ('k', '\u{301}', '\u{1E31}'), ('k', '\u{30C}', '\u{1E9}'), ('k', '\u{323}', '\u{1E33}'),
+    ('k', '\u{327}', '\u{137}'), ('k', '\u{331}', '\u{1E35}'), ('l', '\u{301}', '\u{13A}'),
+    ('l', '\u{30C}', '\u{13E}'), ('l', '\u{323}', '\u{1E37}'), ('l', '\u{327}', '\u{13C}'),
+    ('l', '\u{32D}', '\u{1E3D}'), ('l', '\u{331}', '\u{1E3B}'), ('m', '\u{301}', '\u{1E3F}'),
+    ('m', '\u{307}', '\u{1E41}'), ('m', '\u{323}', '\u{1E43}'), ('n', '\u{300}', '\u{1F9}'),
+    ('n', '\u{301}', '\u{144}'), ('n', '\u{303}', '\u{F1}'), ('n', '\u{307}', '\u{1E45}'),
+    ('n', '\u{30C}', '\u{148}'), ('n', '\u{323}', '\u{1E47}'), ('n', '\u{327}', '\u{146}'),
+    ('n', '\u{32D}', '\u{1E4B}'), ('n', '\u{331}', '\u{1E49}'), ('o', '\u{300}', '\u{F2}'),
+    ('o', '\u{301}', '\u{F3}'), ('o', '\u{302}', '\u{F4}'), ('o', '\u{303}', '\u{F5}'),
+    ('o', '\u{304}', '\u{14D}'), ('o', '\u{306}', '\u{14F}'), ('o', '\u{307}', '\u{22F}'),
+    ('o', '\u{308}', '\u{F6}'), ('o', '\u{309}', '\u{1ECF}'), ('o', '\u{30B}', '\u{151}'),
+    ('o', '\u{30C}', '\u{1D2}'), ('o', '\u{30F}', '\u{20D}'), ('o', '\u{311}', '\u{20F}'),
+    ('o', '\u{31B}', '\u{1A1}'), ('o', '\u{323}', '\u{1ECD}'), ('o', '\u{328}', '\u{1EB}'),
+    ('p', '\u{301}', '\u{1E55}'), ('p', '\u{307}', '\u{1E57}'), ('r', '\u{301}', '\u{155}'),
+    ('r', '\u{307}', '\u{1E59}'), ('r', '\u{30C}', '\u{159}'), ('r', '\u{30F}', '\u{211}'),
+    ('r', '\u{311}', '\u{213}'), ('r', '\u{323}', '\u{1E5B}'), ('r', '\u{327}', '\u{157}'),
+    ('r', '\u{331}', '\u{1E5F}'), ('s', '\u{301}', '\u{15B}'), ('s', '\u{302}', '\u{15D}'),
+    ('s', '\u{307}', '\u{1E61}'), ('s', '\u{30C}', '\u{161}'), ('s', '\u{323}', '\u{1E63}'),
+    ('s', '\u{326}', '\u{219}'), ('s', '\u{327}', '\u{15F}'), ('t', '\u{307}', '\u{1E6B}'),
+    ('t', '\u{308}', '\u{1E97}'), ('t', '\u{30C}', '\u{165}'), ('t', '\u{323}', '\u{1E6D}'),
+    ('t', '\u{326}', '\u{21B}'), ('t', '\u{327}', '\u{163}'), ('t', '\u{32D}', '\u{1E71}'),
+    ('t', '\u{331}', '\u{1E6F}'), ('u', '\u{300}', '\u{F9}'), ('u', '\u{301}', '\u{FA}'),
+    ('u', '\u{302}', '\u{FB}'), ('u', '\u{303}', '\u{169}'), ('u', '\u{304}', '\u{16B}'),
+    ('u', '\u{306}', '\u{16D}'), ('u', '\u{308}', '\u{FC}'), ('u', '\u{309}', '\u{1EE7}'),
+    ('u', '\u{30A}', '\u{16F}'), ('u', '\u{30B}', '\u{171}'), ('u', '\u{30C}', '\u{1D4}'),
+    ('u', '\u{30F}', '\u{215}'), ('u', '\u{311}', '\u{217}'), ('u', '\u{31B}', '\u{1B0}'),
+    ('u', '\u{323}', '\u{1EE5}'), ('u', '\u{324}', '\u{1E73}'), ('u', '\u{328}', '\u{173}'),
+    ('u', '\u{32D}', '\u{1E77}'), ('u', '\u{330}', '\u{1E75}'), ('v', '\u{303}', '\u{1E7D}'),
+    ('v', '\u{323}', '\u{1E7F}'), ('w', '\u{300}', '\u{1E81}'), ('w', '\u{301}', '\u{1E83}'),
+    ('w', '\u{302}', '\u{175}'), ('w', '\u{307}', '\u{1E87}'), ('w', '\u{308}', '\u{1E85}'),
+    ('w', '\u{30A}', '\u{1E98}'), ('w', '\u{323}', '\u{1E89}'), ('x', '\u{307}', '\u{1E8B}'),
+    ('x', '\u{308}', '\u{1E8D}'), ('y', '\u{300}', '\u{1EF3}'), ('y', '\u{301}', '\u{FD}'),
+    ('y', '\u{302}', '\u{177}'), ('y', '\u{303}', '\u{1EF9}'), ('y', '\u{304}', '\u{233}'),
+    ('y', '\u{307}', '\u{1E8F}'), ('y', '\u{308}', '\u{FF}'), ('y', '\u{309}', '\u{1EF7}'),
+    ('y', '\u{30A}', '\u{1E99}'), ('y', '\u{323}', '\u{1EF5}'), ('z', '\u{301}', '\u{17A}'),
+    ('z', '\u{302}', '\u{1E91}'), ('z', '\u{307}', '\u{17C}'), ('z', '\u{30C}', '\u{17E}'),
+    ('z', '\u{323}', '\u{1E93}'), ('z', '\u{331}', '\u{1E95}'), ('\u{C2}', '\u{300}', '\u{1EA6}'),
+    ('\u{C2}', '\u{301}', '\u{1EA4}'), ('\u{C2}', '\u{303}', '\u{1EAA}'),
+    ('\u{C2}', '\u{309}', '\u{1EA8}'), ('\u{C4}', '\u{304}', '\u{1DE}'),
+    ('\u{C5}', '\u{301}', '\u{1FA}'), ('\u{C6}', '\u{301}', '\u{1FC}'),
+    ('\u{C6}', '\u{304}', '\u{1E2}'), ('\u{C7}', '\u{301}', '\u{1E08}'),
+    ('\u{CA}', '\u{300}', '\u{1EC0}'), ('\u{CA}', '\u{301}', '\u{1EBE}'),
+    ('\u{CA}', '\u{303}', '\u{1EC4}'), ('\u{CA}', '\u{309}', '\u{1EC2}'),
+    ('\u{CF}', '\u{301}', '\u{1E2E}'), ('\u{D4}', '\u{300}', '\u{1ED2}'),
+    ('\u{D4}', '\u{301}', '\u{1ED0}'), ('\u{D4}', '\u{303}', '\u{1ED6}'),
+    ('\u{D4}', '\u{309}', '\u{1ED4}'), ('\u{D5}', '\u{301}', '\u{1E4C}'),
+    ('\u{D5}', '\u{304}', '\u{22C}'), ('\u{D5}', '\u{308}', '\u{1E4E}'),
+    ('\u{D6}', '\u{304}', '\u{22A}'), ('\u{D8}', '\u{301}', '\u{1FE}'),
+    ('\u{DC}', '\u{300}', '\u{1DB}'), ('\u{DC}', '\u{301}', '\u{1D7}'),
+    ('\u{DC}', '\u{304}', '\u{1D5}'), ('\u{DC}', '\u{30C}', '\u{1D9}'),
+    ('\u{E2}', '\u{300}', '\u{1EA7}'), ('\u{E2}', '\u{301}', '\u{1EA5}'),
+    ('\u{E2}', '\u{303}', '\u{1EAB}'), ('\u{E2}', '\u{309}', '\u{1EA9}'),
+    ('\u{E4}', '\u{304}', '\u{1DF}'), ('\u{E5}', '\u{301}', '\u{1FB}'),
+    ('\u{E6}', '\u{301}', '\u{1FD}'), ('\u{E6}', '\u{304}', '\u{1E3}'),
+    ('\u{E7}', '\u{301}', '\u{1E09}'), ('\u{EA}', '\u{300}', '\u{1EC1}'),
+    ('\u{EA}', '\u{301}', '\u{1EBF}'), ('\u{EA}', '\u{303}', '\u{1EC5}'),
+    ('\u{EA}', '\u{309}', '\u{1EC3}'), ('\u{EF}', '\u{301}', '\u{1E2F}'),
+    ('\u{F4}', '\u{300}', '\u{1ED3}'), ('\u{F4}', '\u{301}', '\u{1ED1}'),
+    ('\u{F4}', '\u{303}', '\u{1ED7}'), ('\u{F4}', '\u{309}', '\u{1ED5}'),
+    ('\u{F5}', '\u{301}', '\u{1E4D}'), ('\u{F5}', '\u{304}', '\u{22D}'),
+    ('\u{F5}', '\u{308}', '\u{1E4F}'), ('\u{F6}', '\u{304}', '\u{22B}'),
+    ('\u{F8}', '\u{301}', '\u{1FF}'), ('\u{FC}', '\u{300}', '\u{1DC}'),
+    ('\u{FC}', '\u{301}', '\u{1D8}'), ('\u{FC}', '\u{304}', '\u{1D6}'),
+    ('\u{FC}', '\u{30C}', '\u{1DA}'), ('\u{102}', '\u{300}', '\u{1EB0}'),
+    ('\u{102}', '\u{301}', '\u{1EAE}'), ('\u{102}', '\u{303}', '\u{1EB4}'),
+    ('\u{102}', '\u{309}', '\u{1EB2}'), ('\u{103}', '\u{300}', '\u{1EB1}'),
+    ('\u{103}', '\u{301}', '\u{1EAF}'), ('\u{103}', '\u{303}', '\u{1EB5}'),
+    ('\u{103}', '\u{309}', '\u{1EB3}'), ('\u{112}', '\u{300}', '\u{1E14}'),
+    ('\u{112}', '\u{301}', '\u{1E16}'), ('\u{113}', '\u{300}', '\u{1E15}'),
+    ('\u{113}', '\u{301}', '\u{1E17}'), ('\u{14C}', '\u{300}', '\u{1E50}'),
+    ('\u{14C}', '\u{301}', '\u{1E52}'), ('\u{14D}', '\u{300}', '\u{1E51}'),
+    ('\u{14D}', '\u{301}', '\u{1E53}'), ('\u{15A}', '\u{307}', '\u{1E64}'),
+    ('\u{15B}', '\u{307}', '\u{1E65}'), ('\u{160}', '\u{307}', '\u{1E66}'),
+    ('\u{161}', '\u{307}', '\u{1E67}'), ('\u{168}', '\u{301}', '\u{1E78}'),
+    ('\u{169}', '\u{301}', '\u{1E79}'), ('\u{16A}', '\u{308}', '\u{1E7A}'),
+    ('\u{16B}', '\u{308}', '\u{1E7B}'), ('\u{17F}', '\u{307}', '\u{1E9B}'),
+    ('\u{1A0}', '\u{300}', '\u{1EDC}'), ('\u{1A0}', '\u{301}', '\u{1EDA}'),
+    ('\u{1A0}', '\u{303}', '\u{1EE0}'), ('\u{1A0}', '\u{309}', '\u{1EDE}'),
+    ('\u{1A0}', '\u{323}', '\u{1EE2}'), ('\u{1A1}', '\u{300}', '\u{1EDD}'),
+    ('\u{1A1}', '\u{301}', '\u{1EDB}'), ('\u{1A1}', '\u{303}', '\u{1EE1}'),
+    ('\u{1A1}', '\u{309}', '\u{1EDF}'), ('\u{1A1}', '\u{323}', '\u{1EE3}'),
+    ('\u{1AF}', '\u{300}', '\u{1EEA}'), ('\u{1AF}', '\u{301}', '\u{1EE8}'),
+    ('\u{1AF}', '\u{303}', '\u{1EEE}'), ('\u{1AF}', '\u{309}', '\u{1EEC}'),
+    ('\u{1AF}', '\u{323}', '\u{1EF0}'), ('\u{1B0}', '\u{300}', '\u{1EEB}'),
+    ('\u{1B0}', '\u{301}', '\u{1EE9}'), ('\u{1B0}', '\u{303}', '\u{1EEF}'),
+    ('\u{1B0}', '\u{309}', '\u{1EED}'), ('\u{1B0}', '\u{323}', '\u{1EF1}'),
+    ('\u{1B7}', '\u{30C}', '\u{1EE}'), ('\u{1EA}', '\u{304}', '\u{1EC}'),
+    ('\u{1EB}', '\u{304}', '\u{1ED}'), ('\u{226}', '\u{304}', '\u{1E0}'),
+    ('\u{227}', '\u{304}', '\u{1E1}'), ('\u{228}', '\u{306}', '\u{1E1C}'),
+    ('\u{229}', '\u{306}', '\u{1E1D}'), ('\u{22E}', '\u{304}', '\u{230}'),
+    ('\u{22F}', '\u{304}', '\u{231}'), ('\u{292}', '\u{30C}', '\u{1EF}'),
+    ('\u{1E36}', '\u{304}', '\u{1E38}'), ('\u{1E37}', '\u{304}', '\u{1E39}'),
+    ('\u{1E5A}', '\u{304}', '\u{1E5C}'), ('\u{1E5B}', '\u{304}', '\u{1E5D}'),
+    ('\u{1E62}', '\u{307}', '\u{1E68}'), ('\u{1E63}', '\u{307}', '\u{1E69}'),
+    ('\u{1EA0}', '\u{302}', '\u{1EAC}'), ('\u{1EA0}', '\u{306}', '\u{1EB6}'),
+    ('\u{1EA1}', '\u{302}', '\u{1EAD}'), ('\u{1EA1}', '\u{306}', '\u{1EB7}'),
+    ('\u{1EB8}', '\u{302}', '\u{1EC6}'), ('\u{1EB9}', '\u{302}', '\u{1EC7}'),
+    ('\u{1ECC}', '\u{302}', '\u{1ED8}'), ('\u{1ECD}', '\u{302}', '\u{1ED9}'),
+];
+
+// The string with every letter and accent that have a composed form in
+// COMPOSITIONS replaced by it
+pub fn nfc(s: &str) -> String {
+    let mut composed = String::with_capacity(s.len());
+    for c in s.chars() {
+        let last = composed.chars().next_back();
+        let found = last.and_then(|last| {
+            COMPOSITIONS
+                .binary_search_by(|&(letter, mark, _)| (letter, mark).cmp(&(last, c)))
+                .ok()
+        });
+        match (last, found) {
+            (Some(last), Some(i)) => {
+                composed.truncate(composed.len() - last.len_utf8());
+                composed.push(COMPOSITIONS[i].2);
+            }
+            _ => composed.push(c),
+        }
+    }
+    composed
+}
+
+// Paths under the root, relative to it, whose last part isn't in NFC, deepest
+// first so renaming them in order works
+pub fn decomposed(config: &Config) -> Result<Vec<String>> {
+    let mut paths = Vec::new();
+    let mut dirs = vec![String::new()];
+    while let Some(dir) = dirs.pop() {
+        let entries = match fs::read_dir(Path::new(&config.root).join(&dir)) {
+            Ok(entries) => entries,
+            Err(err) if err.kind() == ErrorKind::NotFound => continue,
+            Err(err) => return Err(err).wrap_err_with(|| format!("Couldn't list {}", dir)),
+        };
+        for entry in entries {
+            let entry = entry?;
+            let name = entry.file_name().to_string_lossy().into_owned();
+            let path = if dir.is_empty() {
+                name.clone()
+            } else {
+                format!("{}/{}", dir, name)
+            };
+            if ignored(config, &path) {
+                continue;
+            }
+            if entry.file_type()?.is_dir() {
+                dirs.push(path.clone());
+            }
+            if nfc(&name) != name {
+                paths.push(path);
+            }
+        }
+    }
+    paths.sort_by_key(|path| std::cmp::Reverse(path.matches('/').count()));
+    Ok(paths)
+}
+
+// Renames the paths under the root that aren't in NFC. One whose NFC
+// spelling is already taken is left alone, since either could be the one
+// that matters.
+pub fn normalize(config: &Config) -> Result<()> {
+    let root = Path::new(&config.root);
+    for path in decomposed(config)? {
+        let (dir, name) = match path.rsplit_once('/') {
+            Some((dir, name)) => (format!("{}/", dir), name),
+            None => (String::new(), path.as_str()),
+        };
+        let renamed = format!("{}{}", dir, nfc(name));
+        if root.join(&renamed).exists() {
+            warn!(
+                "Both {} and {} exist, spelled differently, not renaming",
+                renamed, path
+            );
+            continue;
+        }
+        fs::rename(root.join(&path), root.join(&renamed))
+            .wrap_err_with(|| format!("Couldn't rename {}", path))?;
+        log!("Renamed {} to its usual spelling", renamed);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn composes() {
+        assert_eq!(nfc("Cafe\u{301}.txt"), "Caf\u{e9}.txt");
+        assert_eq!(nfc("e\u{323}\u{302}"), "\u{1ec7}");
+        assert_eq!(nfc("\u{301}x"), "\u{301}x");
+        assert_eq!(nfc("plain"), "plain");
+    }
+
+    #[test]
+    fn renames_decomposed_names() {
+        let root = crate::TempDir::new("unicode");
+        fs::create_dir_all(root.join("Re\u{301}sume\u{301}")).unwrap();
+        fs::write(root.join("Re\u{301}sume\u{301}/nai\u{308}ve"), "").unwrap();
+        let config = Config {
+            root: root.to_string_lossy().into_owned(),
+            ..Config::default()
+        };
+
+        normalize(&config).unwrap();
+        assert!(root.join("R\u{e9}sum\u{e9}/na\u{ef}ve").exists());
+        assert!(decomposed(&config).unwrap().is_empty());
+    }
+}
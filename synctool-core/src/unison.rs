@@ -0,0 +1,349 @@
+// The unison backend, which does a two-way sync of the whole tree.
+
+use crate::{
+    config::{Config, Host, Symlinks},
+    output, progress,
+    runner::Runner,
+    ssh::{self, ssh},
+};
+use eyre::Result;
+use std::{
+    collections::HashSet,
+    fs::File,
+    io::{self, stdout, Read, Write},
+    os::unix::io::FromRawFd,
+    process::{exit, Command, Stdio},
+};
+
+// Compares `unison -version` on both ends. If the remote can't be reached
+// the check is skipped, so waking it up still gets a chance to work.
+pub fn unison_versions_match(runner: &dyn Runner, config: &Config, host: &Host) -> Result<bool> {
+    let remote = host.address.as_str();
+    let servercmd = host.unison_servercmd.as_deref().unwrap_or("unison");
+    let local = runner.output(Command::new(local_unison(config, host)).arg("-version"))?;
+    let remote_output =
+        runner.output(ssh(host).args([servercmd, "-version"]).stdin(Stdio::null()))?;
+
+    let local_version = String::from_utf8_lossy(&local.stdout).trim().to_string();
+    let remote_version = String::from_utf8_lossy(&remote_output.stdout)
+        .trim()
+        .to_string();
+
+    if !remote_output.status.success() {
+        if remote_output.status.code() == Some(127) {
+            warn!("Unison isn't installed on {}", remote);
+            return Ok(false);
+        }
+        return Ok(true);
+    }
+
+    let compatible = versions_compatible(&local_version, &remote_version);
+    if !compatible {
+        warn!("Unison version mismatch:");
+        warn!("  local:  {}", local_version);
+        warn!("  {}: {}", remote, remote_version);
+        warn!("Install matching versions, or 2.52+ on both machines");
+    }
+
+    Ok(compatible)
+}
+
+// Whether unisons printing these -version lines can sync with each other.
+// Unison 2.52 and later can talk to any other 2.52+, but older versions only
+// work with the same major.minor version built with the same OCaml version.
+fn versions_compatible(local: &str, remote: &str) -> bool {
+    let parse = |version: &str| -> Option<(u32, u32, String)> {
+        // "unison version 2.53.3 (ocaml 4.14.1)"
+        let mut words = version.strip_prefix("unison version ")?.split(' ');
+        let mut numbers = words.next()?.split('.');
+        let major = numbers.next()?.parse().ok()?;
+        let minor = numbers.next()?.parse().ok()?;
+        let ocaml = words.collect::<Vec<_>>().join(" ");
+        Some((major, minor, ocaml))
+    };
+
+    match (parse(local), parse(remote)) {
+        (Some(local), Some(remote)) => {
+            (local.0, local.1) >= (2, 52) && (remote.0, remote.1) >= (2, 52)
+                || local == remote
+                || (local.0, local.1) == (remote.0, remote.1)
+                    && (local.2.is_empty() || remote.2.is_empty())
+        }
+        // Unknown output format, let unison itself decide
+        _ => true,
+    }
+}
+
+// The local unison binary used with the host
+pub fn local_unison<'a>(config: &'a Config, host: &'a Host) -> &'a str {
+    host.unison_path.as_deref().unwrap_or(&config.unison.path)
+}
+
+// Picks a local unison and one on the host that can sync with each other,
+// out of the host's unison_path (or [unison] path) and [unison] binaries here
+// and its unison_servercmd and unison_servercmds there, preferring the
+// configured pair. The host is updated to use them. Returns Ok(false) if no
+// pair works.
+pub fn choose_unisons(runner: &dyn Runner, config: &Config, host: &mut Host) -> Result<bool> {
+    // Each once, keeping the order they're preferred in
+    let mut seen = HashSet::new();
+    let mut locals = vec![local_unison(config, host).to_string()];
+    locals.extend(config.unison.binaries.iter().cloned());
+    locals.retain(|path| seen.insert(path.clone()));
+    let mut seen = HashSet::new();
+    let mut remotes = vec![host
+        .unison_servercmd
+        .clone()
+        .unwrap_or_else(|| "unison".to_string())];
+    remotes.extend(host.unison_servercmds.iter().cloned());
+    remotes.retain(|command| seen.insert(command.clone()));
+    if locals.len() == 1 && remotes.len() == 1 {
+        return unison_versions_match(runner, config, host);
+    }
+
+    let local_versions = locals
+        .iter()
+        .map(
+            |path| match runner.output(Command::new(path).arg("-version")) {
+                Ok(output) => String::from_utf8_lossy(&output.stdout).trim().to_string(),
+                // Not installed here
+                Err(_) => String::new(),
+            },
+        )
+        .collect::<Vec<_>>();
+    // A line per candidate, empty for the ones that don't run
+    let script = remotes
+        .iter()
+        .map(|command| format!("echo \"$({} -version 2>/dev/null)\"", command))
+        .collect::<Vec<_>>()
+        .join("; ");
+    let output = runner.output(ssh(host).arg(script).stdin(Stdio::null()))?;
+    if !output.status.success() {
+        return Ok(true);
+    }
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let remote_versions = stdout.lines().map(str::trim).collect::<Vec<_>>();
+
+    for (local, local_version) in locals.iter().zip(&local_versions) {
+        for (remote, remote_version) in remotes.iter().zip(&remote_versions) {
+            if local_version.is_empty()
+                || remote_version.is_empty()
+                || !versions_compatible(local_version, remote_version)
+            {
+                continue;
+            }
+            if *local != locals[0] || *remote != remotes[0] {
+                log!(
+                    "Using {} here and {} on {} ({} and {})",
+                    local,
+                    remote,
+                    host.name,
+                    local_version,
+                    remote_version
+                );
+            }
+            host.unison_path = Some(local.clone());
+            host.unison_servercmd = Some(remote.clone());
+            return Ok(true);
+        }
+    }
+
+    warn!("No unison here can sync with one on {}:", host.name);
+    for (local, version) in locals.iter().zip(&local_versions) {
+        warn!("  here: {} ({})", local, version);
+    }
+    for (remote, version) in remotes.iter().zip(&remote_versions) {
+        warn!("  {}: {} ({})", host.name, remote, version);
+    }
+    Ok(false)
+}
+
+// Returns Ok(true) if sync was successful, Ok(false) if sync failed.
+pub fn unison(
+    runner: &dyn Runner,
+    config: &Config,
+    host: &Host,
+    interactive: bool,
+    print: bool,
+    extra_args: &[String],
+) -> Result<bool> {
+    let remote_folder = remote_root(config, host);
+    let mut command_struct = Command::new(local_unison(config, host));
+    let mut command = &mut command_struct;
+
+    for (option, value) in unison_options(config, host, interactive) {
+        command = command.arg(format!("-{}", option));
+        if let Some(value) = value {
+            command = command.arg(value);
+        }
+    }
+
+    if output::quiet() {
+        command = command.arg("-silent");
+    }
+    command = command
+        .args(&config.unison.args)
+        .args(&host.unison_args)
+        .args(extra_args);
+
+    command = command.args([config.root.as_str(), remote_folder.as_str()]);
+    command = command
+        .stdin(Stdio::inherit())
+        .stdout(Stdio::inherit())
+        .stderr(Stdio::inherit());
+
+    if print {
+        let mut args = String::new();
+        for a in command.get_args() {
+            args.push_str(&format!("{:?} ", a));
+        }
+        log!("command: unison {}", args);
+        exit(0);
+    }
+
+    if progress::watched() && !interactive && !runner.simulated() {
+        return run_watched(runner, command);
+    }
+    let unison_status = runner.status(command)?;
+    Ok(unison_status.success())
+}
+
+// Runs unison with its stdout and stderr going through one pipe, passing its
+// output on while following the progress in it
+fn run_watched(runner: &dyn Runner, command: &mut Command) -> Result<bool> {
+    let mut fds = [0; 2];
+    if unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_CLOEXEC) } != 0 {
+        return Err(io::Error::last_os_error().into());
+    }
+    let (mut reader, writer) = unsafe { (File::from_raw_fd(fds[0]), File::from_raw_fd(fds[1])) };
+    command.stdout(writer.try_clone()?).stderr(writer);
+    let mut process = runner.spawn(command)?;
+    // The command's copies of the write end have to be closed for the reads
+    // to end
+    command.stdout(Stdio::inherit()).stderr(Stdio::inherit());
+
+    let mut buf = [0; 4096];
+    let mut pending = Vec::new();
+    loop {
+        let n = reader.read(&mut buf)?;
+        if n == 0 {
+            break;
+        }
+        stdout().write_all(&buf[..n])?;
+        stdout().flush()?;
+        pending.extend_from_slice(&buf[..n]);
+        while let Some(end) = pending.iter().position(|&b| b == b'\n' || b == b'\r') {
+            let line = pending.drain(..=end).collect::<Vec<_>>();
+            progress::unison_line(&String::from_utf8_lossy(&line));
+        }
+    }
+    Ok(process.wait()?.success())
+}
+
+// The remote root as given to unison, which is also how -prefer options name it
+pub fn remote_root(config: &Config, host: &Host) -> String {
+    format!("ssh://{}/{}/", host.address, host.root(config))
+}
+
+// The unison preferences synctool sets for a host, as (name, value) pairs.
+// A value of None is a boolean preference that's switched on.
+pub fn unison_options(
+    config: &Config,
+    host: &Host,
+    interactive: bool,
+) -> Vec<(&'static str, Option<String>)> {
+    let mut options = vec![("auto", None), ("sshargs", Some(ssh::args_line(host)))];
+
+    if !interactive {
+        options.push(("batch", None));
+    }
+
+    for ignore in &config.ignores {
+        options.push(("ignore", Some(ignore.clone())));
+    }
+
+    if let Some(servercmd) = &host.unison_servercmd {
+        options.push(("servercmd", Some(servercmd.clone())));
+    }
+
+    // A mask of the permission bits to sync, with unison's default otherwise
+    if !host.perms {
+        options.push(("perms", Some("0".to_string())));
+    }
+    if host.owner {
+        options.push(("owner", None));
+    }
+    if host.group {
+        options.push(("group", None));
+    }
+    if host.numeric_ids {
+        options.push(("numericids", None));
+    }
+    // Both need unison 2.53 or later
+    if host.xattrs {
+        options.push(("xattrs", None));
+    }
+    if host.acls {
+        options.push(("acl", None));
+    }
+    // The links skipped otherwise are ignored for each run (see sync_with)
+    if host.symlinks == Symlinks::Follow {
+        options.push(("follow", Some("Regex .*".to_string())));
+    }
+
+    options
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::runner::{MockRunner, Reply};
+
+    fn reply(stdout: &str) -> Vec<Reply> {
+        vec![Reply {
+            code: 0,
+            stdout: stdout.to_string(),
+        }]
+    }
+
+    #[test]
+    fn chooses_unisons_that_can_talk() {
+        let mut config = Config::default();
+        config.unison.binaries = vec!["unison-2.51".to_string()];
+        let mut host = config.host("laptop").unwrap().clone();
+        host.unison_servercmds = vec!["unison-2.48".to_string()];
+
+        let runner = MockRunner::new();
+        runner.script_replies("unison-2.51", reply("unison version 2.51.5 (ocaml 4.14.1)"));
+        runner.script_replies("unison", reply("unison version 2.53.3 (ocaml 4.14.1)"));
+        runner.script_replies("ssh", reply("unison version 2.51.5 (ocaml 4.14.1)\n\n"));
+        assert!(choose_unisons(&runner, &config, &mut host).unwrap());
+        assert_eq!(host.unison_path.as_deref(), Some("unison-2.51"));
+        assert_eq!(host.unison_servercmd.as_deref(), Some("unison"));
+
+        let runner = MockRunner::new();
+        runner.script_replies("unison", reply("unison version 2.53.3 (ocaml 4.14.1)"));
+        runner.script_replies("ssh", reply("unison version 2.48.4\n\n"));
+        let mut host = config.host("laptop").unwrap().clone();
+        host.unison_servercmds = vec!["unison-2.48".to_string()];
+        assert!(!choose_unisons(&runner, &config, &mut host).unwrap());
+    }
+
+    #[test]
+    fn tries_each_unison_once() {
+        let mut config = Config::default();
+        config.unison.binaries = vec!["unison-2.51".to_string(), "unison".to_string()];
+        let mut host = config.host("laptop").unwrap().clone();
+        host.unison_servercmds = vec!["unison-2.51".to_string(), "unison".to_string()];
+
+        let runner = MockRunner::new();
+        runner.script_replies("unison", reply("unison version 2.53.3 (ocaml 4.14.1)"));
+        runner.script_replies("ssh", reply("unison version 2.53.3 (ocaml 4.14.1)\n\n"));
+        assert!(choose_unisons(&runner, &config, &mut host).unwrap());
+        let commands = runner.commands();
+        assert_eq!(commands[..2], ["unison -version", "unison-2.51 -version"]);
+        assert!(commands[2].ends_with(
+            "echo \"$(unison -version 2>/dev/null)\"; echo \"$(unison-2.51 -version 2>/dev/null)\""
+        ));
+    }
+}
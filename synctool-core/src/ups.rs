@@ -0,0 +1,136 @@
+// Whether a host is running on battery, checked before waking it (that would
+// only drain its UPS during a power cut) and before shutting it down. A host
+// with ups set is asked about with NUT's upsc from here, which works while
+// it's off; an awake host without one is asked through its agent, or else
+// its power supplies in sysfs are read over ssh.
+
+use crate::{agent::Agent, config::Host, protocol::Message, runner::Runner, ssh::ssh};
+use eyre::{ensure, Result};
+use std::{
+    fs,
+    process::{Command, Stdio},
+};
+
+const UEVENTS: &str = "cat /sys/class/power_supply/*/uevent";
+
+// Some(true) on battery, Some(false) on mains, None if there's no telling.
+pub fn on_battery(runner: &dyn Runner, host: &Host, awake: bool) -> Result<Option<bool>> {
+    if let Some(ups) = &host.ups {
+        let output = runner.output(
+            Command::new("upsc")
+                .args([ups, "ups.status"])
+                .stdin(Stdio::null()),
+        )?;
+        ensure!(output.status.success(), "upsc couldn't reach {}", ups);
+        // e.g. "OL" on line, "OB LB" on battery and low
+        let status = String::from_utf8_lossy(&output.stdout);
+        return Ok(Some(status.split_whitespace().any(|flag| flag == "OB")));
+    }
+    if !awake {
+        return Ok(None);
+    }
+    if host.agent.is_some() {
+        let status = Agent::connect(runner, host)?.request(Message::new("status"))?;
+        return Ok(match status.get("power") {
+            Some("battery") => Some(true),
+            Some("ac") => Some(false),
+            _ => None,
+        });
+    }
+    let output = runner.output(ssh(host).arg(UEVENTS).stdin(Stdio::null()))?;
+    Ok(read_uevents(&String::from_utf8_lossy(&output.stdout)))
+}
+
+// The same for this machine, from its own sysfs
+pub fn local_on_battery() -> Option<bool> {
+    let mut uevents = String::new();
+    for entry in fs::read_dir("/sys/class/power_supply").ok()?.flatten() {
+        uevents += &fs::read_to_string(entry.path().join("uevent")).unwrap_or_default();
+    }
+    read_uevents(&uevents)
+}
+
+// Reads the uevent files of every power supply, one after the other. A UPS
+// that's discharging means battery, and so do mains supplies that are all
+// offline; machines without either can't tell.
+fn read_uevents(uevents: &str) -> Option<bool> {
+    let mut supplies: Vec<Vec<(&str, &str)>> = Vec::new();
+    for (key, value) in uevents.lines().filter_map(|line| line.split_once('=')) {
+        if key == "POWER_SUPPLY_NAME" || supplies.is_empty() {
+            supplies.push(Vec::new());
+        }
+        supplies.last_mut()?.push((key, value));
+    }
+    let get = |supply: &Vec<(&str, &str)>, wanted: &str| {
+        supply
+            .iter()
+            .find(|(key, _)| *key == wanted)
+            .map(|(_, value)| value.to_string())
+    };
+
+    let ups_discharging = supplies.iter().any(|supply| {
+        get(supply, "POWER_SUPPLY_TYPE").as_deref() == Some("UPS")
+            && get(supply, "POWER_SUPPLY_STATUS").as_deref() == Some("Discharging")
+    });
+    if ups_discharging {
+        return Some(true);
+    }
+    let mains = supplies
+        .iter()
+        .filter(|supply| get(supply, "POWER_SUPPLY_TYPE").as_deref() == Some("Mains"))
+        .map(|supply| get(supply, "POWER_SUPPLY_ONLINE").as_deref() == Some("1"))
+        .collect::<Vec<_>>();
+    if mains.is_empty() {
+        None
+    } else {
+        Some(!mains.contains(&true))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        config::Config,
+        runner::{MockRunner, Reply},
+    };
+
+    #[test]
+    fn reads_uevents() {
+        let laptop = "\
+POWER_SUPPLY_NAME=AC
+POWER_SUPPLY_TYPE=Mains
+POWER_SUPPLY_ONLINE=0
+POWER_SUPPLY_NAME=BAT0
+POWER_SUPPLY_TYPE=Battery
+POWER_SUPPLY_STATUS=Discharging
+";
+        assert_eq!(read_uevents(laptop), Some(true));
+        assert_eq!(
+            read_uevents(&laptop.replace("ONLINE=0", "ONLINE=1")),
+            Some(false)
+        );
+        let ups = "POWER_SUPPLY_NAME=ups\nPOWER_SUPPLY_TYPE=UPS\nPOWER_SUPPLY_STATUS=Discharging\n";
+        assert_eq!(read_uevents(ups), Some(true));
+        assert_eq!(read_uevents(""), None);
+    }
+
+    #[test]
+    fn asks_nut() {
+        let runner = MockRunner::new();
+        let reply = |stdout: &str| Reply {
+            code: 0,
+            stdout: stdout.to_string(),
+        };
+        runner.script_replies("upsc", vec![reply("OL\n"), reply("OB LB\n")]);
+        let config =
+            Config::parse("[hosts.nas]\naddress = \"10.13.13.8\"\nups = \"ups@nas\"\n").unwrap();
+        let nas = config.host("nas").unwrap();
+
+        assert_eq!(on_battery(&runner, nas, false).unwrap(), Some(false));
+        assert_eq!(on_battery(&runner, nas, false).unwrap(), Some(true));
+        assert_eq!(runner.commands()[0], "upsc ups@nas ups.status");
+        let desktop = config.host("desktop").unwrap();
+        assert_eq!(on_battery(&runner, desktop, false).unwrap(), None);
+    }
+}
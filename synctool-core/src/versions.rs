@@ -0,0 +1,540 @@
+// Per-file sync state, so a third machine can join without false conflicts or
+// lost updates. Every machine keeps a table in the state dir with a version
+// vector for each file: how many times each machine changed it, as far as this
+// one knows. A scan bumps this machine's count for files whose contents changed
+// since the last scan.
+//
+// Before syncing with a host that runs synctool (its synctool setting), both
+// ends scan and their tables are compared path by path. If one end's vector
+// includes everything the other's does, its copy is newer and wins; if neither
+// does, the file changed on both since they last matched and is a real
+// conflict. After a successful sync the ends adopt each other's vectors for
+// every file whose contents now match, so a change made on the laptop and
+// synced to the desktop is known to be newer than the RPi's copy too.
+//
+// The table file has a line per file: path, content hash (- once deleted),
+// size, mtime and vector, separated by tabs, e.g.
+//
+//     notes/todo.md	9c2e1f04a8b3d6e7	812	1686000000	laptop=3,desktop=1
+
+use crate::{
+    config::{Config, Host, NotifyClass},
+    hostname,
+    ignore::ignored,
+    notify,
+    runner::Runner,
+    ssh::ssh,
+    state_dir,
+};
+use eyre::{eyre, Result, WrapErr};
+use std::{
+    collections::BTreeMap,
+    fs::{self, File},
+    io::{ErrorKind, Read, Write},
+    os::unix::fs::MetadataExt,
+    path::{Path, PathBuf},
+    process::Stdio,
+};
+
+// How many times each machine changed a file
+pub type Vector = BTreeMap<String, u64>;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Entry {
+    // None once the file is deleted
+    pub hash: Option<u64>,
+    pub size: u64,
+    pub mtime: i64,
+    pub vector: Vector,
+}
+
+#[derive(Default)]
+pub struct Table {
+    pub entries: BTreeMap<String, Entry>,
+}
+
+// How a file on this end compares to the other end's
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Order {
+    Same,
+    Newer,
+    Older,
+    Conflict,
+    // Neither end has any history for it yet, so it's left to the backend
+    Unknown,
+}
+
+pub fn path() -> PathBuf {
+    state_dir().join("versions")
+}
+
+impl Table {
+    pub fn load() -> Result<Table> {
+        match fs::read_to_string(path()) {
+            Ok(text) => Table::parse(&text).wrap_err_with(|| format!("In {}", path().display())),
+            Err(err) if err.kind() == ErrorKind::NotFound => Ok(Table::default()),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    pub fn save(&self) -> Result<()> {
+        fs::create_dir_all(state_dir())?;
+        let temp = path().with_extension("tmp");
+        fs::write(&temp, self.to_text())?;
+        fs::rename(temp, path())?;
+        Ok(())
+    }
+
+    pub fn parse(text: &str) -> Result<Table> {
+        let mut table = Table::default();
+        for (number, line) in text.lines().enumerate() {
+            let bad = || eyre!("line {}: {:?} isn't a version entry", number + 1, line);
+            let fields = line.split('\t').collect::<Vec<_>>();
+            let (path, hash, size, mtime, vector) = match fields.as_slice() {
+                [path, hash, size, mtime, vector] => (path, hash, size, mtime, vector),
+                _ => return Err(bad()),
+            };
+
+            let mut counts = Vector::new();
+            for count in vector.split(',').filter(|count| !count.is_empty()) {
+                let (machine, count) = count.split_once('=').ok_or_else(bad)?;
+                counts.insert(machine.to_string(), count.parse().map_err(|_| bad())?);
+            }
+            let entry = Entry {
+                hash: match *hash {
+                    "-" => None,
+                    hash => Some(u64::from_str_radix(hash, 16).map_err(|_| bad())?),
+                },
+                size: size.parse().map_err(|_| bad())?,
+                mtime: mtime.parse().map_err(|_| bad())?,
+                vector: counts,
+            };
+            table.entries.insert(path.to_string(), entry);
+        }
+        Ok(table)
+    }
+
+    pub fn to_text(&self) -> String {
+        let mut text = String::new();
+        for (path, entry) in &self.entries {
+            let hash = entry
+                .hash
+                .map_or("-".to_string(), |hash| format!("{:016x}", hash));
+            let vector = entry
+                .vector
+                .iter()
+                .map(|(machine, count)| format!("{}={}", machine, count))
+                .collect::<Vec<_>>()
+                .join(",");
+            text.push_str(&format!(
+                "{}\t{}\t{}\t{}\t{}\n",
+                path, hash, entry.size, entry.mtime, vector
+            ));
+        }
+        text
+    }
+
+    // Walks the tree under the config's root, bumping machine's count for
+    // every file that was created, changed or deleted since the last scan.
+    // Files are only hashed again when their size or mtime changed. The first
+    // scan records files without history, since nobody knows who changed what
+    // before tracking started.
+    pub fn scan(&mut self, config: &Config, machine: &str) -> Result<()> {
+        let first = self.entries.is_empty();
+        let mut seen = BTreeMap::new();
+        walk(config, Path::new(&config.root), "", &mut seen)?;
+
+        for (path, (size, mtime)) in &seen {
+            let unchanged = self.entries.get(path).is_some_and(|entry| {
+                entry.hash.is_some() && entry.size == *size && entry.mtime == *mtime
+            });
+            if unchanged {
+                continue;
+            }
+            let hash = hash_file(&Path::new(&config.root).join(path))?;
+            let entry = self.entries.entry(path.clone()).or_insert_with(|| Entry {
+                hash: None,
+                size: 0,
+                mtime: 0,
+                vector: Vector::new(),
+            });
+            if entry.hash != Some(hash) && !first {
+                *entry.vector.entry(machine.to_string()).or_insert(0) += 1;
+            }
+            entry.hash = Some(hash);
+            entry.size = *size;
+            entry.mtime = *mtime;
+        }
+
+        for (path, entry) in &mut self.entries {
+            if entry.hash.is_some() && !seen.contains_key(path) {
+                entry.hash = None;
+                *entry.vector.entry(machine.to_string()).or_insert(0) += 1;
+            }
+        }
+        Ok(())
+    }
+
+    // Takes the other end's history for every file whose contents match, e.g.
+    // after they were synced.
+    pub fn merge_matching(&mut self, other: &Table) {
+        for (path, theirs) in &other.entries {
+            if let Some(ours) = self.entries.get_mut(path) {
+                if ours.hash == theirs.hash {
+                    for (machine, count) in &theirs.vector {
+                        let ours = ours.vector.entry(machine.clone()).or_insert(0);
+                        *ours = (*ours).max(*count);
+                    }
+                }
+            }
+        }
+    }
+}
+
+// A missing entry is a file that was never seen, which is older than anything.
+pub fn compare(ours: Option<&Entry>, theirs: Option<&Entry>) -> Order {
+    let hash = |entry: Option<&Entry>| entry.and_then(|entry| entry.hash);
+    if hash(ours) == hash(theirs) {
+        return Order::Same;
+    }
+
+    let empty = Vector::new();
+    let ours = ours.map_or(&empty, |entry| &entry.vector);
+    let theirs = theirs.map_or(&empty, |entry| &entry.vector);
+    let includes = |a: &Vector, b: &Vector| {
+        b.iter()
+            .all(|(machine, count)| a.get(machine).unwrap_or(&0) >= count)
+    };
+    match (includes(ours, theirs), includes(theirs, ours)) {
+        _ if ours.is_empty() && theirs.is_empty() => Order::Unknown,
+        (true, false) => Order::Newer,
+        (false, true) => Order::Older,
+        // Equal vectors with different contents can only come from a machine
+        // that lost its table, so neither side can be trusted over the other
+        _ => Order::Conflict,
+    }
+}
+
+// Every path that differs between the two tables, with how ours compares.
+pub fn plan(ours: &Table, theirs: &Table) -> Vec<(String, Order)> {
+    let mut paths = ours.entries.keys().collect::<Vec<_>>();
+    paths.extend(theirs.entries.keys());
+    paths.sort();
+    paths.dedup();
+
+    paths
+        .into_iter()
+        .map(|path| {
+            let order = compare(ours.entries.get(path), theirs.entries.get(path));
+            (path.clone(), order)
+        })
+        .filter(|(_, order)| *order != Order::Same)
+        .collect()
+}
+
+// Scans this end and the remote, returning how each file that differs
+// compares, or None if the remote didn't answer.
+pub fn plan_with(
+    runner: &dyn Runner,
+    config: &Config,
+    host: &Host,
+    synctool: &str,
+) -> Result<Option<Vec<(String, Order)>>> {
+    Ok(tables(runner, config, host, synctool)?.map(|(ours, theirs)| plan(&ours, &theirs)))
+}
+
+// Scans the remote and this end, returning their tables, or None if the
+// remote didn't answer. It's asked first, so an asleep remote doesn't cost a
+// scan here.
+pub fn tables(
+    runner: &dyn Runner,
+    config: &Config,
+    host: &Host,
+    synctool: &str,
+) -> Result<Option<(Table, Table)>> {
+    let output = runner.output(
+        ssh(host)
+            .arg(format!("{} versions --scan", synctool))
+            .stdin(Stdio::null()),
+    )?;
+    if !output.status.success() {
+        return Ok(None);
+    }
+    let theirs = Table::parse(&String::from_utf8_lossy(&output.stdout))
+        .wrap_err_with(|| format!("Bad file versions from {}", host.name))?;
+
+    let mut ours = Table::load()?;
+    ours.scan(config, &hostname())?;
+    ours.save()?;
+    Ok(Some((ours, theirs)))
+}
+
+// The plan for the tables before a sync, warning about files changed on both
+// ends.
+pub fn before_sync(host: &Host, ours: &Table, theirs: &Table) -> Vec<(String, Order)> {
+    let plan = plan(ours, theirs);
+    let conflicts = plan
+        .iter()
+        .filter(|(_, order)| *order == Order::Conflict)
+        .collect::<Vec<_>>();
+    for (path, _) in conflicts.iter().take(10) {
+        warn!("{} changed on both ends since they last matched", path);
+    }
+    if conflicts.len() > 10 {
+        warn!(
+            "...and {} more files changed on both ends",
+            conflicts.len() - 10
+        );
+    }
+    if !conflicts.is_empty() {
+        notify::queue(
+            NotifyClass::Conflict,
+            format!(
+                "{} file(s) changed on both {} and {} since they last matched",
+                conflicts.len(),
+                host.name,
+                hostname()
+            ),
+        );
+    }
+    plan
+}
+
+// rsync excludes that keep a push from overwriting files that are newer on the
+// remote, or changed on both ends.
+pub fn rsync_excludes(plan: &[(String, Order)]) -> Vec<String> {
+    plan.iter()
+        .filter(|(_, order)| matches!(order, Order::Older | Order::Conflict))
+        .map(|(path, _)| {
+            // Wildcards in the path have to be taken literally
+            let mut pattern = String::new();
+            for c in path.chars() {
+                if matches!(c, '*' | '?' | '[' | '\\') {
+                    pattern.push('\\');
+                }
+                pattern.push(c);
+            }
+            format!("--exclude=/{}", pattern)
+        })
+        .collect()
+}
+
+// unison -preferpartial options that settle files one end has a newer copy
+// of. Beyond a few hundred the command line gets silly, and unison is left to
+// ask about them as it always has.
+pub fn unison_preferences(
+    plan: &[(String, Order)],
+    local_root: &str,
+    remote_root: &str,
+) -> Vec<String> {
+    let settled = plan
+        .iter()
+        .filter_map(|(path, order)| match order {
+            Order::Newer => Some((path, local_root)),
+            Order::Older => Some((path, remote_root)),
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+    if settled.len() > 300 {
+        debug!(
+            "Not telling unison which end is newer for {} files",
+            settled.len()
+        );
+        return Vec::new();
+    }
+
+    settled
+        .into_iter()
+        .flat_map(|(path, root)| {
+            [
+                "-preferpartial".to_string(),
+                format!("Path {} -> {}", path, root),
+            ]
+        })
+        .collect()
+}
+
+// After a successful sync, both ends rescan and take each other's history for
+// the files that now match.
+pub fn after_sync(runner: &dyn Runner, config: &Config, host: &Host, synctool: &str) -> Result<()> {
+    let mut ours = Table::load()?;
+    ours.scan(config, &hostname())?;
+
+    let mut process = runner.spawn(
+        ssh(host)
+            .arg(format!("{} versions --merge", synctool))
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped()),
+    )?;
+    if let Some(mut stdin) = process.take_stdin() {
+        stdin.write_all(ours.to_text().as_bytes())?;
+    }
+    let mut text = String::new();
+    if let Some(mut stdout) = process.take_stdout() {
+        stdout.read_to_string(&mut text)?;
+    }
+    if !process.wait()?.success() {
+        return Err(eyre!("Couldn't update the file versions on {}", host.name));
+    }
+
+    let theirs =
+        Table::parse(&text).wrap_err_with(|| format!("Bad file versions from {}", host.name))?;
+    ours.merge_matching(&theirs);
+    ours.save()
+}
+
+// Files under dir (at rel relative to the root) that aren't ignored, with
+// their size and mtime. Symlinks aren't followed or tracked.
+fn walk(
+    config: &Config,
+    dir: &Path,
+    rel: &str,
+    seen: &mut BTreeMap<String, (u64, i64)>,
+) -> Result<()> {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(err) if err.kind() == ErrorKind::NotFound => return Ok(()),
+        Err(err) => return Err(err).wrap_err_with(|| format!("Couldn't list {}", dir.display())),
+    };
+    for entry in entries {
+        let entry = entry?;
+        let name = entry.file_name().to_string_lossy().into_owned();
+        let path = if rel.is_empty() {
+            name.clone()
+        } else {
+            format!("{}/{}", rel, name)
+        };
+        // Tabs and newlines would break the table, and the partial dir is rsync's
+        if name.contains(['\t', '\n']) || name == ".rsync-partial" || ignored(config, &path) {
+            continue;
+        }
+
+        let metadata = entry.metadata()?;
+        if metadata.is_dir() {
+            walk(config, &entry.path(), &path, seen)?;
+        } else if metadata.is_file() {
+            seen.insert(path, (metadata.len(), metadata.mtime()));
+        }
+    }
+    Ok(())
+}
+
+// 64 bit FNV-1a, which is stable across builds unlike std's hasher. It's a
+// fast way to tell that contents changed, not proof that they're the same:
+// anything that acts on a match without a second look has to be fine with a
+// collision.
+pub(crate) fn hash_file(path: &Path) -> Result<u64> {
+    let mut file =
+        File::open(path).wrap_err_with(|| format!("Couldn't read {}", path.display()))?;
+    let mut hash: u64 = 0xcbf29ce484222325;
+    let mut buf = [0; 64 * 1024];
+    loop {
+        let n = file.read(&mut buf)?;
+        if n == 0 {
+            return Ok(hash);
+        }
+        for &byte in &buf[..n] {
+            hash ^= byte as u64;
+            hash = hash.wrapping_mul(0x100000001b3);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(hash: u64, vector: &[(&str, u64)]) -> Entry {
+        Entry {
+            hash: Some(hash),
+            size: 0,
+            mtime: 0,
+            vector: vector
+                .iter()
+                .map(|(machine, count)| (machine.to_string(), *count))
+                .collect(),
+        }
+    }
+
+    fn table(entries: &[(&str, Entry)]) -> Table {
+        Table {
+            entries: entries
+                .iter()
+                .map(|(path, entry)| (path.to_string(), entry.clone()))
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn orders() {
+        let old = entry(1, &[("laptop", 1)]);
+        let edited = entry(2, &[("laptop", 2)]);
+        let elsewhere = entry(3, &[("laptop", 1), ("rpi", 1)]);
+        assert_eq!(compare(Some(&edited), Some(&old)), Order::Newer);
+        assert_eq!(compare(Some(&old), Some(&edited)), Order::Older);
+        assert_eq!(compare(Some(&edited), Some(&elsewhere)), Order::Conflict);
+        assert_eq!(compare(None, Some(&old)), Order::Older);
+        assert_eq!(compare(Some(&old), Some(&entry(1, &[]))), Order::Same);
+        assert_eq!(
+            compare(Some(&entry(1, &[])), Some(&entry(2, &[]))),
+            Order::Unknown
+        );
+    }
+
+    // A change made on the laptop reaches the RPi through the desktop
+    #[test]
+    fn propagates_through_a_middle_machine() {
+        let original = entry(1, &[("laptop", 1)]);
+        let mut laptop = table(&[("f", entry(2, &[("laptop", 2)]))]);
+        let mut desktop = table(&[("f", original.clone())]);
+        let rpi = table(&[("f", original)]);
+
+        assert_eq!(plan(&laptop, &desktop), [("f".to_string(), Order::Newer)]);
+        // The sync copies the laptop's file to the desktop
+        desktop.entries.get_mut("f").unwrap().hash = Some(2);
+        desktop.merge_matching(&laptop);
+        laptop.merge_matching(&desktop);
+
+        assert_eq!(plan(&desktop, &rpi), [("f".to_string(), Order::Newer)]);
+        assert!(plan(&laptop, &desktop).is_empty());
+    }
+
+    #[test]
+    fn text_round_trip() {
+        let mut deleted = entry(0, &[("laptop", 2), ("desktop", 1)]);
+        deleted.hash = None;
+        let table = table(&[("a b/c", entry(0xabc, &[("laptop", 1)])), ("d", deleted)]);
+        let parsed = Table::parse(&table.to_text()).unwrap();
+        assert_eq!(parsed.entries, table.entries);
+        assert!(Table::parse("a\tzz\t1\t1\t").is_err());
+    }
+
+    #[test]
+    fn scans() {
+        let root = crate::TempDir::new("versions");
+        fs::create_dir_all(root.join("target")).unwrap();
+        fs::write(root.join("a"), "one").unwrap();
+        fs::write(root.join("target/x"), "ignored").unwrap();
+        let config = Config {
+            root: root.to_string_lossy().into_owned(),
+            ..Config::default()
+        };
+
+        let mut table = Table::default();
+        table.scan(&config, "me").unwrap();
+        assert_eq!(table.entries.keys().collect::<Vec<_>>(), ["a"]);
+        assert!(table.entries["a"].vector.is_empty());
+
+        fs::write(root.join("a"), "two!").unwrap();
+        table.scan(&config, "me").unwrap();
+        assert_eq!(table.entries["a"].vector["me"], 1);
+
+        table.scan(&config, "me").unwrap();
+        assert_eq!(table.entries["a"].vector["me"], 1);
+
+        fs::remove_file(root.join("a")).unwrap();
+        table.scan(&config, "me").unwrap();
+        assert_eq!(table.entries["a"].hash, None);
+        assert_eq!(table.entries["a"].vector["me"], 2);
+    }
+}
@@ -0,0 +1,345 @@
+// Waking hosts with their wake_command, usually run on an always-on machine
+// like the RPi, or their management controller if they have one, or else each
+// of their wake steps in turn until one brings them up, and checking whether
+// hosts are up with their probe, and at which of their addresses.
+
+use crate::{
+    audit, bmc,
+    config::{Config, Host, PowerMethod, Probe, WakeMethod, WakeStep},
+    events, homeassistant,
+    runner::Runner,
+    ssh, ups,
+};
+use eyre::{bail, ensure, Result};
+use std::{
+    iter,
+    net::{TcpStream, ToSocketAddrs},
+    process::{Command, Stdio},
+    thread::{self, sleep},
+    time::{Duration, Instant},
+};
+
+// Three quick pings, so a lost packet doesn't count as down, waiting at most
+// a second for replies
+pub fn ping(runner: &dyn Runner, host: &str) -> Result<bool> {
+    Ok(runner
+        .status(
+            Command::new("ping")
+                .args(["-c", "3", "-i", "0.2", "-W", "1", host])
+                .stdin(Stdio::null())
+                .stdout(Stdio::null())
+                .stderr(Stdio::null()),
+        )?
+        .success())
+}
+
+// Whether host answers its probe
+pub fn reachable(runner: &dyn Runner, host: &Host) -> Result<bool> {
+    let mut command = match &host.probe {
+        Probe::Ping => return ping(runner, &host.address),
+        Probe::Tcp(_) if runner.simulated() => return Ok(true),
+        Probe::Tcp(port) => {
+            let connected = (host.address.as_str(), *port)
+                .to_socket_addrs()
+                .ok()
+                .and_then(|mut addresses| addresses.next())
+                .is_some_and(|address| {
+                    TcpStream::connect_timeout(&address, Duration::from_secs(3)).is_ok()
+                });
+            return Ok(connected);
+        }
+        Probe::Ssh => {
+            let mut command = Command::new("ssh");
+            command
+                .args(["-o", "BatchMode=yes"])
+                .args(ssh::args(host))
+                .args([&host.address, "echo ok"]);
+            command
+        }
+        Probe::Http(url) => {
+            let mut command = Command::new("curl");
+            command.args(["-fsS", "-o", "/dev/null", "--max-time", "5", url]);
+            command
+        }
+    };
+    Ok(runner
+        .status(
+            command
+                .stdin(Stdio::null())
+                .stdout(Stdio::null())
+                .stderr(Stdio::null()),
+        )?
+        .success())
+}
+
+// Probes every host at once, so checking many takes as long as the slowest.
+// A probe that couldn't run counts as down.
+pub fn reachable_all(runner: &dyn Runner, hosts: &[&Host]) -> Vec<bool> {
+    thread::scope(|scope| {
+        let probes = hosts
+            .iter()
+            .map(|host| scope.spawn(move || reachable(runner, host).unwrap_or(false)))
+            .collect::<Vec<_>>();
+        probes
+            .into_iter()
+            .map(|probe| probe.join().unwrap_or(false))
+            .collect()
+    })
+}
+
+// The host with the quickest of its addresses to accept a connection (on the
+// probe's port, or ssh's) as its address. If none do, it's left as it is.
+pub fn fastest_address(runner: &dyn Runner, host: &Host) -> Host {
+    if host.addresses.is_empty() || runner.simulated() {
+        return host.clone();
+    }
+    let port = match host.probe {
+        Probe::Tcp(port) => port,
+        _ => 22,
+    };
+    let candidates = iter::once(&host.address)
+        .chain(&host.addresses)
+        .collect::<Vec<_>>();
+    let latencies = thread::scope(|scope| {
+        let probes = candidates
+            .iter()
+            .map(|address| {
+                scope.spawn(move || {
+                    let address = (address.as_str(), port).to_socket_addrs().ok()?.next()?;
+                    let start = Instant::now();
+                    TcpStream::connect_timeout(&address, Duration::from_secs(3)).ok()?;
+                    Some(start.elapsed())
+                })
+            })
+            .collect::<Vec<_>>();
+        probes
+            .into_iter()
+            .map(|probe| probe.join().ok().flatten())
+            .collect::<Vec<_>>()
+    });
+
+    let fastest = candidates
+        .iter()
+        .zip(latencies)
+        .filter_map(|(address, latency)| Some((*address, latency?)))
+        .min_by_key(|(_, latency)| *latency);
+    match fastest {
+        Some((address, latency)) => {
+            log!(
+                "Reaching {} at {} ({} ms)",
+                host.name,
+                address,
+                latency.as_millis()
+            );
+            Host {
+                address: address.clone(),
+                ..host.clone()
+            }
+        }
+        None => {
+            warn!("None of the addresses of {} answered", host.name);
+            host.clone()
+        }
+    }
+}
+
+pub fn wake_host(runner: &dyn Runner, config: &Config, host: &Host) -> Result<()> {
+    let result = events::phase("wake", || wake(runner, config, host));
+    let outcome = match &result {
+        Ok(()) => "ok".to_string(),
+        Err(err) => format!("{:#}", err),
+    };
+    audit::record(runner, "wake", &host.name, &outcome);
+    result
+}
+
+fn wake(runner: &dyn Runner, config: &Config, host: &Host) -> Result<()> {
+    match ups::on_battery(runner, host, false) {
+        Ok(Some(true)) => bail!(
+            "{} is on battery, so it's not being woken during a power cut",
+            host.name
+        ),
+        Ok(_) => {}
+        Err(err) => warn!(
+            "Couldn't check whether {} is on battery: {err:#}",
+            host.name
+        ),
+    }
+    phase!("Waking {}", host.name);
+    let default = [WakeStep {
+        method: match host.power_method {
+            PowerMethod::HomeAssistant => WakeMethod::HomeAssistant,
+            method if method.bmc() => WakeMethod::Bmc,
+            _ => WakeMethod::Command,
+        },
+        timeout: None,
+    }];
+    let steps = match host.wake.is_empty() {
+        true => &default[..],
+        false => &host.wake[..],
+    };
+
+    // On to the next way whenever one fails, or doesn't bring it up in time
+    for (i, step) in steps.iter().enumerate() {
+        if steps.len() > 1 {
+            log!("Trying {}", step);
+        }
+        let result = wake_with(runner, config, host, &step.method)
+            .and_then(|()| wait_for(runner, host, step.timeout.unwrap_or(host.wake_timeout)));
+        match result {
+            Ok(()) => return Ok(()),
+            Err(err) if i + 1 == steps.len() => return Err(err),
+            Err(err) => warn!("{:#}", err),
+        }
+    }
+    unreachable!("hosts always have a way of waking")
+}
+
+fn wake_with(runner: &dyn Runner, config: &Config, host: &Host, method: &WakeMethod) -> Result<()> {
+    match method {
+        WakeMethod::Bmc => bmc::power_on(runner, host)?,
+        WakeMethod::HomeAssistant => homeassistant::wake(runner, config, host)?,
+        WakeMethod::Command => match (&host.wake_command, &host.wake_via) {
+            (Some(command), Some(via)) => {
+                let via = config.host(via)?;
+                runner.output(ssh::ssh(via).arg(command))?;
+            }
+            (Some(command), None) => {
+                runner.output(Command::new("sh").args(["-c", command]))?;
+            }
+            (None, _) => bail!("Set wake_command for {} in the config", host.name),
+        },
+        WakeMethod::Wol { mac, via } => {
+            let mut command = match via {
+                Some(via) => {
+                    let mut command = ssh::ssh(config.host(via)?);
+                    command.arg(format!("wakeonlan {}", mac));
+                    command
+                }
+                None => {
+                    let mut command = Command::new("wakeonlan");
+                    command.arg(mac);
+                    command
+                }
+            };
+            let output = runner.output(command.stdin(Stdio::null()))?;
+            ensure!(
+                output.status.success(),
+                "wakeonlan {} failed{}",
+                mac,
+                via.as_ref()
+                    .map_or(String::new(), |via| format!(" on {}", via))
+            );
+        }
+        WakeMethod::Plug(urls) => {
+            for (i, url) in urls.iter().enumerate() {
+                if i > 0 {
+                    // Long enough for the machine to notice it lost power
+                    sleep(Duration::from_secs(10));
+                }
+                let output = runner.output(
+                    Command::new("curl")
+                        .args(["-fsS", "--max-time", "10", "-X", "POST", url])
+                        .stdin(Stdio::null()),
+                )?;
+                ensure!(
+                    output.status.success(),
+                    "Couldn't reach the plug at {}",
+                    url
+                );
+            }
+        }
+    }
+    Ok(())
+}
+
+// Probes the host every wake_poll_interval until it answers or the seconds
+// are up, saying how long it's been every so often
+fn wait_for(runner: &dyn Runner, host: &Host, seconds: u64) -> Result<()> {
+    log!("Waiting {} seconds for {} to turn on", seconds, host.name);
+    let timeout = Duration::from_secs(seconds);
+    let interval = Duration::from_secs(host.wake_poll_interval);
+    let start = Instant::now();
+    let mut shown = 0;
+    loop {
+        if reachable(runner, host)? {
+            log!("{} is up after {}s", host.name, start.elapsed().as_secs());
+            return Ok(());
+        }
+        let elapsed = start.elapsed();
+        ensure!(
+            elapsed < timeout,
+            "Could not reach {} within {} seconds",
+            host.name,
+            seconds
+        );
+        if elapsed.as_secs() / 15 > shown {
+            shown = elapsed.as_secs() / 15;
+            log!(
+                "Still waiting for {}: {}s so far, {}s left",
+                host.name,
+                elapsed.as_secs(),
+                (timeout - elapsed).as_secs()
+            );
+        }
+        sleep(interval.min(timeout - elapsed));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::runner::{MockRunner, SystemRunner};
+    use std::net::TcpListener;
+
+    #[test]
+    fn picks_an_address_that_answers() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let config = Config::parse(&format!(
+            "[hosts.nas]\naddress = \"nas.invalid\"\naddresses = [\"127.0.0.1\"]\nprobe = \"tcp:{}\"\n",
+            listener.local_addr().unwrap().port()
+        ))
+        .unwrap();
+
+        let nas = fastest_address(&SystemRunner, config.host("nas").unwrap());
+        assert_eq!(nas.address, "127.0.0.1");
+    }
+
+    #[test]
+    fn waits_between_probes() {
+        let runner = MockRunner::new();
+        runner.script("ping", &[1, 1, 0]);
+        let config =
+            Config::parse("[hosts.nas]\naddress = \"10.13.13.8\"\nwake_poll_interval = \"1s\"\n")
+                .unwrap();
+        let nas = config.host("nas").unwrap();
+        let start = Instant::now();
+        wait_for(&runner, nas, 60).unwrap();
+        assert!(start.elapsed() >= Duration::from_secs(2));
+        assert_eq!(runner.commands().len(), 3);
+
+        let runner = MockRunner::new();
+        runner.script("ping", &[1]);
+        assert!(wait_for(&runner, nas, 0).is_err());
+    }
+
+    #[test]
+    fn tries_each_way_in_turn() {
+        let runner = MockRunner::new();
+        runner.script("ping", &[1, 0]);
+        let config = Config::parse(
+            "[hosts.desktop]\nwake = [\"wol 00:11:22:33:44:55 for 0\", \"wol 00:11:22:33:44:55 via rpi\"]\n",
+        )
+        .unwrap();
+
+        wake(&runner, &config, config.host("desktop").unwrap()).unwrap();
+        let commands = runner.commands();
+        assert_eq!(commands[0], "wakeonlan 00:11:22:33:44:55");
+        assert!(commands[1].starts_with("ping"));
+        assert_eq!(
+            commands[2],
+            "ssh -o ConnectTimeout=8 10.13.13.6 wakeonlan 00:11:22:33:44:55"
+        );
+        assert_eq!(commands.len(), 4);
+    }
+}
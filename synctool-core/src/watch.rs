@@ -0,0 +1,210 @@
+// Watch mode: notices files changing under the root with inotify, so the
+// daemon can sync soon after an edit instead of at the next interval, and the
+// agent can tell the other end about edits here (see agent.rs).
+//
+// Changes are gathered into a batch, which is only synced once nothing has
+// changed for the debounce time, and with settle on, once every file that was
+// written to has been closed again. A burst of build output or a big git
+// checkout is then one sync rather than dozens. max_batch_wait bounds how long
+// a batch can be put off by changes that keep coming, like a log file that is
+// always open.
+//
+// Changes the ignores cover never count, and ignored directories like target
+// or node_modules aren't watched at all. Besides the config's ignores, that
+// includes whatever .gitignore and .syncignore files say, which are read again
+// when they change. Those only keep changes from starting a sync; the sync
+// itself still carries the files.
+
+use crate::{
+    config::Config,
+    ignore::{change_ignored, IgnoreFiles, IGNORE_FILES},
+};
+use std::{
+    collections::{HashMap, HashSet},
+    ffi::{CString, OsStr},
+    fs::{self, File},
+    io::{self, Read},
+    os::unix::{
+        ffi::OsStrExt,
+        io::{AsRawFd, FromRawFd},
+    },
+    path::{Path, PathBuf},
+    time::{Duration, Instant},
+};
+
+const MASK: u32 = libc::IN_MODIFY
+    | libc::IN_CLOSE_WRITE
+    | libc::IN_CREATE
+    | libc::IN_DELETE
+    | libc::IN_MOVED_FROM
+    | libc::IN_MOVED_TO
+    | libc::IN_DONT_FOLLOW
+    | libc::IN_ONLYDIR;
+
+pub struct Watcher {
+    pub root: String,
+    pub ignores: Vec<String>,
+    files: IgnoreFiles,
+    inotify: Option<File>,
+    // Watch descriptors and the directories they watch, relative to the root
+    dirs: HashMap<i32, PathBuf>,
+    warned_limit: bool,
+}
+
+impl Watcher {
+    pub fn new(root: &str, ignores: &[String]) -> Watcher {
+        let inotify = unsafe {
+            let fd = libc::inotify_init1(libc::IN_NONBLOCK | libc::IN_CLOEXEC);
+            (fd >= 0).then(|| File::from_raw_fd(fd))
+        };
+        let mut watcher = Watcher {
+            root: root.to_string(),
+            ignores: ignores.to_vec(),
+            files: IgnoreFiles::default(),
+            inotify,
+            dirs: HashMap::new(),
+            warned_limit: false,
+        };
+        if watcher.inotify.is_none() {
+            warn!("Couldn't watch {} for changes", root);
+        } else {
+            watcher.add_tree(Path::new(""));
+            log!("Watching {} directories for changes", watcher.dirs.len());
+        }
+        watcher
+    }
+
+    // Adds every pending change to batch. Pass a throwaway batch to forget
+    // them, e.g. the changes a sync itself made.
+    pub fn read(&mut self, batch: &mut Batch) {
+        let mut buf = [0u8; 16384];
+        loop {
+            let len = match self.inotify.as_mut().map(|inotify| inotify.read(&mut buf)) {
+                Some(Ok(len)) if len > 0 => len,
+                _ => return,
+            };
+            let mut offset = 0;
+            while offset + std::mem::size_of::<libc::inotify_event>() <= len {
+                let event = unsafe {
+                    std::ptr::read_unaligned(buf[offset..].as_ptr() as *const libc::inotify_event)
+                };
+                let name_start = offset + std::mem::size_of::<libc::inotify_event>();
+                let name = &buf[name_start..name_start + event.len as usize];
+                let name = &name[..name.iter().position(|&b| b == 0).unwrap_or(name.len())];
+                offset = name_start + event.len as usize;
+                self.handle(event.wd, event.mask, OsStr::from_bytes(name), batch);
+            }
+        }
+    }
+
+    fn handle(&mut self, wd: i32, mask: u32, name: &OsStr, batch: &mut Batch) {
+        if mask & libc::IN_Q_OVERFLOW != 0 {
+            // Some changes were lost, possibly new directories among them
+            self.add_tree(Path::new(""));
+            batch.change(PathBuf::new(), false);
+            return;
+        }
+        if mask & libc::IN_IGNORED != 0 {
+            self.dirs.remove(&wd);
+            return;
+        }
+        let dir = match self.dirs.get(&wd) {
+            Some(dir) => dir.clone(),
+            None => return,
+        };
+        let path = dir.join(name);
+        if IGNORE_FILES.iter().any(|file| name == *file) {
+            // Rereads the rules, and watches directories they no longer ignore
+            self.add_tree(&dir);
+        }
+        let is_dir = mask & libc::IN_ISDIR != 0;
+        if change_ignored(&self.ignores, &self.files, &path.to_string_lossy(), is_dir) {
+            return;
+        }
+        if is_dir && mask & (libc::IN_CREATE | libc::IN_MOVED_TO) != 0 {
+            self.add_tree(&path);
+        }
+        batch.change(path, mask & libc::IN_MODIFY != 0);
+    }
+
+    // Watches dir and every directory under it
+    fn add_tree(&mut self, dir: &Path) {
+        let inotify = match &self.inotify {
+            Some(inotify) => inotify,
+            None => return,
+        };
+        let full = Path::new(&self.root).join(dir);
+        let path = match CString::new(full.as_os_str().as_bytes()) {
+            Ok(path) => path,
+            Err(_) => return,
+        };
+        let wd = unsafe { libc::inotify_add_watch(inotify.as_raw_fd(), path.as_ptr(), MASK) };
+        if wd < 0 {
+            let err = io::Error::last_os_error();
+            if err.raw_os_error() == Some(libc::ENOSPC) && !self.warned_limit {
+                warn!("Ran out of inotify watches, raise fs.inotify.max_user_watches to watch everything");
+                self.warned_limit = true;
+            }
+            return;
+        }
+        self.dirs.insert(wd, dir.to_path_buf());
+        self.files
+            .load(Path::new(&self.root), &dir.to_string_lossy());
+
+        // Gone again already, or unreadable
+        let entries = match fs::read_dir(&full) {
+            Ok(entries) => entries,
+            Err(_) => return,
+        };
+        for entry in entries.flatten() {
+            let path = dir.join(entry.file_name());
+            let ignored =
+                || change_ignored(&self.ignores, &self.files, &path.to_string_lossy(), true);
+            if entry.file_type().is_ok_and(|kind| kind.is_dir()) && !ignored() {
+                self.add_tree(&path);
+            }
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct Batch {
+    first: Option<Instant>,
+    last: Option<Instant>,
+    changed: HashSet<PathBuf>,
+    // Files modified but not yet closed
+    writing: HashSet<PathBuf>,
+}
+
+impl Batch {
+    fn change(&mut self, path: PathBuf, still_writing: bool) {
+        let now = Instant::now();
+        self.first.get_or_insert(now);
+        self.last = Some(now);
+        if still_writing {
+            self.writing.insert(path.clone());
+        } else {
+            self.writing.remove(&path);
+        }
+        self.changed.insert(path);
+    }
+
+    // How many files changed
+    pub fn changed(&self) -> usize {
+        self.changed.len()
+    }
+
+    // Whether the batch should be synced now, by the [daemon] settings
+    pub fn ready(&self, config: &Config) -> bool {
+        let debounce = Duration::from_secs(config.daemon_debounce);
+        let max_wait = Duration::from_secs(config.daemon_max_batch_wait);
+        match (self.first, self.last) {
+            (Some(first), Some(last)) => {
+                first.elapsed() >= max_wait
+                    || (last.elapsed() >= debounce
+                        && (!config.daemon_settle || self.writing.is_empty()))
+            }
+            _ => false,
+        }
+    }
+}
@@ -0,0 +1,114 @@
+// End-to-end runs of the sync flows against this machine over ssh, with two
+// temporary directories standing in for the local and remote trees.
+//
+// These need `ssh localhost` to work without a password prompt, plus unison
+// and rsync installed, so they're ignored by default:
+//
+//     cargo test -p synctool-core --test loopback -- --ignored --test-threads=1
+//
+// Set SYNCTOOL_E2E_HOST to use a different address, e.g. a local sshd started
+// on another port through an ~/.ssh/config entry.
+
+use std::{
+    env, fs,
+    path::{Path, PathBuf},
+};
+use synctool_core::{
+    config::{quote, Config},
+    runner::SystemRunner,
+    sync::{sync_to_host, SyncOptions},
+};
+
+struct Fixture {
+    dir: PathBuf,
+    config: Config,
+}
+
+impl Fixture {
+    fn new(name: &str) -> Fixture {
+        let dir = env::temp_dir().join(format!("synctool-e2e-{}-{}", std::process::id(), name));
+        let _ = fs::remove_dir_all(&dir);
+        for sub in ["local", "remote", "state"] {
+            fs::create_dir_all(dir.join(sub)).unwrap();
+        }
+        // Keep move detection snapshots out of the real state dir
+        env::set_var("XDG_STATE_HOME", dir.join("state"));
+
+        let address = env::var("SYNCTOOL_E2E_HOST").unwrap_or_else(|_| "localhost".to_string());
+        let config = Config::parse(&format!(
+            "[sync]\nroot = {}\nignores = [\"Name target\"]\n\n[hosts.loopback]\naddress = {}\nroot = {}\n",
+            quote(&dir.join("local").to_string_lossy()),
+            quote(&address),
+            quote(&dir.join("remote").to_string_lossy()),
+        ))
+        .unwrap();
+
+        Fixture { dir, config }
+    }
+
+    fn local(&self, path: &str) -> PathBuf {
+        self.dir.join("local").join(path)
+    }
+
+    fn remote(&self, path: &str) -> PathBuf {
+        self.dir.join("remote").join(path)
+    }
+
+    fn sync(&self, sync_options: &SyncOptions) {
+        let host = self.config.host("loopback").unwrap();
+        sync_to_host(&SystemRunner, &self.config, host, sync_options).unwrap();
+    }
+}
+
+impl Drop for Fixture {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.dir);
+    }
+}
+
+fn write(path: &Path, contents: &str) {
+    fs::create_dir_all(path.parent().unwrap()).unwrap();
+    fs::write(path, contents).unwrap();
+}
+
+fn read(path: &Path) -> String {
+    fs::read_to_string(path).unwrap_or_else(|err| panic!("{}: {}", path.display(), err))
+}
+
+#[test]
+#[ignore]
+fn unison_syncs_both_ways() {
+    let fixture = Fixture::new("unison");
+    write(&fixture.local("notes/a.txt"), "from local");
+    write(&fixture.remote("notes/b.txt"), "from remote");
+    write(&fixture.local("proj/target/big.o"), "build output");
+
+    fixture.sync(&SyncOptions::default());
+
+    assert_eq!(read(&fixture.remote("notes/a.txt")), "from local");
+    assert_eq!(read(&fixture.local("notes/b.txt")), "from remote");
+    assert!(!fixture.remote("proj/target").exists());
+}
+
+#[test]
+#[ignore]
+fn rsync_pushes_and_replays_moves() {
+    let fixture = Fixture::new("rsync");
+    let sync_options = SyncOptions {
+        use_rsync: true,
+        jobs: 2,
+        ..SyncOptions::default()
+    };
+    write(&fixture.local("photos/2023/a.jpg"), "jpeg");
+    write(&fixture.local("code/target/big.o"), "build output");
+
+    fixture.sync(&sync_options);
+    assert_eq!(read(&fixture.remote("photos/2023/a.jpg")), "jpeg");
+    assert!(!fixture.remote("code/target").exists());
+
+    // Directories are tracked by inode, so the rename is replayed with mv
+    fs::rename(fixture.local("photos"), fixture.local("pictures")).unwrap();
+    fixture.sync(&sync_options);
+    assert_eq!(read(&fixture.remote("pictures/2023/a.jpg")), "jpeg");
+    assert!(!fixture.remote("photos/2023/a.jpg").exists());
+}